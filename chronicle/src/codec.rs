@@ -0,0 +1,711 @@
+//! Serialization of domain events to and from the byte payloads that are
+//! stored by event stores
+//!
+//! Event stores like `chronicle_postgres` only deal in bytes. An `EventCodec`
+//! describes how to convert between these payloads and the events that are
+//! used in the domain, and a `CodecEventStore` wraps a byte oriented event
+//! store in order to present a typed view of it.
+
+use futures::{Async, Future, Poll, Stream};
+use futures::future::{self, Either, FutureResult, MapErr};
+use serde::{Deserialize, Serialize};
+use serde_json::{self, Map, Value};
+use std::fmt;
+use std::marker::PhantomData;
+use std::string::FromUtf8Error;
+use uuid::Uuid;
+
+use {AsWrongExpectedVersion, EventStore, ExpectedVersion, NewEvent, PersistedEvent, Transaction};
+use {SequenceNumber, TransactionEntry, WrongExpectedVersion};
+
+
+/// Converts events to and from the payloads that are stored in an event store
+pub trait EventCodec {
+    /// The type of the events that are encoded by the codec
+    type Event;
+
+    /// An error that may be yielded when encoding or decoding events
+    type Error;
+
+    /// Convert the event to a payload
+    fn encode(&self, event: &Self::Event) -> Result<Vec<u8>, Self::Error>;
+
+    /// Convert a payload back to an event
+    fn decode(&self, payload: &[u8]) -> Result<Self::Event, Self::Error>;
+}
+
+
+/// A codec that stores events as JSON
+pub struct JsonCodec<Event> {
+    event: PhantomData<Event>,
+}
+
+
+impl<Event> JsonCodec<Event> {
+    /// Create a new JSON codec
+    pub fn new() -> JsonCodec<Event> {
+        JsonCodec { event: PhantomData }
+    }
+}
+
+
+impl<Event> Default for JsonCodec<Event> {
+    fn default() -> JsonCodec<Event> {
+        JsonCodec::new()
+    }
+}
+
+
+impl<Event> Clone for JsonCodec<Event> {
+    fn clone(&self) -> JsonCodec<Event> {
+        *self
+    }
+}
+
+
+impl<Event> Copy for JsonCodec<Event> {}
+
+
+impl<Event> fmt::Debug for JsonCodec<Event> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "JsonCodec")
+    }
+}
+
+
+impl<Event> EventCodec for JsonCodec<Event>
+    where Event: Serialize + Deserialize
+{
+    type Event = Event;
+    type Error = serde_json::Error;
+
+    fn encode(&self, event: &Event) -> Result<Vec<u8>, serde_json::Error> {
+        serde_json::to_vec(event)
+    }
+
+    fn decode(&self, payload: &[u8]) -> Result<Event, serde_json::Error> {
+        serde_json::from_slice(payload)
+    }
+}
+
+
+/// A codec that stores events in a compact binary form
+///
+/// Events are encoded using a subset of [MessagePack](http://msgpack.org/),
+/// which is self-describing, so unlike formats that rely on the shape of the
+/// type it supports everything that can be represented as JSON, including
+/// internally tagged enums.
+pub struct BinaryCodec<Event> {
+    event: PhantomData<Event>,
+}
+
+
+impl<Event> BinaryCodec<Event> {
+    /// Create a new binary codec
+    pub fn new() -> BinaryCodec<Event> {
+        BinaryCodec { event: PhantomData }
+    }
+}
+
+
+impl<Event> Default for BinaryCodec<Event> {
+    fn default() -> BinaryCodec<Event> {
+        BinaryCodec::new()
+    }
+}
+
+
+impl<Event> Clone for BinaryCodec<Event> {
+    fn clone(&self) -> BinaryCodec<Event> {
+        *self
+    }
+}
+
+
+impl<Event> Copy for BinaryCodec<Event> {}
+
+
+impl<Event> fmt::Debug for BinaryCodec<Event> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "BinaryCodec")
+    }
+}
+
+
+impl<Event> EventCodec for BinaryCodec<Event>
+    where Event: Serialize + Deserialize
+{
+    type Event = Event;
+    type Error = BinaryCodecError;
+
+    fn encode(&self, event: &Event) -> Result<Vec<u8>, BinaryCodecError> {
+        let value = serde_json::to_value(event)?;
+        let mut payload = Vec::new();
+        write_value(&mut payload, &value)?;
+        Ok(payload)
+    }
+
+    fn decode(&self, payload: &[u8]) -> Result<Event, BinaryCodecError> {
+        let mut reader = Reader {
+            payload: payload,
+            depth: 0,
+        };
+        let value = reader.read_value()?;
+
+        if !reader.payload.is_empty() {
+            return Err(BinaryCodecError::TrailingBytes);
+        }
+
+        Ok(serde_json::from_value(value)?)
+    }
+}
+
+
+/// An error that may be yielded by the `BinaryCodec`
+#[derive(Debug)]
+pub enum BinaryCodecError {
+    /// The event could not be converted to or from its serialized form
+    Serde(serde_json::Error),
+    /// A string, array or map was too long to be encoded
+    TooLong(usize),
+    /// The payload ended before a value was fully decoded
+    UnexpectedEof,
+    /// The payload contained a marker for a type that is not supported
+    UnsupportedMarker(u8),
+    /// A map in the payload had a key that was not a string
+    NonStringKey,
+    /// A string in the payload was not valid UTF-8
+    InvalidUtf8(FromUtf8Error),
+    /// There were bytes remaining in the payload after decoding the event
+    TrailingBytes,
+    /// The arrays and maps in the payload were nested more than `MAX_DEPTH`
+    /// levels deep
+    TooDeep,
+}
+
+
+impl From<serde_json::Error> for BinaryCodecError {
+    fn from(src: serde_json::Error) -> BinaryCodecError {
+        BinaryCodecError::Serde(src)
+    }
+}
+
+
+impl From<FromUtf8Error> for BinaryCodecError {
+    fn from(src: FromUtf8Error) -> BinaryCodecError {
+        BinaryCodecError::InvalidUtf8(src)
+    }
+}
+
+
+/// Write a marker, followed by the lowest `size` bytes of `n` in big endian
+/// order
+fn write_be(payload: &mut Vec<u8>, marker: u8, n: u64, size: usize) {
+    payload.push(marker);
+    for i in (0..size).rev() {
+        payload.push((n >> (i * 8)) as u8);
+    }
+}
+
+
+/// Write a string, prefixed by its length
+fn write_str(payload: &mut Vec<u8>, s: &str) -> Result<(), BinaryCodecError> {
+    match s.len() {
+        len if len < 32 => payload.push(0xa0 | len as u8),
+        len if len <= 0xff => write_be(payload, 0xd9, len as u64, 1),
+        len if len <= 0xffff => write_be(payload, 0xda, len as u64, 2),
+        len if len as u64 <= 0xffff_ffff => write_be(payload, 0xdb, len as u64, 4),
+        len => return Err(BinaryCodecError::TooLong(len)),
+    }
+
+    payload.extend_from_slice(s.as_bytes());
+    Ok(())
+}
+
+
+/// Write the length of an array or map, using the markers for lengths that
+/// fit in 4, 16 and 32 bits respectively
+fn write_len(payload: &mut Vec<u8>,
+             len: usize,
+             markers: (u8, u8, u8))
+             -> Result<(), BinaryCodecError> {
+    match len {
+        len if len < 16 => payload.push(markers.0 | len as u8),
+        len if len <= 0xffff => write_be(payload, markers.1, len as u64, 2),
+        len if len as u64 <= 0xffff_ffff => write_be(payload, markers.2, len as u64, 4),
+        len => return Err(BinaryCodecError::TooLong(len)),
+    }
+
+    Ok(())
+}
+
+
+fn write_value(payload: &mut Vec<u8>, value: &Value) -> Result<(), BinaryCodecError> {
+    match *value {
+        Value::Null => payload.push(0xc0),
+        Value::Bool(false) => payload.push(0xc2),
+        Value::Bool(true) => payload.push(0xc3),
+        Value::Number(ref n) => {
+            if let Some(n) = n.as_u64() {
+                match n {
+                    n if n < 0x80 => payload.push(n as u8),
+                    n if n <= 0xff => write_be(payload, 0xcc, n, 1),
+                    n if n <= 0xffff => write_be(payload, 0xcd, n, 2),
+                    n if n <= 0xffff_ffff => write_be(payload, 0xce, n, 4),
+                    n => write_be(payload, 0xcf, n, 8),
+                }
+            } else if let Some(n) = n.as_i64() {
+                match n {
+                    n if n >= -32 => payload.push(n as u8),
+                    n if n >= -0x80 => write_be(payload, 0xd0, n as u64, 1),
+                    n if n >= -0x8000 => write_be(payload, 0xd1, n as u64, 2),
+                    n if n >= -0x8000_0000 => write_be(payload, 0xd2, n as u64, 4),
+                    n => write_be(payload, 0xd3, n as u64, 8),
+                }
+            } else if let Some(n) = n.as_f64() {
+                write_be(payload, 0xcb, n.to_bits(), 8);
+            }
+        },
+        Value::String(ref s) => write_str(payload, s)?,
+        Value::Array(ref values) => {
+            write_len(payload, values.len(), (0x90, 0xdc, 0xdd))?;
+            for value in values {
+                write_value(payload, value)?;
+            }
+        },
+        Value::Object(ref entries) => {
+            write_len(payload, entries.len(), (0x80, 0xde, 0xdf))?;
+            for (key, value) in entries {
+                write_str(payload, key)?;
+                write_value(payload, value)?;
+            }
+        },
+    }
+
+    Ok(())
+}
+
+
+/// The maximum number of arrays and maps that can be nested inside each
+/// other when decoding a binary payload. This stops malicious or corrupt
+/// payloads from overflowing the stack.
+pub const MAX_DEPTH: usize = 128;
+
+
+/// Reads values from the front of a binary payload
+struct Reader<'a> {
+    payload: &'a [u8],
+    /// The number of arrays and maps that the reader is currently inside
+    depth: usize,
+}
+
+
+impl<'a> Reader<'a> {
+    fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], BinaryCodecError> {
+        if self.payload.len() < len {
+            return Err(BinaryCodecError::UnexpectedEof);
+        }
+
+        let (bytes, rest) = self.payload.split_at(len);
+        self.payload = rest;
+        Ok(bytes)
+    }
+
+    fn read_be(&mut self, size: usize) -> Result<u64, BinaryCodecError> {
+        let bytes = self.read_bytes(size)?;
+        Ok(bytes.iter().fold(0, |n, &byte| (n << 8) | byte as u64))
+    }
+
+    fn read_string(&mut self, len: usize) -> Result<String, BinaryCodecError> {
+        let bytes = self.read_bytes(len)?;
+        Ok(String::from_utf8(bytes.to_vec())?)
+    }
+
+    /// Enter an array or map, failing if it would be nested too deeply
+    fn enter(&mut self) -> Result<(), BinaryCodecError> {
+        if self.depth >= MAX_DEPTH {
+            return Err(BinaryCodecError::TooDeep);
+        }
+
+        self.depth += 1;
+        Ok(())
+    }
+
+    fn read_array(&mut self, len: usize) -> Result<Value, BinaryCodecError> {
+        self.enter()?;
+        let mut values = Vec::new();
+        for _ in 0..len {
+            values.push(self.read_value()?);
+        }
+        self.depth -= 1;
+        Ok(Value::Array(values))
+    }
+
+    fn read_map(&mut self, len: usize) -> Result<Value, BinaryCodecError> {
+        self.enter()?;
+        let mut entries = Map::new();
+        for _ in 0..len {
+            let key = match self.read_value()? {
+                Value::String(key) => key,
+                _ => return Err(BinaryCodecError::NonStringKey),
+            };
+            entries.insert(key, self.read_value()?);
+        }
+        self.depth -= 1;
+        Ok(Value::Object(entries))
+    }
+
+    fn read_value(&mut self) -> Result<Value, BinaryCodecError> {
+        let marker = self.read_bytes(1)?[0];
+
+        Ok(match marker {
+            marker if marker <= 0x7f => Value::from(marker),
+            marker if marker >= 0xe0 => Value::from(marker as i8),
+            marker if marker & 0xf0 == 0x80 => self.read_map((marker & 0x0f) as usize)?,
+            marker if marker & 0xf0 == 0x90 => self.read_array((marker & 0x0f) as usize)?,
+            marker if marker & 0xe0 == 0xa0 => {
+                Value::String(self.read_string((marker & 0x1f) as usize)?)
+            },
+            0xc0 => Value::Null,
+            0xc2 => Value::Bool(false),
+            0xc3 => Value::Bool(true),
+            0xca => Value::from(f32::from_bits(self.read_be(4)? as u32)),
+            0xcb => Value::from(f64::from_bits(self.read_be(8)?)),
+            0xcc => Value::from(self.read_be(1)?),
+            0xcd => Value::from(self.read_be(2)?),
+            0xce => Value::from(self.read_be(4)?),
+            0xcf => Value::from(self.read_be(8)?),
+            0xd0 => Value::from(self.read_be(1)? as u8 as i8),
+            0xd1 => Value::from(self.read_be(2)? as u16 as i16),
+            0xd2 => Value::from(self.read_be(4)? as u32 as i32),
+            0xd3 => Value::from(self.read_be(8)? as i64),
+            0xd9..=0xdb => {
+                let len = self.read_be(1 << (marker - 0xd9))?;
+                Value::String(self.read_string(len as usize)?)
+            },
+            0xdc | 0xdd => {
+                let len = self.read_be(2 << (marker - 0xdc))?;
+                self.read_array(len as usize)?
+            },
+            0xde | 0xdf => {
+                let len = self.read_be(2 << (marker - 0xde))?;
+                self.read_map(len as usize)?
+            },
+            marker => return Err(BinaryCodecError::UnsupportedMarker(marker)),
+        })
+    }
+}
+
+
+/// An event store that encodes and decodes the events of an underlying byte
+/// oriented event store using a codec
+#[derive(Debug, Clone)]
+pub struct CodecEventStore<S, C> {
+    event_store: S,
+    codec: C,
+}
+
+
+impl<S, C> CodecEventStore<S, C> {
+    /// Wrap the event store, using the codec to convert its payloads
+    pub fn new(event_store: S, codec: C) -> CodecEventStore<S, C> {
+        CodecEventStore {
+            event_store: event_store,
+            codec: codec,
+        }
+    }
+
+    /// The underlying event store
+    pub fn event_store(&self) -> &S {
+        &self.event_store
+    }
+
+    /// The codec used to convert payloads
+    pub fn codec(&self) -> &C {
+        &self.codec
+    }
+
+    /// Encode the payloads of the events using the codec
+    fn encode_events(&self,
+                     events: Vec<NewEvent<C::Event>>)
+                     -> Result<Vec<NewEvent<Vec<u8>>>, C::Error>
+        where C: EventCodec
+    {
+        events.into_iter()
+            .map(|event| {
+                let payload = self.codec.encode(&event.payload)?;
+                Ok(event.map(|_| payload))
+            })
+            .collect()
+    }
+}
+
+
+impl<S, C> EventStore for CodecEventStore<S, C>
+    where S: EventStore<Event = Vec<u8>>,
+          C: EventCodec + Clone
+{
+    type Offset = S::Offset;
+    type Event = C::Event;
+    type AppendError = CodecStoreError<C::Error, S::AppendError>;
+    type AppendFuture = Either<FutureResult<(), Self::AppendError>,
+                               MapErr<S::AppendFuture,
+                                      fn(S::AppendError) -> Self::AppendError>>;
+    type EventsStream = DecodeStream<S::EventsStream, C>;
+    type AllEventsStream = DecodeStream<S::AllEventsStream, C>;
+
+    fn append_events(&self,
+                     source_id: Uuid,
+                     expected_version: ExpectedVersion,
+                     events: Vec<NewEvent<C::Event>>)
+                     -> Self::AppendFuture {
+        match self.encode_events(events) {
+            Ok(events) => {
+                let store_error = CodecStoreError::Store as fn(_) -> _;
+                let future = self.event_store.append_events(source_id, expected_version, events);
+                Either::B(future.map_err(store_error))
+            },
+            Err(err) => Either::A(future::err(CodecStoreError::Codec(err))),
+        }
+    }
+
+    fn append_events_idempotent(&self,
+                                idempotency_key: Uuid,
+                                source_id: Uuid,
+                                expected_version: ExpectedVersion,
+                                events: Vec<NewEvent<C::Event>>)
+                                -> Self::AppendFuture {
+        match self.encode_events(events) {
+            Ok(events) => {
+                let store_error = CodecStoreError::Store as fn(_) -> _;
+                let future = self.event_store.append_events_idempotent(idempotency_key,
+                                                                       source_id,
+                                                                       expected_version,
+                                                                       events);
+                Either::B(future.map_err(store_error))
+            },
+            Err(err) => Either::A(future::err(CodecStoreError::Codec(err))),
+        }
+    }
+
+    fn append_transaction(&self, transaction: Transaction<C::Event>) -> Self::AppendFuture {
+        let entries = transaction.entries
+            .into_iter()
+            .map(|entry| {
+                Ok(TransactionEntry {
+                    source_id: entry.source_id,
+                    expected_version: entry.expected_version,
+                    events: self.encode_events(entry.events)?,
+                })
+            })
+            .collect::<Result<Vec<_>, _>>();
+
+        match entries {
+            Ok(entries) => {
+                let store_error = CodecStoreError::Store as fn(_) -> _;
+                let future = self.event_store.append_transaction(Transaction { entries: entries });
+                Either::B(future.map_err(store_error))
+            },
+            Err(err) => Either::A(future::err(CodecStoreError::Codec(err))),
+        }
+    }
+
+    fn events(&self, source_id: Uuid, offset: S::Offset) -> Self::EventsStream {
+        DecodeStream {
+            stream: self.event_store.events(source_id, offset),
+            codec: self.codec.clone(),
+        }
+    }
+
+    fn events_from(&self, source_id: Uuid, sequence_number: SequenceNumber) -> Self::EventsStream {
+        DecodeStream {
+            stream: self.event_store.events_from(source_id, sequence_number),
+            codec: self.codec.clone(),
+        }
+    }
+
+    fn all_events(&self, offset: S::Offset) -> Self::AllEventsStream {
+        DecodeStream {
+            stream: self.event_store.all_events(offset),
+            codec: self.codec.clone(),
+        }
+    }
+
+    fn all_events_of_types(&self,
+                           event_types: &[&str],
+                           offset: S::Offset)
+                           -> Self::AllEventsStream {
+        DecodeStream {
+            stream: self.event_store.all_events_of_types(event_types, offset),
+            codec: self.codec.clone(),
+        }
+    }
+}
+
+
+/// An error that may be yielded by a `CodecEventStore`
+#[derive(Debug, Clone, PartialEq)]
+pub enum CodecStoreError<CodecError, StoreError> {
+    /// An error occurred when encoding or decoding an event
+    Codec(CodecError),
+    /// An error occurred in the underlying event store
+    Store(StoreError),
+}
+
+
+impl<CodecError, StoreError> AsWrongExpectedVersion for CodecStoreError<CodecError, StoreError>
+    where StoreError: AsWrongExpectedVersion
+{
+    fn as_wrong_expected_version(&self) -> Option<&WrongExpectedVersion> {
+        match *self {
+            CodecStoreError::Codec(_) => None,
+            CodecStoreError::Store(ref error) => error.as_wrong_expected_version(),
+        }
+    }
+}
+
+
+/// A stream that decodes the payloads of the events in an underlying stream
+#[derive(Debug)]
+pub struct DecodeStream<S, C> {
+    stream: S,
+    codec: C,
+}
+
+
+impl<S, C, Offset> Stream for DecodeStream<S, C>
+    where S: Stream<Item = PersistedEvent<Offset, Vec<u8>>>,
+          C: EventCodec
+{
+    type Item = PersistedEvent<Offset, C::Event>;
+    type Error = CodecStoreError<C::Error, S::Error>;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        match self.stream.poll().map_err(CodecStoreError::Store)? {
+            Async::Ready(Some(event)) => {
+                let payload = self.codec.decode(&event.payload).map_err(CodecStoreError::Codec)?;
+                Ok(Async::Ready(Some(event.map(|_| payload))))
+            },
+            Async::Ready(None) => Ok(Async::Ready(None)),
+            Async::NotReady => Ok(Async::NotReady),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+
+
+    fn round_trip<C: EventCodec>(codec: &C, event: &C::Event) -> C::Event
+        where C::Error: fmt::Debug
+    {
+        codec.decode(&codec.encode(event).unwrap()).unwrap()
+    }
+
+
+    fn sample_values() -> Vec<Value> {
+        vec![json!(null),
+             json!(true),
+             json!(false),
+             json!(0),
+             json!(127),
+             json!(128),
+             json!(70000),
+             json!(u64::MAX),
+             json!(-1),
+             json!(-33),
+             json!(-40000),
+             json!(i64::MIN),
+             json!(1.5),
+             json!(""),
+             json!("x".repeat(40)),
+             json!("x".repeat(300)),
+             json!((0..20).collect::<Vec<_>>()),
+             json!({ "type": "created", "description": "Buy milk", "tags": ["a", "b"] })]
+    }
+
+
+    #[test]
+    fn json_round_trip() {
+        let codec = JsonCodec::new();
+
+        for value in sample_values() {
+            assert_eq!(round_trip(&codec, &value), value);
+        }
+    }
+
+
+    #[test]
+    fn binary_round_trip() {
+        let codec = BinaryCodec::new();
+
+        for value in sample_values() {
+            assert_eq!(round_trip(&codec, &value), value);
+        }
+    }
+
+
+    #[test]
+    fn binary_is_compact() {
+        let codec = BinaryCodec::new();
+
+        assert_eq!(codec.encode(&json!(5)).unwrap(), vec![0x05]);
+        assert_eq!(codec.encode(&json!(-5)).unwrap(), vec![0xfb]);
+        assert_eq!(codec.encode(&json!("ab")).unwrap(), vec![0xa2, b'a', b'b']);
+        assert_eq!(codec.encode(&json!({ "a": [true] })).unwrap(),
+                   vec![0x81, 0xa1, b'a', 0x91, 0xc3]);
+    }
+
+
+    #[test]
+    fn binary_decode_errors() {
+        let codec = BinaryCodec::<Value>::new();
+
+        match codec.decode(&[0xa2, b'a']) {
+            Err(BinaryCodecError::UnexpectedEof) => {},
+            result => panic!("unexpected result: {:?}", result),
+        }
+        match codec.decode(&[0xc1]) {
+            Err(BinaryCodecError::UnsupportedMarker(0xc1)) => {},
+            result => panic!("unexpected result: {:?}", result),
+        }
+        match codec.decode(&[0xc0, 0xc0]) {
+            Err(BinaryCodecError::TrailingBytes) => {},
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+
+    #[test]
+    fn binary_decode_nested_values() {
+        let codec = BinaryCodec::<Value>::new();
+
+        // Arrays nested up to the maximum depth are decoded
+        let mut payload = vec![0x91; MAX_DEPTH];
+        payload.push(0xc0);
+        let mut expected = json!(null);
+        for _ in 0..MAX_DEPTH {
+            expected = json!([expected]);
+        }
+        assert_eq!(codec.decode(&payload).unwrap(), expected);
+
+        // Deeper nesting is rejected rather than overflowing the stack
+        for &marker in &[0x91, 0x81] {
+            let mut payload = Vec::new();
+            for _ in 0..100_000 {
+                payload.push(marker);
+                if marker == 0x81 {
+                    payload.extend_from_slice(&[0xa1, b'a']);
+                }
+            }
+            match codec.decode(&payload) {
+                Err(BinaryCodecError::TooDeep) => {},
+                result => panic!("unexpected result: {:?}", result),
+            }
+        }
+    }
+}
@@ -0,0 +1,568 @@
+//! A conformance suite for `EventStore` implementations
+//!
+//! Each check is exposed as a function that takes an empty event store and a
+//! way of creating distinct payloads, panicking if the event store does not
+//! behave as expected. The `event_store_conformance_tests!` macro generates a
+//! test for each of these checks:
+//!
+//! ```rust,ignore
+//! #[macro_use]
+//! extern crate chronicle;
+//!
+//! mod conformance {
+//!     event_store_conformance_tests! {
+//!         new_store: MemoryEventStore::new(),
+//!         payload: |i| vec![i as u8],
+//!     }
+//! }
+//! ```
+//!
+//! Attributes, like `#[ignore]`, may be supplied before the store and will be
+//! applied to every test. Event stores that share their storage between
+//! tests should isolate each test, for example using a scratch schema. The
+//! concurrency checks append from clones of the event store on separate
+//! threads, so the clones should not share a connection or a transaction,
+//! otherwise the storage's own concurrency control goes untested.
+
+use futures::{Future, Stream};
+use std::fmt;
+use std::ops::Range;
+use std::thread;
+use uuid::Uuid;
+
+use {EventStore, ExpectedVersion, Metadata, NewEvent, PersistedEvent, Transaction};
+
+
+/// Generate a test for each of the checks in the conformance suite
+#[macro_export]
+macro_rules! event_store_conformance_tests {
+    ($(#[$attr:meta])* new_store: $new_store:expr, payload: $payload:expr $(,)*) => {
+        event_store_conformance_tests! {
+            @tests [$($attr),*] $new_store, $payload;
+            append_and_read_events,
+            read_events_from_offset,
+            read_events_from_sequence_number,
+            non_contiguous_global_offsets,
+            concurrent_appends,
+            empty_appends,
+            wrong_expected_version,
+            concurrent_conflicting_appends,
+            idempotent_appends,
+            idempotency_keys_per_source,
+            concurrent_idempotent_appends,
+            transactional_appends,
+            concurrent_conflicting_transactions
+        }
+    };
+    (@tests [$($attr:meta),*] $new_store:expr, $payload:expr; ) => {};
+    (@tests [$($attr:meta),*] $new_store:expr, $payload:expr; $name:ident $(, $rest:ident)*) => {
+        #[test]
+        $(#[$attr])*
+        fn $name() {
+            $crate::conformance::$name(&$new_store, $payload);
+        }
+
+        event_store_conformance_tests! {
+            @tests [$($attr),*] $new_store, $payload; $($rest),*
+        }
+    };
+}
+
+
+fn new_events<Event, F>(payload: &F, range: Range<usize>) -> Vec<NewEvent<Event>>
+    where F: Fn(usize) -> Event
+{
+    range.map(|i| NewEvent::with_type("conformance", 0, Metadata::new(), payload(i))).collect()
+}
+
+
+fn append<S, F>(event_store: &S,
+                source_id: Uuid,
+                expected_version: ExpectedVersion,
+                payload: &F,
+                range: Range<usize>)
+                -> Result<(), S::AppendError>
+    where S: EventStore,
+          F: Fn(usize) -> S::Event
+{
+    event_store.append_events(source_id, expected_version, new_events(payload, range)).wait()
+}
+
+
+fn append_idempotent<S, F>(event_store: &S,
+                           idempotency_key: Uuid,
+                           source_id: Uuid,
+                           expected_version: ExpectedVersion,
+                           payload: &F,
+                           range: Range<usize>)
+                           -> Result<(), S::AppendError>
+    where S: EventStore,
+          F: Fn(usize) -> S::Event
+{
+    event_store.append_events_idempotent(idempotency_key,
+                                         source_id,
+                                         expected_version,
+                                         new_events(payload, range))
+        .wait()
+}
+
+
+fn events<S>(event_store: &S,
+             source_id: Uuid,
+             offset: S::Offset)
+             -> Vec<PersistedEvent<S::Offset, S::Event>>
+    where S: EventStore,
+          <S::EventsStream as Stream>::Error: fmt::Debug
+{
+    event_store.events(source_id, offset).collect().wait().expect("failed to stream events")
+}
+
+
+/// Every event for the given sources, in order of their global offsets.
+/// Events for other sources are ignored, allowing event stores to be shared
+/// between tests.
+fn all_events<S>(event_store: &S,
+                 source_ids: &[Uuid],
+                 offset: S::Offset)
+                 -> Vec<PersistedEvent<S::Offset, S::Event>>
+    where S: EventStore,
+          <S::AllEventsStream as Stream>::Error: fmt::Debug
+{
+    event_store.all_events(offset)
+        .filter(|event| source_ids.contains(&event.source_id))
+        .collect()
+        .wait()
+        .expect("failed to stream all events")
+}
+
+
+fn payloads<Offset, Event>(events: Vec<PersistedEvent<Offset, Event>>) -> Vec<Event> {
+    events.into_iter().map(|event| event.payload).collect()
+}
+
+
+fn assert_increasing<Offset, Event>(events: &[PersistedEvent<Offset, Event>])
+    where Offset: PartialOrd + fmt::Debug
+{
+    for window in events.windows(2) {
+        assert!(window[0].offset < window[1].offset,
+                "offsets are not increasing: {:?} followed by {:?}",
+                window[0].offset,
+                window[1].offset);
+    }
+}
+
+
+/// Events are read back in the order that they were appended, with
+/// sequence numbers starting at zero
+pub fn append_and_read_events<S, F>(event_store: &S, payload: F)
+    where S: EventStore,
+          S::Offset: fmt::Debug,
+          S::Event: fmt::Debug + PartialEq,
+          S::AppendError: fmt::Debug,
+          <S::EventsStream as Stream>::Error: fmt::Debug,
+          F: Fn(usize) -> S::Event
+{
+    let source_id = Uuid::new_v4();
+
+    append(event_store, source_id, ExpectedVersion::NoStream, &payload, 0..2).unwrap();
+    append(event_store, source_id, ExpectedVersion::Exact(1), &payload, 2..3).unwrap();
+
+    let events = events(event_store, source_id, S::Offset::default());
+
+    assert_increasing(&events);
+    assert!(events.iter().all(|event| event.source_id == source_id));
+    assert!(events.iter().all(|event| event.event_type == "conformance"));
+    assert_eq!(events.iter().map(|event| event.sequence_number).collect::<Vec<_>>(),
+               vec![0, 1, 2]);
+    assert_eq!(payloads(events), vec![payload(0), payload(1), payload(2)]);
+    assert_eq!(payloads(self::events(event_store, Uuid::new_v4(), S::Offset::default())),
+               vec![]);
+}
+
+
+/// Streams only include the events at or after the given offset
+pub fn read_events_from_offset<S, F>(event_store: &S, payload: F)
+    where S: EventStore,
+          S::Offset: Clone + fmt::Debug,
+          S::Event: fmt::Debug + PartialEq,
+          S::AppendError: fmt::Debug,
+          <S::EventsStream as Stream>::Error: fmt::Debug,
+          <S::AllEventsStream as Stream>::Error: fmt::Debug,
+          F: Fn(usize) -> S::Event
+{
+    let source_id = Uuid::new_v4();
+
+    append(event_store, source_id, ExpectedVersion::NoStream, &payload, 0..3).unwrap();
+
+    let offset = events(event_store, source_id, S::Offset::default())[1].offset.clone();
+    let events = events(event_store, source_id, offset.clone());
+
+    assert_eq!(events.iter().map(|event| event.sequence_number).collect::<Vec<_>>(),
+               vec![1, 2]);
+    assert_eq!(payloads(events), vec![payload(1), payload(2)]);
+    assert_eq!(payloads(all_events(event_store, &[source_id], offset)),
+               vec![payload(1), payload(2)]);
+}
+
+
+/// Streams from a sequence number only include the events of the source at or
+/// after that sequence number, even when other sources were appended to in
+/// between
+pub fn read_events_from_sequence_number<S, F>(event_store: &S, payload: F)
+    where S: EventStore,
+          S::Event: fmt::Debug + PartialEq,
+          S::AppendError: fmt::Debug,
+          <S::EventsStream as Stream>::Error: fmt::Debug,
+          F: Fn(usize) -> S::Event
+{
+    let (source_id, other_id) = (Uuid::new_v4(), Uuid::new_v4());
+
+    append(event_store, source_id, ExpectedVersion::NoStream, &payload, 0..2).unwrap();
+    append(event_store, other_id, ExpectedVersion::NoStream, &payload, 2..4).unwrap();
+    append(event_store, source_id, ExpectedVersion::Exact(1), &payload, 4..6).unwrap();
+
+    let events_from = |sequence_number| {
+        event_store.events_from(source_id, sequence_number)
+            .collect()
+            .wait()
+            .expect("failed to stream events")
+    };
+
+    let events = events_from(1);
+    assert_eq!(events.iter().map(|event| event.sequence_number).collect::<Vec<_>>(),
+               vec![1, 2, 3]);
+    assert_eq!(payloads(events), vec![payload(1), payload(4), payload(5)]);
+    assert_eq!(payloads(events_from(0)).len(), 4);
+    assert_eq!(payloads(events_from(4)), vec![]);
+}
+
+
+/// The global offsets of the events in a source may have gaps where events
+/// were appended to other sources, but the global log is always ordered by
+/// offset
+pub fn non_contiguous_global_offsets<S, F>(event_store: &S, payload: F)
+    where S: EventStore,
+          S::Offset: Clone + fmt::Debug,
+          S::Event: fmt::Debug + PartialEq,
+          S::AppendError: fmt::Debug,
+          <S::EventsStream as Stream>::Error: fmt::Debug,
+          <S::AllEventsStream as Stream>::Error: fmt::Debug,
+          F: Fn(usize) -> S::Event
+{
+    let source_id_1 = Uuid::new_v4();
+    let source_id_2 = Uuid::new_v4();
+
+    append(event_store, source_id_1, ExpectedVersion::NoStream, &payload, 0..1).unwrap();
+    append(event_store, source_id_2, ExpectedVersion::NoStream, &payload, 1..2).unwrap();
+    append(event_store, source_id_1, ExpectedVersion::Exact(0), &payload, 2..3).unwrap();
+
+    let all_events = all_events(event_store, &[source_id_1, source_id_2], S::Offset::default());
+
+    assert_increasing(&all_events);
+    assert_eq!(all_events.iter().map(|event| event.source_id).collect::<Vec<_>>(),
+               vec![source_id_1, source_id_2, source_id_1]);
+
+    let gap = all_events[1].offset.clone();
+    let events_1 = events(event_store, source_id_1, S::Offset::default());
+
+    assert_increasing(&events_1);
+    assert_eq!(events_1.iter().map(|event| event.sequence_number).collect::<Vec<_>>(),
+               vec![0, 1]);
+    assert_eq!(payloads(events_1), vec![payload(0), payload(2)]);
+    assert_eq!(payloads(events(event_store, source_id_1, gap)), vec![payload(2)]);
+    assert_eq!(payloads(all_events), vec![payload(0), payload(1), payload(2)]);
+}
+
+
+/// Concurrent appends to the same source that expect any version are all
+/// persisted, without any gaps in the sequence numbers
+pub fn concurrent_appends<S, F>(event_store: &S, payload: F)
+    where S: EventStore + Clone + Send + 'static,
+          S::Offset: fmt::Debug,
+          S::Event: Send + 'static,
+          S::AppendError: fmt::Debug,
+          <S::EventsStream as Stream>::Error: fmt::Debug,
+          F: Fn(usize) -> S::Event
+{
+    let source_id = Uuid::new_v4();
+    let writers = (0..4)
+        .map(|i| {
+            let event_store = event_store.clone();
+            let events = new_events(&payload, (i * 5)..(i * 5 + 5));
+            thread::spawn(move || {
+                event_store.append_events(source_id, ExpectedVersion::Any, events)
+                    .wait()
+                    .map_err(|error| format!("{:?}", error))
+            })
+        })
+        .collect::<Vec<_>>();
+
+    for writer in writers {
+        writer.join().expect("writer panicked").unwrap();
+    }
+
+    let events = events(event_store, source_id, S::Offset::default());
+
+    assert_increasing(&events);
+    assert_eq!(events.iter().map(|event| event.sequence_number).collect::<Vec<_>>(),
+               (0..20).collect::<Vec<_>>());
+}
+
+
+/// Appending no events does not create the source, but still checks the
+/// expected version
+pub fn empty_appends<S, F>(event_store: &S, payload: F)
+    where S: EventStore,
+          S::Offset: fmt::Debug,
+          S::Event: fmt::Debug + PartialEq,
+          S::AppendError: fmt::Debug,
+          <S::EventsStream as Stream>::Error: fmt::Debug,
+          F: Fn(usize) -> S::Event
+{
+    let source_id = Uuid::new_v4();
+
+    append(event_store, source_id, ExpectedVersion::Any, &payload, 0..0).unwrap();
+    append(event_store, source_id, ExpectedVersion::NoStream, &payload, 0..0).unwrap();
+    assert!(append(event_store, source_id, ExpectedVersion::Exact(0), &payload, 0..0).is_err());
+    assert_eq!(payloads(events(event_store, source_id, S::Offset::default())), vec![]);
+
+    append(event_store, source_id, ExpectedVersion::NoStream, &payload, 0..1).unwrap();
+    append(event_store, source_id, ExpectedVersion::Exact(0), &payload, 0..0).unwrap();
+    assert!(append(event_store, source_id, ExpectedVersion::NoStream, &payload, 0..0).is_err());
+    assert_eq!(payloads(events(event_store, source_id, S::Offset::default())),
+               vec![payload(0)]);
+}
+
+
+/// Appends are rejected when the source is not at the expected version,
+/// without persisting any of the events
+pub fn wrong_expected_version<S, F>(event_store: &S, payload: F)
+    where S: EventStore,
+          S::Offset: fmt::Debug,
+          S::Event: fmt::Debug + PartialEq,
+          S::AppendError: fmt::Debug,
+          <S::EventsStream as Stream>::Error: fmt::Debug,
+          F: Fn(usize) -> S::Event
+{
+    let source_id = Uuid::new_v4();
+
+    assert!(append(event_store, source_id, ExpectedVersion::Exact(0), &payload, 0..1).is_err());
+    append(event_store, source_id, ExpectedVersion::NoStream, &payload, 1..3).unwrap();
+    assert!(append(event_store, source_id, ExpectedVersion::NoStream, &payload, 3..4).is_err());
+    assert!(append(event_store, source_id, ExpectedVersion::Exact(0), &payload, 4..5).is_err());
+    assert!(append(event_store, source_id, ExpectedVersion::Exact(2), &payload, 5..6).is_err());
+    append(event_store, source_id, ExpectedVersion::Exact(1), &payload, 6..7).unwrap();
+
+    assert_eq!(payloads(events(event_store, source_id, S::Offset::default())),
+               vec![payload(1), payload(2), payload(6)]);
+}
+
+
+/// When concurrent writers expect the same version of a source, exactly one
+/// of them succeeds
+pub fn concurrent_conflicting_appends<S, F>(event_store: &S, payload: F)
+    where S: EventStore + Clone + Send + 'static,
+          S::Offset: fmt::Debug,
+          S::Event: fmt::Debug + PartialEq + Send + 'static,
+          <S::EventsStream as Stream>::Error: fmt::Debug,
+          F: Fn(usize) -> S::Event
+{
+    let source_id = Uuid::new_v4();
+    let writers = (0..4)
+        .map(|i| {
+            let event_store = event_store.clone();
+            let events = new_events(&payload, i..(i + 1));
+            thread::spawn(move || {
+                event_store.append_events(source_id, ExpectedVersion::NoStream, events)
+                    .wait()
+                    .is_ok()
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let successes = writers.into_iter()
+        .map(|writer| writer.join().expect("writer panicked"))
+        .collect::<Vec<_>>();
+
+    assert_eq!(successes.iter().filter(|&&success| success).count(), 1);
+
+    let winner = successes.iter().position(|&success| success).unwrap();
+    assert_eq!(payloads(events(event_store, source_id, S::Offset::default())),
+               vec![payload(winner)]);
+}
+
+
+/// Retrying an append with the same idempotency key succeeds without
+/// appending the events again, even though the source has moved on from the
+/// expected version
+pub fn idempotent_appends<S, F>(event_store: &S, payload: F)
+    where S: EventStore,
+          S::Offset: fmt::Debug,
+          S::Event: fmt::Debug + PartialEq,
+          S::AppendError: fmt::Debug,
+          <S::EventsStream as Stream>::Error: fmt::Debug,
+          F: Fn(usize) -> S::Event
+{
+    let source_id = Uuid::new_v4();
+    let (key_1, key_2, key_3) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+    let no_stream = ExpectedVersion::NoStream;
+
+    append_idempotent(event_store, key_1, source_id, no_stream, &payload, 0..2).unwrap();
+    append_idempotent(event_store, key_1, source_id, no_stream, &payload, 0..2).unwrap();
+    append_idempotent(event_store, key_2, source_id, ExpectedVersion::Any, &payload, 2..3)
+        .unwrap();
+    append_idempotent(event_store, key_2, source_id, ExpectedVersion::Any, &payload, 2..3)
+        .unwrap();
+
+    // Rejected appends do not use up their key
+    assert!(append_idempotent(event_store, key_3, source_id, no_stream, &payload, 3..4).is_err());
+    append_idempotent(event_store, key_3, source_id, ExpectedVersion::Exact(2), &payload, 3..4)
+        .unwrap();
+
+    assert_eq!(payloads(events(event_store, source_id, S::Offset::default())),
+               vec![payload(0), payload(1), payload(2), payload(3)]);
+}
+
+
+/// Idempotency keys are scoped to their source, so the same key may be used
+/// to append to different sources
+pub fn idempotency_keys_per_source<S, F>(event_store: &S, payload: F)
+    where S: EventStore,
+          S::Offset: fmt::Debug,
+          S::Event: fmt::Debug + PartialEq,
+          S::AppendError: fmt::Debug,
+          <S::EventsStream as Stream>::Error: fmt::Debug,
+          F: Fn(usize) -> S::Event
+{
+    let (source_id_1, source_id_2) = (Uuid::new_v4(), Uuid::new_v4());
+    let key = Uuid::new_v4();
+    let any = ExpectedVersion::Any;
+
+    append_idempotent(event_store, key, source_id_1, any, &payload, 0..1).unwrap();
+    append_idempotent(event_store, key, source_id_2, any, &payload, 1..2).unwrap();
+    append_idempotent(event_store, key, source_id_2, any, &payload, 1..2).unwrap();
+
+    assert_eq!(payloads(events(event_store, source_id_1, S::Offset::default())),
+               vec![payload(0)]);
+    assert_eq!(payloads(events(event_store, source_id_2, S::Offset::default())),
+               vec![payload(1)]);
+}
+
+
+/// When concurrent writers retry the same append, the events are only
+/// appended once and every writer succeeds
+pub fn concurrent_idempotent_appends<S, F>(event_store: &S, payload: F)
+    where S: EventStore + Clone + Send + 'static,
+          S::Offset: fmt::Debug,
+          S::Event: fmt::Debug + PartialEq + Send + 'static,
+          S::AppendError: fmt::Debug,
+          <S::EventsStream as Stream>::Error: fmt::Debug,
+          F: Fn(usize) -> S::Event
+{
+    let source_id = Uuid::new_v4();
+    let idempotency_key = Uuid::new_v4();
+    let writers = (0..4)
+        .map(|_| {
+            let event_store = event_store.clone();
+            let events = new_events(&payload, 0..2);
+            thread::spawn(move || {
+                event_store.append_events_idempotent(idempotency_key,
+                                                     source_id,
+                                                     ExpectedVersion::Any,
+                                                     events)
+                    .wait()
+                    .map_err(|error| format!("{:?}", error))
+            })
+        })
+        .collect::<Vec<_>>();
+
+    for writer in writers {
+        writer.join().expect("writer panicked").unwrap();
+    }
+
+    assert_eq!(payloads(events(event_store, source_id, S::Offset::default())),
+               vec![payload(0), payload(1)]);
+}
+
+
+/// The entries of a transaction are appended together and in order, or not
+/// at all if any of the sources is not at its expected version
+pub fn transactional_appends<S, F>(event_store: &S, payload: F)
+    where S: EventStore,
+          S::Offset: fmt::Debug,
+          S::Event: fmt::Debug + PartialEq,
+          S::AppendError: fmt::Debug,
+          <S::EventsStream as Stream>::Error: fmt::Debug,
+          <S::AllEventsStream as Stream>::Error: fmt::Debug,
+          F: Fn(usize) -> S::Event
+{
+    let source_id_1 = Uuid::new_v4();
+    let source_id_2 = Uuid::new_v4();
+    let source_ids = [source_id_1, source_id_2];
+
+    let transaction = Transaction::new()
+        .with_events(source_id_1, ExpectedVersion::NoStream, new_events(&payload, 0..2))
+        .with_events(source_id_2, ExpectedVersion::NoStream, new_events(&payload, 2..3))
+        .with_events(source_id_1, ExpectedVersion::Exact(1), new_events(&payload, 3..4));
+    event_store.append_transaction(transaction).wait().unwrap();
+
+    let all_events = all_events(event_store, &source_ids, S::Offset::default());
+
+    assert_increasing(&all_events);
+    assert_eq!(all_events.iter()
+                   .map(|event| (event.source_id, event.sequence_number))
+                   .collect::<Vec<_>>(),
+               vec![(source_id_1, 0), (source_id_1, 1), (source_id_2, 0), (source_id_1, 2)]);
+    assert_eq!(payloads(all_events),
+               vec![payload(0), payload(1), payload(2), payload(3)]);
+
+    // The second source has moved on, so neither source is appended to
+    let transaction = Transaction::new()
+        .with_events(source_id_1, ExpectedVersion::Exact(2), new_events(&payload, 4..5))
+        .with_events(source_id_2, ExpectedVersion::NoStream, new_events(&payload, 5..6));
+    assert!(event_store.append_transaction(transaction).wait().is_err());
+
+    assert_eq!(payloads(events(event_store, source_id_1, S::Offset::default())),
+               vec![payload(0), payload(1), payload(3)]);
+    assert_eq!(payloads(events(event_store, source_id_2, S::Offset::default())),
+               vec![payload(2)]);
+    assert!(event_store.append_transaction(Transaction::new()).wait().is_ok());
+}
+
+
+/// When concurrent transactions expect the same versions of their sources,
+/// exactly one of them succeeds, and none of the events of the others are
+/// appended
+pub fn concurrent_conflicting_transactions<S, F>(event_store: &S, payload: F)
+    where S: EventStore + Clone + Send + 'static,
+          S::Offset: fmt::Debug,
+          S::Event: fmt::Debug + PartialEq + Send + 'static,
+          <S::EventsStream as Stream>::Error: fmt::Debug,
+          F: Fn(usize) -> S::Event
+{
+    let source_id_1 = Uuid::new_v4();
+    let source_id_2 = Uuid::new_v4();
+    let writers = (0..4)
+        .map(|i| {
+            let event_store = event_store.clone();
+            let no_stream = ExpectedVersion::NoStream;
+            let transaction = Transaction::new()
+                .with_events(source_id_1, no_stream, new_events(&payload, i..(i + 1)))
+                .with_events(source_id_2, no_stream, new_events(&payload, i..(i + 1)));
+            thread::spawn(move || event_store.append_transaction(transaction).wait().is_ok())
+        })
+        .collect::<Vec<_>>();
+
+    let successes = writers.into_iter()
+        .map(|writer| writer.join().expect("writer panicked"))
+        .collect::<Vec<_>>();
+
+    assert_eq!(successes.iter().filter(|&&success| success).count(), 1);
+
+    let winner = successes.iter().position(|&success| success).unwrap();
+    assert_eq!(payloads(events(event_store, source_id_1, S::Offset::default())),
+               vec![payload(winner)]);
+    assert_eq!(payloads(events(event_store, source_id_2, S::Offset::default())),
+               vec![payload(winner)]);
+}
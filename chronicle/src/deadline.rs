@@ -0,0 +1,193 @@
+//! Storage for commands that are scheduled to be dispatched at a later time
+//!
+//! Deadlines allow for behaviour like "archive the task if it hasn't been
+//! completed within a week". A deadline pairs a command with the time that
+//! it is due, and is identified by an id that can be used to cancel it before
+//! it becomes due.
+
+use futures::{Async, Future, Poll};
+use futures::future::{self, Either, FutureResult, MapErr};
+use std::time::SystemTime;
+use uuid::Uuid;
+
+use codec::{CodecStoreError, EventCodec};
+
+
+/// A command that is scheduled to be dispatched once it is due
+#[derive(Debug, Clone, PartialEq)]
+pub struct Deadline<Command> {
+    /// The unique identifier of the deadline
+    pub deadline_id: Uuid,
+    /// The time at which the command should be dispatched
+    pub due_at: SystemTime,
+    /// The command to dispatch
+    pub command: Command,
+}
+
+
+impl<Command> Deadline<Command> {
+    /// Create a deadline for the command
+    pub fn new(deadline_id: Uuid, due_at: SystemTime, command: Command) -> Deadline<Command> {
+        Deadline {
+            deadline_id: deadline_id,
+            due_at: due_at,
+            command: command,
+        }
+    }
+
+    /// Apply a transformation to the command
+    pub fn map<NewCommand, F>(self, f: F) -> Deadline<NewCommand>
+        where F: FnOnce(Command) -> NewCommand
+    {
+        Deadline {
+            deadline_id: self.deadline_id,
+            due_at: self.due_at,
+            command: f(self.command),
+        }
+    }
+}
+
+
+/// A repository for storing the deadlines that are yet to be dispatched
+pub trait DeadlineStore {
+    /// The type of the commands that are scheduled
+    type Command;
+
+    /// An error that may be yielded when accessing the deadlines
+    type Error;
+
+    /// A future that will be returned by the `schedule` function
+    type ScheduleFuture: Future<Item = (), Error = Self::Error>;
+
+    /// A future that will be returned by the `cancel` function
+    type CancelFuture: Future<Item = bool, Error = Self::Error>;
+
+    /// A future that will be returned by the `acknowledge` function
+    type AcknowledgeFuture: Future<Item = bool, Error = Self::Error>;
+
+    /// A future that will be returned by the `due` function
+    type DueFuture: Future<Item = Vec<Deadline<Self::Command>>, Error = Self::Error>;
+
+    /// Store the deadline, replacing any existing deadline with the same id
+    fn schedule(&self, deadline: Deadline<Self::Command>) -> Self::ScheduleFuture;
+
+    /// Remove the deadline with the given id, resolving to `true` if it was
+    /// still pending
+    fn cancel(&self, deadline_id: Uuid) -> Self::CancelFuture;
+
+    /// Remove the deadline with the given id once its command has been
+    /// dispatched, resolving to `true` if it was removed. The deadline is
+    /// only removed if it is still due at the given time, so that it is kept
+    /// if it was rescheduled while the command was being dispatched.
+    fn acknowledge(&self, deadline_id: Uuid, due_at: SystemTime) -> Self::AcknowledgeFuture;
+
+    /// The deadlines that are due at the given time, in the order that they
+    /// became due
+    fn due(&self, now: SystemTime) -> Self::DueFuture;
+}
+
+
+/// A deadline store that encodes and decodes the commands of an underlying
+/// byte oriented deadline store using a codec
+#[derive(Debug, Clone)]
+pub struct CodecDeadlineStore<S, C> {
+    deadline_store: S,
+    codec: C,
+}
+
+
+impl<S, C> CodecDeadlineStore<S, C> {
+    /// Wrap the deadline store, using the codec to convert its commands
+    pub fn new(deadline_store: S, codec: C) -> CodecDeadlineStore<S, C> {
+        CodecDeadlineStore {
+            deadline_store: deadline_store,
+            codec: codec,
+        }
+    }
+
+    /// The underlying deadline store
+    pub fn deadline_store(&self) -> &S {
+        &self.deadline_store
+    }
+
+    /// The codec used to convert commands
+    pub fn codec(&self) -> &C {
+        &self.codec
+    }
+}
+
+
+impl<S, C> DeadlineStore for CodecDeadlineStore<S, C>
+    where S: DeadlineStore<Command = Vec<u8>>,
+          C: EventCodec + Clone
+{
+    type Command = C::Event;
+    type Error = CodecStoreError<C::Error, S::Error>;
+    type ScheduleFuture = Either<FutureResult<(), Self::Error>,
+                                 MapErr<S::ScheduleFuture, fn(S::Error) -> Self::Error>>;
+    type CancelFuture = MapErr<S::CancelFuture, fn(S::Error) -> Self::Error>;
+    type AcknowledgeFuture = MapErr<S::AcknowledgeFuture, fn(S::Error) -> Self::Error>;
+    type DueFuture = DecodeDeadlines<S::DueFuture, C>;
+
+    fn schedule(&self, deadline: Deadline<C::Event>) -> Self::ScheduleFuture {
+        match self.codec.encode(&deadline.command) {
+            Ok(command) => {
+                let store_error = CodecStoreError::Store as fn(_) -> _;
+                let future = self.deadline_store.schedule(deadline.map(|_| command));
+                Either::B(future.map_err(store_error))
+            },
+            Err(err) => Either::A(future::err(CodecStoreError::Codec(err))),
+        }
+    }
+
+    fn cancel(&self, deadline_id: Uuid) -> Self::CancelFuture {
+        let store_error = CodecStoreError::Store as fn(_) -> _;
+        self.deadline_store.cancel(deadline_id).map_err(store_error)
+    }
+
+    fn acknowledge(&self, deadline_id: Uuid, due_at: SystemTime) -> Self::AcknowledgeFuture {
+        let store_error = CodecStoreError::Store as fn(_) -> _;
+        self.deadline_store.acknowledge(deadline_id, due_at).map_err(store_error)
+    }
+
+    fn due(&self, now: SystemTime) -> Self::DueFuture {
+        DecodeDeadlines {
+            future: self.deadline_store.due(now),
+            codec: self.codec.clone(),
+        }
+    }
+}
+
+
+/// A future that decodes the commands of the deadlines resolved by an
+/// underlying future
+#[derive(Debug)]
+pub struct DecodeDeadlines<F, C> {
+    future: F,
+    codec: C,
+}
+
+
+impl<F, C> Future for DecodeDeadlines<F, C>
+    where F: Future<Item = Vec<Deadline<Vec<u8>>>>,
+          C: EventCodec
+{
+    type Item = Vec<Deadline<C::Event>>;
+    type Error = CodecStoreError<C::Error, F::Error>;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let deadlines = match self.future.poll().map_err(CodecStoreError::Store)? {
+            Async::Ready(deadlines) => deadlines,
+            Async::NotReady => return Ok(Async::NotReady),
+        };
+
+        let deadlines = deadlines.into_iter()
+            .map(|deadline| {
+                let command = self.codec.decode(&deadline.command).map_err(CodecStoreError::Codec)?;
+                Ok(deadline.map(|_| command))
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Async::Ready(deadlines))
+    }
+}
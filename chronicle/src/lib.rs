@@ -1,15 +1,287 @@
 extern crate futures;
+extern crate serde;
+#[cfg_attr(test, macro_use)]
+extern crate serde_json;
 extern crate uuid;
 
 
-use futures::Stream;
+use futures::{Future, Stream};
+use std::collections::BTreeMap;
+use std::time::SystemTime;
 use uuid::Uuid;
 
+pub mod codec;
+#[macro_use]
+pub mod conformance;
+pub mod deadline;
+pub mod outbox;
+pub mod projection;
+pub mod upcast;
+
+pub use codec::{CodecEventStore, EventCodec};
+pub use deadline::{Deadline, DeadlineStore};
+pub use outbox::{Outbox, Publisher};
+pub use projection::{CheckpointStore, Projection};
+pub use upcast::{UpcastEventStore, UpcastPayload, Upcasters};
+
 
 /// The sequence number within a single source of events
 pub type SequenceNumber = u32;
 
 
+/// The version of the schema of an event
+pub type EventVersion = u32;
+
+
+/// Describes the type of a domain event, allowing it to be identified in the
+/// event store without having to decode its payload.
+pub trait EventType {
+    /// The name of the type of the event, for example the name of the enum
+    /// variant
+    fn event_type(&self) -> &str;
+
+    /// The version of the schema of the event type. This should be bumped
+    /// when the shape of the event changes.
+    fn event_version(&self) -> EventVersion {
+        0
+    }
+}
+
+
+/// The version that a source of events is expected to be at when appending
+/// new events to it. This allows for optimistic concurrency control when
+/// multiple writers are attempting to update the same source.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ExpectedVersion {
+    /// Append the events regardless of the current version of the source
+    Any,
+    /// The source must not contain any events yet
+    NoStream,
+    /// The last event in the source must have the given sequence number
+    Exact(SequenceNumber),
+}
+
+
+impl ExpectedVersion {
+    /// Check if the version is satisfied by the sequence number of the last
+    /// event in a source, or `None` if the source is empty
+    pub fn is_satisfied_by(&self, current: Option<SequenceNumber>) -> bool {
+        match (*self, current) {
+            (ExpectedVersion::Any, _) => true,
+            (ExpectedVersion::NoStream, None) => true,
+            (ExpectedVersion::Exact(expected), Some(current)) => expected == current,
+            (ExpectedVersion::NoStream, Some(_)) |
+            (ExpectedVersion::Exact(_), None) => false,
+        }
+    }
+}
+
+
+/// An error signifying that a source of events was not at the expected version
+/// when attempting to append to it
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct WrongExpectedVersion {
+    /// The source that was being appended to
+    pub source_id: Uuid,
+    /// The version that the source was expected to be at
+    pub expected: ExpectedVersion,
+    /// The sequence number of the last event in the source, or `None` if the
+    /// source was empty
+    pub current: Option<SequenceNumber>,
+}
+
+
+/// An append error that may have been caused by a source not being at the
+/// expected version. This allows conflicts to be detected regardless of the
+/// event store, for example so that the command can be retried.
+pub trait AsWrongExpectedVersion {
+    /// The conflict that caused the error, if any
+    fn as_wrong_expected_version(&self) -> Option<&WrongExpectedVersion>;
+}
+
+
+impl AsWrongExpectedVersion for WrongExpectedVersion {
+    fn as_wrong_expected_version(&self) -> Option<&WrongExpectedVersion> {
+        Some(self)
+    }
+}
+
+
+/// Metadata that is stored alongside each event
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Metadata {
+    /// The unique identifier of the event
+    pub event_id: Uuid,
+    /// The time at which the event was recorded. Note that event stores may
+    /// store this at a lower precision.
+    pub recorded_at: SystemTime,
+    /// The identifier of the overall operation or workflow that the event
+    /// was a part of, allowing related events to be traced across sources
+    pub correlation_id: Option<Uuid>,
+    /// The identifier of the command or event that directly caused the event
+    pub causation_id: Option<Uuid>,
+    /// Arbitrary user-defined headers
+    pub headers: BTreeMap<String, String>,
+}
+
+
+impl Metadata {
+    /// Create metadata for an event recorded now, with a new event id
+    pub fn new() -> Metadata {
+        Metadata {
+            event_id: Uuid::new_v4(),
+            recorded_at: SystemTime::now(),
+            correlation_id: None,
+            causation_id: None,
+            headers: BTreeMap::new(),
+        }
+    }
+
+    /// Set the correlation id of the event
+    pub fn with_correlation_id(mut self, correlation_id: Uuid) -> Metadata {
+        self.correlation_id = Some(correlation_id);
+        self
+    }
+
+    /// Set the causation id of the event
+    pub fn with_causation_id(mut self, causation_id: Uuid) -> Metadata {
+        self.causation_id = Some(causation_id);
+        self
+    }
+
+    /// Add a user-defined header to the event
+    pub fn with_header<K, V>(mut self, key: K, value: V) -> Metadata
+        where K: Into<String>,
+              V: Into<String>
+    {
+        self.headers.insert(key.into(), value.into());
+        self
+    }
+}
+
+
+impl Default for Metadata {
+    fn default() -> Metadata {
+        Metadata::new()
+    }
+}
+
+
+/// An event that is yet to be appended to the event store, along with its
+/// metadata
+#[derive(Debug, Clone, PartialEq)]
+pub struct NewEvent<Event> {
+    /// The name of the type of the event
+    pub event_type: String,
+    /// The version of the schema of the event type
+    pub event_version: EventVersion,
+    /// The metadata to store alongside the event
+    pub metadata: Metadata,
+    /// The event payload to be stored
+    pub payload: Event,
+}
+
+
+impl<Event> NewEvent<Event> {
+    /// Create a new event with freshly generated metadata
+    pub fn new(payload: Event) -> NewEvent<Event>
+        where Event: EventType
+    {
+        NewEvent::with_metadata(Metadata::new(), payload)
+    }
+
+    /// Create a new event with the given metadata
+    pub fn with_metadata(metadata: Metadata, payload: Event) -> NewEvent<Event>
+        where Event: EventType
+    {
+        let event_type = payload.event_type().to_string();
+        let event_version = payload.event_version();
+
+        NewEvent::with_type(event_type, event_version, metadata, payload)
+    }
+
+    /// Create a new event with an explicit event type and version. This is
+    /// useful for payloads that have already been serialized.
+    pub fn with_type<S>(event_type: S,
+                        event_version: EventVersion,
+                        metadata: Metadata,
+                        payload: Event)
+                        -> NewEvent<Event>
+        where S: Into<String>
+    {
+        NewEvent {
+            event_type: event_type.into(),
+            event_version: event_version,
+            metadata: metadata,
+            payload: payload,
+        }
+    }
+
+    /// Apply a transformation to the event
+    pub fn map<NewPayload, F>(self, f: F) -> NewEvent<NewPayload>
+        where F: FnOnce(Event) -> NewPayload
+    {
+        NewEvent {
+            event_type: self.event_type,
+            event_version: self.event_version,
+            metadata: self.metadata,
+            payload: f(self.payload),
+        }
+    }
+}
+
+
+/// The events to append to a single source as part of a `Transaction`
+#[derive(Debug, Clone, PartialEq)]
+pub struct TransactionEntry<Event> {
+    /// The source to append the events to
+    pub source_id: Uuid,
+    /// The version that the source is expected to be at
+    pub expected_version: ExpectedVersion,
+    /// The events to append to the source
+    pub events: Vec<NewEvent<Event>>,
+}
+
+
+/// A set of appends to one or more sources that are committed together,
+/// either all succeeding or all failing
+#[derive(Debug, Clone, PartialEq)]
+pub struct Transaction<Event> {
+    /// The appends that make up the transaction, in the order that they are
+    /// applied
+    pub entries: Vec<TransactionEntry<Event>>,
+}
+
+
+impl<Event> Transaction<Event> {
+    /// Create an empty transaction
+    pub fn new() -> Transaction<Event> {
+        Transaction { entries: Vec::new() }
+    }
+
+    /// Append the events to the source as part of the transaction
+    pub fn with_events(mut self,
+                       source_id: Uuid,
+                       expected_version: ExpectedVersion,
+                       events: Vec<NewEvent<Event>>)
+                       -> Transaction<Event> {
+        self.entries.push(TransactionEntry {
+            source_id: source_id,
+            expected_version: expected_version,
+            events: events,
+        });
+        self
+    }
+}
+
+
+impl<Event> Default for Transaction<Event> {
+    fn default() -> Transaction<Event> {
+        Transaction::new()
+    }
+}
+
+
 /// An event with associated metadata that corresponds to how it was stored in
 /// the event store.
 #[derive(Debug, Clone, PartialEq)]
@@ -21,20 +293,30 @@ pub struct PersistedEvent<Offset, Event> {
     pub source_id: Uuid,
     /// The sequence number within a single source of events
     pub sequence_number: SequenceNumber,
+    /// The name of the type of the event
+    pub event_type: String,
+    /// The version of the schema of the event type
+    pub event_version: EventVersion,
+    /// The metadata that was supplied when the event was appended
+    pub metadata: Metadata,
     /// The event payload that was stored by the client of the event store
     pub payload: Event,
 }
 
 
 impl<Offset, Event> PersistedEvent<Offset, Event> {
-    /// Take the event data by reference, cloning the source id and offsets
+    /// Take the event data by reference, cloning the source id, offsets,
+    /// event type and metadata
     pub fn as_ref(&self) -> PersistedEvent<Offset, &Event>
         where Offset: Clone
     {
         PersistedEvent {
             offset: self.offset.clone(),
             source_id: self.source_id,
-            sequence_number: self.sequence_number.clone(),
+            sequence_number: self.sequence_number,
+            event_type: self.event_type.clone(),
+            event_version: self.event_version,
+            metadata: self.metadata.clone(),
             payload: &self.payload,
         }
     }
@@ -47,6 +329,9 @@ impl<Offset, Event> PersistedEvent<Offset, Event> {
             offset: self.offset,
             source_id: self.source_id,
             sequence_number: self.sequence_number,
+            event_type: self.event_type,
+            event_version: self.event_version,
+            metadata: self.metadata,
             payload: f(self.payload),
         }
     }
@@ -57,20 +342,123 @@ impl<Offset, Event> PersistedEvent<Offset, Event> {
 pub trait EventStore {
     /// The type of the offests into the event store. Relational DBs
     /// will probably use a monotonically increasing sequence number,
-    /// where as distributed data sources may use timestamps.
-    type Offset: PartialOrd;
+    /// where as distributed data sources may use timestamps. The default
+    /// offset should refer to the start of the event store.
+    type Offset: PartialOrd + Default;
 
     /// The event store's native serialized form
     type Event;
 
+    /// An error that may be yielded when appending events to the event store
+    type AppendError;
+
+    /// A future that will be returned by the `append_events` function
+    type AppendFuture: Future<Item = (), Error = Self::AppendError>;
+
     /// A lazily loaded source of events
     type EventsStream: Stream<Item = PersistedEvent<Self::Offset, Self::Event>>;
 
-    /// Append the events to the event store for the specified source id
-    fn append_events(&self, source_id: Uuid, events: Vec<Self::Event>);
+    /// A lazily loaded stream of every event in the event store
+    type AllEventsStream: Stream<Item = PersistedEvent<Self::Offset, Self::Event>>;
+
+    /// Append the events to the event store for the specified source id,
+    /// returning a future that either resolves once the events have been
+    /// persisted, or yields an `AppendError` on failure.
+    ///
+    /// The events will only be appended if the source is at the expected
+    /// version. This check must be performed atomically with the write.
+    fn append_events(&self,
+                     source_id: Uuid,
+                     expected_version: ExpectedVersion,
+                     events: Vec<NewEvent<Self::Event>>)
+                     -> Self::AppendFuture;
+
+    /// Append the events as with `append_events`, unless a batch with the
+    /// same idempotency key has already been appended. In that case the
+    /// events are not appended again, and the future resolves successfully,
+    /// as it did for the original batch. This allows appends to be retried,
+    /// for example when the response to a command was lost.
+    ///
+    /// Keys are scoped to the source id, so the same key may be used to
+    /// append to different sources. Keys are only recorded once their events
+    /// have been appended, so a batch that was rejected or empty may be
+    /// retried with the same key.
+    fn append_events_idempotent(&self,
+                                idempotency_key: Uuid,
+                                source_id: Uuid,
+                                expected_version: ExpectedVersion,
+                                events: Vec<NewEvent<Self::Event>>)
+                                -> Self::AppendFuture;
+
+    /// Append the events in the transaction to their sources atomically. If
+    /// any of the sources is not at its expected version then none of the
+    /// events are appended, and the error refers to the first such source.
+    ///
+    /// A source may appear more than once in a transaction, in which case
+    /// each expected version is checked against the events that precede it
+    /// in the transaction.
+    fn append_transaction(&self, transaction: Transaction<Self::Event>) -> Self::AppendFuture;
 
     /// Stream the events back from the event store for the specified source id
     fn events(&self, source_id: Uuid, offset: Self::Offset) -> Self::EventsStream;
+
+    /// Stream the events back from the event store for the specified source
+    /// id, starting at the event with the given sequence number. This allows
+    /// the state of a source to be resumed from a snapshot without reading
+    /// the events that precede it.
+    fn events_from(&self, source_id: Uuid, sequence_number: SequenceNumber) -> Self::EventsStream;
+
+    /// Stream back every event in the event store, regardless of source id,
+    /// in order of their global offsets. This is useful for building
+    /// projections and read models.
+    fn all_events(&self, offset: Self::Offset) -> Self::AllEventsStream;
+
+    /// Stream back every event in the event store that has one of the given
+    /// event types, in order of their global offsets
+    fn all_events_of_types(&self,
+                           event_types: &[&str],
+                           offset: Self::Offset)
+                           -> Self::AllEventsStream;
+}
+
+
+/// The error that may be yielded when streaming the events of a single source
+/// from the event store
+pub type EventsError<S> = <<S as EventStore>::EventsStream as Stream>::Error;
+
+
+/// The error that may be yielded when streaming every event from the event
+/// store
+pub type AllEventsError<S> = <<S as EventStore>::AllEventsStream as Stream>::Error;
+
+
+/// A repository for storing snapshots of the state of each source of events.
+/// This allows the state to be restored without having to replay the entire
+/// event history for the source.
+pub trait SnapshotStore {
+    /// The state that is stored in the snapshots
+    type State;
+
+    /// An error that may be yielded when saving or loading snapshots
+    type Error;
+
+    /// A future that will be returned by the `save_snapshot` function
+    type SaveFuture: Future<Item = (), Error = Self::Error>;
+
+    /// A future that will be returned by the `load_snapshot` function
+    type LoadFuture: Future<Item = Option<(SequenceNumber, Self::State)>, Error = Self::Error>;
+
+    /// Save the state of the source as of the event with the specified
+    /// sequence number
+    fn save_snapshot(&self,
+                     source_id: Uuid,
+                     sequence_number: SequenceNumber,
+                     state: Self::State)
+                     -> Self::SaveFuture;
+
+    /// Load the most recent snapshot for the source, along with the sequence
+    /// number of the last event that was applied to it
+    fn load_snapshot(&self, source_id: Uuid) -> Self::LoadFuture;
 }
 
 
@@ -81,12 +469,97 @@ mod tests {
     use super::*;
 
 
+    #[test]
+    fn expected_version_is_satisfied_by() {
+        assert!(ExpectedVersion::Any.is_satisfied_by(None));
+        assert!(ExpectedVersion::Any.is_satisfied_by(Some(3)));
+        assert!(ExpectedVersion::NoStream.is_satisfied_by(None));
+        assert!(!ExpectedVersion::NoStream.is_satisfied_by(Some(0)));
+        assert!(ExpectedVersion::Exact(3).is_satisfied_by(Some(3)));
+        assert!(!ExpectedVersion::Exact(3).is_satisfied_by(Some(4)));
+        assert!(!ExpectedVersion::Exact(0).is_satisfied_by(None));
+    }
+
+
+    #[test]
+    fn metadata_builder() {
+        let correlation_id = Uuid::new_v4();
+        let causation_id = Uuid::new_v4();
+        let metadata = Metadata::new()
+            .with_correlation_id(correlation_id)
+            .with_causation_id(causation_id)
+            .with_header("user", "bob");
+
+        assert_eq!(metadata.correlation_id, Some(correlation_id));
+        assert_eq!(metadata.causation_id, Some(causation_id));
+        assert_eq!(metadata.headers.get("user").map(String::as_str), Some("bob"));
+        assert!(metadata.event_id != Metadata::new().event_id);
+    }
+
+
+    #[test]
+    fn transaction_builder() {
+        let (source_id_1, source_id_2) = (Uuid::new_v4(), Uuid::new_v4());
+        let transaction = Transaction::new()
+            .with_events(source_id_1, ExpectedVersion::NoStream, vec![])
+            .with_events(source_id_2, ExpectedVersion::Exact(1), vec![]);
+
+        assert_eq!(transaction.entries,
+                   vec![TransactionEntry::<()> {
+                            source_id: source_id_1,
+                            expected_version: ExpectedVersion::NoStream,
+                            events: vec![],
+                        },
+                        TransactionEntry {
+                            source_id: source_id_2,
+                            expected_version: ExpectedVersion::Exact(1),
+                            events: vec![],
+                        }]);
+    }
+
+
+    #[derive(Debug, Clone, PartialEq)]
+    enum Greeting {
+        Hello,
+        Goodbye,
+    }
+
+
+    impl EventType for Greeting {
+        fn event_type(&self) -> &str {
+            match *self {
+                Greeting::Hello => "hello",
+                Greeting::Goodbye => "goodbye",
+            }
+        }
+
+        fn event_version(&self) -> EventVersion {
+            1
+        }
+    }
+
+
+    #[test]
+    fn new_event_with_event_type() {
+        let hello = NewEvent::new(Greeting::Hello);
+        let goodbye = NewEvent::new(Greeting::Goodbye);
+        let raw = NewEvent::with_type("hello", 1, Metadata::new(), b"hello".to_vec());
+
+        assert_eq!((hello.event_type.as_str(), hello.event_version), ("hello", 1));
+        assert_eq!((goodbye.event_type.as_str(), goodbye.event_version), ("goodbye", 1));
+        assert_eq!((raw.event_type.as_str(), raw.event_version), ("hello", 1));
+    }
+
+
     #[test]
     fn persisted_event_map() {
         let event = PersistedEvent {
             offset: 123,
             source_id: Uuid::new_v4(),
             sequence_number: 354,
+            event_type: "greeting".to_string(),
+            event_version: 2,
+            metadata: Metadata::new(),
             payload: "hello",
         };
 
@@ -95,6 +568,9 @@ mod tests {
         assert_eq!(new_event.offset, event.offset);
         assert_eq!(new_event.source_id, event.source_id);
         assert_eq!(new_event.sequence_number, event.sequence_number);
+        assert_eq!(new_event.event_type, event.event_type);
+        assert_eq!(new_event.event_version, event.event_version);
+        assert_eq!(new_event.metadata, event.metadata);
         assert_eq!(new_event.payload, event.payload);
     }
 
@@ -105,6 +581,9 @@ mod tests {
             offset: 123,
             source_id: Uuid::new_v4(),
             sequence_number: 354,
+            event_type: "greeting".to_string(),
+            event_version: 2,
+            metadata: Metadata::new(),
             payload: "hello",
         };
 
@@ -113,6 +592,9 @@ mod tests {
         assert_eq!(new_event.offset, event.offset);
         assert_eq!(new_event.source_id, event.source_id);
         assert_eq!(new_event.sequence_number, event.sequence_number);
+        assert_eq!(new_event.event_type, event.event_type);
+        assert_eq!(new_event.event_version, event.event_version);
+        assert_eq!(new_event.metadata, event.metadata);
         assert_eq!(new_event.payload, &event.payload);
     }
 }
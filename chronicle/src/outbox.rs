@@ -0,0 +1,126 @@
+//! Publishing committed events to the outside world
+//!
+//! Publishing events to a message broker straight after appending them risks
+//! losing messages if the process stops in between, or publishing events that
+//! were never committed. Instead, event stores that support an `Outbox`
+//! record a pending publication for each event atomically with the append.
+//! The `relay` then drains the outbox, passing the events to a `Publisher`
+//! and only removing them from the outbox once they have been published.
+//!
+//! Delivery is at-least-once: if the relay is stopped after an event has
+//! been published but before it has been acknowledged, the event will be
+//! published again when the relay resumes. Each event carries its offset in
+//! the event store, which consumers can use to discard duplicates.
+
+use futures::{Future, IntoFuture, future};
+use futures::future::{Either, Loop};
+
+use PersistedEvent;
+
+
+/// The maximum number of pending events to load from the outbox at a time
+const BATCH_SIZE: usize = 256;
+
+
+/// A record of the events that are yet to be published
+pub trait Outbox {
+    /// The type of the offsets into the event store
+    type Offset;
+
+    /// The type of the events that are published
+    type Event;
+
+    /// An error that may be yielded when accessing the outbox
+    type Error;
+
+    /// A future that will be returned by the `pending` function
+    type PendingFuture: Future<Item = Vec<PersistedEvent<Self::Offset, Self::Event>>,
+                               Error = Self::Error>;
+
+    /// A future that will be returned by the `acknowledge` function
+    type AcknowledgeFuture: Future<Item = (), Error = Self::Error>;
+
+    /// Load up to `limit` of the events that are waiting to be published, in
+    /// order of their offsets
+    fn pending(&self, limit: usize) -> Self::PendingFuture;
+
+    /// Remove the event at the given offset from the outbox once it has been
+    /// published
+    fn acknowledge(&self, offset: Self::Offset) -> Self::AcknowledgeFuture;
+}
+
+
+/// Publishes events to an external system, such as a message broker
+pub trait Publisher {
+    /// The type of the offsets into the event store
+    type Offset;
+
+    /// The type of the events that are published
+    type Event;
+
+    /// An error that may be yielded when publishing an event
+    type Error;
+
+    /// A future that will be returned by the `publish` function
+    type PublishFuture: IntoFuture<Item = (), Error = Self::Error>;
+
+    /// Publish the event, resolving once the external system has accepted it
+    fn publish(&self, event: PersistedEvent<Self::Offset, Self::Event>) -> Self::PublishFuture;
+}
+
+
+/// An error that may occur when relaying events from an outbox
+#[derive(Debug, Clone, PartialEq)]
+pub enum RelayError<OutboxError, PublishError> {
+    /// An error occurred when loading or acknowledging the pending events
+    Outbox(OutboxError),
+    /// An error occurred when publishing an event
+    Publish(PublishError),
+}
+
+
+/// Publish the pending events in the outbox, one at a time and in order of
+/// their offsets. Each event is acknowledged once it has been published, and
+/// the future resolves to the number of events that were published once the
+/// outbox has been drained.
+pub fn relay<'a, O, P>(outbox: &'a O,
+                       publisher: &'a P)
+                       -> impl Future<Item = usize, Error = RelayError<O::Error, P::Error>> + 'a
+    where O: Outbox,
+          O::Offset: Clone,
+          O::PendingFuture: 'a,
+          O::AcknowledgeFuture: 'a,
+          P: Publisher<Offset = O::Offset, Event = O::Event>,
+          <P::PublishFuture as IntoFuture>::Future: 'a
+{
+    future::loop_fn(0, move |published| {
+        outbox.pending(BATCH_SIZE)
+            .map_err(RelayError::Outbox)
+            .and_then(move |events| {
+                if events.is_empty() {
+                    return Either::A(future::ok(Loop::Break(published)));
+                }
+
+                let count = events.len();
+                let relayed = future::loop_fn(events.into_iter(), move |mut events| {
+                    let event = match events.next() {
+                        Some(event) => event,
+                        None => return Either::A(future::ok(Loop::Break(()))),
+                    };
+
+                    let offset = event.offset.clone();
+                    let acknowledged = publisher.publish(event)
+                        .into_future()
+                        .map_err(RelayError::Publish)
+                        .and_then(move |()| {
+                            outbox.acknowledge(offset).map_err(RelayError::Outbox)
+                        })
+                        .map(move |()| Loop::Continue(events));
+
+                    Either::B(acknowledged)
+                });
+
+                Either::B(relayed.map(move |()| Loop::Continue(published + count)))
+            })
+    })
+}
@@ -0,0 +1,131 @@
+//! Projections of the event store into read models
+//!
+//! A projection consumes the events from the global event stream, updating
+//! a read model as it goes. The offset of the last event that was processed
+//! is saved to a `CheckpointStore`, allowing the projection to resume where
+//! it left off after a restart.
+
+use futures::{Future, IntoFuture, Stream};
+
+use {EventStore, PersistedEvent};
+
+
+/// A read model that is built up by consuming the events in an event store
+pub trait Projection {
+    /// The type of the offsets into the event store
+    type Offset;
+
+    /// The type of the events that the projection consumes
+    type Event;
+
+    /// An error that may be yielded when handling an event
+    type Error;
+
+    /// A future that will be returned by the `handle_event` function
+    type HandleFuture: IntoFuture<Item = (), Error = Self::Error>;
+
+    /// Update the read model with the event
+    fn handle_event(&mut self,
+                    event: PersistedEvent<Self::Offset, Self::Event>)
+                    -> Self::HandleFuture;
+}
+
+
+/// A repository for storing the offset of the last event that was processed
+/// by each projection
+pub trait CheckpointStore {
+    /// The type of the offsets into the event store
+    type Offset;
+
+    /// An error that may be yielded when saving or loading checkpoints
+    type Error;
+
+    /// A future that will be returned by the `save_checkpoint` function
+    type SaveFuture: Future<Item = (), Error = Self::Error>;
+
+    /// A future that will be returned by the `load_checkpoint` function
+    type LoadFuture: Future<Item = Option<Self::Offset>, Error = Self::Error>;
+
+    /// Save the offset of the last event that was processed by the projection
+    fn save_checkpoint(&self, projection_id: &str, offset: Self::Offset) -> Self::SaveFuture;
+
+    /// Load the offset of the last event that was processed by the
+    /// projection, or `None` if it has not processed any events yet
+    fn load_checkpoint(&self, projection_id: &str) -> Self::LoadFuture;
+}
+
+
+/// An error that may occur when running a projection
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProjectionError<CheckpointError, EventsError, HandleError> {
+    /// An error occurred when saving or loading the checkpoint
+    Checkpoint(CheckpointError),
+    /// An error occurred when streaming the events
+    Events(EventsError),
+    /// An error occurred when the projection was handling an event
+    Handle(HandleError),
+}
+
+
+/// Run the projection over the events in the event store, starting after the
+/// last checkpoint. The checkpoint is saved after each event is handled, and
+/// the future resolves once the projection has caught up with the store.
+pub fn run<'a, P, E, C>
+    (projection_id: &'a str,
+     projection: &'a mut P,
+     event_store: &'a E,
+     checkpoint_store: &'a C)
+     -> impl Future<Item = (),
+                    Error = ProjectionError<C::Error,
+                                            <E::AllEventsStream as Stream>::Error,
+                                            P::Error>> + 'a
+    where P: Projection,
+          P::Offset: Clone + PartialOrd + Default,
+          E: EventStore<Offset = P::Offset, Event = P::Event>,
+          C: CheckpointStore<Offset = P::Offset>
+{
+    run_with(projection_id,
+             projection,
+             checkpoint_store,
+             move |offset| event_store.all_events(offset))
+}
+
+
+/// Run the projection over a stream of events, starting after the last
+/// checkpoint. The `events` function will be called with the offset to
+/// start streaming from, which allows for projections to be run over live
+/// subscriptions to an event store.
+pub fn run_with<'a, P, C, F, S>
+    (projection_id: &'a str,
+     projection: &'a mut P,
+     checkpoint_store: &'a C,
+     events: F)
+     -> impl Future<Item = (), Error = ProjectionError<C::Error, S::Error, P::Error>> + 'a
+    where P: Projection,
+          P::Offset: Clone + PartialOrd + Default,
+          C: CheckpointStore<Offset = P::Offset>,
+          F: FnOnce(P::Offset) -> S + 'a,
+          S: Stream<Item = PersistedEvent<P::Offset, P::Event>> + 'a
+{
+    checkpoint_store.load_checkpoint(projection_id)
+        .map_err(ProjectionError::Checkpoint)
+        .and_then(move |checkpoint| {
+            let offset = checkpoint.clone().unwrap_or_default();
+
+            events(offset)
+                .map_err(ProjectionError::Events)
+                // The checkpoint refers to an event that was already handled
+                .filter(move |event| checkpoint.as_ref().map_or(true, |c| event.offset > *c))
+                .for_each(move |event| {
+                    let offset = event.offset.clone();
+
+                    projection.handle_event(event)
+                        .into_future()
+                        .map_err(ProjectionError::Handle)
+                        .and_then(move |()| {
+                            checkpoint_store.save_checkpoint(projection_id, offset)
+                                .map_err(ProjectionError::Checkpoint)
+                        })
+                })
+        })
+}
@@ -0,0 +1,452 @@
+//! Upcasting of stored events for schema evolution
+//!
+//! Events are immutable once they have been stored, but the shape of the
+//! events used in the domain will change over time. Rather than migrating the
+//! stored events, old payloads are transformed into their current shape as
+//! they are read from the event store. Each transformation, or 'upcaster',
+//! converts a payload of a specific event type from one version to the next,
+//! and these are chained together until the payload reaches the latest
+//! version.
+//!
+//! Upcasters operate on JSON values. The event type and version that each
+//! event was stored with determine which upcasters are applied to it, and are
+//! updated as the payload is upcast. An `UpcastEventStore` can wrap either a
+//! store of JSON values, or a store of bytes containing JSON, in which case a
+//! `CodecEventStore` can then decode the upcast payloads into domain events.
+
+use futures::{Async, Poll, Stream};
+use serde_json::{self, Value};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use {EventStore, EventVersion, ExpectedVersion, NewEvent, PersistedEvent, SequenceNumber};
+use Transaction;
+
+
+/// A transformation from one version of an event to the next
+type Upcaster = Box<dyn Fn(Value) -> Value + Send + Sync>;
+
+
+/// A single link in a chain of upcasters
+struct Step {
+    /// The event type of the payload once it has been upcast, if the upcaster
+    /// renames the event
+    renamed: Option<String>,
+    upcaster: Upcaster,
+}
+
+
+/// A chain of upcasters, keyed by event type and the version that they
+/// upcast from
+pub struct Upcasters {
+    steps: BTreeMap<(String, EventVersion), Step>,
+}
+
+
+impl Upcasters {
+    /// Create an empty chain of upcasters
+    pub fn new() -> Upcasters {
+        Upcasters { steps: BTreeMap::new() }
+    }
+
+    /// Register an upcaster that converts payloads of the given event type
+    /// from `version` to `version + 1`
+    pub fn register<S, F>(mut self,
+                          event_type: S,
+                          version: EventVersion,
+                          upcaster: F)
+                          -> Upcasters
+        where S: Into<String>,
+              F: Fn(Value) -> Value + Send + Sync + 'static
+    {
+        let step = Step {
+            renamed: None,
+            upcaster: Box::new(upcaster),
+        };
+
+        self.steps.insert((event_type.into(), version), step);
+        self
+    }
+
+    /// Register an upcaster that converts payloads of the given event type
+    /// from `version` to `version + 1`, renaming the event type. The
+    /// upcasters for the new event type will be applied from `version + 1`
+    /// onwards.
+    pub fn register_renamed<S, T, F>(mut self,
+                                     event_type: S,
+                                     version: EventVersion,
+                                     new_event_type: T,
+                                     upcaster: F)
+                                     -> Upcasters
+        where S: Into<String>,
+              T: Into<String>,
+              F: Fn(Value) -> Value + Send + Sync + 'static
+    {
+        let step = Step {
+            renamed: Some(new_event_type.into()),
+            upcaster: Box::new(upcaster),
+        };
+
+        self.steps.insert((event_type.into(), version), step);
+        self
+    }
+
+    /// The latest version of the given event type, which is the version that
+    /// all payloads of that type will be upcast to
+    pub fn current_version(&self, event_type: &str) -> EventVersion {
+        self.steps
+            .keys()
+            .filter(|(ty, _)| ty == event_type)
+            .map(|&(_, version)| version + 1)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Whether there are any upcasters for the event type at the given version
+    fn needs_upcast(&self, event_type: &str, event_version: EventVersion) -> bool {
+        self.steps.contains_key(&(event_type.to_string(), event_version))
+    }
+
+    /// Convert the payload of the event to the latest version of its event
+    /// type, updating the event type and version to match. Events that are
+    /// already at their latest version are returned unchanged.
+    pub fn upcast<Offset>(&self,
+                          mut event: PersistedEvent<Offset, Value>)
+                          -> PersistedEvent<Offset, Value> {
+        while let Some(step) = self.steps.get(&(event.event_type.clone(), event.event_version)) {
+            event.payload = (step.upcaster)(event.payload);
+            event.event_version += 1;
+
+            if let Some(ref renamed) = step.renamed {
+                event.event_type = renamed.clone();
+            }
+        }
+
+        event
+    }
+}
+
+
+impl Default for Upcasters {
+    fn default() -> Upcasters {
+        Upcasters::new()
+    }
+}
+
+
+impl fmt::Debug for Upcasters {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Upcasters")
+            .field("upcasters", &self.steps.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+
+/// A payload that can be upcast, by converting it to and from a JSON value
+pub trait UpcastPayload: Sized {
+    /// Convert the payload to a JSON value
+    fn into_value(self) -> Result<Value, serde_json::Error>;
+
+    /// Convert an upcast JSON value back to a payload
+    fn from_value(value: Value) -> Result<Self, serde_json::Error>;
+}
+
+
+impl UpcastPayload for Value {
+    fn into_value(self) -> Result<Value, serde_json::Error> {
+        Ok(self)
+    }
+
+    fn from_value(value: Value) -> Result<Value, serde_json::Error> {
+        Ok(value)
+    }
+}
+
+
+/// Byte payloads are expected to contain JSON, as written by the `JsonCodec`.
+/// Only the payloads that need to be upcast are parsed.
+impl UpcastPayload for Vec<u8> {
+    fn into_value(self) -> Result<Value, serde_json::Error> {
+        serde_json::from_slice(&self)
+    }
+
+    fn from_value(value: Value) -> Result<Vec<u8>, serde_json::Error> {
+        serde_json::to_vec(&value)
+    }
+}
+
+
+/// An event store that upcasts the payloads of an underlying event store as
+/// they are read
+///
+/// Appended events are passed through unchanged, so they should be created
+/// at the latest versions of their event types, for example by keeping the
+/// versions declared by their `EventType` implementations in step with the
+/// upcasters.
+#[derive(Debug, Clone)]
+pub struct UpcastEventStore<S> {
+    event_store: S,
+    upcasters: Arc<Upcasters>,
+}
+
+
+impl<S> UpcastEventStore<S> {
+    /// Wrap the event store, upcasting its payloads with the upcasters
+    pub fn new(event_store: S, upcasters: Upcasters) -> UpcastEventStore<S> {
+        UpcastEventStore {
+            event_store: event_store,
+            upcasters: Arc::new(upcasters),
+        }
+    }
+
+    /// The underlying event store
+    pub fn event_store(&self) -> &S {
+        &self.event_store
+    }
+
+    /// The upcasters that are applied to the payloads
+    pub fn upcasters(&self) -> &Upcasters {
+        &self.upcasters
+    }
+
+}
+
+
+impl<S> EventStore for UpcastEventStore<S>
+    where S: EventStore,
+          S::Event: UpcastPayload
+{
+    type Offset = S::Offset;
+    type Event = S::Event;
+    type AppendError = S::AppendError;
+    type AppendFuture = S::AppendFuture;
+    type EventsStream = UpcastStream<S::EventsStream>;
+    type AllEventsStream = UpcastStream<S::AllEventsStream>;
+
+    fn append_events(&self,
+                     source_id: Uuid,
+                     expected_version: ExpectedVersion,
+                     events: Vec<NewEvent<S::Event>>)
+                     -> S::AppendFuture {
+        self.event_store.append_events(source_id, expected_version, events)
+    }
+
+    fn append_events_idempotent(&self,
+                                idempotency_key: Uuid,
+                                source_id: Uuid,
+                                expected_version: ExpectedVersion,
+                                events: Vec<NewEvent<S::Event>>)
+                                -> S::AppendFuture {
+        self.event_store.append_events_idempotent(idempotency_key,
+                                                  source_id,
+                                                  expected_version,
+                                                  events)
+    }
+
+    fn append_transaction(&self, transaction: Transaction<S::Event>) -> S::AppendFuture {
+        self.event_store.append_transaction(transaction)
+    }
+
+    fn events(&self, source_id: Uuid, offset: S::Offset) -> Self::EventsStream {
+        UpcastStream {
+            stream: self.event_store.events(source_id, offset),
+            upcasters: self.upcasters.clone(),
+        }
+    }
+
+    fn events_from(&self, source_id: Uuid, sequence_number: SequenceNumber) -> Self::EventsStream {
+        UpcastStream {
+            stream: self.event_store.events_from(source_id, sequence_number),
+            upcasters: self.upcasters.clone(),
+        }
+    }
+
+    fn all_events(&self, offset: S::Offset) -> Self::AllEventsStream {
+        UpcastStream {
+            stream: self.event_store.all_events(offset),
+            upcasters: self.upcasters.clone(),
+        }
+    }
+
+    /// Note that the event types are matched against the types that the
+    /// events were stored with, before they are upcast.
+    fn all_events_of_types(&self,
+                           event_types: &[&str],
+                           offset: S::Offset)
+                           -> Self::AllEventsStream {
+        UpcastStream {
+            stream: self.event_store.all_events_of_types(event_types, offset),
+            upcasters: self.upcasters.clone(),
+        }
+    }
+}
+
+
+/// An error that may be yielded by an `UpcastStream`
+#[derive(Debug)]
+pub enum UpcastStreamError<EventsError> {
+    /// A payload that needed to be upcast could not be converted to or from
+    /// JSON
+    Upcast(serde_json::Error),
+    /// An error occurred in the underlying stream
+    Events(EventsError),
+}
+
+
+/// A stream that upcasts the payloads of the events in an underlying stream
+#[derive(Debug)]
+pub struct UpcastStream<S> {
+    stream: S,
+    upcasters: Arc<Upcasters>,
+}
+
+
+impl<S, Offset, P> Stream for UpcastStream<S>
+    where S: Stream<Item = PersistedEvent<Offset, P>>,
+          P: UpcastPayload
+{
+    type Item = PersistedEvent<Offset, P>;
+    type Error = UpcastStreamError<S::Error>;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        match self.stream.poll().map_err(UpcastStreamError::Events)? {
+            Async::Ready(Some(event)) => {
+                if !self.upcasters.needs_upcast(&event.event_type, event.event_version) {
+                    return Ok(Async::Ready(Some(event)));
+                }
+
+                let event = transpose(event.map(P::into_value))?;
+                let event = transpose(self.upcasters.upcast(event).map(P::from_value))?;
+
+                Ok(Async::Ready(Some(event)))
+            },
+            Async::Ready(None) => Ok(Async::Ready(None)),
+            Async::NotReady => Ok(Async::NotReady),
+        }
+    }
+}
+
+
+/// Pull the result of converting the payload out of the event
+fn transpose<Offset, P, EventsError>
+    (event: PersistedEvent<Offset, Result<P, serde_json::Error>>)
+     -> Result<PersistedEvent<Offset, P>, UpcastStreamError<EventsError>> {
+    Ok(PersistedEvent {
+        offset: event.offset,
+        source_id: event.source_id,
+        sequence_number: event.sequence_number,
+        event_type: event.event_type,
+        event_version: event.event_version,
+        metadata: event.metadata,
+        payload: event.payload.map_err(UpcastStreamError::Upcast)?,
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::time::UNIX_EPOCH;
+
+    use Metadata;
+    use super::*;
+
+
+    fn upcasters() -> Upcasters {
+        Upcasters::new()
+            .register("created", 0, |mut payload| {
+                payload["priority"] = json!(0);
+                payload
+            })
+            .register_renamed("description_changed", 0, "description_updated", |mut payload| {
+                payload["type"] = json!("description_updated");
+                payload
+            })
+            .register("description_updated", 1, |mut payload| {
+                let description = payload.as_object_mut().unwrap().remove("description");
+                payload["text"] = description.unwrap_or(Value::Null);
+                payload
+            })
+    }
+
+
+    fn event(event_type: &str,
+             event_version: EventVersion,
+             payload: Value)
+             -> PersistedEvent<usize, Value> {
+        PersistedEvent {
+            offset: 0,
+            source_id: Uuid::nil(),
+            sequence_number: 0,
+            event_type: event_type.to_string(),
+            event_version: event_version,
+            metadata: Metadata {
+                event_id: Uuid::nil(),
+                recorded_at: UNIX_EPOCH,
+                correlation_id: None,
+                causation_id: None,
+                headers: BTreeMap::new(),
+            },
+            payload: payload,
+        }
+    }
+
+
+    #[test]
+    fn current_version() {
+        let upcasters = upcasters();
+
+        assert_eq!(upcasters.current_version("created"), 1);
+        assert_eq!(upcasters.current_version("description_changed"), 1);
+        assert_eq!(upcasters.current_version("description_updated"), 2);
+        assert_eq!(upcasters.current_version("completed"), 0);
+    }
+
+
+    #[test]
+    fn upcast_events() {
+        let upcasters = upcasters();
+
+        assert_eq!(upcasters.upcast(event("created", 0, json!({ "description": "A" }))),
+                   event("created", 1, json!({ "description": "A", "priority": 0 })));
+        assert_eq!(upcasters.upcast(event("completed", 0, json!({}))),
+                   event("completed", 0, json!({})));
+    }
+
+
+    #[test]
+    fn upcast_events_at_their_current_version() {
+        let upcasters = upcasters();
+
+        // The stored version is used, even though the payload doesn't say
+        // which version it is at
+        assert_eq!(upcasters.upcast(event("created", 1, json!({ "description": "A" }))),
+                   event("created", 1, json!({ "description": "A" })));
+        assert_eq!(upcasters.upcast(event("created", 2, json!({ "description": "A" }))),
+                   event("created", 2, json!({ "description": "A" })));
+    }
+
+
+    #[test]
+    fn upcast_renamed_events() {
+        let upcasters = upcasters();
+
+        assert_eq!(upcasters.upcast(event("description_changed",
+                                          0,
+                                          json!({ "type": "description_changed",
+                                                  "description": "A" }))),
+                   event("description_updated",
+                         2,
+                         json!({ "type": "description_updated", "text": "A" })));
+        assert_eq!(upcasters.upcast(event("description_updated",
+                                          1,
+                                          json!({ "type": "description_updated",
+                                                  "description": "A" }))),
+                   event("description_updated",
+                         2,
+                         json!({ "type": "description_updated", "text": "A" })));
+    }
+}
@@ -0,0 +1,333 @@
+//! Custom derives for removing the boilerplate from domain code
+//!
+//! # `#[derive(EventType)]`
+//!
+//! Implements `chronicle::EventType` for an event enum, naming each variant
+//! in `snake_case`. The name and version of a variant can be overridden, and
+//! the default version for every variant can be set on the enum:
+//!
+//! ```rust,ignore
+//! #[derive(EventType)]
+//! #[event_type(version = "1")]
+//! pub enum Event {
+//!     Created { description: String },
+//!     #[event_type(name = "description_updated", version = "2")]
+//!     DescriptionChanged { description: String },
+//! }
+//! ```
+//!
+//! # `#[derive(ApplyEvent)]`
+//!
+//! Implements `chronicle_domain::ApplyEvent` for an event enum, dispatching
+//! each variant to an `apply_<variant>` method on the state, with the fields
+//! of the variant as arguments:
+//!
+//! ```rust,ignore
+//! #[derive(ApplyEvent)]
+//! #[apply_event(state = "State")]
+//! pub enum Event {
+//!     Created { description: String },
+//!     Completed,
+//! }
+//!
+//! impl State {
+//!     fn apply_created(&mut self, description: String) { ... }
+//!     fn apply_completed(&mut self) { ... }
+//! }
+//! ```
+//!
+//! # `#[derive(Aggregate)]`
+//!
+//! Implements `chronicle_domain::Aggregate` for an aggregate whose state is
+//! `None` until it has been created. The aggregate must provide the following
+//! methods, with the 'not yet created' checks being handled by the derive:
+//!
+//! ```rust,ignore
+//! #[derive(Aggregate)]
+//! #[aggregate(state = "State", event = "Event", command = "Command",
+//!             command_error = "CommandError")]
+//! pub struct Task;
+//!
+//! impl Task {
+//!     /// Handle a command before the aggregate has been created
+//!     fn create(command: Command) -> Result<Vec<Event>, CommandError> { ... }
+//!     /// Handle a command once the aggregate has been created
+//!     fn handle(state: &State, command: Command) -> Result<Vec<Event>, CommandError> { ... }
+//!     /// Create the state from the first event, if possible
+//!     fn created(event: Event) -> Option<State> { ... }
+//! }
+//! ```
+//!
+//! Events that are applied once the aggregate has been created are passed
+//! to `ApplyEvent::apply_to`. The `EventsFuture` defaults to a `Result`,
+//! but can be changed with the `events_future` attribute.
+
+// The `quote!` macro requires deep recursion.
+#![recursion_limit = "192"]
+
+extern crate proc_macro;
+#[macro_use]
+extern crate quote;
+extern crate syn;
+
+use proc_macro::TokenStream;
+use quote::Tokens;
+use syn::{Attribute, Body, DeriveInput, Ident, Lit, MetaItem, NestedMetaItem, VariantData};
+
+
+#[proc_macro_derive(EventType, attributes(event_type))]
+pub fn derive_event_type(input: TokenStream) -> TokenStream {
+    expand(input, expand_event_type)
+}
+
+
+#[proc_macro_derive(ApplyEvent, attributes(apply_event))]
+pub fn derive_apply_event(input: TokenStream) -> TokenStream {
+    expand(input, expand_apply_event)
+}
+
+
+#[proc_macro_derive(Aggregate, attributes(aggregate))]
+pub fn derive_aggregate(input: TokenStream) -> TokenStream {
+    expand(input, expand_aggregate)
+}
+
+
+fn expand<F>(input: TokenStream, f: F) -> TokenStream
+    where F: FnOnce(&DeriveInput) -> Result<Tokens, String>
+{
+    let input = syn::parse_derive_input(&input.to_string()).unwrap();
+    match f(&input) {
+        Ok(expanded) => expanded.parse().unwrap(),
+        Err(msg) => panic!("{}", msg),
+    }
+}
+
+
+/// Collect the `name = "value"` pairs from the attributes with the given name
+fn attr_values(attrs: &[Attribute], name: &str) -> Result<Vec<(String, String)>, String> {
+    let mut values = Vec::new();
+
+    for attr in attrs {
+        match attr.value {
+            MetaItem::List(ref ident, ref items) if ident == name => {
+                for item in items {
+                    match *item {
+                        NestedMetaItem::MetaItem(MetaItem::NameValue(ref key,
+                                                                      Lit::Str(ref value, _))) => {
+                            values.push((key.to_string(), value.clone()));
+                        },
+                        _ => return Err(format!("expected `#[{}(key = \"value\")]`", name)),
+                    }
+                }
+            },
+            _ => {},
+        }
+    }
+
+    Ok(values)
+}
+
+
+/// Convert a `CamelCase` identifier to `snake_case`
+fn snake_case(ident: &Ident) -> String {
+    let mut snake_case = String::new();
+
+    for (i, ch) in ident.as_ref().chars().enumerate() {
+        if ch.is_uppercase() {
+            if i > 0 {
+                snake_case.push('_');
+            }
+            snake_case.extend(ch.to_lowercase());
+        } else {
+            snake_case.push(ch);
+        }
+    }
+
+    snake_case
+}
+
+
+fn parse_version(version: &str) -> Result<u32, String> {
+    version.parse().map_err(|_| format!("invalid event version: `{}`", version))
+}
+
+
+fn expand_event_type(input: &DeriveInput) -> Result<Tokens, String> {
+    let mut default_version = 0;
+    for (key, value) in attr_values(&input.attrs, "event_type")? {
+        match key.as_str() {
+            "version" => default_version = parse_version(&value)?,
+            key => return Err(format!("unknown event_type attribute: `{}`", key)),
+        }
+    }
+
+    let ident = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    let (type_arms, version_arms) = match input.body {
+        Body::Enum(ref variants) => {
+            let mut type_arms = Vec::new();
+            let mut version_arms = Vec::new();
+
+            for variant in variants {
+                let mut name = snake_case(&variant.ident);
+                let mut version = default_version;
+                for (key, value) in attr_values(&variant.attrs, "event_type")? {
+                    match key.as_str() {
+                        "name" => name = value,
+                        "version" => version = parse_version(&value)?,
+                        key => return Err(format!("unknown event_type attribute: `{}`", key)),
+                    }
+                }
+
+                let variant_ident = &variant.ident;
+                let pattern = match variant.data {
+                    VariantData::Struct(_) => quote! { #ident::#variant_ident { .. } },
+                    VariantData::Tuple(_) => quote! { #ident::#variant_ident(..) },
+                    VariantData::Unit => quote! { #ident::#variant_ident },
+                };
+
+                type_arms.push(quote! { #pattern => #name, });
+                version_arms.push(quote! { #pattern => #version, });
+            }
+
+            (type_arms, version_arms)
+        },
+        Body::Struct(_) => {
+            let name = snake_case(ident);
+            (vec![quote! { _ => #name, }], vec![quote! { _ => #default_version, }])
+        },
+    };
+
+    Ok(quote! {
+        impl #impl_generics ::chronicle::EventType for #ident #ty_generics #where_clause {
+            fn event_type(&self) -> &str {
+                match *self {
+                    #(#type_arms)*
+                }
+            }
+
+            fn event_version(&self) -> ::chronicle::EventVersion {
+                match *self {
+                    #(#version_arms)*
+                }
+            }
+        }
+    })
+}
+
+
+fn expand_apply_event(input: &DeriveInput) -> Result<Tokens, String> {
+    let mut state = None;
+    for (key, value) in attr_values(&input.attrs, "apply_event")? {
+        match key.as_str() {
+            "state" => state = Some(syn::parse_type(&value)?),
+            key => return Err(format!("unknown apply_event attribute: `{}`", key)),
+        }
+    }
+    let state = state.ok_or("missing `#[apply_event(state = \"...\")]` attribute")?;
+
+    let variants = match input.body {
+        Body::Enum(ref variants) => variants,
+        Body::Struct(_) => return Err("`ApplyEvent` can only be derived for enums".to_string()),
+    };
+
+    let ident = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    let arms = variants.iter().map(|variant| {
+        let variant_ident = &variant.ident;
+        let method = Ident::new(format!("apply_{}", snake_case(variant_ident)));
+
+        match variant.data {
+            VariantData::Struct(ref fields) => {
+                let fields = fields.iter().map(|field| &field.ident).collect::<Vec<_>>();
+                let args = fields.clone();
+                quote! { #ident::#variant_ident { #(#fields),* } => state.#method(#(#args),*), }
+            },
+            VariantData::Tuple(ref fields) => {
+                let fields = (0..fields.len())
+                    .map(|i| Ident::new(format!("field_{}", i)))
+                    .collect::<Vec<_>>();
+                let args = fields.clone();
+                quote! { #ident::#variant_ident(#(#fields),*) => state.#method(#(#args),*), }
+            },
+            VariantData::Unit => quote! { #ident::#variant_ident => state.#method(), },
+        }
+    });
+
+    Ok(quote! {
+        impl #impl_generics ::chronicle_domain::ApplyEvent<#state> for #ident #ty_generics
+            #where_clause
+        {
+            fn apply_to(self, state: &mut #state) {
+                match self {
+                    #(#arms)*
+                }
+            }
+        }
+    })
+}
+
+
+fn expand_aggregate(input: &DeriveInput) -> Result<Tokens, String> {
+    let (mut state, mut event, mut command, mut command_error) = (None, None, None, None);
+    let mut events_future = None;
+    for (key, value) in attr_values(&input.attrs, "aggregate")? {
+        let ty = Some(syn::parse_type(&value)?);
+        match key.as_str() {
+            "state" => state = ty,
+            "event" => event = ty,
+            "command" => command = ty,
+            "command_error" => command_error = ty,
+            "events_future" => events_future = ty,
+            key => return Err(format!("unknown aggregate attribute: `{}`", key)),
+        }
+    }
+
+    let missing = |name| format!("missing `#[aggregate({} = \"...\")]` attribute", name);
+    let state = state.ok_or_else(|| missing("state"))?;
+    let event = event.ok_or_else(|| missing("event"))?;
+    let command = command.ok_or_else(|| missing("command"))?;
+    let command_error = command_error.ok_or_else(|| missing("command_error"))?;
+    let events_future = match events_future {
+        Some(events_future) => quote! { #events_future },
+        None => quote! { ::std::result::Result<::std::vec::Vec<#event>, #command_error> },
+    };
+
+    let ident = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let aggregate = quote! { <#ident #ty_generics> };
+    let option = quote! { ::std::option::Option };
+
+    Ok(quote! {
+        impl #impl_generics ::chronicle_domain::Aggregate for #ident #ty_generics #where_clause {
+            type State = #option<#state>;
+            type Event = #event;
+            type Command = #command;
+            type CommandError = #command_error;
+            type EventsFuture = #events_future;
+
+            fn initial_state() -> #option<#state> {
+                #option::None
+            }
+
+            fn handle_command(state: &#option<#state>, command: #command) -> #events_future {
+                match *state {
+                    #option::Some(ref state) => #aggregate::handle(state, command),
+                    #option::None => #aggregate::create(command),
+                }
+            }
+
+            fn apply_event(state: &mut #option<#state>, event: #event) {
+                match *state {
+                    #option::Some(ref mut state) => {
+                        ::chronicle_domain::ApplyEvent::apply_to(event, state)
+                    },
+                    #option::None => *state = #aggregate::created(event),
+                }
+            }
+        }
+    })
+}
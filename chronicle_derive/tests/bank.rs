@@ -0,0 +1,16 @@
+//! Check the derives and the transfer saga against the domain of the bank
+//! example
+
+extern crate chronicle;
+#[macro_use]
+extern crate chronicle_derive;
+extern crate chronicle_domain;
+extern crate chronicle_memory;
+extern crate futures;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate uuid;
+
+#[path = "../../examples/bank/src/domain/mod.rs"]
+pub mod domain;
@@ -0,0 +1,74 @@
+extern crate chronicle;
+#[macro_use]
+extern crate chronicle_derive;
+extern crate chronicle_domain;
+
+use chronicle::EventType;
+use chronicle_domain::ApplyEvent;
+
+
+#[derive(Debug, EventType, ApplyEvent)]
+#[event_type(version = "1")]
+#[apply_event(state = "Counter")]
+enum Event {
+    Reset,
+    Incremented(i32, i32),
+    #[event_type(name = "renamed", version = "3")]
+    Named { name: String },
+}
+
+
+#[derive(Debug, Default, PartialEq)]
+struct Counter {
+    name: String,
+    count: i32,
+}
+
+impl Counter {
+    fn apply_reset(&mut self) {
+        self.count = 0;
+    }
+
+    fn apply_incremented(&mut self, by: i32, times: i32) {
+        self.count += by * times;
+    }
+
+    fn apply_named(&mut self, name: String) {
+        self.name = name;
+    }
+}
+
+
+#[derive(Debug, EventType)]
+struct AccountOpened;
+
+
+#[test]
+fn event_type_names() {
+    assert_eq!(Event::Reset.event_type(), "reset");
+    assert_eq!(Event::Incremented(1, 2).event_type(), "incremented");
+    assert_eq!(Event::Named { name: "A".to_string() }.event_type(), "renamed");
+    assert_eq!(AccountOpened.event_type(), "account_opened");
+}
+
+
+#[test]
+fn event_type_versions() {
+    assert_eq!(Event::Reset.event_version(), 1);
+    assert_eq!(Event::Incremented(1, 2).event_version(), 1);
+    assert_eq!(Event::Named { name: "A".to_string() }.event_version(), 3);
+    assert_eq!(AccountOpened.event_version(), 0);
+}
+
+
+#[test]
+fn apply_events() {
+    let mut counter = Counter::default();
+
+    Event::Incremented(2, 3).apply_to(&mut counter);
+    Event::Named { name: "A".to_string() }.apply_to(&mut counter);
+    assert_eq!(counter, Counter { name: "A".to_string(), count: 6 });
+
+    Event::Reset.apply_to(&mut counter);
+    assert_eq!(counter, Counter { name: "A".to_string(), count: 0 });
+}
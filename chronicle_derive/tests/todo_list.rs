@@ -0,0 +1,12 @@
+//! Check the derives against the `Task` aggregate from the todo list example
+
+extern crate chronicle;
+#[macro_use]
+extern crate chronicle_derive;
+extern crate chronicle_domain;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+
+#[path = "../../examples/todo_list/src/domain/task.rs"]
+mod task;
@@ -0,0 +1,82 @@
+//! Sources of the current time
+//!
+//! Anything that depends on the passage of time, like the deadlines of
+//! process managers, reads the time from a `Clock`. This allows the system
+//! clock to be swapped out for a `ManualClock` in tests, making time
+//! dependent behaviour deterministic.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+
+/// A source of the current time
+pub trait Clock {
+    /// The current time
+    fn now(&self) -> SystemTime;
+}
+
+
+/// A clock that reads the time from the operating system
+#[derive(Debug, Copy, Clone, Default)]
+pub struct SystemClock;
+
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+
+/// A clock that only moves when it is told to. Clones of the clock share the
+/// same time.
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    now: Arc<Mutex<SystemTime>>,
+}
+
+
+impl ManualClock {
+    /// Create a clock that is stopped at the given time
+    pub fn new(now: SystemTime) -> ManualClock {
+        ManualClock { now: Arc::new(Mutex::new(now)) }
+    }
+
+    /// Move the clock forward by the duration
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+
+    /// Set the clock to the given time
+    pub fn set(&self, now: SystemTime) {
+        *self.now.lock().unwrap() = now;
+    }
+}
+
+
+impl Clock for ManualClock {
+    fn now(&self) -> SystemTime {
+        *self.now.lock().unwrap()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::UNIX_EPOCH;
+
+    use super::*;
+
+
+    #[test]
+    fn manual_clock() {
+        let clock = ManualClock::new(UNIX_EPOCH);
+        let shared = clock.clone();
+
+        assert_eq!(clock.now(), UNIX_EPOCH);
+        shared.advance(Duration::from_secs(5));
+        assert_eq!(clock.now(), UNIX_EPOCH + Duration::from_secs(5));
+        shared.set(UNIX_EPOCH);
+        assert_eq!(clock.now(), UNIX_EPOCH);
+    }
+}
@@ -0,0 +1,493 @@
+//! Dispatching commands to the aggregates that handle them
+//!
+//! Handlers are registered with a `CommandBus` per command type. Each
+//! command knows the id of the aggregate that it targets, and is executed
+//! against that aggregate via a `Repository`. Middleware may be added to the
+//! bus to observe or reject commands, for example for logging or
+//! authorization.
+
+use chronicle::{AsWrongExpectedVersion, EventStore, EventType, WrongExpectedVersion};
+use futures::{Future, Stream, future};
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::fmt;
+use uuid::Uuid;
+
+use Aggregate;
+use repository::{Repository, RepositoryError};
+
+
+/// A command that can be dispatched via a `CommandBus`, addressed to a
+/// specific instance of an aggregate
+pub trait TargetedCommand: Any + fmt::Debug {
+    /// The aggregate that handles the command
+    type Aggregate: Aggregate;
+
+    /// The id of the aggregate that the command should be executed against
+    fn aggregate_id(&self) -> Uuid;
+
+    /// Convert the command into the command type of the aggregate
+    fn into_command(self) -> <Self::Aggregate as Aggregate>::Command;
+}
+
+
+/// The events that result from a command
+pub type EventsOf<C> = Vec<<<C as TargetedCommand>::Aggregate as Aggregate>::Event>;
+
+/// The error that results from a command being rejected by its aggregate
+pub type CommandErrorOf<C> = <<C as TargetedCommand>::Aggregate as Aggregate>::CommandError;
+
+
+/// An aggregate command addressed to the aggregate with the given id. This
+/// can be used when the aggregate's commands don't include the id.
+pub struct Addressed<A: Aggregate> {
+    /// The id of the aggregate that the command should be executed against
+    pub aggregate_id: Uuid,
+    /// The command to execute
+    pub command: A::Command,
+}
+
+
+impl<A: Aggregate> Addressed<A> {
+    /// Address the command to the aggregate with the given id
+    pub fn new(aggregate_id: Uuid, command: A::Command) -> Addressed<A> {
+        Addressed {
+            aggregate_id: aggregate_id,
+            command: command,
+        }
+    }
+}
+
+
+impl<A: Aggregate> fmt::Debug for Addressed<A>
+    where A::Command: fmt::Debug
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Addressed")
+            .field("aggregate_id", &self.aggregate_id)
+            .field("command", &self.command)
+            .finish()
+    }
+}
+
+
+impl<A: Aggregate> Clone for Addressed<A>
+    where A::Command: Clone
+{
+    fn clone(&self) -> Addressed<A> {
+        Addressed {
+            aggregate_id: self.aggregate_id,
+            command: self.command.clone(),
+        }
+    }
+}
+
+
+impl<A> TargetedCommand for Addressed<A>
+    where A: Aggregate + 'static,
+          A::Command: fmt::Debug
+{
+    type Aggregate = A;
+
+    fn aggregate_id(&self) -> Uuid {
+        self.aggregate_id
+    }
+
+    fn into_command(self) -> A::Command {
+        self.command
+    }
+}
+
+
+/// An error that may occur when dispatching a command
+///
+/// The errors of the event stores that the commands are handled with are
+/// only kept as debug representations, as they can differ between the
+/// handlers of a bus.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DispatchError<CommandError> {
+    /// No handler has been registered for the type of the command
+    NoHandler,
+    /// The command was rejected by middleware, with the given reason
+    Rejected(String),
+    /// The command was rejected by the aggregate
+    Command(CommandError),
+    /// Another command was executed against the aggregate concurrently. The
+    /// command may be retried against the new state of the aggregate.
+    WrongExpectedVersion(WrongExpectedVersion),
+    /// An error occurred when streaming the events of the aggregate
+    Events(String),
+    /// An error occurred when appending the resulting events
+    Append(String),
+}
+
+
+/// A description of a command that is being dispatched, passed to middleware
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommandContext {
+    /// The id of the aggregate that the command targets
+    pub aggregate_id: Uuid,
+    /// A debug representation of the command, useful for logging
+    pub description: String,
+}
+
+
+/// Hooks that are run around the handling of each command
+pub trait Middleware {
+    /// Called before the command is handled. The command may be downcast to
+    /// its concrete type, for example to check that it is authorized.
+    /// Returning an error rejects the command without handling it.
+    fn before_dispatch(&self, context: &CommandContext, command: &dyn Any) -> Result<(), String> {
+        let _ = (context, command);
+        Ok(())
+    }
+
+    /// Called once the command has been handled or rejected, with either the
+    /// number of events that were committed or the error
+    fn after_dispatch(&self, context: &CommandContext, result: Result<usize, &dyn fmt::Debug>) {
+        let _ = (context, result);
+    }
+}
+
+
+/// A future that resolves to the events that were committed by a command
+pub type DispatchFuture<'a, C> =
+    Box<dyn Future<Item = EventsOf<C>, Error = DispatchError<CommandErrorOf<C>>> + 'a>;
+
+
+/// A handler for a specific command type
+trait Handler<C: TargetedCommand>: Send + Sync {
+    fn handle<'a>(&'a self, command: C) -> DispatchFuture<'a, C>;
+}
+
+
+impl<C, S> Handler<C> for Repository<C::Aggregate, S>
+    where C: TargetedCommand,
+          C::Aggregate: Send + Sync,
+          <C::Aggregate as Aggregate>::Event: Clone + EventType,
+          S: EventStore<Event = <C::Aggregate as Aggregate>::Event> + Send + Sync,
+          <S::EventsStream as Stream>::Error: fmt::Debug,
+          S::AppendError: AsWrongExpectedVersion + fmt::Debug
+{
+    fn handle<'a>(&'a self, command: C) -> DispatchFuture<'a, C> {
+        let id = command.aggregate_id();
+
+        Box::new(self.execute(id, command.into_command()).map_err(|error| match error {
+            RepositoryError::Command(error) => DispatchError::Command(error),
+            RepositoryError::Events(error) => DispatchError::Events(format!("{:?}", error)),
+            RepositoryError::Append(error) => {
+                match error.as_wrong_expected_version() {
+                    Some(conflict) => DispatchError::WrongExpectedVersion(*conflict),
+                    None => DispatchError::Append(format!("{:?}", error)),
+                }
+            },
+        }))
+    }
+}
+
+
+/// Routes commands to the repositories of the aggregates that handle them
+pub struct CommandBus {
+    /// The handlers for each command type, each being a boxed `Handler<C>`
+    handlers: HashMap<TypeId, Box<dyn Any + Send + Sync>>,
+    middleware: Vec<Box<dyn Middleware + Send + Sync>>,
+}
+
+
+impl CommandBus {
+    /// Create a command bus with no handlers
+    pub fn new() -> CommandBus {
+        CommandBus {
+            handlers: HashMap::new(),
+            middleware: Vec::new(),
+        }
+    }
+
+    /// Handle commands of type `C` by executing them via the repository,
+    /// replacing any existing handler for the command type
+    pub fn register<C, S>(&mut self, repository: Repository<C::Aggregate, S>)
+        where C: TargetedCommand,
+              C::Aggregate: Send + Sync,
+              <C::Aggregate as Aggregate>::Event: Clone + EventType,
+              S: EventStore<Event = <C::Aggregate as Aggregate>::Event> + Send + Sync + 'static,
+              <S::EventsStream as Stream>::Error: fmt::Debug,
+              S::AppendError: AsWrongExpectedVersion + fmt::Debug
+    {
+        let handler: Box<dyn Handler<C>> = Box::new(repository);
+        self.handlers.insert(TypeId::of::<C>(), Box::new(handler));
+    }
+
+    /// Add middleware to the bus. Middleware is run in the order that it
+    /// was added.
+    pub fn add_middleware<M>(&mut self, middleware: M)
+        where M: Middleware + Send + Sync + 'static
+    {
+        self.middleware.push(Box::new(middleware));
+    }
+
+    /// Dispatch the command to its handler, returning a future of the events
+    /// that were committed as a result
+    pub fn dispatch<'a, C>(&'a self, command: C) -> DispatchFuture<'a, C>
+        where C: TargetedCommand,
+              CommandErrorOf<C>: fmt::Debug
+    {
+        let handler = self.handlers
+            .get(&TypeId::of::<C>())
+            .and_then(|handler| handler.downcast_ref::<Box<dyn Handler<C>>>());
+
+        let context = CommandContext {
+            aggregate_id: command.aggregate_id(),
+            description: format!("{:?}", command),
+        };
+
+        let rejection = self.middleware
+            .iter()
+            .filter_map(|middleware| middleware.before_dispatch(&context, &command).err())
+            .next();
+
+        let error = match (handler, rejection) {
+            (_, Some(reason)) => DispatchError::Rejected(reason),
+            (None, None) => DispatchError::NoHandler,
+            (Some(handler), None) => {
+                return Box::new(handler.handle(command).then(move |result| {
+                    self.after_dispatch(&context, &result);
+                    result
+                }));
+            },
+        };
+
+        let result = Err(error);
+        self.after_dispatch(&context, &result);
+        Box::new(future::result(result))
+    }
+
+    fn after_dispatch<Event, E>(&self,
+                                context: &CommandContext,
+                                result: &Result<Vec<Event>, DispatchError<E>>)
+        where E: fmt::Debug
+    {
+        for middleware in &self.middleware {
+            let result = match *result {
+                Ok(ref events) => Ok(events.len()),
+                Err(ref error) => Err(error as &dyn fmt::Debug),
+            };
+            middleware.after_dispatch(context, result);
+        }
+    }
+}
+
+
+impl Default for CommandBus {
+    fn default() -> CommandBus {
+        CommandBus::new()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use chronicle::{EventType, ExpectedVersion};
+    use chronicle_memory::MemoryEventStore;
+    use std::sync::{Arc, Mutex};
+
+    use Aggregate;
+    use repository::tests::YieldOnce;
+    use super::*;
+
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct Added(u32);
+
+    impl EventType for Added {
+        fn event_type(&self) -> &str {
+            "added"
+        }
+    }
+
+
+    /// A total that can't exceed ten
+    struct Total;
+
+    impl Aggregate for Total {
+        type State = u32;
+        type Event = Added;
+        type Command = u32;
+        type CommandError = &'static str;
+        type EventsFuture = YieldOnce<Vec<Added>, &'static str>;
+
+        fn initial_state() -> u32 {
+            0
+        }
+
+        fn handle_command(state: &u32, amount: u32) -> YieldOnce<Vec<Added>, &'static str> {
+            YieldOnce::new(if state + amount > 10 {
+                Err("too big")
+            } else {
+                Ok(vec![Added(amount)])
+            })
+        }
+
+        fn apply_event(state: &mut u32, Added(amount): Added) {
+            *state += amount;
+        }
+    }
+
+
+    /// A command with the aggregate id included
+    #[derive(Debug)]
+    struct Add {
+        total_id: Uuid,
+        amount: u32,
+    }
+
+    impl TargetedCommand for Add {
+        type Aggregate = Total;
+
+        fn aggregate_id(&self) -> Uuid {
+            self.total_id
+        }
+
+        fn into_command(self) -> u32 {
+            self.amount
+        }
+    }
+
+
+    /// Records the commands that were dispatched
+    #[derive(Clone)]
+    struct Log(Arc<Mutex<Vec<String>>>);
+
+    impl Middleware for Log {
+        fn after_dispatch(&self, context: &CommandContext, result: Result<usize, &dyn fmt::Debug>) {
+            let entry = match result {
+                Ok(events) => format!("{}: {} events", context.description, events),
+                Err(error) => format!("{}: {:?}", context.description, error),
+            };
+            self.0.lock().unwrap().push(entry);
+        }
+    }
+
+
+    /// Only allows small amounts to be added
+    struct Authorize;
+
+    impl Middleware for Authorize {
+        fn before_dispatch(&self, _: &CommandContext, command: &dyn Any) -> Result<(), String> {
+            match command.downcast_ref::<Add>() {
+                Some(command) if command.amount > 5 => Err("unauthorized".to_string()),
+                _ => Ok(()),
+            }
+        }
+    }
+
+
+    fn command_bus(event_store: &MemoryEventStore<Added>) -> CommandBus {
+        let mut command_bus = CommandBus::new();
+        command_bus.register::<Add, _>(Repository::new(event_store.clone()));
+        command_bus.register::<Addressed<Total>, _>(Repository::new(event_store.clone()));
+        command_bus
+    }
+
+
+    #[test]
+    fn dispatch_commands() {
+        let event_store = MemoryEventStore::new();
+        let command_bus = command_bus(&event_store);
+        let total_id = Uuid::new_v4();
+
+        let add = Add {
+            total_id: total_id,
+            amount: 4,
+        };
+
+        assert_eq!(command_bus.dispatch(add).wait().unwrap(), vec![Added(4)]);
+        assert_eq!(command_bus.dispatch(Addressed::<Total>::new(total_id, 5)).wait().unwrap(),
+                   vec![Added(5)]);
+
+        let repository = Repository::<Total, _>::new(event_store);
+        assert_eq!(repository.load(total_id).wait().unwrap().0, 9);
+    }
+
+
+    #[test]
+    fn dispatch_rejected_command() {
+        let command_bus = command_bus(&MemoryEventStore::new());
+        let command = Addressed::<Total>::new(Uuid::new_v4(), 11);
+
+        match command_bus.dispatch(command).wait() {
+            Err(DispatchError::Command("too big")) => {},
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+
+    #[test]
+    fn dispatch_concurrent_commands() {
+        let command_bus = command_bus(&MemoryEventStore::new());
+        let total_id = Uuid::new_v4();
+
+        // Both commands will load the total before either of them has had a
+        // chance to append their events
+        let (result_1, result_2) = command_bus.dispatch(Addressed::<Total>::new(total_id, 1))
+            .then(Ok::<_, ()>)
+            .join(command_bus.dispatch(Addressed::<Total>::new(total_id, 2)).then(Ok))
+            .wait()
+            .unwrap();
+
+        assert_eq!(result_1, Ok(vec![Added(1)]));
+        assert_eq!(result_2,
+                   Err(DispatchError::WrongExpectedVersion(WrongExpectedVersion {
+                       source_id: total_id,
+                       expected: ExpectedVersion::NoStream,
+                       current: Some(0),
+                   })));
+    }
+
+
+    #[test]
+    fn dispatch_without_handler() {
+        let command_bus = CommandBus::new();
+        let command = Addressed::<Total>::new(Uuid::new_v4(), 1);
+
+        match command_bus.dispatch(command).wait() {
+            Err(DispatchError::NoHandler) => {},
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+
+    #[test]
+    fn dispatch_with_middleware() {
+        let event_store = MemoryEventStore::new();
+        let mut command_bus = command_bus(&event_store);
+        let log = Log(Arc::new(Mutex::new(Vec::new())));
+        let total_id = Uuid::new_v4();
+
+        command_bus.add_middleware(log.clone());
+        command_bus.add_middleware(Authorize);
+
+        let small = Add {
+            total_id: total_id,
+            amount: 1,
+        };
+        let large = Add {
+            total_id: total_id,
+            amount: 6,
+        };
+
+        command_bus.dispatch(small).wait().unwrap();
+        match command_bus.dispatch(large).wait() {
+            Err(DispatchError::Rejected(ref reason)) if reason == "unauthorized" => {},
+            result => panic!("unexpected result: {:?}", result),
+        }
+
+        // The aggregate was not consulted for the rejected command
+        let repository = Repository::<Total, _>::new(event_store);
+        assert_eq!(repository.load(total_id).wait().unwrap().0, 1);
+
+        assert_eq!(*log.0.lock().unwrap(),
+                   vec![format!("Add {{ total_id: {:?}, amount: 1 }}: 1 events", total_id),
+                        format!("Add {{ total_id: {:?}, amount: 6 }}: Rejected(\"unauthorized\")",
+                                total_id)]);
+    }
+}
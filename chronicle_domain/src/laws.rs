@@ -0,0 +1,362 @@
+//! Property based checks of the laws that aggregates are expected to obey
+//!
+//! Sequences of commands are generated with `quickcheck` and handled in turn,
+//! applying the resulting events to the state of the aggregate. Failing
+//! sequences are shrunk to a minimal counterexample. The following laws are
+//! checked:
+//!
+//! - the events emitted by `handle_command` can always be applied without
+//!   panicking
+//! - handling a command leaves the state unchanged, so that it is only ever
+//!   changed by applying events. This can only be broken by state with
+//!   interior mutability, like a `Cell`, that is changed by `handle_command`.
+//! - replaying the emitted history from the initial state reproduces the
+//!   same state
+//!
+//! This module is only available when the `quickcheck` feature is enabled.
+
+use futures::{Future, IntoFuture};
+use quickcheck::{Arbitrary, Gen, QuickCheck, Testable, TestResult};
+use std::any::Any;
+use std::fmt;
+use std::marker::PhantomData;
+use std::panic::{self, AssertUnwindSafe};
+
+use Aggregate;
+
+
+/// Check that the aggregate obeys the laws for randomly generated sequences
+/// of commands, panicking if a counterexample is found
+pub fn check_laws<A>()
+    where A: Aggregate + 'static,
+          A::State: Clone + fmt::Debug + PartialEq,
+          A::Event: Clone,
+          A::Command: Arbitrary + fmt::Debug
+{
+    check_laws_with::<A, _>(|_| true);
+}
+
+
+/// Check that the aggregate obeys the laws, along with an invariant that must
+/// hold for every state that the aggregate passes through
+pub fn check_laws_with<A, F>(invariant: F)
+    where A: Aggregate + 'static,
+          A::State: Clone + fmt::Debug + PartialEq,
+          A::Event: Clone,
+          A::Command: Arbitrary + fmt::Debug,
+          F: Fn(&A::State) -> bool + Send + 'static
+{
+    QuickCheck::new().quickcheck(Laws {
+        invariant: invariant,
+        aggregate: PhantomData::<fn() -> A>,
+    });
+}
+
+
+struct Laws<A, F> {
+    invariant: F,
+    aggregate: PhantomData<fn() -> A>,
+}
+
+
+impl<A, F> Laws<A, F>
+    where A: Aggregate,
+          A::State: Clone + fmt::Debug + PartialEq,
+          A::Event: Clone,
+          F: Fn(&A::State) -> bool
+{
+    fn check(&self, commands: Vec<A::Command>) -> Result<(), String> {
+        let mut state = A::initial_state();
+        let mut history = Vec::new();
+
+        if !(self.invariant)(&state) {
+            return Err(format!("invariant violated by the initial state {:?}", state));
+        }
+
+        for command in commands {
+            let previous = state.clone();
+            let result = A::handle_command(&state, command).into_future().wait();
+
+            if state != previous {
+                return Err(format!("state changed from {:?} to {:?} while handling a command",
+                                   previous,
+                                   state));
+            }
+
+            let events = match result {
+                Ok(events) => events,
+                Err(_) => continue,
+            };
+
+            for event in events {
+                history.push(event.clone());
+                A::apply_event(&mut state, event);
+
+                if !(self.invariant)(&state) {
+                    return Err(format!("invariant violated by state {:?}", state));
+                }
+            }
+        }
+
+        let mut replayed = A::initial_state();
+        for event in history {
+            A::apply_event(&mut replayed, event);
+        }
+
+        if replayed != state {
+            return Err(format!("replaying the history resulted in {:?}, but expected {:?}",
+                               replayed,
+                               state));
+        }
+
+        Ok(())
+    }
+
+    /// Check the commands, converting panics into failures
+    fn check_unwind(&self, commands: Vec<A::Command>) -> Result<(), String> {
+        match panic::catch_unwind(AssertUnwindSafe(|| self.check(commands))) {
+            Ok(result) => result,
+            Err(payload) => Err(format!("panicked: {}", panic_message(&payload))),
+        }
+    }
+}
+
+
+fn panic_message(payload: &Box<dyn Any + Send>) -> &str {
+    match payload.downcast_ref::<&str>() {
+        Some(msg) => msg,
+        None => payload.downcast_ref::<String>().map_or("<unknown>", |msg| msg),
+    }
+}
+
+
+impl<A, F> Testable for Laws<A, F>
+    where A: Aggregate + 'static,
+          A::State: Clone + fmt::Debug + PartialEq,
+          A::Event: Clone,
+          A::Command: Arbitrary + fmt::Debug,
+          F: Fn(&A::State) -> bool + Send + 'static
+{
+    fn result<G: Gen>(&self, g: &mut G) -> TestResult {
+        let mut commands = Vec::<A::Command>::arbitrary(g);
+        let mut error = match self.check_unwind(commands.clone()) {
+            Ok(()) => return TestResult::passed(),
+            Err(error) => error,
+        };
+
+        // Keep shrinking the commands for as long as they still fail
+        'shrink: loop {
+            for shrunk in commands.shrink() {
+                if let Err(shrunk_error) = self.check_unwind(shrunk.clone()) {
+                    commands = shrunk;
+                    error = shrunk_error;
+                    continue 'shrink;
+                }
+            }
+            break;
+        }
+
+        TestResult::error(format!("{} after handling the commands {:?}", error, commands))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use quickcheck::{Arbitrary, Gen};
+    use std::cell::Cell;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use Aggregate;
+    use super::*;
+
+
+    #[derive(Debug, Clone, PartialEq)]
+    enum Command {
+        Add(u8),
+        Clear,
+    }
+
+    impl Arbitrary for Command {
+        fn arbitrary<G: Gen>(g: &mut G) -> Command {
+            match u8::arbitrary(g) {
+                n if n < 32 => Command::Clear,
+                n => Command::Add(n),
+            }
+        }
+    }
+
+
+    /// A total that is cleared by applying the negation of the total
+    struct Total;
+
+    impl Aggregate for Total {
+        type State = u32;
+        type Event = i64;
+        type Command = Command;
+        type CommandError = ();
+        type EventsFuture = Result<Vec<i64>, ()>;
+
+        fn initial_state() -> u32 {
+            0
+        }
+
+        fn handle_command(state: &u32, command: Command) -> Result<Vec<i64>, ()> {
+            match command {
+                Command::Add(n) => Ok(vec![n as i64]),
+                Command::Clear if *state == 0 => Ok(vec![]),
+                Command::Clear => Ok(vec![-(*state as i64)]),
+            }
+        }
+
+        fn apply_event(state: &mut u32, event: i64) {
+            *state = (*state as i64 + event) as u32;
+        }
+    }
+
+
+    /// A total that forgets to negate the total when it is cleared
+    struct BrokenTotal;
+
+    impl Aggregate for BrokenTotal {
+        type State = u32;
+        type Event = i64;
+        type Command = Command;
+        type CommandError = ();
+        type EventsFuture = Result<Vec<i64>, ()>;
+
+        fn initial_state() -> u32 {
+            0
+        }
+
+        fn handle_command(state: &u32, command: Command) -> Result<Vec<i64>, ()> {
+            match command {
+                Command::Add(n) => Ok(vec![n as i64]),
+                Command::Clear => Ok(vec![*state as i64]),
+            }
+        }
+
+        fn apply_event(state: &mut u32, event: i64) {
+            *state = (*state as i64 + event) as u32;
+        }
+    }
+
+
+    /// A total that panics when it overflows a byte
+    struct PanickingTotal;
+
+    impl Aggregate for PanickingTotal {
+        type State = u8;
+        type Event = u8;
+        type Command = Command;
+        type CommandError = ();
+        type EventsFuture = Result<Vec<u8>, ()>;
+
+        fn initial_state() -> u8 {
+            0
+        }
+
+        fn handle_command(_: &u8, command: Command) -> Result<Vec<u8>, ()> {
+            match command {
+                Command::Add(n) => Ok(vec![n]),
+                Command::Clear => Ok(vec![]),
+            }
+        }
+
+        fn apply_event(state: &mut u8, event: u8) {
+            *state = state.checked_add(event).expect("overflowed");
+        }
+    }
+
+
+    /// A total that counts the commands that it handles, without emitting
+    /// any events for them
+    struct CountingTotal;
+
+    impl Aggregate for CountingTotal {
+        type State = Cell<u32>;
+        type Event = u32;
+        type Command = Command;
+        type CommandError = ();
+        type EventsFuture = Result<Vec<u32>, ()>;
+
+        fn initial_state() -> Cell<u32> {
+            Cell::new(0)
+        }
+
+        fn handle_command(state: &Cell<u32>, _: Command) -> Result<Vec<u32>, ()> {
+            state.set(state.get() + 1);
+            Ok(vec![])
+        }
+
+        fn apply_event(state: &mut Cell<u32>, event: u32) {
+            state.set(event);
+        }
+    }
+
+
+    /// The number of events applied to `SequencedTotal`s, across all of
+    /// their histories
+    static APPLIED_EVENTS: AtomicUsize = AtomicUsize::new(0);
+
+
+    /// A total that depends on more than the events that were applied to it
+    struct SequencedTotal;
+
+    impl Aggregate for SequencedTotal {
+        type State = usize;
+        type Event = u8;
+        type Command = Command;
+        type CommandError = ();
+        type EventsFuture = Result<Vec<u8>, ()>;
+
+        fn initial_state() -> usize {
+            0
+        }
+
+        fn handle_command(_: &usize, command: Command) -> Result<Vec<u8>, ()> {
+            match command {
+                Command::Add(n) => Ok(vec![n]),
+                Command::Clear => Ok(vec![0]),
+            }
+        }
+
+        fn apply_event(state: &mut usize, _: u8) {
+            *state = APPLIED_EVENTS.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+
+    #[test]
+    fn laws_hold() {
+        check_laws::<Total>();
+    }
+
+
+    #[test]
+    #[should_panic(expected = "invariant violated")]
+    fn invariant_violated() {
+        check_laws_with::<BrokenTotal, _>(|total| *total < 1000);
+    }
+
+
+    #[test]
+    #[should_panic(expected = "panicked: overflowed")]
+    fn apply_event_panics() {
+        check_laws::<PanickingTotal>();
+    }
+
+
+    #[test]
+    #[should_panic(expected = "while handling a command")]
+    fn handle_command_changes_state() {
+        check_laws::<CountingTotal>();
+    }
+
+
+    #[test]
+    #[should_panic(expected = "replaying the history")]
+    fn replay_differs() {
+        check_laws::<SequencedTotal>();
+    }
+}
@@ -1,15 +1,38 @@
+extern crate chronicle;
 extern crate futures;
+#[cfg(feature = "quickcheck")]
+extern crate quickcheck;
+extern crate uuid;
+
+#[cfg(test)]
+extern crate chronicle_memory;
 
 use futures::IntoFuture;
 
+pub mod clock;
+pub mod command_bus;
+#[cfg(feature = "quickcheck")]
+pub mod laws;
+pub mod process_manager;
+pub mod repository;
+pub mod saga;
+pub mod scheduler;
+pub mod snapshot;
+pub mod testing;
+
+pub use command_bus::CommandBus;
+pub use process_manager::{ProcessManager, ProcessRunner};
+pub use repository::Repository;
+pub use scheduler::Scheduler;
+
 /// An aggregate that is responsible for validating and applying
 /// commands.
 ///
 /// Aggregates may consist of one domain object, or be composed of a cluster
 /// of domain objects. They should in general be designed in such that
 /// transactions do not cross aggregate boundaries. Doing so increases the
-/// complexity of maintaining a valid state, and requires the use of something
-/// like sagas (not yet implemented).
+/// complexity of maintaining a valid state. Workflows that span multiple
+/// aggregates should instead be coordinated by a `ProcessManager`.
 ///
 /// # References
 ///
@@ -45,3 +68,12 @@ pub trait Aggregate {
     /// always succeed.
     fn apply_event(state: &mut Self::State, event: Self::Event);
 }
+
+
+/// Applies an event to the state of an aggregate that has already been
+/// created. This is usually derived for event enums with `chronicle_derive`,
+/// dispatching each variant to a corresponding method on the state.
+pub trait ApplyEvent<State> {
+    /// Apply the event to the state
+    fn apply_to(self, state: &mut State);
+}
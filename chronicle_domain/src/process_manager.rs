@@ -0,0 +1,826 @@
+//! Coordinating workflows that span multiple aggregates
+//!
+//! Aggregates should not be modified in the same transaction, so workflows
+//! that involve more than one aggregate are driven by a process manager. The
+//! process manager reacts to the events in the global event stream, records
+//! its progress as events of its own, and emits commands to the aggregates
+//! that take part in the workflow.
+//!
+//! Each instance of a process is event sourced from its own stream in an
+//! event store, and the offset of the last event that was handled is saved to
+//! a `CheckpointStore`, allowing the process manager to resume where it left
+//! off after a restart.
+//!
+//! Commands are dispatched before the process' events are appended and the
+//! checkpoint is saved. If the process manager is stopped in between, the
+//! event will be handled again when it resumes, so commands may be delivered
+//! more than once. Aggregates should be prepared to ignore duplicate
+//! commands, for example by including the process id in them.
+//!
+//! A process instance may also set a deadline, by which it expects to have
+//! received its next event. Deadlines are checked against the `Clock` of the
+//! `ProcessRunner`, and once a deadline has passed the process instance is
+//! given the chance to react to the timeout.
+
+use chronicle::{AllEventsError, CheckpointStore, EventStore, EventType, EventsError,
+                ExpectedVersion, Metadata, NewEvent, PersistedEvent};
+use futures::{Future, IntoFuture, Stream, future};
+use futures::future::{Either, Loop};
+use std::collections::BTreeMap;
+use std::marker::PhantomData;
+use std::rc::Rc;
+use std::time::SystemTime;
+use uuid::Uuid;
+
+use clock::{Clock, SystemClock};
+
+
+/// A long running process that coordinates a workflow between aggregates
+pub trait ProcessManager {
+    /// The events from the global event stream that the process reacts to
+    type Input;
+
+    /// The state of a single instance of the process
+    type State;
+
+    /// The events that record the progress of the process
+    type Event;
+
+    /// The commands that the process emits to other aggregates
+    type Command;
+
+    /// The seed state, before the process has been started
+    fn initial_state() -> Self::State;
+
+    /// The id of the process instance that should handle the event, or `None`
+    /// if the event is not relevant to the process
+    fn process_id<Offset>(event: &PersistedEvent<Offset, Self::Input>) -> Option<Uuid>;
+
+    /// Decide how the process instance should react to an event, based on its
+    /// current state and the current time. Events that have already been
+    /// handled should be ignored, as they may be delivered again after a
+    /// restart.
+    fn react(process_id: Uuid,
+             state: &Self::State,
+             event: Self::Input,
+             now: SystemTime)
+             -> Reaction<Self::Event, Self::Command>;
+
+    /// Apply an event to the state of the process. Note that this should
+    /// always succeed.
+    fn apply_event(state: &mut Self::State, event: Self::Event);
+
+    /// The time by which the process instance expects to have received its
+    /// next event, if any
+    fn deadline(state: &Self::State) -> Option<SystemTime> {
+        let _ = state;
+        None
+    }
+
+    /// Decide how the process instance should react to its deadline passing
+    /// without it having received the events that it was waiting for
+    fn time_out(process_id: Uuid,
+                state: &Self::State,
+                now: SystemTime)
+                -> Reaction<Self::Event, Self::Command> {
+        let _ = (process_id, state, now);
+        Reaction::new()
+    }
+}
+
+
+/// The reaction of a process to an event
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reaction<Event, Command> {
+    /// The events to record the progress of the process
+    pub events: Vec<Event>,
+    /// The commands to dispatch to other aggregates
+    pub commands: Vec<Command>,
+}
+
+
+impl<Event, Command> Reaction<Event, Command> {
+    /// A reaction that does nothing
+    pub fn new() -> Reaction<Event, Command> {
+        Reaction {
+            events: Vec::new(),
+            commands: Vec::new(),
+        }
+    }
+
+    /// Record an event as part of the reaction
+    pub fn with_event(mut self, event: Event) -> Reaction<Event, Command> {
+        self.events.push(event);
+        self
+    }
+
+    /// Dispatch a command as part of the reaction
+    pub fn with_command(mut self, command: Command) -> Reaction<Event, Command> {
+        self.commands.push(command);
+        self
+    }
+}
+
+
+impl<Event, Command> Default for Reaction<Event, Command> {
+    fn default() -> Reaction<Event, Command> {
+        Reaction::new()
+    }
+}
+
+
+/// An error that may occur when running a process manager
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProcessError<CheckpointError, InputError, EventsError, DispatchError, AppendError> {
+    /// An error occurred when saving or loading the checkpoint
+    Checkpoint(CheckpointError),
+    /// An error occurred when streaming the events that the process reacts to
+    Input(InputError),
+    /// An error occurred when streaming the events of a process instance
+    Events(EventsError),
+    /// An error occurred when dispatching a command
+    Dispatch(DispatchError),
+    /// An error occurred when appending the events of a process instance.
+    /// This will happen if the process manager is being run concurrently.
+    Append(AppendError),
+}
+
+
+/// The `ProcessError` of a runner that stores its process instances in `S`
+/// and its checkpoints in `C`
+type RunError<S, C, InputError, DispatchError> = ProcessError<<C as CheckpointStore>::Error,
+                                                              InputError,
+                                                              EventsError<S>,
+                                                              DispatchError,
+                                                              <S as EventStore>::AppendError>;
+
+
+/// An error that may occur when handling the deadline of a process instance
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeadlineError<EventsError, DispatchError, AppendError> {
+    /// An error occurred when streaming the events of the process instances
+    Events(EventsError),
+    /// An error occurred when dispatching a command
+    Dispatch(DispatchError),
+    /// An error occurred when appending the events of a process instance.
+    /// This will happen if the process instance was updated concurrently.
+    Append(AppendError),
+}
+
+
+/// An error that may occur when committing the reaction of a process instance
+#[derive(Debug)]
+enum CommitError<DispatchError, AppendError> {
+    Dispatch(DispatchError),
+    Append(AppendError),
+}
+
+
+/// Runs a process manager, storing the state of each process instance in an
+/// event store
+#[derive(Debug, Clone)]
+pub struct ProcessRunner<P, S, C, Cl = SystemClock> {
+    name: String,
+    event_store: S,
+    checkpoint_store: C,
+    clock: Cl,
+    process: PhantomData<P>,
+}
+
+
+impl<P, S, C> ProcessRunner<P, S, C>
+    where P: ProcessManager,
+          S: EventStore<Event = P::Event>,
+          C: CheckpointStore
+{
+    /// Create a runner for the process manager that uses the system clock.
+    /// The name is used to identify the checkpoint of the process manager.
+    pub fn new<N: Into<String>>(name: N,
+                                event_store: S,
+                                checkpoint_store: C)
+                                -> ProcessRunner<P, S, C> {
+        ProcessRunner {
+            name: name.into(),
+            event_store: event_store,
+            checkpoint_store: checkpoint_store,
+            clock: SystemClock,
+            process: PhantomData,
+        }
+    }
+}
+
+
+impl<P, S, C, Cl> ProcessRunner<P, S, C, Cl>
+    where P: ProcessManager,
+          S: EventStore<Event = P::Event>,
+          C: CheckpointStore,
+          Cl: Clock
+{
+    /// Use a different clock for timing the process instances
+    pub fn with_clock<NewCl: Clock>(self, clock: NewCl) -> ProcessRunner<P, S, C, NewCl> {
+        ProcessRunner {
+            name: self.name,
+            event_store: self.event_store,
+            checkpoint_store: self.checkpoint_store,
+            clock: clock,
+            process: PhantomData,
+        }
+    }
+
+    /// The event store that the process instances are stored in
+    pub fn event_store(&self) -> &S {
+        &self.event_store
+    }
+
+    /// The checkpoint store that the progress of the process manager is
+    /// saved to
+    pub fn checkpoint_store(&self) -> &C {
+        &self.checkpoint_store
+    }
+
+    /// The clock that is used for timing the process instances
+    pub fn clock(&self) -> &Cl {
+        &self.clock
+    }
+
+    /// Load the current state of the process instance, along with the
+    /// version that it was loaded at
+    pub fn load(&self,
+                process_id: Uuid)
+                -> impl Future<Item = (P::State, ExpectedVersion), Error = EventsError<S>> {
+        self.event_store
+            .events(process_id, S::Offset::default())
+            .fold((P::initial_state(), ExpectedVersion::NoStream),
+                  |(mut state, _), event| {
+                let version = ExpectedVersion::Exact(event.sequence_number);
+                P::apply_event(&mut state, event.payload);
+                Ok((state, version))
+            })
+    }
+
+    /// Run the process manager over the events in the event store, starting
+    /// after the last checkpoint. Commands are passed to the `dispatch`
+    /// function, and the future resolves once the process manager has caught
+    /// up with the store.
+    pub fn run<'a, E, D, F>
+        (&'a self,
+         event_store: &'a E,
+         dispatch: D)
+         -> impl Future<Item = (), Error = RunError<S, C, AllEventsError<E>, F::Error>> + 'a
+        where P: 'a,
+              P::Event: EventType,
+              C::Offset: Clone + PartialOrd + Default + 'a,
+              E: EventStore<Offset = C::Offset, Event = P::Input>,
+              E::AllEventsStream: 'a,
+              D: Fn(P::Command) -> F + 'a,
+              F: IntoFuture<Item = ()>,
+              F::Future: 'a,
+              F::Error: 'a
+    {
+        self.run_with(move |offset| event_store.all_events(offset), dispatch)
+    }
+
+    /// Run the process manager over a stream of events, starting after the
+    /// last checkpoint. The `events` function will be called with the offset
+    /// to start streaming from, which allows for process managers to be run
+    /// over live subscriptions to an event store.
+    pub fn run_with<'a, E, St, D, F>
+        (&'a self,
+         events: E,
+         dispatch: D)
+         -> impl Future<Item = (), Error = RunError<S, C, St::Error, F::Error>> + 'a
+        where P: 'a,
+              P::Event: EventType,
+              C::Offset: Clone + PartialOrd + Default + 'a,
+              E: FnOnce(C::Offset) -> St + 'a,
+              St: Stream<Item = PersistedEvent<C::Offset, P::Input>> + 'a,
+              D: Fn(P::Command) -> F + 'a,
+              F: IntoFuture<Item = ()>,
+              F::Future: 'a,
+              F::Error: 'a
+    {
+        let name = &self.name[..];
+        let checkpoint_store = &self.checkpoint_store;
+        let dispatch = Rc::new(dispatch);
+
+        checkpoint_store.load_checkpoint(name)
+            .map_err(ProcessError::Checkpoint)
+            .and_then(move |checkpoint| {
+                let offset = checkpoint.clone().unwrap_or_default();
+
+                events(offset)
+                    .map_err(ProcessError::Input)
+                    // The checkpoint refers to an event that was already handled
+                    .filter(move |event| checkpoint.as_ref().map_or(true, |c| event.offset > *c))
+                    .for_each(move |event| {
+                        let offset = event.offset.clone();
+                        let handled = match P::process_id(&event) {
+                            Some(process_id) => {
+                                Either::A(self.handle_event(process_id, event, dispatch.clone()))
+                            },
+                            None => Either::B(future::ok(())),
+                        };
+
+                        handled.and_then(move |()| {
+                            checkpoint_store.save_checkpoint(name, offset)
+                                .map_err(ProcessError::Checkpoint)
+                        })
+                    })
+            })
+    }
+
+    /// Handle an event with the process instance, dispatching the resulting
+    /// commands and then recording the resulting events
+    fn handle_event<'a, Offset, D, F, CheckpointError, InputError>
+        (&'a self,
+         process_id: Uuid,
+         event: PersistedEvent<Offset, P::Input>,
+         dispatch: Rc<D>)
+         -> impl Future<Item = (),
+                        Error = ProcessError<CheckpointError,
+                                             InputError,
+                                             EventsError<S>,
+                                             F::Error,
+                                             S::AppendError>> + 'a
+        where P: 'a,
+              P::Event: EventType,
+              Offset: 'a,
+              D: Fn(P::Command) -> F + 'a,
+              F: IntoFuture<Item = ()>,
+              F::Future: 'a,
+              F::Error: 'a,
+              CheckpointError: 'a,
+              InputError: 'a
+    {
+        // Trace the events of the process back to the event that caused them
+        let causation_id = event.metadata.event_id;
+        let correlation_id = event.metadata.correlation_id.unwrap_or(causation_id);
+        let metadata = Metadata::new()
+            .with_causation_id(causation_id)
+            .with_correlation_id(correlation_id);
+        let payload = event.payload;
+
+        self.load(process_id)
+            .map_err(ProcessError::Events)
+            .and_then(move |(state, version)| {
+                let reaction = P::react(process_id, &state, payload, self.clock.now());
+
+                let dispatch = move |command| dispatch(command);
+
+                self.commit(process_id, version, metadata, reaction, dispatch)
+                    .map_err(|error| match error {
+                        CommitError::Dispatch(error) => ProcessError::Dispatch(error),
+                        CommitError::Append(error) => ProcessError::Append(error),
+                    })
+            })
+    }
+
+    /// Find the process instances that have deadlines, along with the times
+    /// of the deadlines.
+    ///
+    /// Note that this replays the events of every process instance in the
+    /// event store on each call, so it is only suitable for tests and for
+    /// small numbers of process instances. Otherwise the deadlines should be
+    /// scheduled in a `DeadlineStore` as the process instances react to
+    /// events, for example using a `Scheduler`, and passed on to `expire`
+    /// once they are due.
+    pub fn pending_deadlines
+        (&self)
+         -> impl Future<Item = Vec<(Uuid, SystemTime)>,
+                        Error = AllEventsError<S>> {
+        self.event_store
+            .all_events(S::Offset::default())
+            .fold(BTreeMap::new(), |mut states, event| {
+                let state = states.entry(event.source_id).or_insert_with(P::initial_state);
+                P::apply_event(state, event.payload);
+                Ok(states)
+            })
+            .map(|states| {
+                states.into_iter()
+                    .filter_map(|(process_id, state)| {
+                        P::deadline(&state).map(|deadline| (process_id, deadline))
+                    })
+                    .collect()
+            })
+    }
+
+    /// Time out the process instance if its deadline has passed, dispatching
+    /// the resulting commands and then recording the resulting events. The
+    /// future resolves to `true` if the process instance was timed out.
+    pub fn expire<'a, D, F>
+        (&'a self,
+         process_id: Uuid,
+         dispatch: D)
+         -> impl Future<Item = bool,
+                        Error = DeadlineError<EventsError<S>, F::Error, S::AppendError>> + 'a
+        where P: 'a,
+              P::Event: EventType,
+              D: Fn(P::Command) -> F + 'a,
+              F: IntoFuture<Item = ()>,
+              F::Future: 'a,
+              F::Error: 'a
+    {
+        self.load(process_id)
+            .map_err(DeadlineError::Events)
+            .and_then(move |(state, version)| {
+                let now = self.clock.now();
+
+                match P::deadline(&state) {
+                    Some(deadline) if deadline <= now => {
+                        let reaction = P::time_out(process_id, &state, now);
+                        let committed = self.commit(process_id,
+                                                    version,
+                                                    Metadata::new(),
+                                                    reaction,
+                                                    dispatch);
+
+                        Either::A(committed.map(|()| true).map_err(|error| match error {
+                            CommitError::Dispatch(error) => DeadlineError::Dispatch(error),
+                            CommitError::Append(error) => DeadlineError::Append(error),
+                        }))
+                    },
+                    Some(_) | None => Either::B(future::ok(false)),
+                }
+            })
+    }
+
+    /// Dispatch the commands of the reaction, and then append its events to
+    /// the process instance. Each event is given a copy of the metadata, with
+    /// a fresh event id.
+    ///
+    /// The commands are dispatched one at a time, in the order of the
+    /// reaction, and each command is only dispatched once the previous one
+    /// has succeeded. This ensures that compensations are run in the order
+    /// that they were given.
+    fn commit<'a, D, F>(&'a self,
+                        process_id: Uuid,
+                        version: ExpectedVersion,
+                        metadata: Metadata,
+                        reaction: Reaction<P::Event, P::Command>,
+                        dispatch: D)
+                        -> impl Future<Item = (),
+                                       Error = CommitError<F::Error, S::AppendError>> + 'a
+        where P: 'a,
+              P::Event: EventType,
+              D: Fn(P::Command) -> F + 'a,
+              F: IntoFuture<Item = ()>,
+              F::Future: 'a,
+              F::Error: 'a
+    {
+        let Reaction { events, commands } = reaction;
+        let new_events = events.into_iter()
+            .map(|event| {
+                let metadata = Metadata { event_id: Uuid::new_v4(), ..metadata.clone() };
+                NewEvent::with_metadata(metadata, event)
+            })
+            .collect::<Vec<_>>();
+
+        let dispatched = future::loop_fn(commands.into_iter(), move |mut commands| {
+            match commands.next() {
+                Some(command) => {
+                    Either::A(dispatch(command).into_future().map(|()| Loop::Continue(commands)))
+                },
+                None => Either::B(future::ok(Loop::Break(()))),
+            }
+        });
+
+        dispatched.map_err(CommitError::Dispatch)
+            .and_then(move |()| if new_events.is_empty() {
+                Either::A(future::ok(()))
+            } else {
+                Either::B(self.event_store
+                    .append_events(process_id, version, new_events)
+                    .map_err(CommitError::Append))
+            })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use chronicle::{EventStore, EventType, NewEvent};
+    use chronicle::ExpectedVersion::*;
+    use chronicle_memory::{MemoryCheckpointStore, MemoryEventStore};
+    use std::cell::RefCell;
+
+    use repository::tests::YieldOnce;
+    use super::*;
+
+
+    /// The events in the global event stream
+    #[derive(Debug, Clone, PartialEq)]
+    enum Bank {
+        Opened,
+        TransferRequested { from: Uuid, to: Uuid, amount: u32 },
+        Withdrawn { transfer_id: Uuid },
+        Deposited { transfer_id: Uuid },
+    }
+
+    impl EventType for Bank {
+        fn event_type(&self) -> &str {
+            match *self {
+                Bank::Opened => "opened",
+                Bank::TransferRequested { .. } => "transfer_requested",
+                Bank::Withdrawn { .. } => "withdrawn",
+                Bank::Deposited { .. } => "deposited",
+            }
+        }
+    }
+
+
+    #[derive(Debug, Clone, PartialEq)]
+    enum Command {
+        Withdraw { account: Uuid, transfer_id: Uuid, amount: u32 },
+        Deposit { account: Uuid, transfer_id: Uuid, amount: u32 },
+    }
+
+
+    #[derive(Debug, Clone, PartialEq)]
+    enum Event {
+        Started { to: Uuid, amount: u32 },
+        Withdrawn,
+        Completed,
+    }
+
+    impl EventType for Event {
+        fn event_type(&self) -> &str {
+            match *self {
+                Event::Started { .. } => "started",
+                Event::Withdrawn => "withdrawn",
+                Event::Completed => "completed",
+            }
+        }
+    }
+
+
+    #[derive(Debug, Clone, PartialEq)]
+    enum State {
+        NotStarted,
+        Withdrawing { to: Uuid, amount: u32 },
+        Depositing,
+        Completed,
+    }
+
+
+    /// Transfers money by withdrawing it from one account and then depositing
+    /// it into another
+    struct Transfer;
+
+    impl ProcessManager for Transfer {
+        type Input = Bank;
+        type State = State;
+        type Event = Event;
+        type Command = Command;
+
+        fn initial_state() -> State {
+            State::NotStarted
+        }
+
+        fn process_id<Offset>(event: &PersistedEvent<Offset, Bank>) -> Option<Uuid> {
+            match event.payload {
+                Bank::Opened => None,
+                Bank::TransferRequested { .. } => Some(event.source_id),
+                Bank::Withdrawn { transfer_id } |
+                Bank::Deposited { transfer_id } => Some(transfer_id),
+            }
+        }
+
+        fn react(transfer_id: Uuid,
+                 state: &State,
+                 event: Bank,
+                 _: SystemTime)
+                 -> Reaction<Event, Command> {
+            match (state, event) {
+                (&State::NotStarted, Bank::TransferRequested { from, to, amount }) => {
+                    Reaction::new()
+                        .with_event(Event::Started {
+                            to: to,
+                            amount: amount,
+                        })
+                        .with_command(Command::Withdraw {
+                            account: from,
+                            transfer_id: transfer_id,
+                            amount: amount,
+                        })
+                },
+                (&State::Withdrawing { to, amount }, Bank::Withdrawn { .. }) => {
+                    Reaction::new()
+                        .with_event(Event::Withdrawn)
+                        .with_command(Command::Deposit {
+                            account: to,
+                            transfer_id: transfer_id,
+                            amount: amount,
+                        })
+                },
+                (&State::Depositing, Bank::Deposited { .. }) => {
+                    Reaction::new().with_event(Event::Completed)
+                },
+                (_, _) => Reaction::new(),
+            }
+        }
+
+        fn apply_event(state: &mut State, event: Event) {
+            *state = match event {
+                Event::Started { to, amount } => {
+                    State::Withdrawing {
+                        to: to,
+                        amount: amount,
+                    }
+                },
+                Event::Withdrawn => State::Depositing,
+                Event::Completed => State::Completed,
+            };
+        }
+    }
+
+
+    type Runner = ProcessRunner<Transfer, MemoryEventStore<Event>, MemoryCheckpointStore<usize>>;
+
+
+    fn runner() -> Runner {
+        Runner::new("transfers", MemoryEventStore::new(), MemoryCheckpointStore::new())
+    }
+
+
+    fn append(event_store: &MemoryEventStore<Bank>, source_id: Uuid, events: Vec<Bank>) {
+        let events = events.into_iter().map(NewEvent::new).collect();
+        event_store.append_events(source_id, Any, events).wait().unwrap();
+    }
+
+
+    /// Run the process manager, returning the commands that were dispatched
+    fn run(runner: &Runner, event_store: &MemoryEventStore<Bank>) -> Vec<Command> {
+        let commands = RefCell::new(Vec::new());
+        let dispatch = |command| {
+            commands.borrow_mut().push(command);
+            Ok::<_, ()>(())
+        };
+
+        runner.run(event_store, dispatch).wait().unwrap();
+        commands.into_inner()
+    }
+
+
+    #[test]
+    fn react_to_events() {
+        let event_store = MemoryEventStore::new();
+        let runner = runner();
+        let (transfer_id, from, to) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+
+        append(&event_store, from, vec![Bank::Opened]);
+        append(&event_store, to, vec![Bank::Opened]);
+        append(&event_store,
+               transfer_id,
+               vec![Bank::TransferRequested {
+                        from: from,
+                        to: to,
+                        amount: 10,
+                    }]);
+
+        assert_eq!(run(&runner, &event_store),
+                   vec![Command::Withdraw {
+                            account: from,
+                            transfer_id: transfer_id,
+                            amount: 10,
+                        }]);
+        assert_eq!(runner.load(transfer_id).wait().unwrap(),
+                   (State::Withdrawing {
+                        to: to,
+                        amount: 10,
+                    },
+                    Exact(0)));
+
+        append(&event_store, from, vec![Bank::Withdrawn { transfer_id: transfer_id }]);
+        append(&event_store, to, vec![Bank::Deposited { transfer_id: transfer_id }]);
+
+        assert_eq!(run(&runner, &event_store),
+                   vec![Command::Deposit {
+                            account: to,
+                            transfer_id: transfer_id,
+                            amount: 10,
+                        }]);
+        assert_eq!(runner.load(transfer_id).wait().unwrap(), (State::Completed, Exact(2)));
+    }
+
+
+    #[test]
+    fn resume_after_restart() {
+        let event_store = MemoryEventStore::new();
+        let process_store = MemoryEventStore::new();
+        let checkpoint_store = MemoryCheckpointStore::new();
+        let (transfer_id, from, to) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+
+        append(&event_store,
+               transfer_id,
+               vec![Bank::TransferRequested {
+                        from: from,
+                        to: to,
+                        amount: 10,
+                    }]);
+
+        let runner = Runner::new("transfers", process_store.clone(), checkpoint_store.clone());
+        assert_eq!(run(&runner, &event_store).len(), 1);
+
+        append(&event_store, from, vec![Bank::Withdrawn { transfer_id: transfer_id }]);
+
+        // Only the events since the checkpoint are handled
+        let runner = Runner::new("transfers", process_store, checkpoint_store);
+        assert_eq!(run(&runner, &event_store),
+                   vec![Command::Deposit {
+                            account: to,
+                            transfer_id: transfer_id,
+                            amount: 10,
+                        }]);
+        assert_eq!(run(&runner, &event_store), vec![]);
+    }
+
+
+    #[test]
+    fn retry_after_dispatch_error() {
+        let event_store = MemoryEventStore::new();
+        let runner = runner();
+        let (transfer_id, from, to) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+
+        append(&event_store,
+               transfer_id,
+               vec![Bank::TransferRequested {
+                        from: from,
+                        to: to,
+                        amount: 10,
+                    }]);
+
+        match runner.run(&event_store, |_| Err("unavailable")).wait() {
+            Err(ProcessError::Dispatch("unavailable")) => {},
+            result => panic!("unexpected result: {:?}", result),
+        }
+
+        // Neither the process nor the checkpoint were updated
+        assert_eq!(runner.load(transfer_id).wait().unwrap(), (State::NotStarted, NoStream));
+        assert_eq!(run(&runner, &event_store).len(), 1);
+    }
+
+
+    #[test]
+    fn trace_process_events() {
+        let event_store = MemoryEventStore::new();
+        let runner = runner();
+        let (transfer_id, correlation_id) = (Uuid::new_v4(), Uuid::new_v4());
+        let requested = Bank::TransferRequested {
+            from: Uuid::new_v4(),
+            to: Uuid::new_v4(),
+            amount: 10,
+        };
+        let metadata = Metadata::new().with_correlation_id(correlation_id);
+        let event_id = metadata.event_id;
+
+        event_store.append_events(transfer_id,
+                           NoStream,
+                           vec![NewEvent::with_metadata(metadata, requested)])
+            .wait()
+            .unwrap();
+        run(&runner, &event_store);
+
+        let events = runner.event_store().events(transfer_id, 0).collect().wait().unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].metadata.causation_id, Some(event_id));
+        assert_eq!(events[0].metadata.correlation_id, Some(correlation_id));
+    }
+
+
+    #[test]
+    fn dispatch_commands_in_order() {
+        let runner = runner();
+        let (transfer_id, from, to) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let withdraw = Command::Withdraw {
+            account: from,
+            transfer_id: transfer_id,
+            amount: 10,
+        };
+        let deposit = Command::Deposit {
+            account: to,
+            transfer_id: transfer_id,
+            amount: 10,
+        };
+        let reaction = Reaction::new().with_command(withdraw.clone()).with_command(deposit.clone());
+
+        // Each command is only dispatched once the previous one has completed
+        let log = RefCell::new(Vec::new());
+        runner.commit(transfer_id,
+                    NoStream,
+                    Metadata::new(),
+                    reaction,
+                    |command: Command| {
+                        log.borrow_mut().push(("dispatched", command.clone()));
+                        YieldOnce::new(Ok::<_, ()>(()))
+                            .map(|()| log.borrow_mut().push(("completed", command)))
+                    })
+            .wait()
+            .unwrap();
+
+        assert_eq!(log.into_inner(),
+                   vec![("dispatched", withdraw.clone()),
+                        ("completed", withdraw),
+                        ("dispatched", deposit.clone()),
+                        ("completed", deposit)]);
+    }
+}
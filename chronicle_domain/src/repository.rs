@@ -0,0 +1,234 @@
+//! Executing commands against aggregates that are stored in an event store
+
+use chronicle::{EventStore, EventType, EventsError, ExpectedVersion, NewEvent};
+use futures::{Future, IntoFuture, Stream};
+use std::marker::PhantomData;
+use uuid::Uuid;
+
+use Aggregate;
+
+
+/// An error that may occur when executing a command via a `Repository`
+#[derive(Debug, Clone, PartialEq)]
+pub enum RepositoryError<CommandError, EventsError, AppendError> {
+    /// The command was rejected by the aggregate
+    Command(CommandError),
+    /// An error occurred when streaming the events of the aggregate
+    Events(EventsError),
+    /// An error occurred when appending the resulting events. This will
+    /// happen if another command was executed against the same aggregate
+    /// concurrently.
+    Append(AppendError),
+}
+
+
+/// A repository of aggregates that are persisted in an event store
+///
+/// The repository takes care of loading the current state of an aggregate,
+/// handling a command, and appending the resulting events back to the event
+/// store. The version that the aggregate was loaded at is used when
+/// appending, ensuring that the events are rejected if another command was
+/// executed in the meantime.
+#[derive(Debug, Clone)]
+pub struct Repository<A, S> {
+    event_store: S,
+    aggregate: PhantomData<A>,
+}
+
+
+impl<A, S> Repository<A, S>
+    where A: Aggregate,
+          S: EventStore<Event = A::Event>
+{
+    /// Create a repository that stores aggregates in the event store
+    pub fn new(event_store: S) -> Repository<A, S> {
+        Repository {
+            event_store: event_store,
+            aggregate: PhantomData,
+        }
+    }
+
+    /// The underlying event store
+    pub fn event_store(&self) -> &S {
+        &self.event_store
+    }
+
+    /// Load the current state of the aggregate, along with the version that
+    /// it was loaded at
+    pub fn load(&self,
+                id: Uuid)
+                -> impl Future<Item = (A::State, ExpectedVersion),
+                               Error = EventsError<S>> {
+        self.event_store
+            .events(id, S::Offset::default())
+            .fold((A::initial_state(), ExpectedVersion::NoStream),
+                  |(mut state, _), event| {
+                let version = ExpectedVersion::Exact(event.sequence_number);
+                A::apply_event(&mut state, event.payload);
+                Ok((state, version))
+            })
+    }
+
+    /// Execute a command against the aggregate with the specified id,
+    /// returning the events that were committed as a result
+    pub fn execute<'a>
+        (&'a self,
+         id: Uuid,
+         command: A::Command)
+         -> impl Future<Item = Vec<A::Event>,
+                        Error = RepositoryError<A::CommandError,
+                                                EventsError<S>,
+                                                S::AppendError>> + 'a
+        where A: 'a,
+              A::Event: Clone + EventType
+    {
+        self.load(id)
+            .map_err(RepositoryError::Events)
+            .and_then(move |(state, version)| {
+                A::handle_command(&state, command)
+                    .into_future()
+                    .map(move |events| (events, version))
+                    .map_err(RepositoryError::Command)
+            })
+            .and_then(move |(events, version)| {
+                let new_events = events.iter().cloned().map(NewEvent::new).collect();
+
+                self.event_store
+                    .append_events(id, version, new_events)
+                    .map(|()| events)
+                    .map_err(RepositoryError::Append)
+            })
+    }
+}
+
+
+#[cfg(test)]
+pub mod tests {
+    use chronicle::{EventType, WrongExpectedVersion};
+    use chronicle::ExpectedVersion::*;
+    use chronicle_memory::{AppendError, MemoryEventStore};
+    use futures::{Async, Future, Poll};
+    use futures::task;
+    use uuid::Uuid;
+
+    use Aggregate;
+    use super::*;
+
+
+    /// A future that yields to the executor once before resolving, allowing
+    /// other futures to make progress in the meantime
+    pub struct YieldOnce<T, E> {
+        result: Option<Result<T, E>>,
+        has_yielded: bool,
+    }
+
+    impl<T, E> YieldOnce<T, E> {
+        pub fn new(result: Result<T, E>) -> YieldOnce<T, E> {
+            YieldOnce {
+                result: Some(result),
+                has_yielded: false,
+            }
+        }
+    }
+
+    impl<T, E> Future for YieldOnce<T, E> {
+        type Item = T;
+        type Error = E;
+
+        fn poll(&mut self) -> Poll<T, E> {
+            if self.has_yielded {
+                self.result.take().expect("polled after completion").map(Async::Ready)
+            } else {
+                self.has_yielded = true;
+                task::current().notify();
+                Ok(Async::NotReady)
+            }
+        }
+    }
+
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct Written(i32);
+
+    impl EventType for Written {
+        fn event_type(&self) -> &str {
+            "written"
+        }
+    }
+
+
+    /// A register that can only be written to once
+    struct Register;
+
+    impl Aggregate for Register {
+        type State = Option<i32>;
+        type Event = Written;
+        type Command = i32;
+        type CommandError = &'static str;
+        type EventsFuture = YieldOnce<Vec<Written>, &'static str>;
+
+        fn initial_state() -> Option<i32> {
+            None
+        }
+
+        fn handle_command(state: &Option<i32>,
+                          command: i32)
+                          -> YieldOnce<Vec<Written>, &'static str> {
+            YieldOnce::new(match *state {
+                Some(_) => Err("already written"),
+                None => Ok(vec![Written(command)]),
+            })
+        }
+
+        fn apply_event(state: &mut Option<i32>, Written(value): Written) {
+            *state = Some(value);
+        }
+    }
+
+
+    #[test]
+    fn execute_appends_the_resulting_events() {
+        let repository = Repository::<Register, _>::new(MemoryEventStore::new());
+        let id = Uuid::new_v4();
+
+        assert_eq!(repository.execute(id, 42).wait(), Ok(vec![Written(42)]));
+        assert_eq!(repository.load(id).wait(), Ok((Some(42), Exact(0))));
+    }
+
+
+    #[test]
+    fn execute_returns_command_errors() {
+        let repository = Repository::<Register, _>::new(MemoryEventStore::new());
+        let id = Uuid::new_v4();
+
+        repository.execute(id, 42).wait().unwrap();
+
+        assert_eq!(repository.execute(id, 7).wait(),
+                   Err(RepositoryError::Command("already written")));
+        assert_eq!(repository.load(id).wait(), Ok((Some(42), Exact(0))));
+    }
+
+
+    #[test]
+    fn execute_rejects_concurrent_commands() {
+        let repository = Repository::<Register, _>::new(MemoryEventStore::new());
+        let id = Uuid::new_v4();
+
+        // Both commands will load the aggregate before either of them has
+        // had a chance to append their events
+        let (result_1, result_2) = repository.execute(id, 1)
+            .then(Ok::<_, ()>)
+            .join(repository.execute(id, 2).then(Ok))
+            .wait()
+            .unwrap();
+
+        assert_eq!(result_1, Ok(vec![Written(1)]));
+        assert_eq!(result_2,
+                   Err(RepositoryError::Append(AppendError::from(WrongExpectedVersion {
+                       source_id: id,
+                       expected: NoStream,
+                       current: Some(0),
+                   }))));
+        assert_eq!(repository.load(id).wait(), Ok((Some(1), Exact(0))));
+    }
+}
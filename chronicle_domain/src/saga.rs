@@ -0,0 +1,687 @@
+//! Sagas, for workflows that can be rolled back
+//!
+//! A saga is a process manager that executes a fixed sequence of steps, each
+//! of which is a command to an aggregate. The saga moves on to the next step
+//! once an event signals that the current step has completed. If a step
+//! fails, or does not complete before its timeout, the compensations of the
+//! steps that have already completed are dispatched in reverse order, undoing
+//! the work of the saga.
+//!
+//! Sagas are run by wrapping them in a `SagaProcess`, which implements
+//! `ProcessManager`:
+//!
+//! ```rust,ignore
+//! let runner = ProcessRunner::<SagaProcess<TransferSaga>, _, _>::new("transfers",
+//!                                                                     saga_store,
+//!                                                                     checkpoint_store);
+//! ```
+
+use chronicle::{EventType, PersistedEvent};
+use std::marker::PhantomData;
+use std::mem;
+use std::time::{Duration, SystemTime};
+use uuid::Uuid;
+
+use process_manager::{ProcessManager, Reaction};
+
+
+/// The definition of a saga
+pub trait Saga: Sized {
+    /// The events from the global event stream that the saga reacts to
+    type Input;
+
+    /// The data that the saga is started with, which is passed to each step
+    type Data: Clone;
+
+    /// The commands that the saga dispatches to other aggregates
+    type Command;
+
+    /// The id of the saga instance that should handle the event, or `None` if
+    /// the event is not relevant to the saga
+    fn saga_id<Offset>(event: &PersistedEvent<Offset, Self::Input>) -> Option<Uuid>;
+
+    /// Start the saga if the event is the one that begins the workflow,
+    /// returning the data for the saga
+    fn start(event: &Self::Input) -> Option<Self::Data>;
+
+    /// The steps of the saga, in the order that they are executed
+    fn steps() -> Vec<Step<Self>>;
+}
+
+
+/// The outcome of a step, as signalled by an event
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    /// The step completed, so the saga can move on to the next step
+    Completed,
+    /// The step failed, so the saga must be compensated
+    Failed,
+}
+
+
+/// Builds a command for a step of a saga, given the saga id and its data
+pub type StepCommand<S> = fn(Uuid, &<S as Saga>::Data) -> <S as Saga>::Command;
+
+
+/// A step of a saga
+pub struct Step<S: Saga> {
+    /// The command that performs the step
+    pub action: StepCommand<S>,
+    /// Determine whether the event signals the outcome of the step
+    pub outcome: fn(&S::Data, &S::Input) -> Option<Outcome>,
+    /// The command that undoes the step, if a later step fails
+    pub compensation: Option<StepCommand<S>>,
+    /// How long to wait for the outcome of the step before failing it
+    pub timeout: Option<Duration>,
+}
+
+
+impl<S: Saga> Step<S> {
+    /// Create a step that can't be compensated, and never times out
+    pub fn new(action: StepCommand<S>,
+               outcome: fn(&S::Data, &S::Input) -> Option<Outcome>)
+               -> Step<S> {
+        Step {
+            action: action,
+            outcome: outcome,
+            compensation: None,
+            timeout: None,
+        }
+    }
+
+    /// Undo the step with the command if a later step fails
+    pub fn with_compensation(mut self, compensation: StepCommand<S>) -> Step<S> {
+        self.compensation = Some(compensation);
+        self
+    }
+
+    /// Fail the step if its outcome is not known within the timeout
+    pub fn with_timeout(mut self, timeout: Duration) -> Step<S> {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    fn deadline(&self, now: SystemTime) -> Option<SystemTime> {
+        self.timeout.map(|timeout| now + timeout)
+    }
+}
+
+
+/// The events that record the progress of a saga
+#[derive(Debug, Clone, PartialEq)]
+pub enum SagaEvent<Data> {
+    /// The saga was started, and the first step was dispatched
+    Started {
+        data: Data,
+        deadline: Option<SystemTime>,
+    },
+    /// The step completed, and the next step was dispatched
+    StepCompleted {
+        step: usize,
+        deadline: Option<SystemTime>,
+    },
+    /// Every step of the saga completed
+    Completed,
+    /// The step failed, and the completed steps were compensated
+    StepFailed { step: usize },
+    /// The step timed out, and the completed steps were compensated
+    StepTimedOut { step: usize },
+}
+
+
+impl<Data> EventType for SagaEvent<Data> {
+    fn event_type(&self) -> &str {
+        match *self {
+            SagaEvent::Started { .. } => "started",
+            SagaEvent::StepCompleted { .. } => "step_completed",
+            SagaEvent::Completed => "completed",
+            SagaEvent::StepFailed { .. } => "step_failed",
+            SagaEvent::StepTimedOut { .. } => "step_timed_out",
+        }
+    }
+}
+
+
+/// The state of a saga instance
+#[derive(Debug, Clone, PartialEq)]
+pub enum SagaState<Data> {
+    /// The saga has not been started yet
+    NotStarted,
+    /// The saga is waiting for the outcome of a step
+    Running {
+        data: Data,
+        step: usize,
+        deadline: Option<SystemTime>,
+    },
+    /// Every step of the saga completed
+    Completed { data: Data },
+    /// A step failed or timed out, and the saga was compensated
+    Aborted { data: Data, step: usize },
+}
+
+
+/// Runs a saga as a process manager
+pub struct SagaProcess<S> {
+    saga: PhantomData<S>,
+}
+
+
+impl<S: Saga> SagaProcess<S> {
+    /// Dispatch the compensations of the steps before the failed step, in
+    /// reverse order
+    fn compensate(saga_id: Uuid,
+                  data: &S::Data,
+                  failed_step: usize,
+                  event: SagaEvent<S::Data>)
+                  -> Reaction<SagaEvent<S::Data>, S::Command> {
+        S::steps()
+            .into_iter()
+            .take(failed_step)
+            .rev()
+            .filter_map(|step| step.compensation)
+            .fold(Reaction::new().with_event(event),
+                  |reaction, compensation| reaction.with_command(compensation(saga_id, data)))
+    }
+}
+
+
+impl<S: Saga> ProcessManager for SagaProcess<S> {
+    type Input = S::Input;
+    type State = SagaState<S::Data>;
+    type Event = SagaEvent<S::Data>;
+    type Command = S::Command;
+
+    fn initial_state() -> SagaState<S::Data> {
+        SagaState::NotStarted
+    }
+
+    fn process_id<Offset>(event: &PersistedEvent<Offset, S::Input>) -> Option<Uuid> {
+        S::saga_id(event)
+    }
+
+    fn react(saga_id: Uuid,
+             state: &SagaState<S::Data>,
+             event: S::Input,
+             now: SystemTime)
+             -> Reaction<SagaEvent<S::Data>, S::Command> {
+        let steps = S::steps();
+
+        match *state {
+            SagaState::NotStarted => {
+                let data = match S::start(&event) {
+                    Some(data) => data,
+                    None => return Reaction::new(),
+                };
+
+                match steps.first() {
+                    Some(first) => {
+                        Reaction::new()
+                            .with_event(SagaEvent::Started {
+                                data: data.clone(),
+                                deadline: first.deadline(now),
+                            })
+                            .with_command((first.action)(saga_id, &data))
+                    },
+                    None => {
+                        Reaction::new()
+                            .with_event(SagaEvent::Started {
+                                data: data,
+                                deadline: None,
+                            })
+                            .with_event(SagaEvent::Completed)
+                    },
+                }
+            },
+            SagaState::Running { ref data, step, .. } => {
+                // The saga may have been started with more steps than it has
+                // now, in which case the outcome of the step can't be known
+                let outcome = match steps.get(step) {
+                    Some(current) => (current.outcome)(data, &event),
+                    None => return Reaction::new(),
+                };
+
+                match outcome {
+                    Some(Outcome::Completed) => {
+                        match steps.get(step + 1) {
+                            Some(next) => {
+                                Reaction::new()
+                                    .with_event(SagaEvent::StepCompleted {
+                                        step: step,
+                                        deadline: next.deadline(now),
+                                    })
+                                    .with_command((next.action)(saga_id, data))
+                            },
+                            None => {
+                                Reaction::new()
+                                    .with_event(SagaEvent::StepCompleted {
+                                        step: step,
+                                        deadline: None,
+                                    })
+                                    .with_event(SagaEvent::Completed)
+                            },
+                        }
+                    },
+                    Some(Outcome::Failed) => {
+                        SagaProcess::<S>::compensate(saga_id,
+                                                     data,
+                                                     step,
+                                                     SagaEvent::StepFailed { step: step })
+                    },
+                    None => Reaction::new(),
+                }
+            },
+            // Late events are ignored once the saga has finished
+            SagaState::Completed { .. } |
+            SagaState::Aborted { .. } => Reaction::new(),
+        }
+    }
+
+    fn apply_event(state: &mut SagaState<S::Data>, event: SagaEvent<S::Data>) {
+        let previous = mem::replace(state, SagaState::NotStarted);
+
+        *state = match (previous, event) {
+            (SagaState::NotStarted, SagaEvent::Started { data, deadline }) => {
+                SagaState::Running {
+                    data: data,
+                    step: 0,
+                    deadline: deadline,
+                }
+            },
+            (SagaState::Running { data, .. }, SagaEvent::StepCompleted { step, deadline }) => {
+                SagaState::Running {
+                    data: data,
+                    step: step + 1,
+                    deadline: deadline,
+                }
+            },
+            (SagaState::Running { data, .. }, SagaEvent::Completed) => {
+                SagaState::Completed { data: data }
+            },
+            (SagaState::Running { data, .. }, SagaEvent::StepFailed { step }) |
+            (SagaState::Running { data, .. }, SagaEvent::StepTimedOut { step }) => {
+                SagaState::Aborted {
+                    data: data,
+                    step: step,
+                }
+            },
+            (previous, _) => previous,
+        };
+    }
+
+    fn deadline(state: &SagaState<S::Data>) -> Option<SystemTime> {
+        match *state {
+            SagaState::Running { deadline, .. } => deadline,
+            _ => None,
+        }
+    }
+
+    fn time_out(saga_id: Uuid,
+                state: &SagaState<S::Data>,
+                _: SystemTime)
+                -> Reaction<SagaEvent<S::Data>, S::Command> {
+        match *state {
+            SagaState::Running { ref data, step, .. } => {
+                SagaProcess::<S>::compensate(saga_id,
+                                             data,
+                                             step,
+                                             SagaEvent::StepTimedOut { step: step })
+            },
+            _ => Reaction::new(),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use chronicle::{EventStore, NewEvent};
+    use chronicle::ExpectedVersion::*;
+    use chronicle_memory::{MemoryCheckpointStore, MemoryEventStore};
+    use futures::{Future, Stream};
+    use std::cell::RefCell;
+    use std::time::UNIX_EPOCH;
+
+    use clock::ManualClock;
+    use process_manager::ProcessRunner;
+    use super::*;
+
+
+    /// The events in the global event stream
+    #[derive(Debug, Clone, PartialEq)]
+    enum Bank {
+        TransferRequested { from: Uuid, to: Uuid, amount: u32 },
+        Withdrawn { transfer_id: Uuid },
+        Deposited { transfer_id: Uuid },
+        DepositRejected { transfer_id: Uuid },
+    }
+
+    impl EventType for Bank {
+        fn event_type(&self) -> &str {
+            match *self {
+                Bank::TransferRequested { .. } => "transfer_requested",
+                Bank::Withdrawn { .. } => "withdrawn",
+                Bank::Deposited { .. } => "deposited",
+                Bank::DepositRejected { .. } => "deposit_rejected",
+            }
+        }
+    }
+
+
+    #[derive(Debug, Clone, PartialEq)]
+    enum Command {
+        Withdraw { account: Uuid, amount: u32 },
+        Deposit { account: Uuid, amount: u32 },
+        Refund { account: Uuid, amount: u32 },
+    }
+
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct Transfer {
+        from: Uuid,
+        to: Uuid,
+        amount: u32,
+    }
+
+
+    struct TransferSaga;
+
+    impl Saga for TransferSaga {
+        type Input = Bank;
+        type Data = Transfer;
+        type Command = Command;
+
+        fn saga_id<Offset>(event: &PersistedEvent<Offset, Bank>) -> Option<Uuid> {
+            match event.payload {
+                Bank::TransferRequested { .. } => Some(event.source_id),
+                Bank::Withdrawn { transfer_id } |
+                Bank::Deposited { transfer_id } |
+                Bank::DepositRejected { transfer_id } => Some(transfer_id),
+            }
+        }
+
+        fn start(event: &Bank) -> Option<Transfer> {
+            match *event {
+                Bank::TransferRequested { from, to, amount } => {
+                    Some(Transfer {
+                        from: from,
+                        to: to,
+                        amount: amount,
+                    })
+                },
+                _ => None,
+            }
+        }
+
+        fn steps() -> Vec<Step<TransferSaga>> {
+            vec![Step::new(withdraw, withdrawn).with_compensation(refund),
+                 Step::new(deposit, deposited).with_timeout(Duration::from_secs(60))]
+        }
+    }
+
+    fn withdraw(_: Uuid, transfer: &Transfer) -> Command {
+        Command::Withdraw {
+            account: transfer.from,
+            amount: transfer.amount,
+        }
+    }
+
+    fn withdrawn(_: &Transfer, event: &Bank) -> Option<Outcome> {
+        match *event {
+            Bank::Withdrawn { .. } => Some(Outcome::Completed),
+            _ => None,
+        }
+    }
+
+    fn refund(_: Uuid, transfer: &Transfer) -> Command {
+        Command::Refund {
+            account: transfer.from,
+            amount: transfer.amount,
+        }
+    }
+
+    fn deposit(_: Uuid, transfer: &Transfer) -> Command {
+        Command::Deposit {
+            account: transfer.to,
+            amount: transfer.amount,
+        }
+    }
+
+    fn deposited(_: &Transfer, event: &Bank) -> Option<Outcome> {
+        match *event {
+            Bank::Deposited { .. } => Some(Outcome::Completed),
+            Bank::DepositRejected { .. } => Some(Outcome::Failed),
+            _ => None,
+        }
+    }
+
+
+    type Runner = ProcessRunner<SagaProcess<TransferSaga>,
+                                MemoryEventStore<SagaEvent<Transfer>>,
+                                MemoryCheckpointStore<usize>,
+                                ManualClock>;
+
+
+    struct Fixture {
+        event_store: MemoryEventStore<Bank>,
+        clock: ManualClock,
+        runner: Runner,
+        transfer_id: Uuid,
+        transfer: Transfer,
+    }
+
+    impl Fixture {
+        /// Request a transfer at the start of the epoch
+        fn new() -> Fixture {
+            let clock = ManualClock::new(UNIX_EPOCH);
+            let fixture = Fixture {
+                event_store: MemoryEventStore::new(),
+                clock: clock.clone(),
+                runner: ProcessRunner::new("transfers",
+                                           MemoryEventStore::new(),
+                                           MemoryCheckpointStore::new())
+                    .with_clock(clock),
+                transfer_id: Uuid::new_v4(),
+                transfer: Transfer {
+                    from: Uuid::new_v4(),
+                    to: Uuid::new_v4(),
+                    amount: 10,
+                },
+            };
+
+            fixture.append(Bank::TransferRequested {
+                from: fixture.transfer.from,
+                to: fixture.transfer.to,
+                amount: fixture.transfer.amount,
+            });
+            fixture
+        }
+
+        fn append(&self, event: Bank) {
+            self.event_store
+                .append_events(self.transfer_id, Any, vec![NewEvent::new(event)])
+                .wait()
+                .unwrap();
+        }
+
+        /// Run the saga, returning the commands that were dispatched
+        fn run(&self) -> Vec<Command> {
+            let commands = RefCell::new(Vec::new());
+            let dispatch = |command| {
+                commands.borrow_mut().push(command);
+                Ok::<_, ()>(())
+            };
+
+            self.runner.run(&self.event_store, dispatch).wait().unwrap();
+            commands.into_inner()
+        }
+
+        /// Expire the saga, returning the commands that were dispatched
+        fn expire(&self) -> (bool, Vec<Command>) {
+            let commands = RefCell::new(Vec::new());
+            let dispatch = |command| {
+                commands.borrow_mut().push(command);
+                Ok::<_, ()>(())
+            };
+
+            let expired = self.runner.expire(self.transfer_id, dispatch).wait().unwrap();
+            (expired, commands.into_inner())
+        }
+
+        fn state(&self) -> SagaState<Transfer> {
+            self.runner.load(self.transfer_id).wait().unwrap().0
+        }
+    }
+
+
+    #[test]
+    fn complete_every_step() {
+        let fixture = Fixture::new();
+        let transfer = fixture.transfer.clone();
+
+        assert_eq!(fixture.run(),
+                   vec![Command::Withdraw {
+                            account: transfer.from,
+                            amount: 10,
+                        }]);
+
+        fixture.append(Bank::Withdrawn { transfer_id: fixture.transfer_id });
+        assert_eq!(fixture.run(),
+                   vec![Command::Deposit {
+                            account: transfer.to,
+                            amount: 10,
+                        }]);
+
+        fixture.append(Bank::Deposited { transfer_id: fixture.transfer_id });
+        assert_eq!(fixture.run(), vec![]);
+        assert_eq!(fixture.state(), SagaState::Completed { data: transfer });
+    }
+
+
+    #[test]
+    fn compensate_failed_step() {
+        let fixture = Fixture::new();
+        let transfer = fixture.transfer.clone();
+
+        fixture.append(Bank::Withdrawn { transfer_id: fixture.transfer_id });
+        fixture.run();
+
+        fixture.append(Bank::DepositRejected { transfer_id: fixture.transfer_id });
+        assert_eq!(fixture.run(),
+                   vec![Command::Refund {
+                            account: transfer.from,
+                            amount: 10,
+                        }]);
+        assert_eq!(fixture.state(),
+                   SagaState::Aborted {
+                       data: transfer,
+                       step: 1,
+                   });
+
+        // Late events are ignored
+        fixture.append(Bank::Deposited { transfer_id: fixture.transfer_id });
+        assert_eq!(fixture.run(), vec![]);
+    }
+
+
+    #[test]
+    fn time_out_step() {
+        let fixture = Fixture::new();
+        let transfer = fixture.transfer.clone();
+
+        // The withdrawal has no timeout
+        fixture.run();
+        assert_eq!(fixture.runner.pending_deadlines().wait().unwrap(), vec![]);
+
+        fixture.clock.advance(Duration::from_secs(30));
+        fixture.append(Bank::Withdrawn { transfer_id: fixture.transfer_id });
+        fixture.run();
+
+        let deadline = UNIX_EPOCH + Duration::from_secs(90);
+        assert_eq!(fixture.runner.pending_deadlines().wait().unwrap(),
+                   vec![(fixture.transfer_id, deadline)]);
+
+        fixture.clock.advance(Duration::from_secs(59));
+        assert_eq!(fixture.expire(), (false, vec![]));
+
+        fixture.clock.advance(Duration::from_secs(1));
+        assert_eq!(fixture.expire(),
+                   (true,
+                    vec![Command::Refund {
+                             account: transfer.from,
+                             amount: 10,
+                         }]));
+        assert_eq!(fixture.state(),
+                   SagaState::Aborted {
+                       data: transfer,
+                       step: 1,
+                   });
+        assert_eq!(fixture.runner.pending_deadlines().wait().unwrap(), vec![]);
+        assert_eq!(fixture.expire(), (false, vec![]));
+    }
+
+
+    #[test]
+    fn record_progress() {
+        let fixture = Fixture::new();
+
+        fixture.run();
+        fixture.append(Bank::Withdrawn { transfer_id: fixture.transfer_id });
+        fixture.run();
+
+        let events = fixture.runner
+            .event_store()
+            .events(fixture.transfer_id, 0)
+            .map(|event| event.payload)
+            .collect()
+            .wait()
+            .unwrap();
+
+        assert_eq!(events,
+                   vec![SagaEvent::Started {
+                            data: fixture.transfer.clone(),
+                            deadline: None,
+                        },
+                        SagaEvent::StepCompleted {
+                            step: 0,
+                            deadline: Some(UNIX_EPOCH + Duration::from_secs(60)),
+                        }]);
+        assert_eq!(fixture.runner.load(fixture.transfer_id).wait().unwrap().1, Exact(1));
+    }
+
+
+    #[test]
+    fn ignore_removed_steps() {
+        let fixture = Fixture::new();
+        let transfer = fixture.transfer.clone();
+
+        // Recorded by a version of the saga that had a third step
+        let events = vec![SagaEvent::Started {
+                              data: transfer.clone(),
+                              deadline: None,
+                          },
+                          SagaEvent::StepCompleted {
+                              step: 0,
+                              deadline: None,
+                          },
+                          SagaEvent::StepCompleted {
+                              step: 1,
+                              deadline: None,
+                          }];
+        fixture.runner
+            .event_store()
+            .append_events(fixture.transfer_id,
+                           NoStream,
+                           events.into_iter().map(NewEvent::new).collect())
+            .wait()
+            .unwrap();
+
+        fixture.append(Bank::Deposited { transfer_id: fixture.transfer_id });
+        assert_eq!(fixture.run(), vec![]);
+        assert_eq!(fixture.state(),
+                   SagaState::Running {
+                       data: transfer,
+                       step: 2,
+                       deadline: None,
+                   });
+    }
+}
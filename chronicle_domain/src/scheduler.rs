@@ -0,0 +1,336 @@
+//! Dispatching commands once their deadlines have passed
+//!
+//! Some behaviour only happens after a period of inactivity, for example
+//! archiving a task if it hasn't been completed within a week. The
+//! `Scheduler` stores commands alongside the time that they are due in a
+//! `DeadlineStore`, and dispatches them once the deadline has passed
+//! according to its `Clock`.
+//!
+//! Deadlines are only removed from the store once their commands have been
+//! dispatched. If the scheduler is stopped in between, the command will be
+//! dispatched again when it resumes, so aggregates should be prepared to
+//! ignore commands that no longer apply. Commands that fail to dispatch are
+//! reported, and are left in the store to be retried, unless they are
+//! acknowledged by the caller.
+
+use chronicle::{Deadline, DeadlineStore};
+use futures::{Future, IntoFuture, future};
+use futures::future::{Either, Loop};
+use std::time::{Duration, SystemTime};
+use uuid::Uuid;
+
+use clock::{Clock, SystemClock};
+
+
+/// The outcome of dispatching the commands that were due
+#[derive(Debug, Clone, PartialEq)]
+pub struct Dispatched<Error> {
+    /// The number of commands that were dispatched successfully
+    pub dispatched: usize,
+    /// The deadlines whose commands could not be dispatched, in the order
+    /// that they became due
+    pub failed: Vec<DispatchFailure<Error>>,
+}
+
+
+/// A deadline whose command could not be dispatched. The deadline is still
+/// pending, and its command will be dispatched again the next time that the
+/// commands that are due are dispatched.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DispatchFailure<Error> {
+    /// The id of the deadline
+    pub deadline_id: Uuid,
+    /// The time at which the deadline was due
+    pub due_at: SystemTime,
+    /// The error returned when dispatching the command
+    pub error: Error,
+}
+
+
+/// Schedules commands to be dispatched at a later time
+#[derive(Debug, Clone)]
+pub struct Scheduler<D, Cl = SystemClock> {
+    deadline_store: D,
+    clock: Cl,
+}
+
+
+impl<D: DeadlineStore> Scheduler<D> {
+    /// Create a scheduler that uses the system clock
+    pub fn new(deadline_store: D) -> Scheduler<D> {
+        Scheduler {
+            deadline_store: deadline_store,
+            clock: SystemClock,
+        }
+    }
+}
+
+
+impl<D, Cl> Scheduler<D, Cl>
+    where D: DeadlineStore,
+          Cl: Clock
+{
+    /// Use a different clock for deciding when deadlines are due
+    pub fn with_clock<NewCl: Clock>(self, clock: NewCl) -> Scheduler<D, NewCl> {
+        Scheduler {
+            deadline_store: self.deadline_store,
+            clock: clock,
+        }
+    }
+
+    /// The store that pending deadlines are saved to
+    pub fn deadline_store(&self) -> &D {
+        &self.deadline_store
+    }
+
+    /// The clock that is used for deciding when deadlines are due
+    pub fn clock(&self) -> &Cl {
+        &self.clock
+    }
+
+    /// Schedule the command to be dispatched at the given time, replacing
+    /// any pending deadline with the same id
+    pub fn schedule_at(&self,
+                       deadline_id: Uuid,
+                       due_at: SystemTime,
+                       command: D::Command)
+                       -> D::ScheduleFuture {
+        self.deadline_store.schedule(Deadline::new(deadline_id, due_at, command))
+    }
+
+    /// Schedule the command to be dispatched once the delay has elapsed,
+    /// replacing any pending deadline with the same id
+    pub fn schedule_in(&self,
+                       deadline_id: Uuid,
+                       delay: Duration,
+                       command: D::Command)
+                       -> D::ScheduleFuture {
+        self.schedule_at(deadline_id, self.clock.now() + delay, command)
+    }
+
+    /// Cancel a pending deadline, resolving to `true` if it had not yet been
+    /// dispatched
+    pub fn cancel(&self, deadline_id: Uuid) -> D::CancelFuture {
+        self.deadline_store.cancel(deadline_id)
+    }
+
+    /// Remove a deadline that has been dealt with, unless it has since been
+    /// rescheduled. This can be used to drop deadlines whose commands failed
+    /// to dispatch, rather than retrying them.
+    pub fn acknowledge(&self, deadline_id: Uuid, due_at: SystemTime) -> D::AcknowledgeFuture {
+        self.deadline_store.acknowledge(deadline_id, due_at)
+    }
+
+    /// Pass the commands that are due to the `dispatch` function, one at a
+    /// time and in the order that they became due. A command that fails to
+    /// dispatch does not prevent the commands after it from being
+    /// dispatched, and is instead reported in the resolved `Dispatched`.
+    pub fn dispatch_due<'a, Di, F>
+        (&'a self,
+         dispatch: Di)
+         -> impl Future<Item = Dispatched<F::Error>, Error = D::Error> + 'a
+        where D::DueFuture: 'a,
+              D::AcknowledgeFuture: 'a,
+              Di: Fn(D::Command) -> F + 'a,
+              F: IntoFuture<Item = ()>,
+              F::Future: 'a,
+              F::Error: 'a
+    {
+        let outcome = Dispatched {
+            dispatched: 0,
+            failed: Vec::new(),
+        };
+
+        self.deadline_store
+            .due(self.clock.now())
+            .and_then(move |deadlines| {
+                let initial = (deadlines.into_iter(), outcome);
+
+                future::loop_fn(initial, move |(mut deadlines, mut outcome)| {
+                    let deadline = match deadlines.next() {
+                        Some(deadline) => deadline,
+                        None => return Either::A(future::ok(Loop::Break(outcome))),
+                    };
+
+                    let Deadline { deadline_id, due_at, command } = deadline;
+                    let store = &self.deadline_store;
+
+                    // Only the deadline that was dispatched is removed, in
+                    // case it was rescheduled while the command was in flight
+                    let dispatching = dispatch(command).into_future();
+                    let dispatched = dispatching.then(move |result| match result {
+                        Ok(()) => {
+                            outcome.dispatched += 1;
+                            Either::A(store.acknowledge(deadline_id, due_at)
+                                .map(move |_| Loop::Continue((deadlines, outcome))))
+                        },
+                        Err(error) => {
+                            outcome.failed.push(DispatchFailure {
+                                deadline_id: deadline_id,
+                                due_at: due_at,
+                                error: error,
+                            });
+                            Either::B(future::ok(Loop::Continue((deadlines, outcome))))
+                        },
+                    });
+
+                    Either::B(dispatched)
+                })
+            })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use chronicle_memory::MemoryDeadlineStore;
+    use futures::Future;
+    use std::cell::RefCell;
+    use std::time::{Duration, UNIX_EPOCH};
+    use uuid::Uuid;
+
+    use clock::ManualClock;
+    use super::*;
+
+
+    fn scheduler() -> Scheduler<MemoryDeadlineStore<&'static str>, ManualClock> {
+        Scheduler::new(MemoryDeadlineStore::new()).with_clock(ManualClock::new(UNIX_EPOCH))
+    }
+
+
+    /// The outcome of dispatching commands that never fail
+    fn outcome(dispatched: usize) -> Dispatched<()> {
+        Dispatched {
+            dispatched: dispatched,
+            failed: vec![],
+        }
+    }
+
+
+    #[test]
+    fn dispatch_due_commands() {
+        let scheduler = scheduler();
+        let dispatched = RefCell::new(Vec::new());
+
+        scheduler.schedule_in(Uuid::new_v4(), Duration::from_secs(20), "B").wait().unwrap();
+        scheduler.schedule_in(Uuid::new_v4(), Duration::from_secs(10), "A").wait().unwrap();
+        scheduler.schedule_in(Uuid::new_v4(), Duration::from_secs(30), "C").wait().unwrap();
+
+        let dispatch = |command| -> Result<(), ()> {
+            dispatched.borrow_mut().push(command);
+            Ok(())
+        };
+
+        assert_eq!(scheduler.dispatch_due(&dispatch).wait(), Ok(outcome(0)));
+
+        scheduler.clock().advance(Duration::from_secs(20));
+        assert_eq!(scheduler.dispatch_due(&dispatch).wait(), Ok(outcome(2)));
+        assert_eq!(*dispatched.borrow(), vec!["A", "B"]);
+
+        // Commands are only dispatched once
+        scheduler.clock().advance(Duration::from_secs(10));
+        assert_eq!(scheduler.dispatch_due(&dispatch).wait(), Ok(outcome(1)));
+        assert_eq!(*dispatched.borrow(), vec!["A", "B", "C"]);
+    }
+
+
+    #[test]
+    fn cancel_deadline() {
+        let scheduler = scheduler();
+        let deadline_id = Uuid::new_v4();
+
+        scheduler.schedule_in(deadline_id, Duration::from_secs(10), "A").wait().unwrap();
+        assert_eq!(scheduler.cancel(deadline_id).wait(), Ok(true));
+        assert_eq!(scheduler.cancel(deadline_id).wait(), Ok(false));
+
+        scheduler.clock().advance(Duration::from_secs(10));
+        assert_eq!(scheduler.dispatch_due(|_| -> Result<(), ()> { panic!("cancelled") }).wait(),
+                   Ok(outcome(0)));
+    }
+
+
+    #[test]
+    fn continue_after_dispatch_error() {
+        let scheduler = scheduler();
+        let deadline_id = Uuid::new_v4();
+        let dispatched = RefCell::new(Vec::new());
+
+        scheduler.schedule_in(deadline_id, Duration::from_secs(10), "A").wait().unwrap();
+        scheduler.schedule_in(Uuid::new_v4(), Duration::from_secs(20), "B").wait().unwrap();
+        scheduler.schedule_in(Uuid::new_v4(), Duration::from_secs(30), "C").wait().unwrap();
+        scheduler.clock().advance(Duration::from_secs(30));
+
+        let failed = scheduler.dispatch_due(|command| {
+            dispatched.borrow_mut().push(command);
+            match command {
+                "A" => Err(command),
+                _ => Ok(()),
+            }
+        });
+
+        // The later deadlines still fire after the first one failed
+        assert_eq!(failed.wait(),
+                   Ok(Dispatched {
+                       dispatched: 2,
+                       failed: vec![DispatchFailure {
+                                        deadline_id: deadline_id,
+                                        due_at: UNIX_EPOCH + Duration::from_secs(10),
+                                        error: "A",
+                                    }],
+                   }));
+        assert_eq!(*dispatched.borrow(), vec!["A", "B", "C"]);
+
+        // The failed command is still pending
+        let retried = scheduler.dispatch_due(|command| -> Result<(), ()> {
+            dispatched.borrow_mut().push(command);
+            Ok(())
+        });
+
+        assert_eq!(retried.wait(), Ok(outcome(1)));
+        assert_eq!(*dispatched.borrow(), vec!["A", "B", "C", "A"]);
+    }
+
+
+    #[test]
+    fn acknowledge_failed_deadline() {
+        let scheduler = scheduler();
+        let deadline_id = Uuid::new_v4();
+
+        scheduler.schedule_in(deadline_id, Duration::from_secs(10), "A").wait().unwrap();
+        scheduler.clock().advance(Duration::from_secs(10));
+
+        let failed = scheduler.dispatch_due(Err::<(), _>).wait().unwrap();
+        for failure in failed.failed {
+            scheduler.acknowledge(failure.deadline_id, failure.due_at).wait().unwrap();
+        }
+
+        assert_eq!(scheduler.dispatch_due(|_| -> Result<(), ()> { panic!("dropped") }).wait(),
+                   Ok(outcome(0)));
+    }
+
+
+    #[test]
+    fn keep_deadline_rescheduled_during_dispatch() {
+        let scheduler = scheduler();
+        let deadline_id = Uuid::new_v4();
+        let dispatched = RefCell::new(Vec::new());
+
+        scheduler.schedule_in(deadline_id, Duration::from_secs(10), "A").wait().unwrap();
+        scheduler.clock().advance(Duration::from_secs(10));
+
+        let dispatch = |command| -> Result<(), ()> {
+            dispatched.borrow_mut().push(command);
+            if command == "A" {
+                scheduler.schedule_in(deadline_id, Duration::from_secs(10), "B").wait().unwrap();
+            }
+            Ok(())
+        };
+
+        assert_eq!(scheduler.dispatch_due(&dispatch).wait(), Ok(outcome(1)));
+
+        // The rescheduled deadline was not removed along with the first one
+        scheduler.clock().advance(Duration::from_secs(10));
+        assert_eq!(scheduler.dispatch_due(&dispatch).wait(), Ok(outcome(1)));
+        assert_eq!(*dispatched.borrow(), vec!["A", "B"]);
+    }
+}
@@ -0,0 +1,149 @@
+//! Restoring the state of aggregates from snapshots
+
+use chronicle::{EventStore, EventsError, SequenceNumber, SnapshotStore};
+use futures::{Future, Stream};
+use uuid::Uuid;
+
+use Aggregate;
+
+
+/// An error that may occur when loading the state of an aggregate
+#[derive(Debug, Clone, PartialEq)]
+pub enum LoadError<SnapshotError, EventsError> {
+    /// An error occurred when loading the snapshot
+    Snapshot(SnapshotError),
+    /// An error occurred when streaming the events
+    Events(EventsError),
+}
+
+
+/// Load the current state of an aggregate, resuming from its most recent
+/// snapshot, if one exists. Only the events that were stored after the
+/// snapshot was taken are read from the event store and applied to the state.
+///
+/// The resulting future yields the state along with the sequence number of
+/// the last event that was applied to it, or `None` if no events have been
+/// stored for the aggregate yet.
+pub fn load<'a, A, E, S>
+    (event_store: &'a E,
+     snapshot_store: &'a S,
+     source_id: Uuid)
+     -> impl Future<Item = (A::State, Option<SequenceNumber>),
+                    Error = LoadError<S::Error, EventsError<E>>> + 'a
+    where A: Aggregate + 'a,
+          E: EventStore<Event = A::Event>,
+          S: SnapshotStore<State = A::State>
+{
+    snapshot_store.load_snapshot(source_id)
+        .map_err(LoadError::Snapshot)
+        .and_then(move |snapshot| {
+            let (state, version) = match snapshot {
+                Some((sequence_number, state)) => (state, Some(sequence_number)),
+                None => (A::initial_state(), None),
+            };
+
+            event_store.events_from(source_id, version.map_or(0, |seq| seq + 1))
+                .map_err(LoadError::Events)
+                .fold((state, version), |(mut state, _), event| {
+                    let version = Some(event.sequence_number);
+                    A::apply_event(&mut state, event.payload);
+                    Ok((state, version))
+                })
+        })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use chronicle::{EventStore, Metadata, NewEvent, SnapshotStore};
+    use chronicle::ExpectedVersion::*;
+    use chronicle_memory::{MemoryEventStore, MemorySnapshotStore};
+    use futures::Future;
+    use uuid::Uuid;
+
+    use Aggregate;
+    use super::*;
+
+
+    fn new_events(payloads: Vec<i32>) -> Vec<NewEvent<i32>> {
+        payloads.into_iter()
+            .map(|payload| NewEvent::with_type("added", 0, Metadata::new(), payload))
+            .collect()
+    }
+
+
+    struct Sum;
+
+    impl Aggregate for Sum {
+        type State = Vec<i32>;
+        type Event = i32;
+        type Command = ();
+        type CommandError = ();
+        type EventsFuture = Result<Vec<i32>, ()>;
+
+        fn initial_state() -> Vec<i32> {
+            Vec::new()
+        }
+
+        fn handle_command(_: &Vec<i32>, _: ()) -> Result<Vec<i32>, ()> {
+            Ok(vec![])
+        }
+
+        fn apply_event(state: &mut Vec<i32>, event: i32) {
+            state.push(event);
+        }
+    }
+
+
+    #[test]
+    fn load_without_events_or_snapshot() {
+        let event_store = MemoryEventStore::new();
+        let snapshot_store = MemorySnapshotStore::new();
+
+        assert_eq!(load::<Sum, _, _>(&event_store, &snapshot_store, Uuid::new_v4()).wait(),
+                   Ok((vec![], None)));
+    }
+
+
+    #[test]
+    fn load_without_snapshot() {
+        let event_store = MemoryEventStore::new();
+        let snapshot_store = MemorySnapshotStore::new();
+        let source_id = Uuid::new_v4();
+
+        event_store.append_events(source_id, Any, new_events(vec![1, 2, 3])).wait().unwrap();
+
+        assert_eq!(load::<Sum, _, _>(&event_store, &snapshot_store, source_id).wait(),
+                   Ok((vec![1, 2, 3], Some(2))));
+    }
+
+
+    #[test]
+    fn load_only_applies_events_after_the_snapshot() {
+        let event_store = MemoryEventStore::new();
+        let snapshot_store = MemorySnapshotStore::new();
+        let source_id = Uuid::new_v4();
+
+        event_store.append_events(source_id, Any, new_events(vec![1, 2, 3, 4])).wait().unwrap();
+        // Use a snapshot that differs from the history, to make sure that
+        // the earlier events are skipped
+        snapshot_store.save_snapshot(source_id, 1, vec![10, 20]).wait().unwrap();
+
+        assert_eq!(load::<Sum, _, _>(&event_store, &snapshot_store, source_id).wait(),
+                   Ok((vec![10, 20, 3, 4], Some(3))));
+    }
+
+
+    #[test]
+    fn load_with_an_up_to_date_snapshot() {
+        let event_store = MemoryEventStore::new();
+        let snapshot_store = MemorySnapshotStore::new();
+        let source_id = Uuid::new_v4();
+
+        event_store.append_events(source_id, Any, new_events(vec![1, 2])).wait().unwrap();
+        snapshot_store.save_snapshot(source_id, 1, vec![10, 20]).wait().unwrap();
+
+        assert_eq!(load::<Sum, _, _>(&event_store, &snapshot_store, source_id).wait(),
+                   Ok((vec![10, 20], Some(1))));
+    }
+}
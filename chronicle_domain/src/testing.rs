@@ -0,0 +1,160 @@
+//! Helpers for testing aggregates in a given/when/then style
+//!
+//! ```rust,ignore
+//! given::<Task>(vec![Event::Created { description: "hi".to_string() }])
+//!     .when(Command::Complete)
+//!     .then_expect(vec![Event::Completed]);
+//! ```
+
+use futures::{Future, IntoFuture};
+use std::fmt;
+
+use Aggregate;
+
+
+/// Start a test from the state that results from applying the given events
+/// to the initial state of the aggregate
+pub fn given<A: Aggregate>(events: Vec<A::Event>) -> Given<A> {
+    let mut state = A::initial_state();
+    for event in events {
+        A::apply_event(&mut state, event);
+    }
+
+    Given { state: state }
+}
+
+
+/// The state of an aggregate that a command will be handled against
+pub struct Given<A: Aggregate> {
+    state: A::State,
+}
+
+
+impl<A: Aggregate> Given<A> {
+    /// Handle the command, waiting for the resulting events
+    pub fn when(self, command: A::Command) -> When<A> {
+        let result = A::handle_command(&self.state, command).into_future().wait();
+
+        When { result: result }
+    }
+
+    /// Assert that applying the given events resulted in the expected state
+    pub fn then_state(self, expected: A::State)
+        where A::State: fmt::Debug + PartialEq
+    {
+        assert_eq!(self.state, expected, "unexpected state");
+    }
+}
+
+
+/// The result of handling a command
+pub struct When<A: Aggregate> {
+    result: Result<Vec<A::Event>, A::CommandError>,
+}
+
+
+impl<A: Aggregate> When<A> {
+    /// Assert that the command resulted in the expected events
+    pub fn then_expect(self, expected: Vec<A::Event>)
+        where A::Event: fmt::Debug + PartialEq,
+              A::CommandError: fmt::Debug
+    {
+        match self.result {
+            Ok(events) => assert_eq!(events, expected, "unexpected events"),
+            Err(error) => panic!("expected events {:?}, but found error {:?}", expected, error),
+        }
+    }
+
+    /// Assert that the command was rejected with the expected error
+    pub fn then_error(self, expected: A::CommandError)
+        where A::Event: fmt::Debug,
+              A::CommandError: fmt::Debug + PartialEq
+    {
+        match self.result {
+            Ok(events) => panic!("expected error {:?}, but found events {:?}", expected, events),
+            Err(error) => assert_eq!(error, expected, "unexpected error"),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use futures::future::{self, FutureResult};
+
+    use Aggregate;
+    use super::*;
+
+
+    /// A counter that can't be decremented below zero
+    struct Counter;
+
+    impl Aggregate for Counter {
+        type State = u32;
+        type Event = i32;
+        type Command = i32;
+        type CommandError = &'static str;
+        type EventsFuture = FutureResult<Vec<i32>, &'static str>;
+
+        fn initial_state() -> u32 {
+            0
+        }
+
+        fn handle_command(state: &u32, command: i32) -> FutureResult<Vec<i32>, &'static str> {
+            if *state as i32 + command < 0 {
+                future::err("below zero")
+            } else if command == 0 {
+                future::ok(vec![])
+            } else {
+                future::ok(vec![command])
+            }
+        }
+
+        fn apply_event(state: &mut u32, event: i32) {
+            *state = (*state as i32 + event) as u32;
+        }
+    }
+
+
+    #[test]
+    fn given_events_then_state() {
+        given::<Counter>(vec![]).then_state(0);
+        given::<Counter>(vec![3, -1, 2]).then_state(4);
+    }
+
+
+    #[test]
+    fn given_events_when_command_then_expect() {
+        given::<Counter>(vec![]).when(2).then_expect(vec![2]);
+        given::<Counter>(vec![3]).when(-3).then_expect(vec![-3]);
+        given::<Counter>(vec![3]).when(0).then_expect(vec![]);
+    }
+
+
+    #[test]
+    fn given_events_when_command_then_error() {
+        given::<Counter>(vec![]).when(-1).then_error("below zero");
+        given::<Counter>(vec![3, -2]).when(-2).then_error("below zero");
+    }
+
+
+    #[test]
+    #[should_panic(expected = "unexpected events")]
+    fn then_expect_with_different_events() {
+        given::<Counter>(vec![]).when(2).then_expect(vec![3]);
+    }
+
+
+    #[test]
+    #[should_panic(expected = "but found error")]
+    fn then_expect_with_error() {
+        given::<Counter>(vec![]).when(-1).then_expect(vec![]);
+    }
+
+
+    #[test]
+    #[should_panic(expected = "but found events")]
+    fn then_error_with_events() {
+        given::<Counter>(vec![]).when(1).then_error("below zero");
+    }
+}
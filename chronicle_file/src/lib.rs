@@ -0,0 +1,1041 @@
+//! A durable event store that persists events to an append-only log on the
+//! local file system, useful for command line tools and single node services
+//!
+//! The log is split into segment files, each named after the global offset
+//! of the first event that it contains. New segments are started once the
+//! current segment reaches the configured size. The location of each event,
+//! along with the events for each source, are indexed in memory, and these
+//! indexes are rebuilt by scanning the log when the store is opened.
+//!
+//! The events of each call to `append_events` are written as a single batch,
+//! with the last record in the batch being flagged. If the process crashes
+//! part way through a write, the torn batch at the tail of the log will be
+//! truncated the next time the store is opened.
+//!
+//! # Example
+//!
+//! ```rust
+//! extern crate chronicle;
+//! extern crate chronicle_file;
+//! extern crate futures;
+//! extern crate tempdir;
+//! extern crate uuid;
+//!
+//!
+//! use chronicle::{EventStore, ExpectedVersion, Metadata, NewEvent};
+//! use chronicle_file::{FileEventStore, Options, SyncPolicy};
+//! use futures::{Future, Stream};
+//! use tempdir::TempDir;
+//! use uuid::Uuid;
+//!
+//!
+//! fn main() {
+//!     let dir = TempDir::new("events").unwrap();
+//!     let source_id = Uuid::new_v4();
+//!
+//!     {
+//!         let options = Options::new().with_sync_policy(SyncPolicy::Always);
+//!         let event_store = FileEventStore::open_with_options(dir.path(), options).unwrap();
+//!         let event = NewEvent::with_type("greeted", 0, Metadata::new(), b"hi".to_vec());
+//!
+//!         event_store.append_events(source_id, ExpectedVersion::NoStream, vec![event])
+//!             .wait()
+//!             .unwrap();
+//!     }
+//!
+//!     // The events are still there once the store is reopened
+//!     let event_store = FileEventStore::open(dir.path()).unwrap();
+//!     let payloads = event_store.events(source_id, 0).map(|e| e.payload).collect().wait();
+//!
+//!     assert_eq!(payloads.unwrap(), vec![b"hi".to_vec()]);
+//! }
+//! ```
+
+
+extern crate byteorder;
+#[cfg_attr(test, macro_use)]
+extern crate chronicle;
+extern crate futures;
+#[cfg(test)]
+extern crate tempdir;
+extern crate uuid;
+
+
+mod record;
+
+
+use chronicle::{EventStore, ExpectedVersion, NewEvent, PersistedEvent, SequenceNumber};
+use chronicle::{AsWrongExpectedVersion, Transaction, WrongExpectedVersion};
+use futures::{Async, Poll, Stream};
+use futures::future::{self, FutureResult};
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+
+use record::{Frame, HEADER_LEN};
+
+
+/// When to flush appended events from the operating system's buffers to disk
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Flush after every append, before the append resolves. This is the
+    /// slowest, but ensures that appended events survive a power failure.
+    Always,
+    /// Flush after the given number of appends. A power failure may lose up
+    /// to this many appends.
+    Every(usize),
+    /// Leave flushing up to the operating system. Events will survive the
+    /// process crashing, but not necessarily a power failure.
+    Never,
+}
+
+
+/// Options for configuring a `FileEventStore`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Options {
+    segment_size: u64,
+    sync_policy: SyncPolicy,
+}
+
+
+impl Options {
+    /// The default options, using 64MiB segments, and flushing after every
+    /// append
+    pub fn new() -> Options {
+        Options {
+            segment_size: 64 * 1024 * 1024,
+            sync_policy: SyncPolicy::Always,
+        }
+    }
+
+    /// The size in bytes after which a new segment will be started. Note that
+    /// each batch of events is kept within a single segment, so segments may
+    /// exceed this size if a large batch is appended.
+    pub fn with_segment_size(mut self, segment_size: u64) -> Options {
+        self.segment_size = segment_size;
+        self
+    }
+
+    /// When to flush appended events to disk
+    pub fn with_sync_policy(mut self, sync_policy: SyncPolicy) -> Options {
+        self.sync_policy = sync_policy;
+        self
+    }
+}
+
+
+impl Default for Options {
+    fn default() -> Options {
+        Options::new()
+    }
+}
+
+
+/// An error that may be returned when opening a `FileEventStore`
+#[derive(Debug)]
+pub enum OpenError {
+    /// An error occurred when accessing the file system
+    Io(io::Error),
+    /// A segment before the tail of the log contains an invalid record, or
+    /// is missing from the log
+    Corrupt {
+        /// The segment containing the invalid record
+        path: PathBuf,
+        /// The position of the invalid record within the segment
+        position: u64,
+    },
+}
+
+
+impl From<io::Error> for OpenError {
+    fn from(src: io::Error) -> OpenError {
+        OpenError::Io(src)
+    }
+}
+
+
+/// An error that may be returned when appending to the `FileEventStore`
+#[derive(Debug)]
+pub enum AppendError {
+    /// An error occurred when writing to the log
+    Io(io::Error),
+    /// The source was not at the expected version
+    WrongExpectedVersion(WrongExpectedVersion),
+}
+
+
+impl From<io::Error> for AppendError {
+    fn from(src: io::Error) -> AppendError {
+        AppendError::Io(src)
+    }
+}
+
+
+impl From<WrongExpectedVersion> for AppendError {
+    fn from(src: WrongExpectedVersion) -> AppendError {
+        AppendError::WrongExpectedVersion(src)
+    }
+}
+
+
+impl AsWrongExpectedVersion for AppendError {
+    fn as_wrong_expected_version(&self) -> Option<&WrongExpectedVersion> {
+        match *self {
+            AppendError::Io(_) => None,
+            AppendError::WrongExpectedVersion(ref error) => Some(error),
+        }
+    }
+}
+
+
+/// The events to write for a source, along with its current version
+type BatchEntry<'a> = (Uuid, Option<SequenceNumber>, &'a [NewEvent<Vec<u8>>]);
+
+
+/// A segment file in the log
+#[derive(Debug)]
+struct Segment {
+    file: File,
+    /// The length of the valid records in the segment
+    len: u64,
+}
+
+
+#[derive(Debug)]
+struct Log {
+    dir: PathBuf,
+    options: Options,
+    segments: Vec<Segment>,
+    /// The segment and position of each event, indexed by global offset
+    locations: Vec<(usize, u64)>,
+    /// The global offsets of the events for each source, in order of
+    /// sequence number
+    sources: HashMap<Uuid, Vec<u64>>,
+    /// The source ids and idempotency keys of the batches that have been
+    /// appended
+    idempotency_keys: HashSet<(Uuid, Uuid)>,
+    /// The number of appends since the log was last flushed to disk
+    unsynced: usize,
+}
+
+
+fn segment_path(dir: &Path, base_offset: u64) -> PathBuf {
+    dir.join(format!("{:020}.log", base_offset))
+}
+
+
+impl Log {
+    fn open(dir: &Path, options: Options) -> Result<Log, OpenError> {
+        fs::create_dir_all(dir)?;
+
+        let mut base_offsets = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().map_or(false, |ext| ext == "log") {
+                let base_offset = path.file_stem()
+                    .and_then(|stem| stem.to_str())
+                    .and_then(|stem| stem.parse::<u64>().ok());
+
+                if let Some(base_offset) = base_offset {
+                    base_offsets.push(base_offset);
+                }
+            }
+        }
+        base_offsets.sort();
+
+        let mut log = Log {
+            dir: dir.to_path_buf(),
+            options: options,
+            segments: Vec::new(),
+            locations: Vec::new(),
+            sources: HashMap::new(),
+            idempotency_keys: HashSet::new(),
+            unsynced: 0,
+        };
+
+        let segment_count = base_offsets.len();
+        for (i, base_offset) in base_offsets.into_iter().enumerate() {
+            log.load_segment(base_offset, i + 1 == segment_count)?;
+        }
+
+        if log.segments.is_empty() {
+            log.create_segment()?;
+        }
+
+        Ok(log)
+    }
+
+    /// Scan the records in a segment, adding them to the indexes. Any
+    /// incomplete batch at the end of the last segment is truncated, but a
+    /// damaged record anywhere else means that the log is corrupt.
+    fn load_segment(&mut self, base_offset: u64, is_last: bool) -> Result<(), OpenError> {
+        let path = segment_path(&self.dir, base_offset);
+        if base_offset != self.locations.len() as u64 {
+            return Err(OpenError::Corrupt {
+                path: path,
+                position: 0,
+            });
+        }
+
+        let mut file = OpenOptions::new().read(true).append(true).open(&path)?;
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf)?;
+
+        let index = self.segments.len();
+        let mut position = 0;
+        let mut valid_len = 0;
+        let mut batch = Vec::new();
+
+        // The length of the frame that scanning stopped at, which extends to
+        // the end of the file if it was incomplete
+        let bad_frame_len = loop {
+            let (body, frame_len) = match record::read_frame(&buf[position..]) {
+                Frame::Complete(body, frame_len) => (body, frame_len),
+                Frame::Incomplete => break buf.len() - position,
+                Frame::Corrupt(frame_len) => break frame_len,
+            };
+
+            let record = match record::decode(body) {
+                Some(record) => record,
+                None => break frame_len,
+            };
+
+            batch.push((record.source_id, position as u64));
+            position += frame_len;
+
+            if record.end_of_batch {
+                for (source_id, position) in batch.drain(..) {
+                    self.index(source_id, index, position);
+                }
+                if let Some(idempotency_key) = record.idempotency_key {
+                    self.idempotency_keys.insert((record.source_id, idempotency_key));
+                }
+                valid_len = position;
+            }
+        };
+
+        // A torn write can only damage the records at the end of the file,
+        // so if there is anything after the bad frame then it must have been
+        // corrupted after it was written
+        if position + bad_frame_len < buf.len() {
+            return Err(OpenError::Corrupt {
+                path: path,
+                position: position as u64,
+            });
+        }
+
+        if valid_len < buf.len() {
+            if !is_last {
+                return Err(OpenError::Corrupt {
+                    path: path,
+                    position: valid_len as u64,
+                });
+            }
+
+            // Truncate the torn tail, making sure that the truncation is
+            // persisted before any new events are appended after it
+            file.set_len(valid_len as u64)?;
+            file.sync_all()?;
+        }
+
+        self.segments.push(Segment {
+            file: file,
+            len: valid_len as u64,
+        });
+
+        Ok(())
+    }
+
+    fn create_segment(&mut self) -> io::Result<()> {
+        let base_offset = self.locations.len() as u64;
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create_new(true)
+            .open(segment_path(&self.dir, base_offset))?;
+
+        // Make sure the new file's directory entry is persisted
+        if self.options.sync_policy != SyncPolicy::Never {
+            File::open(&self.dir)?.sync_all()?;
+        }
+
+        self.segments.push(Segment {
+            file: file,
+            len: 0,
+        });
+
+        Ok(())
+    }
+
+    fn index(&mut self, source_id: Uuid, segment: usize, position: u64) {
+        let offset = self.locations.len() as u64;
+        self.locations.push((segment, position));
+        self.sources.entry(source_id).or_default().push(offset);
+    }
+
+    /// The sequence number of the last event for the source, or `None` if
+    /// no events have been stored for it yet
+    fn current_version(&self, source_id: Uuid) -> Option<SequenceNumber> {
+        self.sources
+            .get(&source_id)
+            .and_then(|offsets| offsets.len().checked_sub(1))
+            .map(|seq| seq as SequenceNumber)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        if let Some(segment) = self.segments.last() {
+            segment.file.sync_data()?;
+        }
+        self.unsynced = 0;
+        Ok(())
+    }
+
+    /// Write a batch of records to the end of the log, returning the segment
+    /// and the position that they were written at
+    fn write_batch(&mut self, batch: &[u8]) -> io::Result<(usize, u64)> {
+        let needs_new_segment = {
+            let segment = self.segments.last().expect("no segments in the log");
+            segment.len > 0 && segment.len + batch.len() as u64 > self.options.segment_size
+        };
+
+        if needs_new_segment {
+            if self.unsynced > 0 {
+                self.sync()?;
+            }
+            self.create_segment()?;
+        }
+
+        let index = self.segments.len() - 1;
+        let position = self.segments[index].len;
+
+        let result = (&self.segments[index].file).write_all(batch).and_then(|()| {
+            self.unsynced += 1;
+            match self.options.sync_policy {
+                SyncPolicy::Always => self.sync(),
+                SyncPolicy::Every(appends) if self.unsynced >= appends => self.sync(),
+                SyncPolicy::Every(_) | SyncPolicy::Never => Ok(()),
+            }
+        });
+
+        match result {
+            Ok(()) => {
+                self.segments[index].len += batch.len() as u64;
+                Ok((index, position))
+            },
+            Err(err) => {
+                // Attempt to remove the partially written batch. If this
+                // fails, it will be truncated when the log is next opened.
+                let _ = self.segments[index].file.set_len(position);
+                Err(err)
+            },
+        }
+    }
+
+    fn append(&mut self,
+              idempotency_key: Option<Uuid>,
+              source_id: Uuid,
+              expected_version: ExpectedVersion,
+              events: &[NewEvent<Vec<u8>>])
+              -> Result<(), AppendError> {
+        if idempotency_key.map_or(false, |key| self.idempotency_keys.contains(&(source_id, key))) {
+            return Ok(());
+        }
+
+        let current = self.current_version(source_id);
+
+        if !expected_version.is_satisfied_by(current) {
+            return Err(AppendError::from(WrongExpectedVersion {
+                source_id: source_id,
+                expected: expected_version,
+                current: current,
+            }));
+        }
+
+        self.write_events(idempotency_key, &[(source_id, current, events)])
+    }
+
+    /// Append the events in the transaction, provided that every source is
+    /// at its expected version. The events are written as a single batch, so
+    /// a torn write truncates the whole transaction when the log is reopened.
+    fn append_transaction(&mut self,
+                          transaction: &Transaction<Vec<u8>>)
+                          -> Result<(), AppendError> {
+        let mut versions = HashMap::new();
+        let mut entries = Vec::with_capacity(transaction.entries.len());
+
+        for entry in &transaction.entries {
+            let current = match versions.get(&entry.source_id) {
+                Some(&current) => current,
+                None => self.current_version(entry.source_id),
+            };
+
+            if !entry.expected_version.is_satisfied_by(current) {
+                return Err(AppendError::from(WrongExpectedVersion {
+                    source_id: entry.source_id,
+                    expected: entry.expected_version,
+                    current: current,
+                }));
+            }
+
+            if !entry.events.is_empty() {
+                let next = current.map_or(0, |seq| seq + 1);
+                let last = next + entry.events.len() as SequenceNumber - 1;
+                versions.insert(entry.source_id, Some(last));
+            }
+
+            entries.push((entry.source_id, current, &entry.events[..]));
+        }
+
+        self.write_events(None, &entries)
+    }
+
+    /// Write the events for each source after its current version as a
+    /// single batch
+    fn write_events(&mut self,
+                    idempotency_key: Option<Uuid>,
+                    entries: &[BatchEntry])
+                    -> Result<(), AppendError> {
+        let count = entries.iter().map(|&(_, _, events)| events.len()).sum::<usize>();
+        if count == 0 {
+            return Ok(());
+        }
+
+        let mut batch = Vec::new();
+        let mut positions = Vec::with_capacity(count);
+
+        for &(source_id, current, events) in entries {
+            let first_sequence_number = current.map_or(0, |seq| seq + 1);
+
+            for (i, event) in events.iter().enumerate() {
+                // The idempotency key is only recorded once the whole batch
+                // has been written, so it is stored on the last record
+                let end_of_batch = positions.len() + 1 == count;
+
+                positions.push((source_id, batch.len() as u64));
+                record::encode(&mut batch,
+                               source_id,
+                               first_sequence_number + i as SequenceNumber,
+                               event,
+                               end_of_batch,
+                               if end_of_batch { idempotency_key } else { None });
+            }
+        }
+
+        let (segment, position) = self.write_batch(&batch)?;
+
+        // The key is recorded for the source of the last record, as it is
+        // when the log is reopened
+        if let (Some(idempotency_key), Some(&(source_id, _))) =
+               (idempotency_key, positions.last()) {
+            self.idempotency_keys.insert((source_id, idempotency_key));
+        }
+        for (source_id, relative_position) in positions {
+            self.index(source_id, segment, position + relative_position);
+        }
+
+        Ok(())
+    }
+
+    /// Read the event at the given global offset
+    fn read(&self, offset: u64) -> io::Result<PersistedEvent<u64, Vec<u8>>> {
+        let (segment, position) = self.locations[offset as usize];
+        let mut file = &self.segments[segment].file;
+
+        let mut header = [0; HEADER_LEN];
+        file.seek(SeekFrom::Start(position))?;
+        file.read_exact(&mut header)?;
+
+        let mut body = vec![0; record::body_len(&header)];
+        file.read_exact(&mut body)?;
+
+        let record = if record::is_valid(&header, &body) {
+            record::decode(&body)
+        } else {
+            None
+        };
+
+        match record {
+            Some(record) => {
+                Ok(PersistedEvent {
+                    offset: offset,
+                    source_id: record.source_id,
+                    sequence_number: record.sequence_number,
+                    event_type: record.event.event_type,
+                    event_version: record.event.event_version,
+                    metadata: record.event.metadata,
+                    payload: record.event.payload,
+                })
+            },
+            None => {
+                Err(io::Error::new(io::ErrorKind::InvalidData,
+                                   format!("invalid record at offset {}", offset)))
+            },
+        }
+    }
+}
+
+
+/// An event store that persists events to a segmented log on disk
+#[derive(Debug, Clone)]
+pub struct FileEventStore {
+    log: Arc<Mutex<Log>>,
+}
+
+
+impl FileEventStore {
+    /// Open the event store in the given directory with the default options,
+    /// creating the directory if it does not exist
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<FileEventStore, OpenError> {
+        FileEventStore::open_with_options(dir, Options::new())
+    }
+
+    /// Open the event store in the given directory, creating the directory
+    /// if it does not exist
+    pub fn open_with_options<P: AsRef<Path>>(dir: P,
+                                             options: Options)
+                                             -> Result<FileEventStore, OpenError> {
+        let log = Log::open(dir.as_ref(), options)?;
+        Ok(FileEventStore { log: Arc::new(Mutex::new(log)) })
+    }
+
+    /// Flush any events that have not yet been synced to disk. This is only
+    /// needed when using a `SyncPolicy` other than `Always`.
+    pub fn sync(&self) -> io::Result<()> {
+        self.log.lock().unwrap().sync()
+    }
+
+    fn stream(&self,
+              source_id: Option<Uuid>,
+              event_types: Option<&[&str]>,
+              sequence_number: SequenceNumber,
+              offset: u64)
+              -> EventsStream {
+        EventsStream {
+            source_id: source_id,
+            event_types: event_types.map(|tys| tys.iter().map(|ty| ty.to_string()).collect()),
+            sequence_number: sequence_number,
+            offset: offset,
+            event_store: self.clone(),
+        }
+    }
+}
+
+
+impl EventStore for FileEventStore {
+    type Offset = u64;
+    type Event = Vec<u8>;
+    type AppendError = AppendError;
+    type AppendFuture = FutureResult<(), AppendError>;
+    type EventsStream = EventsStream;
+    type AllEventsStream = EventsStream;
+
+    fn append_events(&self,
+                     source_id: Uuid,
+                     expected_version: ExpectedVersion,
+                     events: Vec<NewEvent<Vec<u8>>>)
+                     -> FutureResult<(), AppendError> {
+        future::result(self.log.lock().unwrap().append(None, source_id, expected_version, &events))
+    }
+
+    fn append_events_idempotent(&self,
+                                idempotency_key: Uuid,
+                                source_id: Uuid,
+                                expected_version: ExpectedVersion,
+                                events: Vec<NewEvent<Vec<u8>>>)
+                                -> FutureResult<(), AppendError> {
+        let mut log = self.log.lock().unwrap();
+        future::result(log.append(Some(idempotency_key), source_id, expected_version, &events))
+    }
+
+    fn append_transaction(&self,
+                          transaction: Transaction<Vec<u8>>)
+                          -> FutureResult<(), AppendError> {
+        future::result(self.log.lock().unwrap().append_transaction(&transaction))
+    }
+
+    fn events(&self, source_id: Uuid, offset: u64) -> EventsStream {
+        self.stream(Some(source_id), None, 0, offset)
+    }
+
+    fn events_from(&self, source_id: Uuid, sequence_number: SequenceNumber) -> EventsStream {
+        self.stream(Some(source_id), None, sequence_number, 0)
+    }
+
+    fn all_events(&self, offset: u64) -> EventsStream {
+        self.stream(None, None, 0, offset)
+    }
+
+    fn all_events_of_types(&self, event_types: &[&str], offset: u64) -> EventsStream {
+        self.stream(None, Some(event_types), 0, offset)
+    }
+}
+
+
+/// A stream of events read from the log. This either contains the events
+/// for a specified source id, or all of the events in the store, optionally
+/// restricted to a set of event types.
+pub struct EventsStream {
+    source_id: Option<Uuid>,
+    event_types: Option<Vec<String>>,
+    /// The sequence number to start from, when streaming a single source
+    sequence_number: SequenceNumber,
+    offset: u64,
+    event_store: FileEventStore,
+}
+
+
+impl Stream for EventsStream {
+    type Item = PersistedEvent<u64, Vec<u8>>;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, io::Error> {
+        let log = self.event_store.log.lock().unwrap();
+
+        loop {
+            let next_offset = match self.source_id {
+                Some(source_id) => {
+                    log.sources.get(&source_id).and_then(|offsets| {
+                        // The offsets of a source are indexed by sequence number
+                        let start = cmp::min(self.sequence_number as usize, offsets.len());
+                        let offsets = &offsets[start..];
+                        let i = offsets.binary_search(&self.offset).unwrap_or_else(|i| i);
+                        offsets.get(i).cloned()
+                    })
+                },
+                None if self.offset < log.locations.len() as u64 => Some(self.offset),
+                None => None,
+            };
+
+            let offset = match next_offset {
+                Some(offset) => offset,
+                None => return Ok(Async::Ready(None)),
+            };
+
+            self.offset = offset + 1;
+            let event = log.read(offset)?;
+
+            let is_included = self.event_types
+                .as_ref()
+                .map_or(true, |tys| tys.contains(&event.event_type));
+
+            if is_included {
+                return Ok(Async::Ready(Some(event)));
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use chronicle::{EventStore, Metadata, PersistedEvent};
+    use chronicle::ExpectedVersion::*;
+    use futures::{Future, Stream};
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+    use tempdir::TempDir;
+    use uuid::Uuid;
+
+    use super::*;
+
+
+    /// Create an event store in a temporary directory, which is removed
+    /// when the returned `TempDir` is dropped
+    fn test_event_store() -> (TempDir, FileEventStore) {
+        let dir = TempDir::new("chronicle_file").unwrap();
+        let event_store = FileEventStore::open(dir.path()).unwrap();
+        (dir, event_store)
+    }
+
+
+    fn new_events(payloads: &[&str]) -> Vec<NewEvent<Vec<u8>>> {
+        payloads.iter()
+            .map(|payload| payload.as_bytes().to_vec())
+            .map(|payload| NewEvent::with_type("test", 0, Metadata::new(), payload))
+            .collect()
+    }
+
+
+    fn payloads(events: Vec<PersistedEvent<u64, Vec<u8>>>) -> Vec<Vec<u8>> {
+        events.into_iter().map(|event| event.payload).collect()
+    }
+
+
+    fn stored_payloads(event_store: &FileEventStore) -> Vec<Vec<u8>> {
+        payloads(event_store.all_events(0).collect().wait().unwrap())
+    }
+
+
+    fn segment_paths(dir: &TempDir) -> Vec<PathBuf> {
+        let mut paths = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect::<Vec<_>>();
+        paths.sort();
+        paths
+    }
+
+
+    #[test]
+    fn reopen_event_store() {
+        let dir = TempDir::new("chronicle_file").unwrap();
+        let source_id_1 = Uuid::new_v4();
+        let source_id_2 = Uuid::new_v4();
+        let metadata = Metadata::new().with_header("user", "brendan");
+
+        {
+            let event_store = FileEventStore::open(dir.path()).unwrap();
+            let events = vec![NewEvent::with_type("a", 1, metadata.clone(), b"A".to_vec())];
+
+            event_store.append_events(source_id_1, NoStream, events).wait().unwrap();
+            event_store.append_events(source_id_2, NoStream, new_events(&["B"])).wait().unwrap();
+        }
+
+        let event_store = FileEventStore::open(dir.path()).unwrap();
+        event_store.append_events(source_id_1, Exact(0), new_events(&["C"])).wait().unwrap();
+
+        let events = event_store.events(source_id_1, 0).collect().wait().unwrap();
+        assert_eq!(events[0],
+                   PersistedEvent {
+                       offset: 0,
+                       source_id: source_id_1,
+                       sequence_number: 0,
+                       event_type: "a".to_string(),
+                       event_version: 1,
+                       metadata: metadata,
+                       payload: b"A".to_vec(),
+                   });
+        assert_eq!(payloads(events), vec![b"A".to_vec(), b"C".to_vec()]);
+        assert_eq!(stored_payloads(&event_store),
+                   vec![b"A".to_vec(), b"B".to_vec(), b"C".to_vec()]);
+    }
+
+
+    #[test]
+    fn reopen_with_idempotency_keys() {
+        let dir = TempDir::new("chronicle_file").unwrap();
+        let source_id_1 = Uuid::new_v4();
+        let idempotency_key = Uuid::new_v4();
+
+        {
+            let event_store = FileEventStore::open(dir.path()).unwrap();
+            event_store.append_events_idempotent(idempotency_key,
+                                                 source_id_1,
+                                                 NoStream,
+                                                 new_events(&["A", "B"]))
+                .wait()
+                .unwrap();
+        }
+
+        // The key is rebuilt from the log, so the retry is not appended
+        let event_store = FileEventStore::open(dir.path()).unwrap();
+        event_store.append_events_idempotent(idempotency_key,
+                                             source_id_1,
+                                             NoStream,
+                                             new_events(&["A", "B"]))
+            .wait()
+            .unwrap();
+
+        assert_eq!(stored_payloads(&event_store), vec![b"A".to_vec(), b"B".to_vec()]);
+    }
+
+
+    #[test]
+    fn reopen_with_transactions() {
+        let dir = TempDir::new("chronicle_file").unwrap();
+        let source_id_1 = Uuid::new_v4();
+        let source_id_2 = Uuid::new_v4();
+
+        {
+            let event_store = FileEventStore::open(dir.path()).unwrap();
+            let transaction = Transaction::new()
+                .with_events(source_id_1, NoStream, new_events(&["A"]))
+                .with_events(source_id_2, NoStream, new_events(&["B", "C"]));
+            event_store.append_transaction(transaction).wait().unwrap();
+        }
+
+        // Both sources are rebuilt from the single batch in the log
+        let event_store = FileEventStore::open(dir.path()).unwrap();
+        let transaction = Transaction::new()
+            .with_events(source_id_2, Exact(1), new_events(&["D"]))
+            .with_events(source_id_1, Exact(0), new_events(&["E"]));
+        event_store.append_transaction(transaction).wait().unwrap();
+
+        assert_eq!(payloads(event_store.events(source_id_1, 0).collect().wait().unwrap()),
+                   vec![b"A".to_vec(), b"E".to_vec()]);
+        assert_eq!(payloads(event_store.events(source_id_2, 0).collect().wait().unwrap()),
+                   vec![b"B".to_vec(), b"C".to_vec(), b"D".to_vec()]);
+    }
+
+
+    #[test]
+    fn all_events_of_types() {
+        let (_dir, event_store) = test_event_store();
+        let events = vec![NewEvent::with_type("a", 0, Metadata::new(), b"A".to_vec()),
+                          NewEvent::with_type("b", 0, Metadata::new(), b"B".to_vec()),
+                          NewEvent::with_type("c", 0, Metadata::new(), b"C".to_vec())];
+
+        event_store.append_events(Uuid::new_v4(), NoStream, events).wait().unwrap();
+
+        let events = event_store.all_events_of_types(&["a", "c"], 0).collect().wait().unwrap();
+        assert_eq!(payloads(events), vec![b"A".to_vec(), b"C".to_vec()]);
+    }
+
+
+    #[test]
+    fn roll_segments() {
+        let dir = TempDir::new("chronicle_file").unwrap();
+        let source_id = Uuid::new_v4();
+        let options = Options::new().with_segment_size(128).with_sync_policy(SyncPolicy::Every(2));
+
+        {
+            let event_store = FileEventStore::open_with_options(dir.path(), options).unwrap();
+            for payload in &["A", "B", "C", "D", "E"] {
+                let events = new_events(&[*payload]);
+                event_store.append_events(source_id, Any, events).wait().unwrap();
+            }
+            event_store.sync().unwrap();
+        }
+
+        assert!(segment_paths(&dir).len() > 1);
+
+        let event_store = FileEventStore::open_with_options(dir.path(), options).unwrap();
+        let events = event_store.events(source_id, 2).collect().wait().unwrap();
+
+        assert_eq!(events.iter().map(|event| event.sequence_number).collect::<Vec<_>>(),
+                   vec![2, 3, 4]);
+        assert_eq!(payloads(events), vec![b"C".to_vec(), b"D".to_vec(), b"E".to_vec()]);
+    }
+
+
+    #[test]
+    fn truncate_torn_records() {
+        let dir = TempDir::new("chronicle_file").unwrap();
+        let source_id = Uuid::new_v4();
+
+        {
+            let event_store = FileEventStore::open(dir.path()).unwrap();
+            event_store.append_events(source_id, NoStream, new_events(&["A"])).wait().unwrap();
+            event_store.append_events(source_id, Exact(0), new_events(&["B"])).wait().unwrap();
+        }
+
+        // Simulate a crash part way through writing the second record
+        let path = segment_paths(&dir).pop().unwrap();
+        let len = fs::metadata(&path).unwrap().len();
+        OpenOptions::new().write(true).open(&path).unwrap().set_len(len - 3).unwrap();
+
+        let event_store = FileEventStore::open(dir.path()).unwrap();
+        assert_eq!(stored_payloads(&event_store), vec![b"A".to_vec()]);
+
+        // New events are appended after the truncated tail
+        event_store.append_events(source_id, Exact(0), new_events(&["C"])).wait().unwrap();
+        let event_store = FileEventStore::open(dir.path()).unwrap();
+        assert_eq!(stored_payloads(&event_store), vec![b"A".to_vec(), b"C".to_vec()]);
+    }
+
+
+    #[test]
+    fn truncate_incomplete_batches() {
+        let dir = TempDir::new("chronicle_file").unwrap();
+        let source_id = Uuid::new_v4();
+
+        {
+            let event_store = FileEventStore::open(dir.path()).unwrap();
+            event_store.append_events(source_id, NoStream, new_events(&["A"])).wait().unwrap();
+        }
+
+        // Simulate a crash after writing the first record of a batch
+        let path = segment_paths(&dir).pop().unwrap();
+        let mut partial_batch = Vec::new();
+        record::encode(&mut partial_batch, source_id, 1, &new_events(&["B"])[0], false, None);
+        partial_batch.extend_from_slice(b"garbage");
+        OpenOptions::new().append(true).open(&path).unwrap().write_all(&partial_batch).unwrap();
+
+        let event_store = FileEventStore::open(dir.path()).unwrap();
+        assert_eq!(stored_payloads(&event_store), vec![b"A".to_vec()]);
+        event_store.append_events(source_id, Exact(0), new_events(&["C", "D"])).wait().unwrap();
+        assert_eq!(stored_payloads(&event_store),
+                   vec![b"A".to_vec(), b"C".to_vec(), b"D".to_vec()]);
+    }
+
+
+    #[test]
+    fn open_with_corrupt_segment() {
+        let dir = TempDir::new("chronicle_file").unwrap();
+        let options = Options::new().with_segment_size(64);
+
+        {
+            let event_store = FileEventStore::open_with_options(dir.path(), options).unwrap();
+            for payload in &["A", "B"] {
+                event_store.append_events(Uuid::new_v4(), Any, new_events(&[*payload]))
+                    .wait()
+                    .unwrap();
+            }
+        }
+
+        // Corrupt the payload of the record in the first segment
+        let path = segment_paths(&dir).remove(0);
+        let mut bytes = fs::read(&path).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        fs::write(&path, bytes).unwrap();
+
+        match FileEventStore::open_with_options(dir.path(), options) {
+            Err(OpenError::Corrupt { path: corrupt_path, position: 0 }) => {
+                assert_eq!(corrupt_path, path)
+            },
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+
+    #[test]
+    fn open_with_corrupt_record_in_last_segment() {
+        let dir = TempDir::new("chronicle_file").unwrap();
+        let source_id = Uuid::new_v4();
+
+        {
+            let event_store = FileEventStore::open(dir.path()).unwrap();
+            for payload in &["A", "B", "C"] {
+                event_store.append_events(source_id, Any, new_events(&[*payload]))
+                    .wait()
+                    .unwrap();
+            }
+        }
+
+        // Corrupt the payload of the middle record, which can't have been
+        // caused by a torn write, so it must not be truncated
+        let path = segment_paths(&dir).pop().unwrap();
+        let mut bytes = fs::read(&path).unwrap();
+        let record_len = bytes.len() / 3;
+        bytes[record_len * 2 - 1] ^= 0xff;
+        fs::write(&path, &bytes).unwrap();
+
+        match FileEventStore::open(dir.path()) {
+            Err(OpenError::Corrupt { path: corrupt_path, position }) => {
+                assert_eq!(corrupt_path, path);
+                assert_eq!(position, record_len as u64);
+            },
+            result => panic!("unexpected result: {:?}", result),
+        }
+
+        assert_eq!(fs::read(&path).unwrap(), bytes);
+    }
+
+
+    mod conformance {
+        use super::test_event_store;
+
+
+        // The directory is kept alive until the end of each test, as it is
+        // part of the same temporary as the event store
+        event_store_conformance_tests! {
+            new_store: test_event_store().1,
+            payload: |i| vec![i as u8],
+        }
+    }
+}
@@ -0,0 +1,329 @@
+//! The on-disk format of the records in the log
+//!
+//! Each record is framed by a header containing the length and a CRC-32
+//! checksum of its body, allowing torn writes to be detected when the log is
+//! scanned:
+//!
+//! ```text
+//! +--------------+--------------+----------------------+
+//! | length (u32) | crc32 (u32)  | body (length bytes)  |
+//! +--------------+--------------+----------------------+
+//! ```
+//!
+//! The body contains the flags, the idempotency key of the batch if the
+//! record ends a batch that was given one, the source id, the sequence
+//! number, and the event itself. All integers are stored in little endian
+//! order, and strings and byte arrays are prefixed by their length.
+
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+use chronicle::{Metadata, NewEvent, SequenceNumber};
+use std::collections::BTreeMap;
+use std::str;
+use std::time::{Duration, UNIX_EPOCH};
+use uuid::Uuid;
+
+
+/// The length of the header that precedes the body of each record
+pub const HEADER_LEN: usize = 8;
+
+/// Set on the last record of each appended batch
+const END_OF_BATCH: u8 = 0b01;
+
+/// Set when the record is followed by the idempotency key of its batch
+const IDEMPOTENCY_KEY: u8 = 0b10;
+
+
+/// A record that was read back from the log
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    /// Whether this was the last record of the batch that it was appended in
+    pub end_of_batch: bool,
+    /// The idempotency key of the batch, stored on its last record
+    pub idempotency_key: Option<Uuid>,
+    pub source_id: Uuid,
+    pub sequence_number: SequenceNumber,
+    pub event: NewEvent<Vec<u8>>,
+}
+
+
+/// The result of reading a frame from the start of a buffer
+#[derive(Debug, Clone, PartialEq)]
+pub enum Frame<'a> {
+    /// The body of a complete record, along with the length of the frame
+    Complete(&'a [u8], usize),
+    /// The buffer ends part way through the frame
+    Incomplete,
+    /// The checksum of the body did not match, along with the length of the
+    /// frame
+    Corrupt(usize),
+}
+
+
+/// Read the frame at the start of the buffer
+pub fn read_frame<'a>(buf: &'a [u8]) -> Frame<'a> {
+    if buf.len() < HEADER_LEN {
+        return Frame::Incomplete;
+    }
+
+    let len = LittleEndian::read_u32(&buf[0..4]) as usize;
+    let checksum = LittleEndian::read_u32(&buf[4..8]);
+
+    match buf[HEADER_LEN..].get(..len) {
+        None => Frame::Incomplete,
+        Some(body) if crc32(body) != checksum => Frame::Corrupt(HEADER_LEN + len),
+        Some(body) => Frame::Complete(body, HEADER_LEN + len),
+    }
+}
+
+
+/// The length of the body of the record, read from its header
+pub fn body_len(header: &[u8]) -> usize {
+    LittleEndian::read_u32(&header[0..4]) as usize
+}
+
+
+/// Check the body of a record against the checksum in its header
+pub fn is_valid(header: &[u8], body: &[u8]) -> bool {
+    LittleEndian::read_u32(&header[4..8]) == crc32(body)
+}
+
+
+/// Encode a framed record onto the end of the buffer
+pub fn encode(buf: &mut Vec<u8>,
+              source_id: Uuid,
+              sequence_number: SequenceNumber,
+              event: &NewEvent<Vec<u8>>,
+              end_of_batch: bool,
+              idempotency_key: Option<Uuid>) {
+    let mut body = Vec::new();
+    let metadata = &event.metadata;
+    let recorded_at = metadata.recorded_at.duration_since(UNIX_EPOCH).unwrap_or_default();
+
+    let mut flags = if end_of_batch { END_OF_BATCH } else { 0 };
+    if idempotency_key.is_some() {
+        flags |= IDEMPOTENCY_KEY;
+    }
+
+    body.push(flags);
+    if let Some(idempotency_key) = idempotency_key {
+        body.extend_from_slice(idempotency_key.as_bytes());
+    }
+    body.extend_from_slice(source_id.as_bytes());
+    body.write_u32::<LittleEndian>(sequence_number).unwrap();
+    write_bytes(&mut body, event.event_type.as_bytes());
+    body.write_u32::<LittleEndian>(event.event_version).unwrap();
+    body.extend_from_slice(metadata.event_id.as_bytes());
+    body.write_u64::<LittleEndian>(recorded_at.as_secs()).unwrap();
+    body.write_u32::<LittleEndian>(recorded_at.subsec_nanos()).unwrap();
+    write_uuid_opt(&mut body, metadata.correlation_id);
+    write_uuid_opt(&mut body, metadata.causation_id);
+    body.write_u32::<LittleEndian>(metadata.headers.len() as u32).unwrap();
+    for (key, value) in &metadata.headers {
+        write_bytes(&mut body, key.as_bytes());
+        write_bytes(&mut body, value.as_bytes());
+    }
+    write_bytes(&mut body, &event.payload);
+
+    buf.write_u32::<LittleEndian>(body.len() as u32).unwrap();
+    buf.write_u32::<LittleEndian>(crc32(&body)).unwrap();
+    buf.extend_from_slice(&body);
+}
+
+
+fn write_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.write_u32::<LittleEndian>(bytes.len() as u32).unwrap();
+    buf.extend_from_slice(bytes);
+}
+
+
+fn write_uuid_opt(buf: &mut Vec<u8>, uuid: Option<Uuid>) {
+    match uuid {
+        Some(uuid) => {
+            buf.push(1);
+            buf.extend_from_slice(uuid.as_bytes());
+        },
+        None => buf.push(0),
+    }
+}
+
+
+/// Decode the body of a record, returning `None` if it is malformed
+pub fn decode(body: &[u8]) -> Option<Record> {
+    let mut reader = Reader { buf: body };
+
+    let flags = reader.u8()?;
+    let idempotency_key = if flags & IDEMPOTENCY_KEY != 0 {
+        Some(reader.uuid()?)
+    } else {
+        None
+    };
+    let source_id = reader.uuid()?;
+    let sequence_number = reader.u32()?;
+    let event_type = reader.string()?;
+    let event_version = reader.u32()?;
+    let event_id = reader.uuid()?;
+    let recorded_at = UNIX_EPOCH + Duration::new(reader.u64()?, reader.u32()?);
+    let correlation_id = reader.uuid_opt()?;
+    let causation_id = reader.uuid_opt()?;
+
+    let mut headers = BTreeMap::new();
+    for _ in 0..reader.u32()? {
+        headers.insert(reader.string()?, reader.string()?);
+    }
+
+    let payload = reader.bytes()?.to_vec();
+
+    if !reader.buf.is_empty() {
+        return None;
+    }
+
+    Some(Record {
+        end_of_batch: flags & END_OF_BATCH != 0,
+        idempotency_key: idempotency_key,
+        source_id: source_id,
+        sequence_number: sequence_number,
+        event: NewEvent {
+            event_type: event_type,
+            event_version: event_version,
+            metadata: Metadata {
+                event_id: event_id,
+                recorded_at: recorded_at,
+                correlation_id: correlation_id,
+                causation_id: causation_id,
+                headers: headers,
+            },
+            payload: payload,
+        },
+    })
+}
+
+
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.buf.len() < len {
+            return None;
+        }
+
+        let (bytes, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|bytes| bytes[0])
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.take(4).map(LittleEndian::read_u32)
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        self.take(8).map(LittleEndian::read_u64)
+    }
+
+    fn uuid(&mut self) -> Option<Uuid> {
+        self.take(16).and_then(|bytes| Uuid::from_bytes(bytes).ok())
+    }
+
+    fn uuid_opt(&mut self) -> Option<Option<Uuid>> {
+        match self.u8()? {
+            0 => Some(None),
+            1 => self.uuid().map(Some),
+            _ => None,
+        }
+    }
+
+    fn bytes(&mut self) -> Option<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    fn string(&mut self) -> Option<String> {
+        self.bytes().and_then(|bytes| str::from_utf8(bytes).ok()).map(String::from)
+    }
+}
+
+
+/// Compute the CRC-32 (IEEE) checksum of the bytes
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    fn record(end_of_batch: bool, idempotency_key: Option<Uuid>) -> Record {
+        let metadata = Metadata::new()
+            .with_correlation_id(Uuid::new_v4())
+            .with_header("user", "brendan");
+
+        Record {
+            end_of_batch: end_of_batch,
+            idempotency_key: idempotency_key,
+            source_id: Uuid::new_v4(),
+            sequence_number: 42,
+            event: NewEvent::with_type("created", 3, metadata, b"hello".to_vec()),
+        }
+    }
+
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+
+    #[test]
+    fn encode_and_decode() {
+        for &(end_of_batch, idempotency_key) in &[(true, None),
+                                                  (false, None),
+                                                  (true, Some(Uuid::new_v4()))] {
+            let record = record(end_of_batch, idempotency_key);
+            let mut buf = Vec::new();
+            encode(&mut buf,
+                   record.source_id,
+                   record.sequence_number,
+                   &record.event,
+                   record.end_of_batch,
+                   record.idempotency_key);
+
+            match read_frame(&buf) {
+                Frame::Complete(body, len) => {
+                    assert_eq!(len, buf.len());
+                    assert_eq!(decode(body), Some(record));
+                },
+                frame => panic!("unexpected frame: {:?}", frame),
+            }
+        }
+    }
+
+
+    #[test]
+    fn read_torn_frames() {
+        let record = record(true, None);
+        let mut buf = Vec::new();
+        encode(&mut buf, record.source_id, 0, &record.event, true, None);
+
+        assert_eq!(read_frame(&buf[..4]), Frame::Incomplete);
+        assert_eq!(read_frame(&buf[..buf.len() - 1]), Frame::Incomplete);
+
+        let last = buf.len() - 1;
+        buf[last] ^= 0xff;
+        assert_eq!(read_frame(&buf), Frame::Corrupt(buf.len()));
+    }
+}
@@ -22,7 +22,7 @@
 //! }
 //!
 //! fn main() {
-//!     use chronicle::EventStore;
+//!     use chronicle::{EventStore, ExpectedVersion, Metadata, NewEvent};
 //!     use futures::{Future, Stream, future};
 //!     use std::thread;
 //!     use uuid::Uuid;
@@ -48,11 +48,16 @@
 //!
 //!     // Append all the events - let's not worry about ordering
 //!     let handles = events.into_iter().map(|(source_id, events)| {
-//!         thread::spawn(move || EVENT_STORE.append_events(source_id, events))
+//!         thread::spawn(move || {
+//!             let events = events.into_iter()
+//!                 .map(|event| NewEvent::with_type("sample", 0, Metadata::new(), event))
+//!                 .collect();
+//!             EVENT_STORE.append_events(source_id, ExpectedVersion::Any, events).wait()
+//!         })
 //!     });
 //!
 //!     for handle in handles.collect::<Vec<_>>() {
-//!         handle.join().unwrap();
+//!         handle.join().unwrap().unwrap();
 //!     }
 //!
 //!
@@ -77,25 +82,66 @@
 
 
 extern crate chashmap;
+#[cfg_attr(test, macro_use)]
 extern crate chronicle;
 extern crate futures;
 extern crate uuid;
 
+#[cfg(test)]
+#[macro_use]
+extern crate serde_json;
+
 
 use chashmap::CHashMap;
-use chronicle::{EventStore, PersistedEvent};
+use chronicle::{CheckpointStore, EventStore, ExpectedVersion, PersistedEvent, SequenceNumber};
+use chronicle::{Deadline, DeadlineStore, NewEvent, Outbox, Publisher, SnapshotStore};
+use chronicle::{AsWrongExpectedVersion, Transaction, WrongExpectedVersion};
 use futures::{Async, Poll, Stream};
-use std::sync::Arc;
+use futures::future::{self, FutureResult};
+use futures::task::{self, Task};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::mem;
+use std::sync::{Arc, Mutex, RwLock};
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::SystemTime;
 use uuid::Uuid;
 
 
+/// The error type of the stores in this crate, none of which can fail
+///
+/// Note that this error has no variants, so can never happen. This will be
+/// replaced by `!` once `#![feature(never_type)]` has been stabilised.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Never {}
+
+
+/// The events of a single source, along with their global offsets
+type SourceEvents<Event> = Vec<(usize, NewEvent<Event>)>;
+
+
 /// An in-memory event store implementation that can be concurrently accessed
 #[derive(Debug, Clone)]
 pub struct MemoryEventStore<Event> {
     offset: Arc<AtomicUsize>,
-    /// The stored event payloads and their global offset number
-    events: CHashMap<Uuid, Vec<(usize, Event)>>,
+    /// The stored events along with their global offset number
+    events: Arc<CHashMap<Uuid, SourceEvents<Event>>>,
+    /// The source id and position within that source of each event, indexed
+    /// by global offset
+    log: Arc<RwLock<Vec<(Uuid, usize)>>>,
+    /// Tasks that are waiting on new events for a specific source id
+    source_subscribers: Arc<CHashMap<Uuid, Vec<Task>>>,
+    /// Tasks that are waiting on new events in the global log
+    all_subscribers: Arc<Mutex<Vec<Task>>>,
+    /// The offsets of the events that are yet to be published, if the outbox
+    /// has been enabled
+    outbox: Option<Arc<Mutex<BTreeSet<usize>>>>,
+    /// The source ids and idempotency keys of the batches that have been
+    /// appended
+    idempotency_keys: Arc<Mutex<HashSet<(Uuid, Uuid)>>>,
+    /// Held for reading by appends to a single source, and for writing by
+    /// transactions, which need to check the versions of several sources
+    /// before appending to any of them
+    transaction_lock: Arc<RwLock<()>>,
 }
 
 
@@ -104,39 +150,252 @@ impl<Event> MemoryEventStore<Event> {
     pub fn new() -> MemoryEventStore<Event> {
         MemoryEventStore {
             offset: Arc::new(AtomicUsize::new(0)),
-            events: CHashMap::new(),
+            events: Arc::new(CHashMap::new()),
+            log: Arc::new(RwLock::new(Vec::new())),
+            source_subscribers: Arc::new(CHashMap::new()),
+            all_subscribers: Arc::new(Mutex::new(Vec::new())),
+            outbox: None,
+            idempotency_keys: Arc::new(Mutex::new(HashSet::new())),
+            transaction_lock: Arc::new(RwLock::new(())),
+        }
+    }
+
+    /// Record each appended event in an outbox, so that it can be relayed to
+    /// a `Publisher`
+    pub fn with_outbox(mut self) -> MemoryEventStore<Event> {
+        self.outbox = Some(Arc::new(Mutex::new(BTreeSet::new())));
+        self
+    }
+
+    /// Subscribe to the events for the specified source id. This will first
+    /// catch up on the events that have already been stored, and will then
+    /// wait for new events to be appended, rather than ending the stream.
+    pub fn subscribe(&self, source_id: Uuid, offset: usize) -> EventsStream<Event>
+        where Event: Clone
+    {
+        EventsStream {
+            source_id: source_id,
+            sequence_number: 0,
+            offset: offset,
+            is_live: true,
+            event_store: self.clone(),
+        }
+    }
+
+    /// Subscribe to every event in the event store. This will first catch up
+    /// on the events that have already been stored, and will then wait for
+    /// new events to be appended, rather than ending the stream.
+    pub fn subscribe_all(&self, offset: usize) -> AllEventsStream<Event>
+        where Event: Clone
+    {
+        AllEventsStream {
+            offset: offset,
+            event_types: None,
+            is_live: true,
+            event_store: self.clone(),
+        }
+    }
+
+    /// Append the events for the source id, unless a batch with the same
+    /// idempotency key has already been appended
+    fn append(&self,
+              idempotency_key: Option<Uuid>,
+              source_id: Uuid,
+              expected_version: ExpectedVersion,
+              events: Vec<NewEvent<Event>>)
+              -> Result<(), AppendError> {
+        if events.is_empty() && expected_version == ExpectedVersion::Any {
+            return Ok(());
+        }
+
+        let _transaction_lock = self.transaction_lock.read().unwrap();
+        let mut result = Ok(());
+
+        // The version check is performed inside the `alter` closure, which
+        // holds the lock for this source id, ensuring that no other writer
+        // can sneak in events between the check and the write.
+        self.events.alter(source_id, |existing_events| {
+            let current = existing_events.as_ref()
+                .and_then(|es| es.len().checked_sub(1))
+                .map(|seq| seq as SequenceNumber);
+
+            // Keys are scoped to the source, so the lock on the source is
+            // enough to stop the same batch being appended twice
+            if let Some(key) = idempotency_key {
+                if self.idempotency_keys.lock().unwrap().contains(&(source_id, key)) {
+                    return existing_events;
+                }
+            }
+
+            if !expected_version.is_satisfied_by(current) {
+                result = Err(AppendError::from(WrongExpectedVersion {
+                    source_id: source_id,
+                    expected: expected_version,
+                    current: current,
+                }));
+
+                return existing_events;
+            }
+
+            if events.is_empty() {
+                return existing_events;
+            }
+
+            let mut existing_events = existing_events.unwrap_or(Vec::new());
+
+            // Hold the lock on the global log while we assign the offsets,
+            // ensuring that the log is always ordered by offset.
+            let mut log = self.log.write().unwrap();
+            self.push_events(&mut log, source_id, &mut existing_events, events);
+
+            if let Some(key) = idempotency_key {
+                self.idempotency_keys.lock().unwrap().insert((source_id, key));
+            }
+
+            Some(existing_events)
+        });
+
+        if result.is_ok() {
+            self.notify_subscribers(source_id);
+        }
+
+        result
+    }
+
+    /// Append the events in the transaction, provided that every source is
+    /// at its expected version
+    fn commit(&self, transaction: Transaction<Event>) -> Result<(), AppendError> {
+        // Exclude every other writer while the versions are checked, so that
+        // none of the sources can change before the events are appended
+        let _transaction_lock = self.transaction_lock.write().unwrap();
+
+        let mut versions = HashMap::new();
+
+        for entry in &transaction.entries {
+            let current = *versions.entry(entry.source_id).or_insert_with(|| {
+                self.events.get(&entry.source_id)
+                    .and_then(|es| es.len().checked_sub(1))
+                    .map(|seq| seq as SequenceNumber)
+            });
+
+            if !entry.expected_version.is_satisfied_by(current) {
+                return Err(AppendError::from(WrongExpectedVersion {
+                    source_id: entry.source_id,
+                    expected: entry.expected_version,
+                    current: current,
+                }));
+            }
+
+            if !entry.events.is_empty() {
+                let next = current.map_or(0, |seq| seq + 1);
+                let last = next + entry.events.len() as SequenceNumber - 1;
+                versions.insert(entry.source_id, Some(last));
+            }
+        }
+
+        let source_ids = transaction.entries
+            .iter()
+            .map(|entry| entry.source_id)
+            .collect::<Vec<_>>();
+
+        // Hold the lock on the global log until every event has been
+        // appended, so that the transaction is never partially visible in it.
+        {
+            let mut log = self.log.write().unwrap();
+
+            for entry in transaction.entries {
+                if entry.events.is_empty() {
+                    continue;
+                }
+
+                self.events.alter(entry.source_id, |existing_events| {
+                    let mut existing_events = existing_events.unwrap_or(Vec::new());
+                    self.push_events(&mut log, entry.source_id, &mut existing_events, entry.events);
+                    Some(existing_events)
+                });
+            }
+        }
+
+        for source_id in source_ids {
+            self.notify_subscribers(source_id);
+        }
+
+        Ok(())
+    }
+
+    /// Assign offsets to the events and push them onto the events for the
+    /// source, recording them in the global log and the outbox
+    fn push_events(&self,
+                   log: &mut Vec<(Uuid, usize)>,
+                   source_id: Uuid,
+                   existing_events: &mut SourceEvents<Event>,
+                   events: Vec<NewEvent<Event>>) {
+        let mut outbox = self.outbox.as_ref().map(|outbox| outbox.lock().unwrap());
+
+        for event in events {
+            // Keep the global offset up to date as we iterate. Opting
+            // for the strongest, sequentially consistent memory ordering
+            // for now. We may be able to relax this though... ¯\_(ツ)_/¯
+            let offset = self.offset.fetch_add(1, Ordering::SeqCst);
+            debug_assert_eq!(offset, log.len());
+
+            log.push((source_id, existing_events.len()));
+            existing_events.push((offset, event));
+
+            if let Some(ref mut outbox) = outbox {
+                outbox.insert(offset);
+            }
+        }
+    }
+
+    /// Wake up any tasks that are waiting on events for the source id
+    fn notify_subscribers(&self, source_id: Uuid) {
+        let source_subscribers = self.source_subscribers.remove(&source_id).unwrap_or_default();
+        let all_subscribers = mem::take(&mut *self.all_subscribers.lock().unwrap());
+
+        for subscriber in source_subscribers.into_iter().chain(all_subscribers) {
+            subscriber.notify();
         }
     }
 }
 
 
+impl<Event> Default for MemoryEventStore<Event> {
+    fn default() -> MemoryEventStore<Event> {
+        MemoryEventStore::new()
+    }
+}
+
 
 impl<Event> EventStore for MemoryEventStore<Event>
     where Event: Clone
 {
     type Offset = usize;
     type Event = Event;
+    type AppendError = AppendError;
+    type AppendFuture = FutureResult<(), AppendError>;
     type EventsStream = EventsStream<Event>;
+    type AllEventsStream = AllEventsStream<Event>;
 
-    fn append_events(&self, source_id: Uuid, events: Vec<Event>) {
-        if events.is_empty() {
-            return;
-        }
-
-        self.events.alter(source_id, |existing_events| {
-            let mut existing_events = existing_events.unwrap_or(Vec::new());
-            let new_events = events.into_iter().map(|event| {
-                // Keep the global offset up to date as we iterate. Opting
-                // for the strongest, sequentially consistent memory ordering
-                // for now. We may be able to relax this though... ¯\_(ツ)_/¯
-                let offset = self.offset.fetch_add(1, Ordering::SeqCst);
-                (offset, event)
-            });
+    fn append_events(&self,
+                     source_id: Uuid,
+                     expected_version: ExpectedVersion,
+                     events: Vec<NewEvent<Event>>)
+                     -> FutureResult<(), AppendError> {
+        future::result(self.append(None, source_id, expected_version, events))
+    }
 
-            existing_events.extend(new_events);
+    fn append_events_idempotent(&self,
+                                idempotency_key: Uuid,
+                                source_id: Uuid,
+                                expected_version: ExpectedVersion,
+                                events: Vec<NewEvent<Event>>)
+                                -> FutureResult<(), AppendError> {
+        future::result(self.append(Some(idempotency_key), source_id, expected_version, events))
+    }
 
-            Some(existing_events)
-        });
+    fn append_transaction(&self, transaction: Transaction<Event>) -> FutureResult<(), AppendError> {
+        future::result(self.commit(transaction))
     }
 
     fn events(&self, source_id: Uuid, offset: Self::Offset) -> EventsStream<Event> {
@@ -144,194 +403,789 @@ impl<Event> EventStore for MemoryEventStore<Event>
             source_id: source_id,
             sequence_number: 0,
             offset: offset,
+            is_live: false,
+            event_store: self.clone(),
+        }
+    }
+
+    fn events_from(&self, source_id: Uuid, sequence_number: SequenceNumber) -> EventsStream<Event> {
+        EventsStream {
+            source_id: source_id,
+            sequence_number: sequence_number as usize,
+            offset: 0,
+            is_live: false,
+            event_store: self.clone(),
+        }
+    }
+
+    fn all_events(&self, offset: Self::Offset) -> AllEventsStream<Event> {
+        AllEventsStream {
+            offset: offset,
+            event_types: None,
+            is_live: false,
+            event_store: self.clone(),
+        }
+    }
+
+    fn all_events_of_types(&self,
+                           event_types: &[&str],
+                           offset: Self::Offset)
+                           -> AllEventsStream<Event> {
+        AllEventsStream {
+            offset: offset,
+            event_types: Some(event_types.iter().map(|ty| ty.to_string()).collect()),
+            is_live: false,
             event_store: self.clone(),
         }
     }
 }
 
 
+/// An error that may be returned when appending to the `MemoryEventStore`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AppendError {
+    /// The source was not at the expected version
+    WrongExpectedVersion(WrongExpectedVersion),
+}
+
+
+impl From<WrongExpectedVersion> for AppendError {
+    fn from(src: WrongExpectedVersion) -> AppendError {
+        AppendError::WrongExpectedVersion(src)
+    }
+}
+
+
+impl AsWrongExpectedVersion for AppendError {
+    fn as_wrong_expected_version(&self) -> Option<&WrongExpectedVersion> {
+        match *self {
+            AppendError::WrongExpectedVersion(ref error) => Some(error),
+        }
+    }
+}
+
+
+impl<Event> Outbox for MemoryEventStore<Event>
+    where Event: Clone
+{
+    type Offset = usize;
+    type Event = Event;
+    type Error = Never;
+    type PendingFuture = FutureResult<Vec<PersistedEvent<usize, Event>>, Never>;
+    type AcknowledgeFuture = FutureResult<(), Never>;
+
+    /// Note that this will always be empty unless the outbox was enabled
+    /// with `with_outbox`.
+    fn pending(&self,
+               limit: usize)
+               -> FutureResult<Vec<PersistedEvent<usize, Event>>, Never> {
+        let offsets = match self.outbox {
+            Some(ref outbox) => outbox.lock().unwrap().iter().take(limit).cloned().collect(),
+            None => Vec::new(),
+        };
+
+        // Look up the events after releasing the lock on the outbox, which is
+        // acquired after the other locks in `append_events`
+        let events = offsets.into_iter()
+            .map(|offset| {
+                let (source_id, index) = self.log.read().unwrap()[offset];
+                let source_events = self.events.get(&source_id).unwrap();
+                let (offset, ref event) = source_events[index];
+
+                PersistedEvent {
+                    source_id: source_id,
+                    offset: offset,
+                    sequence_number: index as SequenceNumber,
+                    event_type: event.event_type.clone(),
+                    event_version: event.event_version,
+                    metadata: event.metadata.clone(),
+                    payload: event.payload.clone(),
+                }
+            })
+            .collect();
+
+        future::ok(events)
+    }
+
+    fn acknowledge(&self, offset: usize) -> FutureResult<(), Never> {
+        if let Some(ref outbox) = self.outbox {
+            outbox.lock().unwrap().remove(&offset);
+        }
+
+        future::ok(())
+    }
+}
+
+
 /// A stream of events for a specified source id
 pub struct EventsStream<Event> {
     source_id: Uuid,
     sequence_number: usize,
     offset: usize,
+    /// Whether to wait for new events once we have caught up
+    is_live: bool,
     event_store: MemoryEventStore<Event>,
 }
 
 
-/// An error that may be returned when polling on the `EventsStream`
-///
-/// Note that this error has no variants, so can never happen. This will be
-/// replaced by `!` once `#![feature(never_type)]` has been stabilised.
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub enum EventsStreamError {}
-
-
-impl<Event> Stream for EventsStream<Event>
+impl<Event> EventsStream<Event>
     where Event: Clone
 {
-    type Item = PersistedEvent<usize, Event>;
-    type Error = EventsStreamError;
-
-    fn poll(&mut self) -> Poll<Option<Self::Item>, EventsStreamError> {
-        use chronicle::SequenceNumber;
-
+    fn next_event(&mut self) -> Option<PersistedEvent<usize, Event>> {
         if let Some(source_events) = self.event_store.events.get(&self.source_id) {
-            while let Some(&(offset, ref payload)) = source_events.get(self.sequence_number) {
+            while let Some(&(offset, ref event)) = source_events.get(self.sequence_number) {
                 let sequence_number = self.sequence_number;
                 self.sequence_number += 1;
 
                 if offset < self.offset {
                     continue;
                 } else {
-                    let persisted_event = PersistedEvent {
+                    return Some(PersistedEvent {
                         source_id: self.source_id,
                         offset: offset,
                         sequence_number: sequence_number as SequenceNumber,
-                        payload: payload.clone(),
-                    };
-
-                    return Ok(Async::Ready(Some(persisted_event)));
+                        event_type: event.event_type.clone(),
+                        event_version: event.event_version,
+                        metadata: event.metadata.clone(),
+                        payload: event.payload.clone(),
+                    });
                 }
             }
         }
 
-        Ok(Async::Ready(None))
+        None
     }
 }
 
 
-#[cfg(test)]
-mod tests {
-    use chronicle::{EventStore, PersistedEvent};
-    use futures::Future;
-    use uuid::Uuid;
+/// An error that may be returned when polling on the `EventsStream`
+pub type EventsStreamError = Never;
 
-    use super::*;
 
+impl<Event> Stream for EventsStream<Event>
+    where Event: Clone
+{
+    type Item = PersistedEvent<usize, Event>;
+    type Error = EventsStreamError;
 
-    #[test]
-    fn append_events_if_none_exist_for_the_source_id() {
-        let event_store = MemoryEventStore::new();
-        let source_id_1 = Uuid::new_v4();
+    fn poll(&mut self) -> Poll<Option<Self::Item>, EventsStreamError> {
+        if let Some(event) = self.next_event() {
+            return Ok(Async::Ready(Some(event)));
+        } else if !self.is_live {
+            return Ok(Async::Ready(None));
+        }
 
-        event_store.append_events(source_id_1, vec!["A", "B", "C"]);
+        // Register our interest in the source before checking again, to
+        // ensure we don't miss any events that were appended in the meantime
+        self.event_store.source_subscribers.upsert(self.source_id,
+                                                   || vec![task::current()],
+                                                   |tasks| tasks.push(task::current()));
 
-        assert_eq!(event_store.events.get(&source_id_1).map(|es| es.clone()),
-                   Some(vec![(0, "A"), (1, "B"), (2, "C")]));
+        match self.next_event() {
+            Some(event) => Ok(Async::Ready(Some(event))),
+            None => Ok(Async::NotReady),
+        }
     }
+}
 
 
-    #[test]
-    fn append_events_for_an_existing_source_id() {
-        let event_store = MemoryEventStore::new();
-        let source_id_1 = Uuid::new_v4();
+/// A stream of all the events in the store, ordered by global offset
+pub struct AllEventsStream<Event> {
+    offset: usize,
+    /// The event types to restrict the stream to, if any
+    event_types: Option<Vec<String>>,
+    /// Whether to wait for new events once we have caught up
+    is_live: bool,
+    event_store: MemoryEventStore<Event>,
+}
 
-        event_store.append_events(source_id_1, vec!["A", "B", "C"]);
-        event_store.append_events(source_id_1, vec![]);
-        event_store.append_events(source_id_1, vec!["D", "E"]);
 
-        assert_eq!(event_store.events.get(&source_id_1).map(|es| es.clone()),
-                   Some(vec![(0, "A"), (1, "B"), (2, "C"), (3, "D"), (4, "E")]));
+impl<Event> AllEventsStream<Event>
+    where Event: Clone
+{
+    fn next_event(&mut self) -> Option<PersistedEvent<usize, Event>> {
+        loop {
+            // Make sure we release the lock on the log before looking up the
+            // event - `append_events` acquires these locks in the opposite order!
+            let (source_id, index) = *self.event_store.log.read().unwrap().get(self.offset)?;
+            let source_events = self.event_store.events.get(&source_id)?;
+            let (offset, ref event) = source_events[index];
+            self.offset += 1;
+
+            if let Some(ref event_types) = self.event_types {
+                if !event_types.contains(&event.event_type) {
+                    continue;
+                }
+            }
+
+            return Some(PersistedEvent {
+                source_id: source_id,
+                offset: offset,
+                sequence_number: index as SequenceNumber,
+                event_type: event.event_type.clone(),
+                event_version: event.event_version,
+                metadata: event.metadata.clone(),
+                payload: event.payload.clone(),
+            });
+        }
     }
+}
 
 
-    #[test]
-    fn append_events_maintianing_the_offsets() {
-        let event_store = MemoryEventStore::new();
-        let source_id_1 = Uuid::new_v4();
-        let source_id_2 = Uuid::new_v4();
+impl<Event> Stream for AllEventsStream<Event>
+    where Event: Clone
+{
+    type Item = PersistedEvent<usize, Event>;
+    type Error = EventsStreamError;
 
-        event_store.append_events(source_id_1, vec!["A", "B", "C"]);
-        event_store.append_events(source_id_2, vec!["a", "b"]);
-        event_store.append_events(source_id_1, vec!["D", "E"]);
-        event_store.append_events(source_id_2, vec!["c", "d"]);
+    fn poll(&mut self) -> Poll<Option<Self::Item>, EventsStreamError> {
+        if let Some(event) = self.next_event() {
+            return Ok(Async::Ready(Some(event)));
+        } else if !self.is_live {
+            return Ok(Async::Ready(None));
+        }
 
-        assert_eq!(event_store.events.get(&source_id_1).map(|es| es.clone()),
-                   Some(vec![(0, "A"), (1, "B"), (2, "C"), (5, "D"), (6, "E")]));
-        assert_eq!(event_store.events.get(&source_id_2).map(|es| es.clone()),
-                   Some(vec![(3, "a"), (4, "b"), (7, "c"), (8, "d")]));
+        // Register our interest in the log before checking again, to ensure
+        // we don't miss any events that were appended in the meantime
+        self.event_store.all_subscribers.lock().unwrap().push(task::current());
+
+        match self.next_event() {
+            Some(event) => Ok(Async::Ready(Some(event))),
+            None => Ok(Async::NotReady),
+        }
     }
+}
 
-    #[test]
-    fn events_on_empty_store() {
-        let event_store = MemoryEventStore::<()>::new();
-        let source_id_1 = Uuid::new_v4();
 
-        let events = event_store.events(source_id_1, 0).collect().wait();
+/// A publisher that collects the events that it is given, for testing relays
+/// without an external message broker. Clones of the publisher share the same
+/// published events.
+#[derive(Debug, Clone)]
+pub struct MemoryPublisher<Event> {
+    published: Arc<Mutex<Vec<PersistedEvent<usize, Event>>>>,
+}
 
-        assert_eq!(events, Ok(Vec::new()));
-    }
 
-    #[test]
-    fn events_on_non_empty_store() {
-        let event_store = MemoryEventStore::new();
-        let source_id_1 = Uuid::new_v4();
+impl<Event> MemoryPublisher<Event> {
+    /// Create a publisher that has not published any events yet
+    pub fn new() -> MemoryPublisher<Event> {
+        MemoryPublisher { published: Arc::new(Mutex::new(Vec::new())) }
+    }
 
-        event_store.append_events(source_id_1, vec!["A", "B", "C"]);
+    /// The events that have been published so far, in the order that they
+    /// were published
+    pub fn published(&self) -> Vec<PersistedEvent<usize, Event>>
+        where Event: Clone
+    {
+        self.published.lock().unwrap().clone()
+    }
+}
 
-        let events = event_store.events(source_id_1, 0).collect().wait();
 
-        assert_eq!(events,
-                   Ok(vec![PersistedEvent {
-                               offset: 0,
-                               source_id: source_id_1,
-                               sequence_number: 0,
-                               payload: "A",
-                           },
-                           PersistedEvent {
-                               offset: 1,
-                               source_id: source_id_1,
-                               sequence_number: 1,
-                               payload: "B",
-                           },
-                           PersistedEvent {
-                               offset: 2,
-                               source_id: source_id_1,
-                               sequence_number: 2,
-                               payload: "C",
-                           }]));
+impl<Event> Default for MemoryPublisher<Event> {
+    fn default() -> MemoryPublisher<Event> {
+        MemoryPublisher::new()
     }
+}
 
-    #[test]
-    fn events_with_out_of_range_offset() {
-        let event_store = MemoryEventStore::new();
-        let source_id_1 = Uuid::new_v4();
 
-        event_store.append_events(source_id_1, vec!["A", "B", "C"]);
+impl<Event> Publisher for MemoryPublisher<Event> {
+    type Offset = usize;
+    type Event = Event;
+    type Error = Never;
+    type PublishFuture = FutureResult<(), Never>;
 
-        let events = event_store.events(source_id_1, 100).collect().wait();
+    fn publish(&self, event: PersistedEvent<usize, Event>) -> FutureResult<(), Never> {
+        self.published.lock().unwrap().push(event);
 
-        assert_eq!(events, Ok(Vec::new()));
+        future::ok(())
     }
+}
 
-    #[test]
-    fn events_with_non_contiguous_global_id_sequence() {
+
+/// An in-memory snapshot store implementation that can be concurrently accessed
+#[derive(Debug, Clone)]
+pub struct MemorySnapshotStore<State> {
+    /// The most recent snapshot for each source id
+    snapshots: Arc<CHashMap<Uuid, (SequenceNumber, State)>>,
+}
+
+
+impl<State> MemorySnapshotStore<State> {
+    /// Create an empty snapshot store
+    pub fn new() -> MemorySnapshotStore<State> {
+        MemorySnapshotStore { snapshots: Arc::new(CHashMap::new()) }
+    }
+}
+
+
+impl<State> Default for MemorySnapshotStore<State> {
+    fn default() -> MemorySnapshotStore<State> {
+        MemorySnapshotStore::new()
+    }
+}
+
+
+impl<State> SnapshotStore for MemorySnapshotStore<State>
+    where State: Clone
+{
+    type State = State;
+    type Error = Never;
+    type SaveFuture = FutureResult<(), Never>;
+    type LoadFuture = FutureResult<Option<(SequenceNumber, State)>, Never>;
+
+    fn save_snapshot(&self,
+                     source_id: Uuid,
+                     sequence_number: SequenceNumber,
+                     state: State)
+                     -> FutureResult<(), Never> {
+        // Don't clobber a snapshot that was taken further along in the history
+        self.snapshots.alter(source_id, |existing| match existing {
+            Some(existing) if existing.0 > sequence_number => Some(existing),
+            _ => Some((sequence_number, state)),
+        });
+
+        future::ok(())
+    }
+
+    fn load_snapshot(&self,
+                     source_id: Uuid)
+                     -> FutureResult<Option<(SequenceNumber, State)>, Never> {
+        future::ok(self.snapshots.get(&source_id).map(|snapshot| snapshot.clone()))
+    }
+}
+
+
+/// An in-memory checkpoint store implementation that can be concurrently
+/// accessed
+#[derive(Debug, Clone)]
+pub struct MemoryCheckpointStore<Offset> {
+    /// The offset of the last event processed by each projection
+    checkpoints: Arc<CHashMap<String, Offset>>,
+}
+
+
+impl<Offset> MemoryCheckpointStore<Offset> {
+    /// Create an empty checkpoint store
+    pub fn new() -> MemoryCheckpointStore<Offset> {
+        MemoryCheckpointStore { checkpoints: Arc::new(CHashMap::new()) }
+    }
+}
+
+
+impl<Offset> Default for MemoryCheckpointStore<Offset> {
+    fn default() -> MemoryCheckpointStore<Offset> {
+        MemoryCheckpointStore::new()
+    }
+}
+
+
+impl<Offset> CheckpointStore for MemoryCheckpointStore<Offset>
+    where Offset: Clone
+{
+    type Offset = Offset;
+    type Error = Never;
+    type SaveFuture = FutureResult<(), Never>;
+    type LoadFuture = FutureResult<Option<Offset>, Never>;
+
+    fn save_checkpoint(&self,
+                       projection_id: &str,
+                       offset: Offset)
+                       -> FutureResult<(), Never> {
+        self.checkpoints.insert(projection_id.to_string(), offset);
+
+        future::ok(())
+    }
+
+    fn load_checkpoint(&self,
+                       projection_id: &str)
+                       -> FutureResult<Option<Offset>, Never> {
+        future::ok(self.checkpoints.get(projection_id).map(|offset| offset.clone()))
+    }
+}
+
+
+/// An in-memory deadline store implementation that can be concurrently
+/// accessed
+#[derive(Debug, Clone)]
+pub struct MemoryDeadlineStore<Command> {
+    /// The pending deadlines, indexed by their ids
+    deadlines: Arc<Mutex<HashMap<Uuid, Deadline<Command>>>>,
+}
+
+
+impl<Command> MemoryDeadlineStore<Command> {
+    /// Create an empty deadline store
+    pub fn new() -> MemoryDeadlineStore<Command> {
+        MemoryDeadlineStore { deadlines: Arc::new(Mutex::new(HashMap::new())) }
+    }
+}
+
+
+impl<Command> Default for MemoryDeadlineStore<Command> {
+    fn default() -> MemoryDeadlineStore<Command> {
+        MemoryDeadlineStore::new()
+    }
+}
+
+
+impl<Command> DeadlineStore for MemoryDeadlineStore<Command>
+    where Command: Clone
+{
+    type Command = Command;
+    type Error = Never;
+    type ScheduleFuture = FutureResult<(), Never>;
+    type CancelFuture = FutureResult<bool, Never>;
+    type AcknowledgeFuture = FutureResult<bool, Never>;
+    type DueFuture = FutureResult<Vec<Deadline<Command>>, Never>;
+
+    fn schedule(&self, deadline: Deadline<Command>) -> FutureResult<(), Never> {
+        self.deadlines.lock().unwrap().insert(deadline.deadline_id, deadline);
+
+        future::ok(())
+    }
+
+    fn cancel(&self, deadline_id: Uuid) -> FutureResult<bool, Never> {
+        future::ok(self.deadlines.lock().unwrap().remove(&deadline_id).is_some())
+    }
+
+    fn acknowledge(&self,
+                   deadline_id: Uuid,
+                   due_at: SystemTime)
+                   -> FutureResult<bool, Never> {
+        let mut deadlines = self.deadlines.lock().unwrap();
+
+        let is_due_at = deadlines.get(&deadline_id).map(|deadline| deadline.due_at) == Some(due_at);
+        if is_due_at {
+            deadlines.remove(&deadline_id);
+        }
+
+        future::ok(is_due_at)
+    }
+
+    fn due(&self, now: SystemTime) -> FutureResult<Vec<Deadline<Command>>, Never> {
+        let mut due = self.deadlines
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|deadline| deadline.due_at <= now)
+            .cloned()
+            .collect::<Vec<_>>();
+
+        due.sort_by_key(|deadline| deadline.due_at);
+
+        future::ok(due)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use chronicle::{CheckpointStore, EventStore, PersistedEvent, Projection, SnapshotStore};
+    use chronicle::codec::{BinaryCodec, CodecEventStore, CodecStoreError, JsonCodec};
+    use chronicle::deadline::CodecDeadlineStore;
+    use chronicle::outbox::{self, RelayError};
+    use chronicle::upcast::{UpcastEventStore, UpcastStreamError, Upcasters};
+    use chronicle::{Metadata, NewEvent, WrongExpectedVersion};
+    use chronicle::ExpectedVersion::*;
+    use chronicle::projection;
+    use futures::Future;
+    use std::collections::BTreeMap;
+    use std::time::{Duration, UNIX_EPOCH};
+    use uuid::Uuid;
+
+    use super::*;
+
+
+    /// Some fixed metadata, to make comparisons easier
+    fn metadata() -> Metadata {
+        Metadata {
+            event_id: Uuid::nil(),
+            recorded_at: UNIX_EPOCH,
+            correlation_id: None,
+            causation_id: None,
+            headers: BTreeMap::new(),
+        }
+    }
+
+
+    fn new_events<Event: Clone>(payloads: &[Event]) -> Vec<NewEvent<Event>> {
+        payloads.iter()
+            .map(|payload| NewEvent::with_type("test", 0, metadata(), payload.clone()))
+            .collect()
+    }
+
+
+    /// The global offsets and payloads that are stored for the source id
+    fn stored_events<Event: Clone>(event_store: &MemoryEventStore<Event>,
+                                   source_id: Uuid)
+                                   -> Option<Vec<(usize, Event)>> {
+        event_store.events.get(&source_id).map(|events| {
+            events.iter().map(|&(offset, ref event)| (offset, event.payload.clone())).collect()
+        })
+    }
+
+
+    #[test]
+    fn append_events_if_none_exist_for_the_source_id() {
+        let event_store = MemoryEventStore::new();
+        let source_id_1 = Uuid::new_v4();
+
+        event_store.append_events(source_id_1, Any, new_events(&["A", "B", "C"])).wait().unwrap();
+
+        assert_eq!(stored_events(&event_store, source_id_1),
+                   Some(vec![(0, "A"), (1, "B"), (2, "C")]));
+    }
+
+
+    #[test]
+    fn append_events_for_an_existing_source_id() {
+        let event_store = MemoryEventStore::new();
+        let source_id_1 = Uuid::new_v4();
+
+        event_store.append_events(source_id_1, Any, new_events(&["A", "B", "C"])).wait().unwrap();
+        event_store.append_events(source_id_1, Any, new_events(&[])).wait().unwrap();
+        event_store.append_events(source_id_1, Any, new_events(&["D", "E"])).wait().unwrap();
+
+        assert_eq!(stored_events(&event_store, source_id_1),
+                   Some(vec![(0, "A"), (1, "B"), (2, "C"), (3, "D"), (4, "E")]));
+    }
+
+
+    #[test]
+    fn append_events_maintianing_the_offsets() {
         let event_store = MemoryEventStore::new();
         let source_id_1 = Uuid::new_v4();
         let source_id_2 = Uuid::new_v4();
 
-        event_store.append_events(source_id_1, vec!["A"]);
-        event_store.append_events(source_id_2, vec!["1", "2"]);
-        event_store.append_events(source_id_1, vec!["B", "C"]);
+        event_store.append_events(source_id_1, Any, new_events(&["A", "B", "C"])).wait().unwrap();
+        event_store.append_events(source_id_2, Any, new_events(&["a", "b"])).wait().unwrap();
+        event_store.append_events(source_id_1, Any, new_events(&["D", "E"])).wait().unwrap();
+        event_store.append_events(source_id_2, Any, new_events(&["c", "d"])).wait().unwrap();
+
+        assert_eq!(stored_events(&event_store, source_id_1),
+                   Some(vec![(0, "A"), (1, "B"), (2, "C"), (5, "D"), (6, "E")]));
+        assert_eq!(stored_events(&event_store, source_id_2),
+                   Some(vec![(3, "a"), (4, "b"), (7, "c"), (8, "d")]));
+    }
+
+
+    #[test]
+    fn append_events_with_expected_versions() {
+        let event_store = MemoryEventStore::new();
+        let source_id_1 = Uuid::new_v4();
+
+        event_store.append_events(source_id_1, NoStream, new_events(&["A", "B"])).wait().unwrap();
+        event_store.append_events(source_id_1, Exact(1), new_events(&["C"])).wait().unwrap();
+        event_store.append_events(source_id_1, Exact(2), new_events(&[])).wait().unwrap();
+
+        assert_eq!(stored_events(&event_store, source_id_1),
+                   Some(vec![(0, "A"), (1, "B"), (2, "C")]));
+    }
+
+
+    #[test]
+    fn append_events_with_wrong_expected_version() {
+        let event_store = MemoryEventStore::new();
+        let source_id_1 = Uuid::new_v4();
+        let source_id_2 = Uuid::new_v4();
+
+        event_store.append_events(source_id_1, Any, new_events(&["A", "B"])).wait().unwrap();
+
+        assert_eq!(event_store.append_events(source_id_1, NoStream, new_events(&["C"])).wait(),
+                   Err(AppendError::WrongExpectedVersion(WrongExpectedVersion {
+                       source_id: source_id_1,
+                       expected: NoStream,
+                       current: Some(1),
+                   })));
+        assert_eq!(event_store.append_events(source_id_1, Exact(0), new_events(&["C"])).wait(),
+                   Err(AppendError::WrongExpectedVersion(WrongExpectedVersion {
+                       source_id: source_id_1,
+                       expected: Exact(0),
+                       current: Some(1),
+                   })));
+        assert_eq!(event_store.append_events(source_id_2, Exact(0), new_events(&["a"])).wait(),
+                   Err(AppendError::WrongExpectedVersion(WrongExpectedVersion {
+                       source_id: source_id_2,
+                       expected: Exact(0),
+                       current: None,
+                   })));
+
+        assert_eq!(stored_events(&event_store, source_id_1),
+                   Some(vec![(0, "A"), (1, "B")]));
+        assert!(event_store.events.get(&source_id_2).is_none());
+    }
+
+
+    #[test]
+    fn append_events_concurrently_with_the_same_expected_version() {
+        use std::thread;
+
+        let event_store = Arc::new(MemoryEventStore::new());
+        let source_id_1 = Uuid::new_v4();
+
+        let handles = (0..8).map(|_| {
+            let event_store = event_store.clone();
+            thread::spawn(move || {
+                event_store.append_events(source_id_1, NoStream, new_events(&["A"])).wait()
+            })
+        });
+
+        let successes = handles.collect::<Vec<_>>()
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .filter(Result::is_ok)
+            .count();
+
+        assert_eq!(successes, 1);
+        assert_eq!(event_store.events.get(&source_id_1).map(|es| es.len()), Some(1));
+    }
+
+    #[test]
+    fn append_transaction_to_multiple_sources() {
+        let event_store = MemoryEventStore::new();
+        let source_id_1 = Uuid::new_v4();
+        let source_id_2 = Uuid::new_v4();
+
+        event_store.append_events(source_id_1, Any, new_events(&["A"])).wait().unwrap();
+
+        let transaction = Transaction::new()
+            .with_events(source_id_1, Exact(0), new_events(&["B", "C"]))
+            .with_events(source_id_2, NoStream, new_events(&["a"]));
+        event_store.append_transaction(transaction).wait().unwrap();
+
+        assert_eq!(stored_events(&event_store, source_id_1),
+                   Some(vec![(0, "A"), (1, "B"), (2, "C")]));
+        assert_eq!(stored_events(&event_store, source_id_2), Some(vec![(3, "a")]));
+
+        let transaction = Transaction::new()
+            .with_events(source_id_2, Exact(0), new_events(&["b"]))
+            .with_events(source_id_1, Exact(1), new_events(&["D"]));
+
+        assert_eq!(event_store.append_transaction(transaction).wait(),
+                   Err(AppendError::WrongExpectedVersion(WrongExpectedVersion {
+                       source_id: source_id_1,
+                       expected: Exact(1),
+                       current: Some(2),
+                   })));
+        assert_eq!(stored_events(&event_store, source_id_2), Some(vec![(3, "a")]));
+    }
+
+
+    #[test]
+    fn events_on_empty_store() {
+        let event_store = MemoryEventStore::<()>::new();
+        let source_id_1 = Uuid::new_v4();
+
+        let events = event_store.events(source_id_1, 0).collect().wait();
+
+        assert_eq!(events, Ok(Vec::new()));
+    }
+
+    #[test]
+    fn events_on_non_empty_store() {
+        let event_store = MemoryEventStore::new();
+        let source_id_1 = Uuid::new_v4();
+
+        event_store.append_events(source_id_1, Any, new_events(&["A", "B", "C"])).wait().unwrap();
+
+        let events = event_store.events(source_id_1, 0).collect().wait();
+
+        assert_eq!(events,
+                   Ok(vec![PersistedEvent {
+                               offset: 0,
+                               source_id: source_id_1,
+                               sequence_number: 0,
+                               event_type: "test".to_string(),
+                               event_version: 0,
+                               metadata: metadata(),
+                               payload: "A",
+                           },
+                           PersistedEvent {
+                               offset: 1,
+                               source_id: source_id_1,
+                               sequence_number: 1,
+                               event_type: "test".to_string(),
+                               event_version: 0,
+                               metadata: metadata(),
+                               payload: "B",
+                           },
+                           PersistedEvent {
+                               offset: 2,
+                               source_id: source_id_1,
+                               sequence_number: 2,
+                               event_type: "test".to_string(),
+                               event_version: 0,
+                               metadata: metadata(),
+                               payload: "C",
+                           }]));
+    }
+
+    #[test]
+    fn events_with_metadata() {
+        let event_store = MemoryEventStore::new();
+        let source_id_1 = Uuid::new_v4();
+        let metadata_a = Metadata::new().with_correlation_id(Uuid::new_v4());
+        let metadata_b = Metadata::new().with_causation_id(metadata_a.event_id);
+
+        let new_events = vec![NewEvent::with_type("test", 0, metadata_a.clone(), "A"),
+                              NewEvent::with_type("test", 0, metadata_b.clone(), "B")];
+        event_store.append_events(source_id_1, Any, new_events).wait().unwrap();
+
+        let events = event_store.events(source_id_1, 0).collect().wait().unwrap();
+
+        assert_eq!(events.into_iter().map(|e| e.metadata).collect::<Vec<_>>(),
+                   vec![metadata_a, metadata_b]);
+    }
+
+    #[test]
+    fn events_with_out_of_range_offset() {
+        let event_store = MemoryEventStore::new();
+        let source_id_1 = Uuid::new_v4();
+
+        event_store.append_events(source_id_1, Any, new_events(&["A", "B", "C"])).wait().unwrap();
+
+        let events = event_store.events(source_id_1, 100).collect().wait();
+
+        assert_eq!(events, Ok(Vec::new()));
+    }
+
+    #[test]
+    fn events_with_non_contiguous_global_id_sequence() {
+        let event_store = MemoryEventStore::new();
+        let source_id_1 = Uuid::new_v4();
+        let source_id_2 = Uuid::new_v4();
+
+        event_store.append_events(source_id_1, Any, new_events(&["A"])).wait().unwrap();
+        event_store.append_events(source_id_2, Any, new_events(&["1", "2"])).wait().unwrap();
+        event_store.append_events(source_id_1, Any, new_events(&["B", "C"])).wait().unwrap();
 
         assert_eq!(event_store.events(source_id_1, 0).collect().wait(),
                    Ok(vec![PersistedEvent {
                                offset: 0,
                                source_id: source_id_1,
                                sequence_number: 0,
+                               event_type: "test".to_string(),
+                               event_version: 0,
+                               metadata: metadata(),
                                payload: "A",
                            },
                            PersistedEvent {
                                offset: 3,
                                source_id: source_id_1,
                                sequence_number: 1,
+                               event_type: "test".to_string(),
+                               event_version: 0,
+                               metadata: metadata(),
                                payload: "B",
                            },
                            PersistedEvent {
                                offset: 4,
                                source_id: source_id_1,
                                sequence_number: 2,
+                               event_type: "test".to_string(),
+                               event_version: 0,
+                               metadata: metadata(),
                                payload: "C",
                            }]));
 
@@ -340,12 +1194,18 @@ mod tests {
                                offset: 1,
                                source_id: source_id_2,
                                sequence_number: 0,
+                               event_type: "test".to_string(),
+                               event_version: 0,
+                               metadata: metadata(),
                                payload: "1",
                            },
                            PersistedEvent {
                                offset: 2,
                                source_id: source_id_2,
                                sequence_number: 1,
+                               event_type: "test".to_string(),
+                               event_version: 0,
+                               metadata: metadata(),
                                payload: "2",
                            }]));
     }
@@ -356,27 +1216,36 @@ mod tests {
         let source_id_1 = Uuid::new_v4();
         let source_id_2 = Uuid::new_v4();
 
-        event_store.append_events(source_id_1, vec!["A", "B"]);
-        event_store.append_events(source_id_2, vec!["1", "2", "3"]);
-        event_store.append_events(source_id_1, vec!["C", "D"]);
+        event_store.append_events(source_id_1, Any, new_events(&["A", "B"])).wait().unwrap();
+        event_store.append_events(source_id_2, Any, new_events(&["1", "2", "3"])).wait().unwrap();
+        event_store.append_events(source_id_1, Any, new_events(&["C", "D"])).wait().unwrap();
 
         assert_eq!(event_store.events(source_id_1, 1).collect().wait(),
                    Ok(vec![PersistedEvent {
                                offset: 1,
                                source_id: source_id_1,
                                sequence_number: 1,
+                               event_type: "test".to_string(),
+                               event_version: 0,
+                               metadata: metadata(),
                                payload: "B",
                            },
                            PersistedEvent {
                                offset: 5,
                                source_id: source_id_1,
                                sequence_number: 2,
+                               event_type: "test".to_string(),
+                               event_version: 0,
+                               metadata: metadata(),
                                payload: "C",
                            },
                            PersistedEvent {
                                offset: 6,
                                source_id: source_id_1,
                                sequence_number: 3,
+                               event_type: "test".to_string(),
+                               event_version: 0,
+                               metadata: metadata(),
                                payload: "D",
                            }]));
 
@@ -385,12 +1254,18 @@ mod tests {
                                offset: 5,
                                source_id: source_id_1,
                                sequence_number: 2,
+                               event_type: "test".to_string(),
+                               event_version: 0,
+                               metadata: metadata(),
                                payload: "C",
                            },
                            PersistedEvent {
                                offset: 6,
                                source_id: source_id_1,
                                sequence_number: 3,
+                               event_type: "test".to_string(),
+                               event_version: 0,
+                               metadata: metadata(),
                                payload: "D",
                            }]));
 
@@ -399,12 +1274,18 @@ mod tests {
                                offset: 5,
                                source_id: source_id_1,
                                sequence_number: 2,
+                               event_type: "test".to_string(),
+                               event_version: 0,
+                               metadata: metadata(),
                                payload: "C",
                            },
                            PersistedEvent {
                                offset: 6,
                                source_id: source_id_1,
                                sequence_number: 3,
+                               event_type: "test".to_string(),
+                               event_version: 0,
+                               metadata: metadata(),
                                payload: "D",
                            }]));
 
@@ -413,7 +1294,635 @@ mod tests {
                                offset: 6,
                                source_id: source_id_1,
                                sequence_number: 3,
+                               event_type: "test".to_string(),
+                               event_version: 0,
+                               metadata: metadata(),
                                payload: "D",
                            }]));
     }
+
+
+    #[test]
+    fn all_events_on_empty_store() {
+        let event_store = MemoryEventStore::<()>::new();
+
+        assert_eq!(event_store.all_events(0).collect().wait(), Ok(Vec::new()));
+    }
+
+
+    #[test]
+    fn all_events_in_offset_order() {
+        let event_store = MemoryEventStore::new();
+        let source_id_1 = Uuid::new_v4();
+        let source_id_2 = Uuid::new_v4();
+
+        event_store.append_events(source_id_1, Any, new_events(&["A"])).wait().unwrap();
+        event_store.append_events(source_id_2, Any, new_events(&["1", "2"])).wait().unwrap();
+        event_store.append_events(source_id_1, Any, new_events(&["B"])).wait().unwrap();
+
+        assert_eq!(event_store.all_events(0).collect().wait(),
+                   Ok(vec![PersistedEvent {
+                               offset: 0,
+                               source_id: source_id_1,
+                               sequence_number: 0,
+                               event_type: "test".to_string(),
+                               event_version: 0,
+                               metadata: metadata(),
+                               payload: "A",
+                           },
+                           PersistedEvent {
+                               offset: 1,
+                               source_id: source_id_2,
+                               sequence_number: 0,
+                               event_type: "test".to_string(),
+                               event_version: 0,
+                               metadata: metadata(),
+                               payload: "1",
+                           },
+                           PersistedEvent {
+                               offset: 2,
+                               source_id: source_id_2,
+                               sequence_number: 1,
+                               event_type: "test".to_string(),
+                               event_version: 0,
+                               metadata: metadata(),
+                               payload: "2",
+                           },
+                           PersistedEvent {
+                               offset: 3,
+                               source_id: source_id_1,
+                               sequence_number: 1,
+                               event_type: "test".to_string(),
+                               event_version: 0,
+                               metadata: metadata(),
+                               payload: "B",
+                           }]));
+    }
+
+
+    #[test]
+    fn all_events_after_offset() {
+        let event_store = MemoryEventStore::new();
+        let source_id_1 = Uuid::new_v4();
+        let source_id_2 = Uuid::new_v4();
+
+        event_store.append_events(source_id_1, Any, new_events(&["A", "B"])).wait().unwrap();
+        event_store.append_events(source_id_2, Any, new_events(&["1"])).wait().unwrap();
+
+        let payloads = |offset| {
+            event_store.all_events(offset).map(|e| e.payload).collect().wait()
+        };
+
+        assert_eq!(payloads(1), Ok(vec!["B", "1"]));
+        assert_eq!(payloads(2), Ok(vec!["1"]));
+        assert_eq!(payloads(3), Ok(vec![]));
+    }
+
+
+    #[test]
+    fn all_events_of_types() {
+        let event_store = MemoryEventStore::new();
+        let source_id_1 = Uuid::new_v4();
+        let source_id_2 = Uuid::new_v4();
+        let new_event = |event_type, payload| {
+            NewEvent::with_type(event_type, 1, metadata(), payload)
+        };
+
+        let new_events_1 = vec![new_event("created", "A"), new_event("completed", "B")];
+        let new_events_2 = vec![new_event("created", "1")];
+        event_store.append_events(source_id_1, Any, new_events_1).wait().unwrap();
+        event_store.append_events(source_id_2, Any, new_events_2).wait().unwrap();
+
+        let events = |event_types: &[&str], offset| {
+            event_store.all_events_of_types(event_types, offset)
+                .map(|e| (e.event_type, e.event_version, e.payload))
+                .collect()
+                .wait()
+        };
+
+        assert_eq!(events(&["created"], 0),
+                   Ok(vec![("created".to_string(), 1, "A"), ("created".to_string(), 1, "1")]));
+        assert_eq!(events(&["created"], 1), Ok(vec![("created".to_string(), 1, "1")]));
+        assert_eq!(events(&["completed", "created"], 0).map(|events| events.len()), Ok(3));
+        assert_eq!(events(&["archived"], 0), Ok(vec![]));
+    }
+
+
+    #[test]
+    fn all_events_with_concurrent_appends() {
+        use std::thread;
+
+        let event_store = MemoryEventStore::new();
+
+        let handles = (0..8).map(|_| {
+            let event_store = event_store.clone();
+            thread::spawn(move || {
+                event_store.append_events(Uuid::new_v4(), Any, new_events(&[1, 2, 3])).wait()
+            })
+        });
+
+        for handle in handles.collect::<Vec<_>>() {
+            handle.join().unwrap().unwrap();
+        }
+
+        let events = event_store.all_events(0).collect().wait().unwrap();
+
+        assert_eq!(events.iter().map(|e| e.offset).collect::<Vec<_>>(),
+                   (0..24).collect::<Vec<_>>());
+        for event in events {
+            assert_eq!(event.payload, event.sequence_number + 1);
+        }
+    }
+
+
+    #[test]
+    fn subscribe_waits_for_new_events() {
+        use std::thread;
+
+        let event_store = MemoryEventStore::new();
+        let source_id_1 = Uuid::new_v4();
+        let source_id_2 = Uuid::new_v4();
+
+        event_store.append_events(source_id_1, Any, new_events(&["A"])).wait().unwrap();
+
+        let subscription = event_store.subscribe(source_id_1, 0);
+        let handle = thread::spawn(move || {
+            subscription.map(|e| e.payload).take(3).collect().wait()
+        });
+
+        event_store.append_events(source_id_2, Any, new_events(&["1"])).wait().unwrap();
+        event_store.append_events(source_id_1, Any, new_events(&["B"])).wait().unwrap();
+        event_store.append_events(source_id_1, Any, new_events(&["C", "D"])).wait().unwrap();
+
+        assert_eq!(handle.join().unwrap(), Ok(vec!["A", "B", "C"]));
+    }
+
+
+    #[test]
+    fn subscribe_all_waits_for_new_events() {
+        use std::thread;
+
+        let event_store = MemoryEventStore::new();
+        let source_id_1 = Uuid::new_v4();
+        let source_id_2 = Uuid::new_v4();
+
+        event_store.append_events(source_id_1, Any, new_events(&["A"])).wait().unwrap();
+
+        let subscription = event_store.subscribe_all(0);
+        let handle = thread::spawn(move || {
+            subscription.map(|e| e.payload).take(4).collect().wait()
+        });
+
+        event_store.append_events(source_id_2, Any, new_events(&["1"])).wait().unwrap();
+        event_store.append_events(source_id_1, Any, new_events(&["B"])).wait().unwrap();
+        event_store.append_events(source_id_2, Any, new_events(&["2"])).wait().unwrap();
+
+        assert_eq!(handle.join().unwrap(), Ok(vec!["A", "1", "B", "2"]));
+    }
+
+
+    #[test]
+    fn load_snapshot_on_empty_store() {
+        let snapshot_store = MemorySnapshotStore::<()>::new();
+
+        assert_eq!(snapshot_store.load_snapshot(Uuid::new_v4()).wait(), Ok(None));
+    }
+
+
+    #[test]
+    fn save_and_load_snapshots() {
+        let snapshot_store = MemorySnapshotStore::new();
+        let source_id_1 = Uuid::new_v4();
+        let source_id_2 = Uuid::new_v4();
+
+        snapshot_store.save_snapshot(source_id_1, 3, "A").wait().unwrap();
+        snapshot_store.save_snapshot(source_id_2, 1, "1").wait().unwrap();
+        snapshot_store.save_snapshot(source_id_1, 5, "B").wait().unwrap();
+
+        assert_eq!(snapshot_store.load_snapshot(source_id_1).wait(), Ok(Some((5, "B"))));
+        assert_eq!(snapshot_store.load_snapshot(source_id_2).wait(), Ok(Some((1, "1"))));
+    }
+
+
+    #[test]
+    fn save_snapshot_ignores_older_snapshots() {
+        let snapshot_store = MemorySnapshotStore::new();
+        let source_id_1 = Uuid::new_v4();
+
+        snapshot_store.save_snapshot(source_id_1, 5, "B").wait().unwrap();
+        snapshot_store.save_snapshot(source_id_1, 3, "A").wait().unwrap();
+
+        assert_eq!(snapshot_store.load_snapshot(source_id_1).wait(), Ok(Some((5, "B"))));
+    }
+
+
+    #[test]
+    fn save_and_load_checkpoints() {
+        let checkpoint_store = MemoryCheckpointStore::new();
+
+        assert_eq!(checkpoint_store.load_checkpoint("counts").wait(), Ok(None));
+
+        checkpoint_store.save_checkpoint("counts", 3).wait().unwrap();
+        checkpoint_store.save_checkpoint("totals", 1).wait().unwrap();
+        checkpoint_store.save_checkpoint("counts", 5).wait().unwrap();
+
+        assert_eq!(checkpoint_store.load_checkpoint("counts").wait(), Ok(Some(5)));
+        assert_eq!(checkpoint_store.load_checkpoint("totals").wait(), Ok(Some(1)));
+    }
+
+
+    #[test]
+    fn schedule_and_cancel_deadlines() {
+        let deadline_store = MemoryDeadlineStore::new();
+        let (id_1, id_2, id_3) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+
+        deadline_store.schedule(Deadline::new(id_1, at(20), "A")).wait().unwrap();
+        deadline_store.schedule(Deadline::new(id_2, at(10), "B")).wait().unwrap();
+        deadline_store.schedule(Deadline::new(id_3, at(30), "C")).wait().unwrap();
+
+        assert_eq!(deadline_store.due(at(5)).wait(), Ok(vec![]));
+        assert_eq!(deadline_store.due(at(20)).wait(),
+                   Ok(vec![Deadline::new(id_2, at(10), "B"), Deadline::new(id_1, at(20), "A")]));
+
+        // Rescheduling replaces the existing deadline
+        deadline_store.schedule(Deadline::new(id_3, at(15), "D")).wait().unwrap();
+
+        assert_eq!(deadline_store.cancel(id_2).wait(), Ok(true));
+        assert_eq!(deadline_store.cancel(id_2).wait(), Ok(false));
+        assert_eq!(deadline_store.due(at(30)).wait(),
+                   Ok(vec![Deadline::new(id_3, at(15), "D"), Deadline::new(id_1, at(20), "A")]));
+    }
+
+
+    #[test]
+    fn acknowledge_deadlines() {
+        let deadline_store = MemoryDeadlineStore::new();
+        let (id_1, id_2) = (Uuid::new_v4(), Uuid::new_v4());
+        let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+
+        deadline_store.schedule(Deadline::new(id_1, at(10), "A")).wait().unwrap();
+        deadline_store.schedule(Deadline::new(id_2, at(10), "B")).wait().unwrap();
+
+        // The second deadline was rescheduled while its command was in flight
+        deadline_store.schedule(Deadline::new(id_2, at(20), "C")).wait().unwrap();
+
+        assert_eq!(deadline_store.acknowledge(id_1, at(10)).wait(), Ok(true));
+        assert_eq!(deadline_store.acknowledge(id_2, at(10)).wait(), Ok(false));
+        assert_eq!(deadline_store.due(at(20)).wait(), Ok(vec![Deadline::new(id_2, at(20), "C")]));
+    }
+
+
+    #[test]
+    fn codec_deadline_store_encodes_commands() {
+        let deadline_store = MemoryDeadlineStore::new();
+        let codec_store = CodecDeadlineStore::new(deadline_store.clone(), JsonCodec::new());
+        let id = Uuid::new_v4();
+
+        codec_store.schedule(Deadline::new(id, UNIX_EPOCH, json!({ "type": "archive" })))
+            .wait()
+            .unwrap();
+
+        assert_eq!(deadline_store.due(UNIX_EPOCH).wait(),
+                   Ok(vec![Deadline::new(id, UNIX_EPOCH, br#"{"type":"archive"}"#.to_vec())]));
+        assert_eq!(codec_store.due(UNIX_EPOCH).wait().unwrap(),
+                   vec![Deadline::new(id, UNIX_EPOCH, json!({ "type": "archive" }))]);
+    }
+
+
+    /// Collects the payloads of the events that it has seen
+    struct Payloads(Vec<&'static str>);
+
+    impl Projection for Payloads {
+        type Offset = usize;
+        type Event = &'static str;
+        type Error = ();
+        type HandleFuture = Result<(), ()>;
+
+        fn handle_event(&mut self, event: PersistedEvent<usize, &'static str>) -> Result<(), ()> {
+            self.0.push(event.payload);
+            Ok(())
+        }
+    }
+
+
+    #[test]
+    fn run_projection_saves_checkpoints() {
+        let event_store = MemoryEventStore::new();
+        let checkpoint_store = MemoryCheckpointStore::new();
+        let mut payloads = Payloads(vec![]);
+
+        event_store.append_events(Uuid::new_v4(), Any, new_events(&["A", "B"])).wait().unwrap();
+        event_store.append_events(Uuid::new_v4(), Any, new_events(&["1"])).wait().unwrap();
+
+        projection::run("payloads", &mut payloads, &event_store, &checkpoint_store)
+            .wait()
+            .unwrap();
+
+        assert_eq!(payloads.0, vec!["A", "B", "1"]);
+        assert_eq!(checkpoint_store.load_checkpoint("payloads").wait(), Ok(Some(2)));
+    }
+
+
+    #[test]
+    fn run_projection_resumes_from_checkpoint() {
+        let event_store = MemoryEventStore::new();
+        let checkpoint_store = MemoryCheckpointStore::new();
+
+        event_store.append_events(Uuid::new_v4(), Any, new_events(&["A", "B"])).wait().unwrap();
+
+        let mut payloads = Payloads(vec![]);
+        projection::run("payloads", &mut payloads, &event_store, &checkpoint_store)
+            .wait()
+            .unwrap();
+        assert_eq!(payloads.0, vec!["A", "B"]);
+
+        event_store.append_events(Uuid::new_v4(), Any, new_events(&["1", "2"])).wait().unwrap();
+
+        // Simulate a restart by starting again with a fresh read model
+        let mut payloads = Payloads(vec![]);
+        projection::run("payloads", &mut payloads, &event_store, &checkpoint_store)
+            .wait()
+            .unwrap();
+        assert_eq!(payloads.0, vec!["1", "2"]);
+        assert_eq!(checkpoint_store.load_checkpoint("payloads").wait(), Ok(Some(3)));
+    }
+
+
+    /// Fails to publish a specific payload, passing the others on to an
+    /// underlying publisher
+    struct FailingPublisher(&'static str, MemoryPublisher<&'static str>);
+
+    impl Publisher for FailingPublisher {
+        type Offset = usize;
+        type Event = &'static str;
+        type Error = &'static str;
+        type PublishFuture = Result<(), &'static str>;
+
+        fn publish(&self, event: PersistedEvent<usize, &'static str>) -> Result<(), &'static str> {
+            if event.payload == self.0 {
+                return Err(event.payload);
+            }
+
+            self.1.publish(event).wait().map_err(|err| match err {})
+        }
+    }
+
+
+    /// The offsets and payloads of the events that have been published
+    fn published(publisher: &MemoryPublisher<&'static str>) -> Vec<(usize, &'static str)> {
+        publisher.published().into_iter().map(|event| (event.offset, event.payload)).collect()
+    }
+
+
+    #[test]
+    fn relay_publishes_pending_events() {
+        let event_store = MemoryEventStore::new().with_outbox();
+        let publisher = MemoryPublisher::new();
+
+        event_store.append_events(Uuid::new_v4(), Any, new_events(&["A", "B"])).wait().unwrap();
+        event_store.append_events(Uuid::new_v4(), Any, new_events(&["1"])).wait().unwrap();
+
+        assert_eq!(outbox::relay(&event_store, &publisher).wait(), Ok(3));
+        assert_eq!(published(&publisher), vec![(0, "A"), (1, "B"), (2, "1")]);
+        assert_eq!(event_store.pending(10).wait(), Ok(vec![]));
+
+        // Only the newly appended events are published
+        event_store.append_events(Uuid::new_v4(), Any, new_events(&["X"])).wait().unwrap();
+
+        assert_eq!(outbox::relay(&event_store, &publisher).wait(), Ok(1));
+        assert_eq!(published(&publisher), vec![(0, "A"), (1, "B"), (2, "1"), (3, "X")]);
+    }
+
+
+    #[test]
+    fn relay_retries_after_publish_error() {
+        let event_store = MemoryEventStore::new().with_outbox();
+        let publisher = MemoryPublisher::new();
+
+        event_store.append_events(Uuid::new_v4(), Any, new_events(&["A", "B", "C"]))
+            .wait()
+            .unwrap();
+
+        let failing_publisher = FailingPublisher("B", publisher.clone());
+        assert_eq!(outbox::relay(&event_store, &failing_publisher).wait(),
+                   Err(RelayError::Publish("B")));
+        assert_eq!(published(&publisher), vec![(0, "A")]);
+
+        // The events that failed to be published are still pending
+        let pending = event_store.pending(10).wait().unwrap();
+        assert_eq!(pending.into_iter().map(|event| event.payload).collect::<Vec<_>>(),
+                   vec!["B", "C"]);
+
+        assert_eq!(outbox::relay(&event_store, &publisher).wait(), Ok(2));
+        assert_eq!(published(&publisher), vec![(0, "A"), (1, "B"), (2, "C")]);
+    }
+
+
+    #[test]
+    fn outbox_is_disabled_by_default() {
+        let event_store = MemoryEventStore::new();
+
+        event_store.append_events(Uuid::new_v4(), Any, new_events(&["A"])).wait().unwrap();
+
+        assert_eq!(event_store.pending(10).wait(), Ok(vec![]));
+    }
+
+
+    #[test]
+    fn codec_event_store_encodes_payloads() {
+        let event_store = MemoryEventStore::new();
+        let json_store = CodecEventStore::new(event_store.clone(), JsonCodec::new());
+        let source_id_1 = Uuid::new_v4();
+
+        json_store.append_events(source_id_1, Any, new_events(&["A".to_string()]))
+            .wait()
+            .unwrap();
+
+        assert_eq!(stored_events(&event_store, source_id_1),
+                   Some(vec![(0, b"\"A\"".to_vec())]));
+        assert_eq!(json_store.events(source_id_1, 0).collect().wait().unwrap(),
+                   vec![PersistedEvent {
+                            offset: 0,
+                            source_id: source_id_1,
+                            sequence_number: 0,
+                            event_type: "test".to_string(),
+                            event_version: 0,
+                            metadata: metadata(),
+                            payload: "A".to_string(),
+                        }]);
+    }
+
+
+    #[test]
+    fn codec_event_store_with_invalid_payload() {
+        let event_store = MemoryEventStore::new();
+        let binary_store = CodecEventStore::new(event_store.clone(), BinaryCodec::<String>::new());
+        let source_id_1 = Uuid::new_v4();
+
+        event_store.append_events(source_id_1, Any, new_events(&[vec![0xc1]])).wait().unwrap();
+
+        match binary_store.all_events(0).collect().wait() {
+            Err(CodecStoreError::Codec(_)) => {},
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+
+    fn task_upcasters() -> Upcasters {
+        Upcasters::new()
+            .register("created", 0, |mut payload| {
+                payload["priority"] = json!("normal");
+                payload
+            })
+            .register_renamed("description_changed", 0, "description_updated", |mut payload| {
+                payload["type"] = json!("description_updated");
+                payload
+            })
+    }
+
+
+    #[test]
+    fn upcast_events_with_mixed_versions() {
+        let event_store = MemoryEventStore::new();
+        let upcast_store = UpcastEventStore::new(event_store.clone(), task_upcasters());
+        let source_id_1 = Uuid::new_v4();
+
+        let stored_events =
+            vec![("created", 0, json!({ "type": "created", "description": "A" })),
+                 ("description_changed", 0, json!({ "type": "description_changed",
+                                                     "description": "B" })),
+                 ("description_updated", 1, json!({ "type": "description_updated",
+                                                     "description": "C" })),
+                 ("completed", 0, json!({ "type": "completed" }))];
+        let stored_events = stored_events.into_iter()
+            .map(|(ty, version, payload)| NewEvent::with_type(ty, version, metadata(), payload))
+            .collect();
+        event_store.append_events(source_id_1, Any, stored_events).wait().unwrap();
+
+        let new_payload = json!({ "type": "created", "description": "D", "priority": "high" });
+        let new_events = vec![NewEvent::with_type("created", 1, metadata(), new_payload)];
+        upcast_store.append_events(source_id_1, Any, new_events).wait().unwrap();
+
+        let events = upcast_store.events(source_id_1, 0).collect().wait().unwrap();
+
+        assert_eq!(events.iter()
+                       .map(|e| (e.event_type.as_str(), e.event_version))
+                       .collect::<Vec<_>>(),
+                   vec![("created", 1),
+                        ("description_updated", 1),
+                        ("description_updated", 1),
+                        ("completed", 0),
+                        ("created", 1)]);
+        assert_eq!(events.into_iter().map(|e| e.payload).collect::<Vec<_>>(),
+                   vec![json!({ "type": "created", "description": "A", "priority": "normal" }),
+                        json!({ "type": "description_updated", "description": "B" }),
+                        json!({ "type": "description_updated", "description": "C" }),
+                        json!({ "type": "completed" }),
+                        json!({ "type": "created", "description": "D", "priority": "high" })]);
+    }
+
+
+    /// Build a task event, as it would be decoded by the `JsonCodec`
+    fn task_event(fields: &[(&str, &str)]) -> BTreeMap<String, String> {
+        fields.iter().map(|&(key, value)| (key.to_string(), value.to_string())).collect()
+    }
+
+
+    #[test]
+    fn upcast_bytes_before_decoding() {
+        let event_store = MemoryEventStore::new();
+        let upcast_store = UpcastEventStore::new(event_store.clone(), task_upcasters());
+        let task_store = CodecEventStore::new(upcast_store, JsonCodec::new());
+        let source_id_1 = Uuid::new_v4();
+
+        let stored_events =
+            vec![("created", 0, br#"{"type":"created","description":"A"}"#.to_vec()),
+                 ("description_changed",
+                  0,
+                  br#"{"type":"description_changed","description":"B"}"#.to_vec())];
+        let stored_events = stored_events.into_iter()
+            .map(|(ty, version, payload)| NewEvent::with_type(ty, version, metadata(), payload))
+            .collect();
+        event_store.append_events(source_id_1, Any, stored_events).wait().unwrap();
+
+        let new_event = task_event(&[("type", "created"),
+                                     ("description", "C"),
+                                     ("priority", "high")]);
+        let new_events = vec![NewEvent::with_type("created", 1, metadata(), new_event)];
+        task_store.append_events(source_id_1, Any, new_events).wait().unwrap();
+
+        let events = task_store.events(source_id_1, 0).collect().wait().unwrap();
+
+        assert_eq!(events.iter()
+                       .map(|e| (e.event_type.as_str(), e.event_version))
+                       .collect::<Vec<_>>(),
+                   vec![("created", 1), ("description_updated", 1), ("created", 1)]);
+        assert_eq!(events.into_iter().map(|e| e.payload).collect::<Vec<_>>(),
+                   vec![task_event(&[("type", "created"),
+                                     ("description", "A"),
+                                     ("priority", "normal")]),
+                        task_event(&[("type", "description_updated"), ("description", "B")]),
+                        task_event(&[("type", "created"),
+                                     ("description", "C"),
+                                     ("priority", "high")])]);
+    }
+
+
+    #[test]
+    fn upcast_invalid_bytes() {
+        let event_store = MemoryEventStore::new();
+        let upcast_store = UpcastEventStore::new(event_store.clone(), task_upcasters());
+        let source_id_1 = Uuid::new_v4();
+
+        // Only payloads that need to be upcast are parsed
+        let stored_events = vec![NewEvent::with_type("completed", 0, metadata(), vec![0xc1]),
+                                 NewEvent::with_type("created", 0, metadata(), vec![0xc1])];
+        event_store.append_events(source_id_1, Any, stored_events).wait().unwrap();
+
+        let mut events = upcast_store.events(source_id_1, 0).wait();
+
+        assert_eq!(events.next().map(|e| e.unwrap().payload), Some(vec![0xc1]));
+        match events.next() {
+            Some(Err(UpcastStreamError::Upcast(_))) => {},
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+
+    mod conformance {
+        use chronicle::codec::{CodecEventStore, JsonCodec};
+
+        use MemoryEventStore;
+
+
+        event_store_conformance_tests! {
+            new_store: MemoryEventStore::<usize>::new(),
+            payload: |i| i,
+        }
+
+
+        mod codec {
+            use super::*;
+
+
+            event_store_conformance_tests! {
+                new_store: CodecEventStore::new(MemoryEventStore::new(), JsonCodec::new()),
+                payload: |i| i.to_string(),
+            }
+        }
+
+
+        mod upcast {
+            use chronicle::upcast::{UpcastEventStore, Upcasters};
+
+            use super::*;
+
+
+            event_store_conformance_tests! {
+                new_store: UpcastEventStore::new(MemoryEventStore::new(), Upcasters::new()),
+                payload: |i| i.to_string().into_bytes(),
+            }
+        }
+    }
 }
@@ -1,8 +1,8 @@
 CREATE TABLE events (
-  offset BIGSERIAL NOT NULL,
+  "offset" BIGSERIAL NOT NULL,
   source_id UUID NOT NULL,
   sequence_number BIGINT NOT NULL,
   payload BYTEA NOT NULL,
-  created_at timestamp not null,
-  PRIMARY KEY(source_id, sequence_number),
+  created_at TIMESTAMP NOT NULL DEFAULT now(),
+  PRIMARY KEY(source_id, sequence_number)
 );
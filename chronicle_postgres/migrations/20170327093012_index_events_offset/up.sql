@@ -0,0 +1 @@
+CREATE UNIQUE INDEX events_offset_idx ON events ("offset");
@@ -0,0 +1,5 @@
+CREATE TABLE checkpoints (
+  projection_id TEXT NOT NULL,
+  "offset" BIGINT NOT NULL,
+  PRIMARY KEY(projection_id)
+);
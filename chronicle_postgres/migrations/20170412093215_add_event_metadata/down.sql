@@ -0,0 +1,7 @@
+DROP INDEX events_event_id_idx;
+
+ALTER TABLE events
+  DROP COLUMN event_id,
+  DROP COLUMN correlation_id,
+  DROP COLUMN causation_id,
+  DROP COLUMN headers;
@@ -0,0 +1,11 @@
+ALTER TABLE events
+  ADD COLUMN event_id UUID,
+  ADD COLUMN correlation_id UUID,
+  ADD COLUMN causation_id UUID,
+  ADD COLUMN headers JSONB NOT NULL DEFAULT '{}';
+
+UPDATE events SET event_id = md5(random()::text || clock_timestamp()::text)::uuid;
+
+ALTER TABLE events ALTER COLUMN event_id SET NOT NULL;
+
+CREATE UNIQUE INDEX events_event_id_idx ON events (event_id);
@@ -0,0 +1,5 @@
+DROP INDEX events_event_type_idx;
+
+ALTER TABLE events
+  DROP COLUMN event_type,
+  DROP COLUMN event_version;
@@ -0,0 +1,8 @@
+ALTER TABLE events
+  ADD COLUMN event_type TEXT NOT NULL DEFAULT '',
+  ADD COLUMN event_version INTEGER NOT NULL DEFAULT 0;
+
+ALTER TABLE events ALTER COLUMN event_type DROP DEFAULT;
+ALTER TABLE events ALTER COLUMN event_version DROP DEFAULT;
+
+CREATE INDEX events_event_type_idx ON events (event_type, "offset");
@@ -0,0 +1,8 @@
+CREATE TABLE deadlines (
+  deadline_id UUID NOT NULL,
+  due_at TIMESTAMP NOT NULL,
+  command BYTEA NOT NULL,
+  PRIMARY KEY(deadline_id)
+);
+
+CREATE INDEX deadlines_due_at_idx ON deadlines (due_at);
@@ -0,0 +1,4 @@
+CREATE TABLE outbox (
+  "offset" BIGINT NOT NULL REFERENCES events ("offset"),
+  PRIMARY KEY("offset")
+);
@@ -0,0 +1,6 @@
+CREATE TABLE idempotency_keys (
+  idempotency_key UUID NOT NULL,
+  source_id UUID NOT NULL,
+  created_at TIMESTAMP NOT NULL DEFAULT now(),
+  PRIMARY KEY(idempotency_key)
+);
@@ -0,0 +1,3 @@
+ALTER TABLE idempotency_keys
+  DROP CONSTRAINT idempotency_keys_pkey,
+  ADD PRIMARY KEY(idempotency_key);
@@ -0,0 +1,125 @@
+use chronicle::CheckpointStore;
+use diesel;
+use diesel::migrations::RunMigrationsError;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::result::Error as DieselError;
+use futures::future::{self, FutureResult};
+use std::sync::{Arc, Mutex};
+
+use embedded_migrations;
+use models;
+use schema::checkpoints;
+
+
+/// A checkpoint store that persists the offsets of projections to the
+/// `checkpoints` table of a Postgres database
+#[derive(Clone)]
+pub struct PostgresCheckpointStore {
+    connection: Arc<Mutex<PgConnection>>,
+}
+
+
+impl PostgresCheckpointStore {
+    /// Create a checkpoint store using an existing connection
+    pub fn new(connection: PgConnection) -> PostgresCheckpointStore {
+        PostgresCheckpointStore { connection: Arc::new(Mutex::new(connection)) }
+    }
+
+    /// Connect to the database at the given url
+    pub fn establish(database_url: &str) -> ConnectionResult<PostgresCheckpointStore> {
+        PgConnection::establish(database_url).map(PostgresCheckpointStore::new)
+    }
+
+    /// Run any pending migrations that are needed for the checkpoint store
+    pub fn run_migrations(&self) -> Result<(), RunMigrationsError> {
+        let connection = self.connection.lock().unwrap();
+        embedded_migrations::run(&*connection)
+    }
+
+    fn try_save_checkpoint(&self, projection_id: &str, offset: i64) -> Result<(), DieselError> {
+        let connection = self.connection.lock().unwrap();
+
+        connection.transaction(|| {
+            let updated = diesel::update(checkpoints::table.find(projection_id))
+                .set(checkpoints::offset.eq(offset))
+                .execute(&*connection)?;
+
+            if updated == 0 {
+                let checkpoint = models::NewCheckpoint {
+                    projection_id: projection_id,
+                    offset: offset,
+                };
+
+                diesel::insert(&checkpoint).into(checkpoints::table).execute(&*connection)?;
+            }
+
+            Ok(())
+        })
+    }
+
+    fn try_load_checkpoint(&self, projection_id: &str) -> Result<Option<i64>, DieselError> {
+        let connection = self.connection.lock().unwrap();
+
+        checkpoints::table.find(projection_id)
+            .select(checkpoints::offset)
+            .first(&*connection)
+            .optional()
+    }
+}
+
+
+impl CheckpointStore for PostgresCheckpointStore {
+    type Offset = i64;
+    type Error = DieselError;
+    type SaveFuture = FutureResult<(), DieselError>;
+    type LoadFuture = FutureResult<Option<i64>, DieselError>;
+
+    fn save_checkpoint(&self, projection_id: &str, offset: i64) -> FutureResult<(), DieselError> {
+        future::result(self.try_save_checkpoint(projection_id, offset))
+    }
+
+    fn load_checkpoint(&self, projection_id: &str) -> FutureResult<Option<i64>, DieselError> {
+        future::result(self.try_load_checkpoint(projection_id))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use chronicle::CheckpointStore;
+    use diesel::prelude::*;
+    use futures::Future;
+    use std::env;
+    use uuid::Uuid;
+
+    use super::*;
+
+
+    fn test_checkpoint_store() -> PostgresCheckpointStore {
+        let database_url = env::var("DATABASE_URL")
+            .expect("DATABASE_URL must be set to run the Postgres tests");
+        let checkpoint_store = PostgresCheckpointStore::establish(&database_url).unwrap();
+        checkpoint_store.run_migrations().unwrap();
+        checkpoint_store.connection.lock().unwrap().begin_test_transaction().unwrap();
+        checkpoint_store
+    }
+
+
+    #[test]
+    #[ignore]
+    fn save_and_load_checkpoints() {
+        let checkpoint_store = test_checkpoint_store();
+        let projection_id_1 = Uuid::new_v4().to_string();
+        let projection_id_2 = Uuid::new_v4().to_string();
+
+        assert_eq!(checkpoint_store.load_checkpoint(&projection_id_1).wait(), Ok(None));
+
+        checkpoint_store.save_checkpoint(&projection_id_1, 3).wait().unwrap();
+        checkpoint_store.save_checkpoint(&projection_id_2, 1).wait().unwrap();
+        checkpoint_store.save_checkpoint(&projection_id_1, 5).wait().unwrap();
+
+        assert_eq!(checkpoint_store.load_checkpoint(&projection_id_1).wait(), Ok(Some(5)));
+        assert_eq!(checkpoint_store.load_checkpoint(&projection_id_2).wait(), Ok(Some(1)));
+    }
+}
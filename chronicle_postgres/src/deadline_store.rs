@@ -0,0 +1,193 @@
+use chronicle::{Deadline, DeadlineStore};
+use diesel;
+use diesel::migrations::RunMigrationsError;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::result::Error as DieselError;
+use futures::future::{self, FutureResult};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use uuid::Uuid;
+
+use embedded_migrations;
+use models;
+use schema::deadlines;
+
+
+/// A deadline store that persists scheduled commands to the `deadlines`
+/// table of a Postgres database
+#[derive(Clone)]
+pub struct PostgresDeadlineStore {
+    connection: Arc<Mutex<PgConnection>>,
+}
+
+
+impl PostgresDeadlineStore {
+    /// Create a deadline store using an existing connection
+    pub fn new(connection: PgConnection) -> PostgresDeadlineStore {
+        PostgresDeadlineStore { connection: Arc::new(Mutex::new(connection)) }
+    }
+
+    /// Connect to the database at the given url
+    pub fn establish(database_url: &str) -> ConnectionResult<PostgresDeadlineStore> {
+        PgConnection::establish(database_url).map(PostgresDeadlineStore::new)
+    }
+
+    /// Run any pending migrations that are needed for the deadline store
+    pub fn run_migrations(&self) -> Result<(), RunMigrationsError> {
+        let connection = self.connection.lock().unwrap();
+        embedded_migrations::run(&*connection)
+    }
+
+    fn try_schedule(&self, deadline: Deadline<Vec<u8>>) -> Result<(), DieselError> {
+        let connection = self.connection.lock().unwrap();
+
+        connection.transaction(|| {
+            let updated = diesel::update(deadlines::table.find(deadline.deadline_id))
+                .set((deadlines::due_at.eq(deadline.due_at),
+                      deadlines::command.eq(&deadline.command)))
+                .execute(&*connection)?;
+
+            if updated == 0 {
+                let deadline = models::NewDeadline {
+                    deadline_id: deadline.deadline_id,
+                    due_at: deadline.due_at,
+                    command: &deadline.command,
+                };
+
+                diesel::insert(&deadline).into(deadlines::table).execute(&*connection)?;
+            }
+
+            Ok(())
+        })
+    }
+
+    fn try_cancel(&self, deadline_id: Uuid) -> Result<bool, DieselError> {
+        let connection = self.connection.lock().unwrap();
+
+        let deleted = diesel::delete(deadlines::table.find(deadline_id)).execute(&*connection)?;
+
+        Ok(deleted > 0)
+    }
+
+    fn try_acknowledge(&self, deadline_id: Uuid, due_at: SystemTime) -> Result<bool, DieselError> {
+        let connection = self.connection.lock().unwrap();
+
+        let deleted = diesel::delete(deadlines::table.find(deadline_id)
+                .filter(deadlines::due_at.eq(due_at)))
+            .execute(&*connection)?;
+
+        Ok(deleted > 0)
+    }
+
+    fn try_due(&self, now: SystemTime) -> Result<Vec<Deadline<Vec<u8>>>, DieselError> {
+        let connection = self.connection.lock().unwrap();
+
+        let deadlines = deadlines::table.filter(deadlines::due_at.le(now))
+            .order(deadlines::due_at.asc())
+            .load::<models::Deadline>(&*connection)?;
+
+        Ok(deadlines.into_iter()
+            .map(|deadline| Deadline::new(deadline.deadline_id, deadline.due_at, deadline.command))
+            .collect())
+    }
+}
+
+
+impl DeadlineStore for PostgresDeadlineStore {
+    type Command = Vec<u8>;
+    type Error = DieselError;
+    type ScheduleFuture = FutureResult<(), DieselError>;
+    type CancelFuture = FutureResult<bool, DieselError>;
+    type AcknowledgeFuture = FutureResult<bool, DieselError>;
+    type DueFuture = FutureResult<Vec<Deadline<Vec<u8>>>, DieselError>;
+
+    fn schedule(&self, deadline: Deadline<Vec<u8>>) -> FutureResult<(), DieselError> {
+        future::result(self.try_schedule(deadline))
+    }
+
+    fn cancel(&self, deadline_id: Uuid) -> FutureResult<bool, DieselError> {
+        future::result(self.try_cancel(deadline_id))
+    }
+
+    fn acknowledge(&self,
+                   deadline_id: Uuid,
+                   due_at: SystemTime)
+                   -> FutureResult<bool, DieselError> {
+        future::result(self.try_acknowledge(deadline_id, due_at))
+    }
+
+    fn due(&self, now: SystemTime) -> FutureResult<Vec<Deadline<Vec<u8>>>, DieselError> {
+        future::result(self.try_due(now))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use chronicle::{Deadline, DeadlineStore};
+    use diesel::prelude::*;
+    use futures::Future;
+    use std::env;
+    use std::time::{Duration, UNIX_EPOCH};
+    use uuid::Uuid;
+
+    use super::*;
+
+
+    fn test_deadline_store() -> PostgresDeadlineStore {
+        let database_url = env::var("DATABASE_URL")
+            .expect("DATABASE_URL must be set to run the Postgres tests");
+        let deadline_store = PostgresDeadlineStore::establish(&database_url).unwrap();
+        deadline_store.run_migrations().unwrap();
+        deadline_store.connection.lock().unwrap().begin_test_transaction().unwrap();
+        deadline_store
+    }
+
+
+    #[test]
+    #[ignore]
+    fn schedule_and_cancel_deadlines() {
+        let deadline_store = test_deadline_store();
+        let (id_1, id_2, id_3) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+
+        deadline_store.schedule(Deadline::new(id_1, at(20), b"A".to_vec())).wait().unwrap();
+        deadline_store.schedule(Deadline::new(id_2, at(10), b"B".to_vec())).wait().unwrap();
+        deadline_store.schedule(Deadline::new(id_3, at(30), b"C".to_vec())).wait().unwrap();
+
+        assert_eq!(deadline_store.due(at(5)).wait(), Ok(vec![]));
+        assert_eq!(deadline_store.due(at(20)).wait(),
+                   Ok(vec![Deadline::new(id_2, at(10), b"B".to_vec()),
+                           Deadline::new(id_1, at(20), b"A".to_vec())]));
+
+        // Rescheduling replaces the existing deadline
+        deadline_store.schedule(Deadline::new(id_3, at(15), b"D".to_vec())).wait().unwrap();
+
+        assert_eq!(deadline_store.cancel(id_2).wait(), Ok(true));
+        assert_eq!(deadline_store.cancel(id_2).wait(), Ok(false));
+        assert_eq!(deadline_store.due(at(30)).wait(),
+                   Ok(vec![Deadline::new(id_3, at(15), b"D".to_vec()),
+                           Deadline::new(id_1, at(20), b"A".to_vec())]));
+    }
+
+
+    #[test]
+    #[ignore]
+    fn acknowledge_deadlines() {
+        let deadline_store = test_deadline_store();
+        let (id_1, id_2) = (Uuid::new_v4(), Uuid::new_v4());
+        let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+
+        deadline_store.schedule(Deadline::new(id_1, at(10), b"A".to_vec())).wait().unwrap();
+        deadline_store.schedule(Deadline::new(id_2, at(10), b"B".to_vec())).wait().unwrap();
+
+        // The second deadline was rescheduled while its command was in flight
+        deadline_store.schedule(Deadline::new(id_2, at(20), b"C".to_vec())).wait().unwrap();
+
+        assert_eq!(deadline_store.acknowledge(id_1, at(10)).wait(), Ok(true));
+        assert_eq!(deadline_store.acknowledge(id_2, at(10)).wait(), Ok(false));
+        assert_eq!(deadline_store.due(at(20)).wait(),
+                   Ok(vec![Deadline::new(id_2, at(20), b"C".to_vec())]));
+    }
+}
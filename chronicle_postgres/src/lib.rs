@@ -1,12 +1,1030 @@
+//! A Postgres backed event store, checkpoint store and deadline store
+//!
+//! The tests in this crate require a running Postgres instance, and are
+//! ignored by default. They can be run with:
+//!
+//! ```text
+//! DATABASE_URL=postgres://localhost/chronicle_test cargo test -- --ignored
+//! ```
+
+
+#[cfg_attr(test, macro_use)]
+extern crate chronicle;
 #[macro_use]
 extern crate diesel;
 #[macro_use]
 extern crate diesel_codegen;
+extern crate futures;
+extern crate serde_json;
 extern crate uuid;
 
 
+mod checkpoint_store;
+mod deadline_store;
 pub mod models;
 pub mod schema;
 
+pub use checkpoint_store::PostgresCheckpointStore;
+pub use deadline_store::PostgresDeadlineStore;
+
 
 embed_migrations!("migrations");
+
+
+use chronicle::{EventStore, EventVersion, ExpectedVersion, Metadata, NewEvent, PersistedEvent};
+use chronicle::{AsWrongExpectedVersion, Outbox, SequenceNumber, Transaction};
+use chronicle::WrongExpectedVersion;
+use diesel::migrations::RunMigrationsError;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::result::DatabaseErrorKind::UniqueViolation;
+use diesel::result::Error as DieselError;
+use futures::{Async, Poll, Stream};
+use futures::future::{self, FutureResult};
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+
+use schema::{events, idempotency_keys, outbox};
+
+
+/// The number of rows to fetch at a time when streaming events
+const BATCH_SIZE: i64 = 256;
+
+/// The number of times that an append is attempted when it keeps colliding
+/// with concurrent writers, before the collision is returned as an error
+const MAX_APPEND_ATTEMPTS: usize = 8;
+
+
+/// An event store that persists events to the `events` table of a Postgres
+/// database
+#[derive(Clone)]
+pub struct PostgresEventStore {
+    connection: Arc<Mutex<PgConnection>>,
+    /// Whether appended events should be recorded in the `outbox` table
+    outbox: bool,
+}
+
+
+impl PostgresEventStore {
+    /// Create an event store using an existing connection
+    pub fn new(connection: PgConnection) -> PostgresEventStore {
+        PostgresEventStore {
+            connection: Arc::new(Mutex::new(connection)),
+            outbox: false,
+        }
+    }
+
+    /// Connect to the database at the given url
+    pub fn establish(database_url: &str) -> ConnectionResult<PostgresEventStore> {
+        PgConnection::establish(database_url).map(PostgresEventStore::new)
+    }
+
+    /// Run any pending migrations that are needed for the event store
+    pub fn run_migrations(&self) -> Result<(), RunMigrationsError> {
+        let connection = self.connection.lock().unwrap();
+        embedded_migrations::run(&*connection)
+    }
+
+    /// Record each appended event in the `outbox` table, in the same
+    /// transaction as the append, so that it can be relayed to a `Publisher`
+    pub fn with_outbox(mut self) -> PostgresEventStore {
+        self.outbox = true;
+        self
+    }
+
+    /// The sequence number of the last event for the source, or `None` if
+    /// no events have been stored for it yet
+    fn current_version(connection: &PgConnection,
+                       source_id: Uuid)
+                       -> Result<Option<SequenceNumber>, DieselError> {
+        use diesel::expression::dsl::max;
+
+        events::table.select(max(events::sequence_number))
+            .filter(events::source_id.eq(source_id))
+            .first::<Option<i64>>(connection)
+            .map(|seq| seq.map(|seq| seq as SequenceNumber))
+    }
+
+    /// Whether a batch with the idempotency key has already been appended to
+    /// the source
+    fn has_idempotency_key(connection: &PgConnection,
+                           source_id: Uuid,
+                           idempotency_key: Uuid)
+                           -> Result<bool, DieselError> {
+        idempotency_keys::table.filter(idempotency_keys::source_id.eq(source_id))
+            .filter(idempotency_keys::idempotency_key.eq(idempotency_key))
+            .select(idempotency_keys::idempotency_key)
+            .first::<Uuid>(connection)
+            .optional()
+            .map(|key| key.is_some())
+    }
+
+    /// Insert the events for the source after its current version,
+    /// recording them in the outbox if it has been enabled
+    fn insert_events(&self,
+                     connection: &PgConnection,
+                     source_id: Uuid,
+                     current: Option<SequenceNumber>,
+                     events: &[NewEvent<Vec<u8>>])
+                     -> Result<(), DieselError> {
+        let first_sequence_number = current.map_or(0, |seq| seq as i64 + 1);
+        let new_events = events.iter()
+            .enumerate()
+            .map(|(i, event)| {
+                models::NewEvent {
+                    source_id: source_id,
+                    sequence_number: first_sequence_number + i as i64,
+                    payload: &event.payload,
+                    created_at: event.metadata.recorded_at,
+                    event_id: event.metadata.event_id,
+                    correlation_id: event.metadata.correlation_id,
+                    causation_id: event.metadata.causation_id,
+                    headers: encode_headers(&event.metadata.headers),
+                    event_type: &event.event_type,
+                    event_version: event.event_version as i32,
+                }
+            })
+            .collect::<Vec<_>>();
+
+        if self.outbox {
+            let entries = diesel::insert(&new_events).into(events::table)
+                .returning(events::offset)
+                .get_results::<i64>(connection)?
+                .into_iter()
+                .map(|offset| models::NewOutboxEntry { offset: offset })
+                .collect::<Vec<_>>();
+
+            diesel::insert(&entries).into(outbox::table).execute(connection)?;
+        } else {
+            diesel::insert(&new_events).into(events::table).execute(connection)?;
+        }
+
+        Ok(())
+    }
+
+    fn try_append_events(&self,
+                         idempotency_key: Option<Uuid>,
+                         source_id: Uuid,
+                         expected_version: ExpectedVersion,
+                         events: &[NewEvent<Vec<u8>>])
+                         -> Result<(), AppendError> {
+        let connection = self.connection.lock().unwrap();
+        let mut attempts = 0;
+
+        loop {
+            attempts += 1;
+            let result = connection.transaction(|| {
+                if let Some(idempotency_key) = idempotency_key {
+                    if PostgresEventStore::has_idempotency_key(&connection,
+                                                               source_id,
+                                                               idempotency_key)? {
+                        return Ok(());
+                    }
+                }
+
+                let current = PostgresEventStore::current_version(&connection, source_id)?;
+
+                if !expected_version.is_satisfied_by(current) {
+                    return Err(AppendError::from(WrongExpectedVersion {
+                        source_id: source_id,
+                        expected: expected_version,
+                        current: current,
+                    }));
+                }
+
+                if events.is_empty() {
+                    return Ok(());
+                }
+
+                self.insert_events(&connection, source_id, current, events)?;
+
+                if let Some(idempotency_key) = idempotency_key {
+                    let key = models::NewIdempotencyKey {
+                        idempotency_key: idempotency_key,
+                        source_id: source_id,
+                    };
+
+                    diesel::insert(&key).into(idempotency_keys::table).execute(&*connection)?;
+                }
+
+                Ok(())
+            });
+
+            // If a concurrent writer appended the same batch first, then
+            // either the events or the idempotency key will have collided
+            // with theirs, so we can resolve as if we had appended it.
+            if let (Some(idempotency_key), &Err(AppendError::Database(_))) =
+                   (idempotency_key, &result) {
+                if PostgresEventStore::has_idempotency_key(&connection,
+                                                           source_id,
+                                                           idempotency_key)? {
+                    return Ok(());
+                }
+            }
+
+            // The primary key on `(source_id, sequence_number)` ensures that
+            // if a concurrent writer managed to append events between our
+            // version check and the insert, we will fail here. Appends that
+            // expected any version are retried, up to `MAX_APPEND_ATTEMPTS`
+            // times. Other unique violations, like a duplicate event id, are
+            // returned as is.
+            match result {
+                Err(AppendError::Database(DieselError::DatabaseError(UniqueViolation, ref info)))
+                    if info.constraint_name() == Some("events_pkey") => {
+                    if expected_version == ExpectedVersion::Any {
+                        if attempts < MAX_APPEND_ATTEMPTS {
+                            continue;
+                        }
+                        return result;
+                    }
+
+                    let current = PostgresEventStore::current_version(&connection, source_id)?;
+
+                    return Err(AppendError::from(WrongExpectedVersion {
+                        source_id: source_id,
+                        expected: expected_version,
+                        current: current,
+                    }));
+                },
+                result => return result,
+            }
+        }
+    }
+
+    fn try_append_transaction(&self,
+                              transaction: &Transaction<Vec<u8>>)
+                              -> Result<(), AppendError> {
+        let connection = self.connection.lock().unwrap();
+        let mut attempts = 0;
+
+        loop {
+            attempts += 1;
+            // Each version check sees the events inserted for the preceding
+            // entries, so a source may appear more than once
+            let result = connection.transaction(|| {
+                for entry in &transaction.entries {
+                    let current = PostgresEventStore::current_version(&connection,
+                                                                      entry.source_id)?;
+
+                    if !entry.expected_version.is_satisfied_by(current) {
+                        return Err(AppendError::from(WrongExpectedVersion {
+                            source_id: entry.source_id,
+                            expected: entry.expected_version,
+                            current: current,
+                        }));
+                    }
+
+                    if !entry.events.is_empty() {
+                        self.insert_events(&connection, entry.source_id, current, &entry.events)?;
+                    }
+                }
+
+                Ok(())
+            });
+
+            // A concurrent writer appended to one of the sources between our
+            // version check and the insert, so the whole transaction was
+            // rolled back. Retrying reports the conflict as a wrong expected
+            // version, or appends after the other writer's events if the
+            // entries expected any version. If the conflicts persist, the
+            // last one is returned.
+            match result {
+                Err(AppendError::Database(DieselError::DatabaseError(UniqueViolation, ref info)))
+                    if info.constraint_name() == Some("events_pkey") &&
+                       attempts < MAX_APPEND_ATTEMPTS => continue,
+                result => return result,
+            }
+        }
+    }
+
+    /// Load a batch of events starting at the given offset, optionally
+    /// restricted to a single source id, starting at the given sequence
+    /// number, or to a set of event types
+    fn load_events(&self,
+                   source_id: Option<Uuid>,
+                   event_types: Option<&[String]>,
+                   sequence_number: i64,
+                   offset: i64)
+                   -> Result<Vec<models::Event>, DieselError> {
+        let connection = self.connection.lock().unwrap();
+
+        let mut query = events::table.filter(events::offset.ge(offset))
+            .order(events::offset.asc())
+            .limit(BATCH_SIZE)
+            .into_boxed();
+
+        if let Some(source_id) = source_id {
+            query = query.filter(events::source_id.eq(source_id))
+                .filter(events::sequence_number.ge(sequence_number));
+        }
+
+        if let Some(event_types) = event_types {
+            query = query.filter(events::event_type.eq_any(event_types));
+        }
+
+        query.load(&*connection)
+    }
+
+    fn try_pending(&self, limit: usize) -> Result<Vec<PersistedEvent<i64, Vec<u8>>>, DieselError> {
+        let connection = self.connection.lock().unwrap();
+
+        let offsets = outbox::table.select(outbox::offset)
+            .order(outbox::offset.asc())
+            .limit(limit as i64)
+            .load::<i64>(&*connection)?;
+
+        let events = events::table.filter(events::offset.eq_any(offsets))
+            .order(events::offset.asc())
+            .load::<models::Event>(&*connection)?;
+
+        events.into_iter().map(persisted_event).collect()
+    }
+
+    fn try_acknowledge(&self, offset: i64) -> Result<(), DieselError> {
+        let connection = self.connection.lock().unwrap();
+
+        diesel::delete(outbox::table.find(offset)).execute(&*connection)?;
+
+        Ok(())
+    }
+}
+
+
+impl EventStore for PostgresEventStore {
+    type Offset = i64;
+    type Event = Vec<u8>;
+    type AppendError = AppendError;
+    type AppendFuture = FutureResult<(), AppendError>;
+    type EventsStream = EventsStream;
+    type AllEventsStream = EventsStream;
+
+    fn append_events(&self,
+                     source_id: Uuid,
+                     expected_version: ExpectedVersion,
+                     events: Vec<NewEvent<Vec<u8>>>)
+                     -> FutureResult<(), AppendError> {
+        future::result(self.try_append_events(None, source_id, expected_version, &events))
+    }
+
+    fn append_events_idempotent(&self,
+                                idempotency_key: Uuid,
+                                source_id: Uuid,
+                                expected_version: ExpectedVersion,
+                                events: Vec<NewEvent<Vec<u8>>>)
+                                -> FutureResult<(), AppendError> {
+        future::result(self.try_append_events(Some(idempotency_key),
+                                              source_id,
+                                              expected_version,
+                                              &events))
+    }
+
+    fn append_transaction(&self,
+                          transaction: Transaction<Vec<u8>>)
+                          -> FutureResult<(), AppendError> {
+        future::result(self.try_append_transaction(&transaction))
+    }
+
+    fn events(&self, source_id: Uuid, offset: i64) -> EventsStream {
+        EventsStream {
+            source_id: Some(source_id),
+            event_types: None,
+            sequence_number: 0,
+            offset: offset,
+            buffer: VecDeque::new(),
+            is_exhausted: false,
+            event_store: self.clone(),
+        }
+    }
+
+    fn events_from(&self, source_id: Uuid, sequence_number: SequenceNumber) -> EventsStream {
+        EventsStream {
+            source_id: Some(source_id),
+            event_types: None,
+            sequence_number: sequence_number as i64,
+            offset: 0,
+            buffer: VecDeque::new(),
+            is_exhausted: false,
+            event_store: self.clone(),
+        }
+    }
+
+    /// Note that offsets are assigned from a sequence when rows are inserted,
+    /// so events from transactions that are yet to commit may be missed if
+    /// they were assigned a lower offset than a concurrently committed event.
+    fn all_events(&self, offset: i64) -> EventsStream {
+        EventsStream {
+            source_id: None,
+            event_types: None,
+            sequence_number: 0,
+            offset: offset,
+            buffer: VecDeque::new(),
+            is_exhausted: false,
+            event_store: self.clone(),
+        }
+    }
+
+    /// Note that this has the same caveats as `all_events`.
+    fn all_events_of_types(&self, event_types: &[&str], offset: i64) -> EventsStream {
+        EventsStream {
+            source_id: None,
+            event_types: Some(event_types.iter().map(|ty| ty.to_string()).collect()),
+            sequence_number: 0,
+            offset: offset,
+            buffer: VecDeque::new(),
+            is_exhausted: false,
+            event_store: self.clone(),
+        }
+    }
+}
+
+
+impl Outbox for PostgresEventStore {
+    type Offset = i64;
+    type Event = Vec<u8>;
+    type Error = DieselError;
+    type PendingFuture = FutureResult<Vec<PersistedEvent<i64, Vec<u8>>>, DieselError>;
+    type AcknowledgeFuture = FutureResult<(), DieselError>;
+
+    /// Note that this will always be empty unless the outbox was enabled
+    /// with `with_outbox`.
+    fn pending(&self,
+               limit: usize)
+               -> FutureResult<Vec<PersistedEvent<i64, Vec<u8>>>, DieselError> {
+        future::result(self.try_pending(limit))
+    }
+
+    fn acknowledge(&self, offset: i64) -> FutureResult<(), DieselError> {
+        future::result(self.try_acknowledge(offset))
+    }
+}
+
+
+/// An error that may be returned when appending to the `PostgresEventStore`
+#[derive(Debug)]
+pub enum AppendError {
+    /// An error that occurred when communicating with the database
+    Database(DieselError),
+    /// The source was not at the expected version
+    WrongExpectedVersion(WrongExpectedVersion),
+}
+
+
+impl From<DieselError> for AppendError {
+    fn from(src: DieselError) -> AppendError {
+        AppendError::Database(src)
+    }
+}
+
+
+impl From<WrongExpectedVersion> for AppendError {
+    fn from(src: WrongExpectedVersion) -> AppendError {
+        AppendError::WrongExpectedVersion(src)
+    }
+}
+
+
+impl AsWrongExpectedVersion for AppendError {
+    fn as_wrong_expected_version(&self) -> Option<&WrongExpectedVersion> {
+        match *self {
+            AppendError::Database(_) => None,
+            AppendError::WrongExpectedVersion(ref error) => Some(error),
+        }
+    }
+}
+
+
+/// A stream of events, loaded lazily in batches. This either contains the
+/// events for a specified source id, or all of the events in the store,
+/// optionally restricted to a set of event types.
+pub struct EventsStream {
+    source_id: Option<Uuid>,
+    event_types: Option<Vec<String>>,
+    /// The sequence number to start from, when streaming a single source
+    sequence_number: i64,
+    offset: i64,
+    buffer: VecDeque<models::Event>,
+    is_exhausted: bool,
+    event_store: PostgresEventStore,
+}
+
+
+impl Stream for EventsStream {
+    type Item = PersistedEvent<i64, Vec<u8>>;
+    type Error = DieselError;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, DieselError> {
+        if self.buffer.is_empty() && !self.is_exhausted {
+            let event_types = self.event_types.as_deref();
+            let events = self.event_store.load_events(self.source_id,
+                                                      event_types,
+                                                      self.sequence_number,
+                                                      self.offset)?;
+
+            self.is_exhausted = (events.len() as i64) < BATCH_SIZE;
+            if let Some(event) = events.last() {
+                self.offset = event.offset + 1;
+            }
+            self.buffer.extend(events);
+        }
+
+        match self.buffer.pop_front() {
+            Some(event) => Ok(Async::Ready(Some(persisted_event(event)?))),
+            None => Ok(Async::Ready(None)),
+        }
+    }
+}
+
+
+/// Convert a row from the `events` table into a persisted event
+fn persisted_event(event: models::Event) -> Result<PersistedEvent<i64, Vec<u8>>, DieselError> {
+    Ok(PersistedEvent {
+        offset: event.offset,
+        source_id: event.source_id,
+        sequence_number: event.sequence_number as SequenceNumber,
+        event_type: event.event_type,
+        event_version: event.event_version as EventVersion,
+        metadata: Metadata {
+            event_id: event.event_id,
+            recorded_at: event.created_at,
+            correlation_id: event.correlation_id,
+            causation_id: event.causation_id,
+            headers: decode_headers(event.headers)?,
+        },
+        payload: event.payload,
+    })
+}
+
+
+/// Convert user supplied headers into a JSON object for storage
+fn encode_headers(headers: &BTreeMap<String, String>) -> Value {
+    Value::Object(headers.iter()
+        .map(|(key, value)| (key.clone(), Value::String(value.clone())))
+        .collect::<Map<_, _>>())
+}
+
+
+/// Convert a stored JSON object back into user headers
+fn decode_headers(headers: Value) -> Result<BTreeMap<String, String>, DieselError> {
+    let headers = match headers {
+        Value::Object(headers) => headers,
+        _ => return Err(DieselError::DeserializationError("headers must be an object".into())),
+    };
+
+    headers.into_iter()
+        .map(|(key, value)| match value {
+            Value::String(value) => Ok((key, value)),
+            _ => Err(DieselError::DeserializationError("header values must be strings".into())),
+        })
+        .collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use chronicle::{EventStore, PersistedEvent, Publisher, WrongExpectedVersion};
+    use chronicle::ExpectedVersion::*;
+    use chronicle::outbox;
+    use diesel::pg::PgConnection;
+    use diesel::prelude::*;
+    use futures::{Future, Stream};
+    use std::cell::RefCell;
+    use std::env;
+    use uuid::Uuid;
+
+    use super::*;
+
+
+    fn database_url() -> String {
+        env::var("DATABASE_URL").expect("DATABASE_URL must be set to run the Postgres tests")
+    }
+
+
+    /// Create an event store that runs inside of a transaction that will
+    /// never be committed
+    fn test_event_store() -> PostgresEventStore {
+        let event_store = PostgresEventStore::establish(&database_url()).unwrap();
+        event_store.run_migrations().unwrap();
+        event_store.connection.lock().unwrap().begin_test_transaction().unwrap();
+        event_store
+    }
+
+
+    fn new_event(metadata: Metadata, payload: &[u8]) -> NewEvent<Vec<u8>> {
+        NewEvent::with_type("test", 0, metadata, payload.to_vec())
+    }
+
+
+    fn new_events(payloads: &[&str]) -> Vec<NewEvent<Vec<u8>>> {
+        payloads.iter().map(|payload| new_event(Metadata::new(), payload.as_bytes())).collect()
+    }
+
+
+    fn payloads(events: Vec<PersistedEvent<i64, Vec<u8>>>) -> Vec<Vec<u8>> {
+        events.into_iter().map(|event| event.payload).collect()
+    }
+
+
+    #[test]
+    #[ignore]
+    fn events_on_empty_store() {
+        let event_store = test_event_store();
+        let source_id_1 = Uuid::new_v4();
+
+        let events = event_store.events(source_id_1, 0).collect().wait().unwrap();
+
+        assert_eq!(events, Vec::new());
+    }
+
+
+    #[test]
+    #[ignore]
+    fn append_and_read_events() {
+        let event_store = test_event_store();
+        let source_id_1 = Uuid::new_v4();
+        let source_id_2 = Uuid::new_v4();
+
+        event_store.append_events(source_id_1, Any, new_events(&["A"])).wait().unwrap();
+        event_store.append_events(source_id_2, Any, new_events(&["1"])).wait().unwrap();
+        event_store.append_events(source_id_1, Any, new_events(&["B", "C"])).wait().unwrap();
+
+        let events = event_store.events(source_id_1, 0).collect().wait().unwrap();
+
+        assert_eq!(events.iter().map(|e| e.source_id).collect::<Vec<_>>(),
+                   vec![source_id_1; 3]);
+        assert_eq!(events.iter().map(|e| e.sequence_number).collect::<Vec<_>>(),
+                   vec![0, 1, 2]);
+        assert!(events[0].offset < events[1].offset);
+        assert!(events[1].offset < events[2].offset);
+        assert_eq!(payloads(events), vec![b"A".to_vec(), b"B".to_vec(), b"C".to_vec()]);
+    }
+
+
+    #[test]
+    #[ignore]
+    fn events_after_offset() {
+        let event_store = test_event_store();
+        let source_id_1 = Uuid::new_v4();
+
+        event_store.append_events(source_id_1, Any, new_events(&["A", "B"])).wait().unwrap();
+
+        let events = event_store.events(source_id_1, 0).collect().wait().unwrap();
+        let events = event_store.events(source_id_1, events[1].offset).collect().wait().unwrap();
+
+        assert_eq!(payloads(events), vec![b"B".to_vec()]);
+    }
+
+
+    #[test]
+    #[ignore]
+    fn events_across_multiple_batches() {
+        let event_store = test_event_store();
+        let source_id_1 = Uuid::new_v4();
+        let payloads_1 = (0..(BATCH_SIZE * 2 + 3))
+            .map(|i| i.to_string().into_bytes())
+            .collect::<Vec<_>>();
+
+        let new_events_1 = payloads_1.iter()
+            .map(|payload| new_event(Metadata::new(), payload))
+            .collect();
+
+        event_store.append_events(source_id_1, Any, new_events_1).wait().unwrap();
+
+        let events = event_store.events(source_id_1, 0).collect().wait().unwrap();
+
+        assert_eq!(payloads(events), payloads_1);
+    }
+
+
+    #[test]
+    #[ignore]
+    fn append_events_with_wrong_expected_version() {
+        let event_store = test_event_store();
+        let source_id_1 = Uuid::new_v4();
+
+        event_store.append_events(source_id_1, NoStream, new_events(&["A"])).wait().unwrap();
+        event_store.append_events(source_id_1, Exact(0), new_events(&["B"])).wait().unwrap();
+
+        match event_store.append_events(source_id_1, Exact(0), new_events(&["C"])).wait() {
+            Err(AppendError::WrongExpectedVersion(err)) => {
+                assert_eq!(err,
+                           WrongExpectedVersion {
+                               source_id: source_id_1,
+                               expected: Exact(0),
+                               current: Some(1),
+                           })
+            },
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+
+    #[test]
+    #[ignore]
+    fn append_events_concurrently_with_the_same_expected_version() {
+        use std::thread;
+
+        PostgresEventStore::establish(&database_url()).unwrap().run_migrations().unwrap();
+
+        let source_id_1 = Uuid::new_v4();
+        let handles = (0..4).map(|_| {
+            thread::spawn(move || {
+                let connection = PgConnection::establish(&database_url()).unwrap();
+                let event_store = PostgresEventStore::new(connection);
+                event_store.append_events(source_id_1, NoStream, new_events(&["A"])).wait()
+            })
+        });
+
+        let successes = handles.collect::<Vec<_>>()
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .filter(Result::is_ok)
+            .count();
+
+        assert_eq!(successes, 1);
+    }
+
+
+    #[test]
+    #[ignore]
+    fn all_events_in_offset_order() {
+        let event_store = test_event_store();
+        let source_id_1 = Uuid::new_v4();
+        let source_id_2 = Uuid::new_v4();
+
+        event_store.append_events(source_id_1, Any, new_events(&["A"])).wait().unwrap();
+        event_store.append_events(source_id_2, Any, new_events(&["1"])).wait().unwrap();
+        event_store.append_events(source_id_1, Any, new_events(&["B"])).wait().unwrap();
+
+        // Other tests may be committing events concurrently, so ignore those
+        let first_offset = event_store.events(source_id_1, 0).collect().wait().unwrap()[0].offset;
+        let events = event_store.all_events(first_offset)
+            .filter(|e| e.source_id == source_id_1 || e.source_id == source_id_2)
+            .collect()
+            .wait()
+            .unwrap();
+
+        assert_eq!(events.iter().map(|e| (e.source_id, e.sequence_number)).collect::<Vec<_>>(),
+                   vec![(source_id_1, 0), (source_id_2, 0), (source_id_1, 1)]);
+        assert_eq!(payloads(events), vec![b"A".to_vec(), b"1".to_vec(), b"B".to_vec()]);
+    }
+
+
+    #[test]
+    #[ignore]
+    fn events_with_metadata() {
+        let event_store = test_event_store();
+        let source_id_1 = Uuid::new_v4();
+        let correlation_id = Uuid::new_v4();
+        let causation_id = Uuid::new_v4();
+        let metadata = Metadata::new()
+            .with_correlation_id(correlation_id)
+            .with_causation_id(causation_id)
+            .with_header("user", "alice");
+        let new_events_1 = vec![new_event(metadata.clone(), b"A")];
+
+        event_store.append_events(source_id_1, Any, new_events_1).wait().unwrap();
+
+        let events = event_store.events(source_id_1, 0).collect().wait().unwrap();
+
+        assert_eq!(events[0].metadata.event_id, metadata.event_id);
+        assert_eq!(events[0].metadata.correlation_id, Some(correlation_id));
+        assert_eq!(events[0].metadata.causation_id, Some(causation_id));
+        assert_eq!(events[0].metadata.headers, metadata.headers);
+    }
+
+
+    #[test]
+    #[ignore]
+    fn append_events_with_duplicate_event_id() {
+        let event_store = test_event_store();
+        let source_id_1 = Uuid::new_v4();
+        let metadata = Metadata::new();
+        let new_events_1 = vec![new_event(metadata.clone(), b"A")];
+        let new_events_2 = vec![new_event(metadata, b"B")];
+
+        event_store.append_events(source_id_1, Any, new_events_1).wait().unwrap();
+
+        match event_store.append_events(source_id_1, Any, new_events_2).wait() {
+            Err(AppendError::Database(_)) => {},
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+
+    #[test]
+    #[ignore]
+    fn all_events_of_types() {
+        let event_store = test_event_store();
+        let source_id_1 = Uuid::new_v4();
+        let new_event = |event_type, payload: &[u8]| {
+            NewEvent::with_type(event_type, 1, Metadata::new(), payload.to_vec())
+        };
+
+        let new_events_1 = vec![new_event("test_created", b"A"), new_event("test_completed", b"B")];
+        event_store.append_events(source_id_1, Any, new_events_1).wait().unwrap();
+
+        let first_offset = event_store.events(source_id_1, 0).collect().wait().unwrap()[0].offset;
+        let events = event_store.all_events_of_types(&["test_completed"], first_offset)
+            .collect()
+            .wait()
+            .unwrap();
+
+        assert_eq!(events.iter().map(|e| (&e.event_type[..], e.event_version)).collect::<Vec<_>>(),
+                   vec![("test_completed", 1)]);
+        assert_eq!(payloads(events), vec![b"B".to_vec()]);
+    }
+
+
+    /// Collects the payloads of the events that it publishes
+    struct Payloads(RefCell<Vec<Vec<u8>>>);
+
+    impl Publisher for Payloads {
+        type Offset = i64;
+        type Event = Vec<u8>;
+        type Error = ();
+        type PublishFuture = Result<(), ()>;
+
+        fn publish(&self, event: PersistedEvent<i64, Vec<u8>>) -> Result<(), ()> {
+            self.0.borrow_mut().push(event.payload);
+            Ok(())
+        }
+    }
+
+
+    #[test]
+    #[ignore]
+    fn record_appended_events_in_outbox() {
+        let event_store = test_event_store().with_outbox();
+        let source_id_1 = Uuid::new_v4();
+
+        event_store.append_events(source_id_1, Any, new_events(&["A", "B"])).wait().unwrap();
+        // Events that fail to be appended are not recorded
+        event_store.append_events(source_id_1, NoStream, new_events(&["X"])).wait().unwrap_err();
+
+        let pending = event_store.pending(10).wait().unwrap();
+        assert_eq!(payloads(pending.clone()), vec![b"A".to_vec(), b"B".to_vec()]);
+
+        event_store.acknowledge(pending[0].offset).wait().unwrap();
+        assert_eq!(payloads(event_store.pending(10).wait().unwrap()), vec![b"B".to_vec()]);
+    }
+
+
+    #[test]
+    #[ignore]
+    fn relay_outbox() {
+        let event_store = test_event_store().with_outbox();
+        let publisher = Payloads(RefCell::new(Vec::new()));
+
+        event_store.append_events(Uuid::new_v4(), Any, new_events(&["A", "B"])).wait().unwrap();
+        event_store.append_events(Uuid::new_v4(), Any, new_events(&["1"])).wait().unwrap();
+
+        assert_eq!(outbox::relay(&event_store, &publisher).wait(), Ok(3));
+        assert_eq!(*publisher.0.borrow(), vec![b"A".to_vec(), b"B".to_vec(), b"1".to_vec()]);
+        assert_eq!(event_store.pending(10).wait().unwrap(), vec![]);
+    }
+
+
+    #[test]
+    #[ignore]
+    fn outbox_is_disabled_by_default() {
+        let event_store = test_event_store();
+
+        event_store.append_events(Uuid::new_v4(), Any, new_events(&["A"])).wait().unwrap();
+
+        assert_eq!(event_store.pending(10).wait().unwrap(), vec![]);
+    }
+
+
+    /// A schema that is created for a single test, so that its events can be
+    /// committed without affecting other tests. The schema is dropped along
+    /// with everything in it once the last handle to it is dropped.
+    struct ScratchSchema {
+        name: String,
+    }
+
+
+    impl ScratchSchema {
+        fn create() -> ScratchSchema {
+            let schema = ScratchSchema { name: format!("scratch_{}", Uuid::new_v4().simple()) };
+            let connection = PgConnection::establish(&database_url()).unwrap();
+            connection.execute(&format!("CREATE SCHEMA {}", schema.name)).unwrap();
+            schema
+        }
+
+        /// Open a new connection that uses the schema
+        fn connect(&self) -> PgConnection {
+            let connection = PgConnection::establish(&database_url()).unwrap();
+            connection.execute(&format!("SET search_path TO {}", self.name)).unwrap();
+            connection
+        }
+    }
+
+
+    impl Drop for ScratchSchema {
+        fn drop(&mut self) {
+            // Errors are ignored, because panicking while a failed test is
+            // unwinding would abort the test run
+            if let Ok(connection) = PgConnection::establish(&database_url()) {
+                let _ = connection.execute(&format!("DROP SCHEMA {} CASCADE", self.name));
+            }
+        }
+    }
+
+
+    /// An event store in a scratch schema, where appends are committed for
+    /// real. Each clone has its own connection, so concurrent writers are
+    /// isolated from each other by Postgres rather than by sharing a
+    /// connection.
+    struct ScratchEventStore {
+        schema: Arc<ScratchSchema>,
+        event_store: PostgresEventStore,
+    }
+
+
+    impl ScratchEventStore {
+        fn new() -> ScratchEventStore {
+            let schema = ScratchSchema::create();
+            let event_store = PostgresEventStore::new(schema.connect());
+            event_store.run_migrations().unwrap();
+
+            ScratchEventStore {
+                schema: Arc::new(schema),
+                event_store: event_store,
+            }
+        }
+    }
+
+
+    impl Clone for ScratchEventStore {
+        fn clone(&self) -> ScratchEventStore {
+            ScratchEventStore {
+                schema: self.schema.clone(),
+                event_store: PostgresEventStore::new(self.schema.connect()),
+            }
+        }
+    }
+
+
+    impl EventStore for ScratchEventStore {
+        type Offset = i64;
+        type Event = Vec<u8>;
+        type AppendError = AppendError;
+        type AppendFuture = FutureResult<(), AppendError>;
+        type EventsStream = EventsStream;
+        type AllEventsStream = EventsStream;
+
+        fn append_events(&self,
+                         source_id: Uuid,
+                         expected_version: ExpectedVersion,
+                         events: Vec<NewEvent<Vec<u8>>>)
+                         -> FutureResult<(), AppendError> {
+            self.event_store.append_events(source_id, expected_version, events)
+        }
+
+        fn append_events_idempotent(&self,
+                                    idempotency_key: Uuid,
+                                    source_id: Uuid,
+                                    expected_version: ExpectedVersion,
+                                    events: Vec<NewEvent<Vec<u8>>>)
+                                    -> FutureResult<(), AppendError> {
+            self.event_store
+                .append_events_idempotent(idempotency_key, source_id, expected_version, events)
+        }
+
+        fn append_transaction(&self,
+                              transaction: Transaction<Vec<u8>>)
+                              -> FutureResult<(), AppendError> {
+            self.event_store.append_transaction(transaction)
+        }
+
+        fn events(&self, source_id: Uuid, offset: i64) -> EventsStream {
+            self.event_store.events(source_id, offset)
+        }
+
+        fn events_from(&self, source_id: Uuid, sequence_number: SequenceNumber) -> EventsStream {
+            self.event_store.events_from(source_id, sequence_number)
+        }
+
+        fn all_events(&self, offset: i64) -> EventsStream {
+            self.event_store.all_events(offset)
+        }
+
+        fn all_events_of_types(&self, event_types: &[&str], offset: i64) -> EventsStream {
+            self.event_store.all_events_of_types(event_types, offset)
+        }
+    }
+
+
+    mod conformance {
+        use super::ScratchEventStore;
+
+
+        event_store_conformance_tests! {
+            #[ignore]
+            new_store: ScratchEventStore::new(),
+            payload: |i| vec![i as u8],
+        }
+    }
+}
@@ -1,15 +1,23 @@
-use diesel::data_types::PgTimestamp;
+use serde_json::Value;
+use std::time::SystemTime;
 use uuid::Uuid;
 
-use schema::events;
+use schema::{checkpoints, deadlines, events, idempotency_keys, outbox};
 
 
-#[derive(Debug, Clone, Copy, Insertable)]
+#[derive(Debug, Clone, Insertable)]
 #[table_name="events"]
 pub struct NewEvent<'a> {
     pub source_id: Uuid,
     pub sequence_number: i64,
     pub payload: &'a [u8],
+    pub created_at: SystemTime,
+    pub event_id: Uuid,
+    pub correlation_id: Option<Uuid>,
+    pub causation_id: Option<Uuid>,
+    pub headers: Value,
+    pub event_type: &'a str,
+    pub event_version: i32,
 }
 
 
@@ -19,5 +27,51 @@ pub struct Event {
     pub source_id: Uuid,
     pub sequence_number: i64,
     pub payload: Vec<u8>,
-    pub created_at: PgTimestamp,
+    pub created_at: SystemTime,
+    pub event_id: Uuid,
+    pub correlation_id: Option<Uuid>,
+    pub causation_id: Option<Uuid>,
+    pub headers: Value,
+    pub event_type: String,
+    pub event_version: i32,
+}
+
+
+#[derive(Debug, Clone, Copy, Insertable)]
+#[table_name="checkpoints"]
+pub struct NewCheckpoint<'a> {
+    pub projection_id: &'a str,
+    pub offset: i64,
+}
+
+
+#[derive(Debug, Clone, Copy, Insertable)]
+#[table_name="deadlines"]
+pub struct NewDeadline<'a> {
+    pub deadline_id: Uuid,
+    pub due_at: SystemTime,
+    pub command: &'a [u8],
+}
+
+
+#[derive(Debug, Clone, Queryable)]
+pub struct Deadline {
+    pub deadline_id: Uuid,
+    pub due_at: SystemTime,
+    pub command: Vec<u8>,
+}
+
+
+#[derive(Debug, Clone, Copy, Insertable)]
+#[table_name="outbox"]
+pub struct NewOutboxEntry {
+    pub offset: i64,
+}
+
+
+#[derive(Debug, Clone, Copy, Insertable)]
+#[table_name="idempotency_keys"]
+pub struct NewIdempotencyKey {
+    pub idempotency_key: Uuid,
+    pub source_id: Uuid,
 }
@@ -5,5 +5,40 @@ table! {
         sequence_number -> BigInt,
         payload -> Binary,
         created_at -> Timestamp,
+        event_id -> Uuid,
+        correlation_id -> Nullable<Uuid>,
+        causation_id -> Nullable<Uuid>,
+        headers -> Jsonb,
+        event_type -> Text,
+        event_version -> Integer,
+    }
+}
+
+table! {
+    checkpoints(projection_id) {
+        projection_id -> Text,
+        offset -> BigInt,
+    }
+}
+
+table! {
+    deadlines(deadline_id) {
+        deadline_id -> Uuid,
+        due_at -> Timestamp,
+        command -> Binary,
+    }
+}
+
+table! {
+    outbox(offset) {
+        offset -> BigInt,
+    }
+}
+
+table! {
+    idempotency_keys(source_id, idempotency_key) {
+        idempotency_key -> Uuid,
+        source_id -> Uuid,
+        created_at -> Timestamp,
     }
 }
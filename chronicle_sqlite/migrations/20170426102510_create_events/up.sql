@@ -0,0 +1,17 @@
+CREATE TABLE events (
+  "offset" INTEGER PRIMARY KEY AUTOINCREMENT,
+  source_id BLOB NOT NULL,
+  sequence_number INTEGER NOT NULL,
+  payload BLOB NOT NULL,
+  created_at INTEGER NOT NULL,
+  event_id BLOB NOT NULL,
+  correlation_id BLOB,
+  causation_id BLOB,
+  headers TEXT NOT NULL DEFAULT '{}',
+  event_type TEXT NOT NULL,
+  event_version INTEGER NOT NULL
+);
+
+CREATE UNIQUE INDEX events_source_id_sequence_number_idx ON events (source_id, sequence_number);
+CREATE UNIQUE INDEX events_event_id_idx ON events (event_id);
+CREATE INDEX events_event_type_idx ON events (event_type, "offset");
@@ -0,0 +1,4 @@
+CREATE TABLE idempotency_keys (
+  idempotency_key BLOB PRIMARY KEY NOT NULL,
+  source_id BLOB NOT NULL
+);
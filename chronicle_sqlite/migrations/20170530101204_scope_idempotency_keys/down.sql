@@ -0,0 +1,8 @@
+CREATE TABLE global_idempotency_keys (
+  idempotency_key BLOB PRIMARY KEY NOT NULL,
+  source_id BLOB NOT NULL
+);
+
+INSERT INTO global_idempotency_keys SELECT idempotency_key, source_id FROM idempotency_keys;
+DROP TABLE idempotency_keys;
+ALTER TABLE global_idempotency_keys RENAME TO idempotency_keys;
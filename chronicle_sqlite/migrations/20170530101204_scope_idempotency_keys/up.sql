@@ -0,0 +1,9 @@
+CREATE TABLE scoped_idempotency_keys (
+  idempotency_key BLOB NOT NULL,
+  source_id BLOB NOT NULL,
+  PRIMARY KEY(source_id, idempotency_key)
+);
+
+INSERT INTO scoped_idempotency_keys SELECT idempotency_key, source_id FROM idempotency_keys;
+DROP TABLE idempotency_keys;
+ALTER TABLE scoped_idempotency_keys RENAME TO idempotency_keys;
@@ -0,0 +1,706 @@
+//! A SQLite backed event store, useful for integration tests and embedded
+//! deployments that can't rely on a Postgres server
+//!
+//! The events are stored in an `events` table with the same shape as the one
+//! used by `chronicle_postgres`. Appends are performed inside of an immediate
+//! transaction, which takes the database's write lock before the expected
+//! version is checked, so the check is atomic with the write even when the
+//! database is shared between multiple connections or processes.
+//!
+//! # Example
+//!
+//! ```rust
+//! extern crate chronicle;
+//! extern crate chronicle_sqlite;
+//! extern crate futures;
+//! extern crate uuid;
+//!
+//!
+//! use chronicle::{EventStore, ExpectedVersion, Metadata, NewEvent};
+//! use chronicle_sqlite::SqliteEventStore;
+//! use futures::{Future, Stream};
+//! use uuid::Uuid;
+//!
+//!
+//! fn main() {
+//!     let event_store = SqliteEventStore::establish(":memory:").unwrap();
+//!     event_store.run_migrations().unwrap();
+//!
+//!     let source_id = Uuid::new_v4();
+//!     let event = NewEvent::with_type("greeted", 0, Metadata::new(), b"hi".to_vec());
+//!
+//!     event_store.append_events(source_id, ExpectedVersion::NoStream, vec![event])
+//!         .wait()
+//!         .unwrap();
+//!
+//!     let payloads = event_store.all_events(0).map(|e| e.payload).collect().wait();
+//!
+//!     assert_eq!(payloads.unwrap(), vec![b"hi".to_vec()]);
+//! }
+//! ```
+
+
+#[cfg_attr(test, macro_use)]
+extern crate chronicle;
+#[macro_use]
+extern crate diesel;
+#[macro_use]
+extern crate diesel_codegen;
+extern crate futures;
+extern crate serde_json;
+#[cfg(test)]
+extern crate tempdir;
+extern crate uuid;
+
+
+pub mod models;
+pub mod schema;
+
+
+embed_migrations!("migrations");
+
+
+use chronicle::{EventStore, EventVersion, ExpectedVersion, Metadata, NewEvent, PersistedEvent};
+use chronicle::{AsWrongExpectedVersion, SequenceNumber, Transaction, WrongExpectedVersion};
+use diesel::connection::SimpleConnection;
+use diesel::migrations::RunMigrationsError;
+use diesel::prelude::*;
+use diesel::result::ConnectionError;
+use diesel::result::Error as DieselError;
+use diesel::sqlite::SqliteConnection;
+use futures::{Async, Poll, Stream};
+use futures::future::{self, FutureResult};
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+use schema::{events, idempotency_keys};
+
+
+/// The number of rows to fetch at a time when streaming events
+const BATCH_SIZE: i64 = 256;
+
+/// How long to wait for other connections to release their locks on the
+/// database before giving up, in milliseconds
+const BUSY_TIMEOUT_MS: u64 = 5000;
+
+
+/// An event store that persists events to the `events` table of a SQLite
+/// database
+#[derive(Clone)]
+pub struct SqliteEventStore {
+    connection: Arc<Mutex<SqliteConnection>>,
+}
+
+
+impl SqliteEventStore {
+    /// Create an event store using an existing connection
+    pub fn new(connection: SqliteConnection) -> SqliteEventStore {
+        SqliteEventStore { connection: Arc::new(Mutex::new(connection)) }
+    }
+
+    /// Open the database at the given url, creating it if it does not exist.
+    /// This may either be a path, or `:memory:` for a new, private,
+    /// in-memory database.
+    pub fn establish(database_url: &str) -> ConnectionResult<SqliteEventStore> {
+        let connection = SqliteConnection::establish(database_url)?;
+
+        connection.batch_execute(&format!("PRAGMA busy_timeout = {}", BUSY_TIMEOUT_MS))
+            .map_err(|err| ConnectionError::BadConnection(err.to_string()))?;
+
+        Ok(SqliteEventStore::new(connection))
+    }
+
+    /// Run any pending migrations that are needed for the event store
+    pub fn run_migrations(&self) -> Result<(), RunMigrationsError> {
+        let connection = self.connection.lock().unwrap();
+        embedded_migrations::run(&*connection)
+    }
+
+    /// The sequence number of the last event for the source, or `None` if
+    /// no events have been stored for it yet
+    fn current_version(connection: &SqliteConnection,
+                       source_id: Uuid)
+                       -> Result<Option<SequenceNumber>, DieselError> {
+        use diesel::expression::dsl::max;
+
+        events::table.select(max(events::sequence_number))
+            .filter(events::source_id.eq(&source_id.as_bytes()[..]))
+            .first::<Option<i64>>(connection)
+            .map(|seq| seq.map(|seq| seq as SequenceNumber))
+    }
+
+    /// Whether a batch with the idempotency key has already been appended to
+    /// the source
+    fn has_idempotency_key(connection: &SqliteConnection,
+                           source_id: Uuid,
+                           idempotency_key: Uuid)
+                           -> Result<bool, DieselError> {
+        idempotency_keys::table.filter(idempotency_keys::source_id.eq(&source_id.as_bytes()[..]))
+            .filter(idempotency_keys::idempotency_key.eq(&idempotency_key.as_bytes()[..]))
+            .select(idempotency_keys::idempotency_key)
+            .first::<Vec<u8>>(connection)
+            .optional()
+            .map(|key| key.is_some())
+    }
+
+    /// Insert the events for the source after its current version
+    fn insert_events(connection: &SqliteConnection,
+                     source_id: Uuid,
+                     current: Option<SequenceNumber>,
+                     events: &[NewEvent<Vec<u8>>])
+                     -> Result<(), DieselError> {
+        let first_sequence_number = current.map_or(0, |seq| seq as i64 + 1);
+        let new_events = events.iter()
+            .enumerate()
+            .map(|(i, event)| {
+                let metadata = &event.metadata;
+
+                models::NewEvent {
+                    source_id: &source_id.as_bytes()[..],
+                    sequence_number: first_sequence_number + i as i64,
+                    payload: &event.payload,
+                    created_at: encode_time(metadata.recorded_at),
+                    event_id: &metadata.event_id.as_bytes()[..],
+                    correlation_id: metadata.correlation_id.as_ref().map(|id| &id.as_bytes()[..]),
+                    causation_id: metadata.causation_id.as_ref().map(|id| &id.as_bytes()[..]),
+                    headers: encode_headers(&metadata.headers),
+                    event_type: &event.event_type,
+                    event_version: event.event_version as i32,
+                }
+            })
+            .collect::<Vec<_>>();
+
+        diesel::insert(&new_events).into(events::table).execute(connection)?;
+
+        Ok(())
+    }
+
+    fn try_append_events(&self,
+                         idempotency_key: Option<Uuid>,
+                         source_id: Uuid,
+                         expected_version: ExpectedVersion,
+                         events: &[NewEvent<Vec<u8>>])
+                         -> Result<(), AppendError> {
+        let connection = self.connection.lock().unwrap();
+
+        immediate_transaction(&connection, || {
+            if let Some(idempotency_key) = idempotency_key {
+                if SqliteEventStore::has_idempotency_key(&connection,
+                                                         source_id,
+                                                         idempotency_key)? {
+                    return Ok(());
+                }
+            }
+
+            let current = SqliteEventStore::current_version(&connection, source_id)?;
+
+            if !expected_version.is_satisfied_by(current) {
+                return Err(AppendError::from(WrongExpectedVersion {
+                    source_id: source_id,
+                    expected: expected_version,
+                    current: current,
+                }));
+            }
+
+            if events.is_empty() {
+                return Ok(());
+            }
+
+            SqliteEventStore::insert_events(&connection, source_id, current, events)?;
+
+            if let Some(idempotency_key) = idempotency_key {
+                let key = models::NewIdempotencyKey {
+                    idempotency_key: &idempotency_key.as_bytes()[..],
+                    source_id: &source_id.as_bytes()[..],
+                };
+
+                diesel::insert(&key).into(idempotency_keys::table).execute(&*connection)?;
+            }
+
+            Ok(())
+        })
+    }
+
+    fn try_append_transaction(&self,
+                              transaction: &Transaction<Vec<u8>>)
+                              -> Result<(), AppendError> {
+        let connection = self.connection.lock().unwrap();
+
+        // Each version check sees the events inserted for the preceding
+        // entries, so a source may appear more than once
+        immediate_transaction(&connection, || {
+            for entry in &transaction.entries {
+                let current = SqliteEventStore::current_version(&connection, entry.source_id)?;
+
+                if !entry.expected_version.is_satisfied_by(current) {
+                    return Err(AppendError::from(WrongExpectedVersion {
+                        source_id: entry.source_id,
+                        expected: entry.expected_version,
+                        current: current,
+                    }));
+                }
+
+                if !entry.events.is_empty() {
+                    SqliteEventStore::insert_events(&connection,
+                                                    entry.source_id,
+                                                    current,
+                                                    &entry.events)?;
+                }
+            }
+
+            Ok(())
+        })
+    }
+
+    /// Load a batch of events starting at the given offset, optionally
+    /// restricted to a single source id, starting at the given sequence
+    /// number, or to a set of event types
+    fn load_events(&self,
+                   source_id: Option<Uuid>,
+                   event_types: Option<&[String]>,
+                   sequence_number: i64,
+                   offset: i64)
+                   -> Result<Vec<models::Event>, DieselError> {
+        let connection = self.connection.lock().unwrap();
+
+        let mut query = events::table.filter(events::offset.ge(offset))
+            .order(events::offset.asc())
+            .limit(BATCH_SIZE)
+            .into_boxed();
+
+        if let Some(ref source_id) = source_id {
+            query = query.filter(events::source_id.eq(source_id.as_bytes().to_vec()))
+                .filter(events::sequence_number.ge(sequence_number));
+        }
+
+        if let Some(event_types) = event_types {
+            query = query.filter(events::event_type.eq_any(event_types));
+        }
+
+        query.load(&*connection)
+    }
+}
+
+
+/// Run the function inside of an immediate transaction. This takes the
+/// database's write lock up front, rather than on the first write, so that
+/// no other connection can append events between our version checks and the
+/// inserts. The transaction is rolled back if the function returns an error.
+fn immediate_transaction<T, F>(connection: &SqliteConnection, f: F) -> Result<T, AppendError>
+    where F: FnOnce() -> Result<T, AppendError>
+{
+    connection.batch_execute("BEGIN IMMEDIATE")?;
+
+    let result = f().and_then(|value| {
+        connection.batch_execute("COMMIT")?;
+        Ok(value)
+    });
+
+    if result.is_err() {
+        connection.batch_execute("ROLLBACK")?;
+    }
+
+    result
+}
+
+
+impl EventStore for SqliteEventStore {
+    type Offset = i64;
+    type Event = Vec<u8>;
+    type AppendError = AppendError;
+    type AppendFuture = FutureResult<(), AppendError>;
+    type EventsStream = EventsStream;
+    type AllEventsStream = EventsStream;
+
+    fn append_events(&self,
+                     source_id: Uuid,
+                     expected_version: ExpectedVersion,
+                     events: Vec<NewEvent<Vec<u8>>>)
+                     -> FutureResult<(), AppendError> {
+        future::result(self.try_append_events(None, source_id, expected_version, &events))
+    }
+
+    fn append_events_idempotent(&self,
+                                idempotency_key: Uuid,
+                                source_id: Uuid,
+                                expected_version: ExpectedVersion,
+                                events: Vec<NewEvent<Vec<u8>>>)
+                                -> FutureResult<(), AppendError> {
+        future::result(self.try_append_events(Some(idempotency_key),
+                                              source_id,
+                                              expected_version,
+                                              &events))
+    }
+
+    fn append_transaction(&self,
+                          transaction: Transaction<Vec<u8>>)
+                          -> FutureResult<(), AppendError> {
+        future::result(self.try_append_transaction(&transaction))
+    }
+
+    fn events(&self, source_id: Uuid, offset: i64) -> EventsStream {
+        EventsStream {
+            source_id: Some(source_id),
+            event_types: None,
+            sequence_number: 0,
+            offset: offset,
+            buffer: VecDeque::new(),
+            is_exhausted: false,
+            event_store: self.clone(),
+        }
+    }
+
+    fn events_from(&self, source_id: Uuid, sequence_number: SequenceNumber) -> EventsStream {
+        EventsStream {
+            source_id: Some(source_id),
+            event_types: None,
+            sequence_number: sequence_number as i64,
+            offset: 0,
+            buffer: VecDeque::new(),
+            is_exhausted: false,
+            event_store: self.clone(),
+        }
+    }
+
+    /// Unlike Postgres, SQLite only allows one writer at a time, so offsets
+    /// are always committed in order, and no events will be missed.
+    fn all_events(&self, offset: i64) -> EventsStream {
+        EventsStream {
+            source_id: None,
+            event_types: None,
+            sequence_number: 0,
+            offset: offset,
+            buffer: VecDeque::new(),
+            is_exhausted: false,
+            event_store: self.clone(),
+        }
+    }
+
+    fn all_events_of_types(&self, event_types: &[&str], offset: i64) -> EventsStream {
+        EventsStream {
+            source_id: None,
+            event_types: Some(event_types.iter().map(|ty| ty.to_string()).collect()),
+            sequence_number: 0,
+            offset: offset,
+            buffer: VecDeque::new(),
+            is_exhausted: false,
+            event_store: self.clone(),
+        }
+    }
+}
+
+
+/// An error that may be returned when appending to the `SqliteEventStore`
+#[derive(Debug)]
+pub enum AppendError {
+    /// An error that occurred when communicating with the database
+    Database(DieselError),
+    /// The source was not at the expected version
+    WrongExpectedVersion(WrongExpectedVersion),
+}
+
+
+impl From<DieselError> for AppendError {
+    fn from(src: DieselError) -> AppendError {
+        AppendError::Database(src)
+    }
+}
+
+
+impl From<WrongExpectedVersion> for AppendError {
+    fn from(src: WrongExpectedVersion) -> AppendError {
+        AppendError::WrongExpectedVersion(src)
+    }
+}
+
+
+impl AsWrongExpectedVersion for AppendError {
+    fn as_wrong_expected_version(&self) -> Option<&WrongExpectedVersion> {
+        match *self {
+            AppendError::Database(_) => None,
+            AppendError::WrongExpectedVersion(ref error) => Some(error),
+        }
+    }
+}
+
+
+/// A stream of events, loaded lazily in batches. This either contains the
+/// events for a specified source id, or all of the events in the store,
+/// optionally restricted to a set of event types.
+pub struct EventsStream {
+    source_id: Option<Uuid>,
+    event_types: Option<Vec<String>>,
+    /// The sequence number to start from, when streaming a single source
+    sequence_number: i64,
+    offset: i64,
+    buffer: VecDeque<models::Event>,
+    is_exhausted: bool,
+    event_store: SqliteEventStore,
+}
+
+
+impl Stream for EventsStream {
+    type Item = PersistedEvent<i64, Vec<u8>>;
+    type Error = DieselError;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, DieselError> {
+        if self.buffer.is_empty() && !self.is_exhausted {
+            let event_types = self.event_types.as_deref();
+            let events = self.event_store.load_events(self.source_id,
+                                                      event_types,
+                                                      self.sequence_number,
+                                                      self.offset)?;
+
+            self.is_exhausted = (events.len() as i64) < BATCH_SIZE;
+            if let Some(event) = events.last() {
+                self.offset = event.offset + 1;
+            }
+            self.buffer.extend(events);
+        }
+
+        match self.buffer.pop_front() {
+            Some(event) => Ok(Async::Ready(Some(persisted_event(event)?))),
+            None => Ok(Async::Ready(None)),
+        }
+    }
+}
+
+
+/// Convert a row from the `events` table into a persisted event
+fn persisted_event(event: models::Event) -> Result<PersistedEvent<i64, Vec<u8>>, DieselError> {
+    Ok(PersistedEvent {
+        offset: event.offset,
+        source_id: decode_uuid(&event.source_id)?,
+        sequence_number: event.sequence_number as SequenceNumber,
+        event_type: event.event_type,
+        event_version: event.event_version as EventVersion,
+        metadata: Metadata {
+            event_id: decode_uuid(&event.event_id)?,
+            recorded_at: decode_time(event.created_at),
+            correlation_id: match event.correlation_id {
+                Some(id) => Some(decode_uuid(&id)?),
+                None => None,
+            },
+            causation_id: match event.causation_id {
+                Some(id) => Some(decode_uuid(&id)?),
+                None => None,
+            },
+            headers: decode_headers(&event.headers)?,
+        },
+        payload: event.payload,
+    })
+}
+
+
+fn decode_uuid(bytes: &[u8]) -> Result<Uuid, DieselError> {
+    Uuid::from_bytes(bytes).map_err(|err| DieselError::DeserializationError(err.to_string().into()))
+}
+
+
+/// Times are stored as the number of nanoseconds since the unix epoch
+fn encode_time(time: SystemTime) -> i64 {
+    let duration = time.duration_since(UNIX_EPOCH).unwrap_or(Duration::new(0, 0));
+    duration.as_secs() as i64 * 1_000_000_000 + duration.subsec_nanos() as i64
+}
+
+
+fn decode_time(nanos: i64) -> SystemTime {
+    UNIX_EPOCH + Duration::new((nanos / 1_000_000_000) as u64, (nanos % 1_000_000_000) as u32)
+}
+
+
+/// Convert user supplied headers into a JSON object for storage
+fn encode_headers(headers: &BTreeMap<String, String>) -> String {
+    Value::Object(headers.iter()
+            .map(|(key, value)| (key.clone(), Value::String(value.clone())))
+            .collect::<Map<_, _>>())
+        .to_string()
+}
+
+
+/// Convert a stored JSON object back into user headers
+fn decode_headers(headers: &str) -> Result<BTreeMap<String, String>, DieselError> {
+    let headers = match serde_json::from_str(headers) {
+        Ok(Value::Object(headers)) => headers,
+        Ok(_) => return Err(DieselError::DeserializationError("headers must be an object".into())),
+        Err(err) => return Err(DieselError::DeserializationError(Box::new(err))),
+    };
+
+    headers.into_iter()
+        .map(|(key, value)| match value {
+            Value::String(value) => Ok((key, value)),
+            _ => Err(DieselError::DeserializationError("header values must be strings".into())),
+        })
+        .collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use chronicle::{EventStore, PersistedEvent};
+    use chronicle::ExpectedVersion::*;
+    use futures::{Future, Stream};
+    use tempdir::TempDir;
+    use uuid::Uuid;
+
+    use super::*;
+
+
+    fn test_event_store() -> SqliteEventStore {
+        let event_store = SqliteEventStore::establish(":memory:").unwrap();
+        event_store.run_migrations().unwrap();
+        event_store
+    }
+
+
+    fn new_event(metadata: Metadata, payload: &[u8]) -> NewEvent<Vec<u8>> {
+        NewEvent::with_type("test", 0, metadata, payload.to_vec())
+    }
+
+
+    fn new_events(payloads: &[&str]) -> Vec<NewEvent<Vec<u8>>> {
+        payloads.iter().map(|payload| new_event(Metadata::new(), payload.as_bytes())).collect()
+    }
+
+
+    fn payloads(events: Vec<PersistedEvent<i64, Vec<u8>>>) -> Vec<Vec<u8>> {
+        events.into_iter().map(|event| event.payload).collect()
+    }
+
+
+    #[test]
+    fn run_migrations_twice() {
+        let event_store = test_event_store();
+        event_store.run_migrations().unwrap();
+    }
+
+
+    #[test]
+    fn events_with_metadata() {
+        let event_store = test_event_store();
+        let source_id_1 = Uuid::new_v4();
+        let correlation_id = Uuid::new_v4();
+        let metadata = Metadata::new()
+            .with_correlation_id(correlation_id)
+            .with_header("user", "alice");
+        let new_events_1 = vec![NewEvent::with_type("a", 2, metadata.clone(), b"A".to_vec())];
+
+        event_store.append_events(source_id_1, Any, new_events_1).wait().unwrap();
+
+        let events = event_store.events(source_id_1, 0).collect().wait().unwrap();
+
+        assert_eq!(events,
+                   vec![PersistedEvent {
+                            offset: 1,
+                            source_id: source_id_1,
+                            sequence_number: 0,
+                            event_type: "a".to_string(),
+                            event_version: 2,
+                            metadata: metadata,
+                            payload: b"A".to_vec(),
+                        }]);
+    }
+
+
+    #[test]
+    fn append_events_with_duplicate_event_id() {
+        let event_store = test_event_store();
+        let source_id_1 = Uuid::new_v4();
+        let metadata = Metadata::new();
+
+        event_store.append_events(source_id_1, Any, vec![new_event(metadata.clone(), b"A")])
+            .wait()
+            .unwrap();
+
+        match event_store.append_events(Uuid::new_v4(), Any, vec![new_event(metadata, b"B")])
+            .wait() {
+            Err(AppendError::Database(_)) => {},
+            result => panic!("unexpected result: {:?}", result),
+        }
+
+        // Nothing from the failed transaction should have been persisted
+        let events = event_store.all_events(0).collect().wait().unwrap();
+        assert_eq!(payloads(events), vec![b"A".to_vec()]);
+    }
+
+
+    #[test]
+    fn all_events_of_types() {
+        let event_store = test_event_store();
+        let events = vec![NewEvent::with_type("a", 0, Metadata::new(), b"A".to_vec()),
+                          NewEvent::with_type("b", 0, Metadata::new(), b"B".to_vec()),
+                          NewEvent::with_type("c", 0, Metadata::new(), b"C".to_vec())];
+
+        event_store.append_events(Uuid::new_v4(), NoStream, events).wait().unwrap();
+
+        let events = event_store.all_events_of_types(&["a", "c"], 0).collect().wait().unwrap();
+        assert_eq!(payloads(events), vec![b"A".to_vec(), b"C".to_vec()]);
+    }
+
+
+    #[test]
+    fn events_across_multiple_batches() {
+        let event_store = test_event_store();
+        let source_id_1 = Uuid::new_v4();
+        let payloads_1 = (0..(BATCH_SIZE * 2 + 3))
+            .map(|i| i.to_string().into_bytes())
+            .collect::<Vec<_>>();
+
+        let new_events_1 = payloads_1.iter()
+            .map(|payload| new_event(Metadata::new(), payload))
+            .collect();
+
+        event_store.append_events(source_id_1, Any, new_events_1).wait().unwrap();
+
+        let events = event_store.events(source_id_1, 0).collect().wait().unwrap();
+
+        assert_eq!(payloads(events), payloads_1);
+    }
+
+
+    #[test]
+    fn append_events_from_multiple_connections() {
+        let dir = TempDir::new("chronicle_sqlite").unwrap();
+        let path = dir.path().join("events.db");
+        let path = path.to_str().unwrap();
+        let event_store_1 = SqliteEventStore::establish(path).unwrap();
+        let event_store_2 = SqliteEventStore::establish(path).unwrap();
+        let source_id_1 = Uuid::new_v4();
+
+        event_store_1.run_migrations().unwrap();
+        event_store_2.run_migrations().unwrap();
+
+        event_store_1.append_events(source_id_1, NoStream, new_events(&["A"])).wait().unwrap();
+
+        match event_store_2.append_events(source_id_1, NoStream, new_events(&["B"])).wait() {
+            Err(AppendError::WrongExpectedVersion(err)) => {
+                assert_eq!(err,
+                           WrongExpectedVersion {
+                               source_id: source_id_1,
+                               expected: NoStream,
+                               current: Some(0),
+                           })
+            },
+            result => panic!("unexpected result: {:?}", result),
+        }
+
+        event_store_2.append_events(source_id_1, Exact(0), new_events(&["C"])).wait().unwrap();
+
+        let events = event_store_1.events(source_id_1, 0).collect().wait().unwrap();
+        assert_eq!(payloads(events), vec![b"A".to_vec(), b"C".to_vec()]);
+    }
+
+
+    mod conformance {
+        use super::test_event_store;
+
+
+        event_store_conformance_tests! {
+            new_store: test_event_store(),
+            payload: |i| vec![i as u8],
+        }
+    }
+}
@@ -0,0 +1,44 @@
+//! SQLite has no native UUID or timestamp types, so UUIDs are stored as
+//! blobs, and times as the number of nanoseconds since the unix epoch.
+
+use schema::{events, idempotency_keys};
+
+
+#[derive(Debug, Clone, Insertable)]
+#[table_name="events"]
+pub struct NewEvent<'a> {
+    pub source_id: &'a [u8],
+    pub sequence_number: i64,
+    pub payload: &'a [u8],
+    pub created_at: i64,
+    pub event_id: &'a [u8],
+    pub correlation_id: Option<&'a [u8]>,
+    pub causation_id: Option<&'a [u8]>,
+    pub headers: String,
+    pub event_type: &'a str,
+    pub event_version: i32,
+}
+
+
+#[derive(Debug, Clone, Queryable)]
+pub struct Event {
+    pub offset: i64,
+    pub source_id: Vec<u8>,
+    pub sequence_number: i64,
+    pub payload: Vec<u8>,
+    pub created_at: i64,
+    pub event_id: Vec<u8>,
+    pub correlation_id: Option<Vec<u8>>,
+    pub causation_id: Option<Vec<u8>>,
+    pub headers: String,
+    pub event_type: String,
+    pub event_version: i32,
+}
+
+
+#[derive(Debug, Clone, Copy, Insertable)]
+#[table_name="idempotency_keys"]
+pub struct NewIdempotencyKey<'a> {
+    pub idempotency_key: &'a [u8],
+    pub source_id: &'a [u8],
+}
@@ -0,0 +1,22 @@
+table! {
+    events(offset) {
+        offset -> BigInt,
+        source_id -> Binary,
+        sequence_number -> BigInt,
+        payload -> Binary,
+        created_at -> BigInt,
+        event_id -> Binary,
+        correlation_id -> Nullable<Binary>,
+        causation_id -> Nullable<Binary>,
+        headers -> Text,
+        event_type -> Text,
+        event_version -> Integer,
+    }
+}
+
+table! {
+    idempotency_keys(source_id, idempotency_key) {
+        idempotency_key -> Binary,
+        source_id -> Binary,
+    }
+}
@@ -1,10 +1,11 @@
-use chronicle_domain::Aggregate;
+use std::collections::HashSet;
 use uuid::Uuid;
 
 use super::Money;
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize, EventType, ApplyEvent)]
 #[serde(tag = "type", rename_all = "snake_case")]
+#[apply_event(state = "State")]
 pub enum Event {
     AccountOpened { initial_balance: Money },
     MoneyDeposited {
@@ -17,6 +18,12 @@ pub enum Event {
         amount: Money,
         balance: Money,
     },
+    MoneyDepositRejected { transfer_id: Uuid, amount: Money },
+    MoneyRefunded {
+        transfer_id: Uuid,
+        amount: Money,
+        balance: Money,
+    },
     AccountOverdrawn { balance: Money },
     AccountClosed,
 }
@@ -26,11 +33,16 @@ pub enum Command {
     OpenAccount { initial_balance: Money },
     DepositMoney { transfer_id: Uuid, amount: Money },
     WithdrawMoney { transfer_id: Uuid, amount: Money },
+    RefundMoney { transfer_id: Uuid, amount: Money },
     CloseAccount,
 }
 
 #[derive(Debug, Clone)]
-pub enum CommandError {}
+pub enum CommandError {
+    NotYetOpened,
+    AlreadyOpened,
+    AccountClosed,
+}
 
 #[derive(Debug, Clone)]
 pub enum Status {
@@ -42,28 +54,130 @@ pub enum Status {
 pub struct State {
     pub balance: Money,
     pub status: Status,
+    /// The transfers that have been withdrawn from the account, allowing
+    /// duplicate commands from the transfer saga to be ignored
+    pub withdrawals: HashSet<Uuid>,
+    /// The transfers that have been deposited into, or rejected by, the
+    /// account
+    pub deposits: HashSet<Uuid>,
+    /// The transfers that have been refunded to the account
+    pub refunds: HashSet<Uuid>,
 }
 
+impl State {
+    fn apply_account_opened(&mut self, _initial_balance: Money) {}
+
+    fn apply_money_deposited(&mut self, transfer_id: Uuid, _amount: Money, balance: Money) {
+        self.deposits.insert(transfer_id);
+        self.balance = balance;
+    }
+
+    fn apply_money_withdrawn(&mut self, transfer_id: Uuid, _amount: Money, balance: Money) {
+        self.withdrawals.insert(transfer_id);
+        self.balance = balance;
+    }
+
+    fn apply_money_deposit_rejected(&mut self, transfer_id: Uuid, _amount: Money) {
+        self.deposits.insert(transfer_id);
+    }
+
+    fn apply_money_refunded(&mut self, transfer_id: Uuid, _amount: Money, balance: Money) {
+        self.refunds.insert(transfer_id);
+        self.balance = balance;
+    }
+
+    fn apply_account_overdrawn(&mut self, _balance: Money) {}
+
+    fn apply_account_closed(&mut self) {
+        self.status = Status::Closed;
+    }
+}
+
+#[derive(Aggregate)]
+#[aggregate(state = "State", event = "Event", command = "Command",
+            command_error = "CommandError")]
 pub struct Account;
 
-impl Aggregate for Account {
-    type State = Option<State>;
-    type Event = Event;
-    type Command = Command;
-    type CommandError = CommandError;
-    type EventsFuture = Result<Vec<Event>, CommandError>;
+impl Account {
+    fn create(command: Command) -> Result<Vec<Event>, CommandError> {
+        match command {
+            Command::OpenAccount { initial_balance } => {
+                Ok(vec![Event::AccountOpened { initial_balance: initial_balance }])
+            },
+            _ => Err(CommandError::NotYetOpened),
+        }
+    }
 
-    fn initial_state() -> Option<State> {
-        None
+    fn handle(state: &State, command: Command) -> Result<Vec<Event>, CommandError> {
+        match command {
+            // Ignore transfers that have already been applied
+            Command::DepositMoney { transfer_id, .. } if state.deposits.contains(&transfer_id) => {
+                Ok(vec![])
+            },
+            Command::WithdrawMoney { transfer_id, .. } if state.withdrawals
+                .contains(&transfer_id) => Ok(vec![]),
+            Command::RefundMoney { transfer_id, .. } if state.refunds.contains(&transfer_id) => {
+                Ok(vec![])
+            },
+            // Reject deposits to closed accounts, allowing the transfer to be
+            // rolled back
+            Command::DepositMoney { transfer_id, amount } if is_closed(state) => {
+                Ok(vec![Event::MoneyDepositRejected {
+                            transfer_id: transfer_id,
+                            amount: amount,
+                        }])
+            },
+            _ if is_closed(state) => Err(CommandError::AccountClosed),
+            Command::OpenAccount { .. } => Err(CommandError::AlreadyOpened),
+            Command::DepositMoney { transfer_id, amount } => {
+                Ok(vec![Event::MoneyDeposited {
+                            transfer_id: transfer_id,
+                            amount: amount,
+                            balance: state.balance + amount,
+                        }])
+            },
+            Command::WithdrawMoney { transfer_id, amount } => {
+                let balance = state.balance - amount;
+                let mut events = vec![Event::MoneyWithdrawn {
+                                          transfer_id: transfer_id,
+                                          amount: amount,
+                                          balance: balance,
+                                      }];
+                if balance < 0 {
+                    events.push(Event::AccountOverdrawn { balance: balance });
+                }
+                Ok(events)
+            },
+            Command::RefundMoney { transfer_id, amount } => {
+                Ok(vec![Event::MoneyRefunded {
+                            transfer_id: transfer_id,
+                            amount: amount,
+                            balance: state.balance + amount,
+                        }])
+            },
+            Command::CloseAccount => Ok(vec![Event::AccountClosed]),
+        }
     }
 
-    fn handle_command(_state: &Option<State>,
-                      _command: Command)
-                      -> Result<Vec<Event>, CommandError> {
-        unimplemented!()
+    fn created(event: Event) -> Option<State> {
+        match event {
+            Event::AccountOpened { initial_balance } => {
+                Some(State {
+                    balance: initial_balance,
+                    status: Status::Active,
+                    withdrawals: HashSet::new(),
+                    deposits: HashSet::new(),
+                    refunds: HashSet::new(),
+                })
+            },
+            _ => None,
+        }
     }
+}
 
-    fn apply_event(_state: &mut Option<State>, _command: Event) {
-        unimplemented!()
+fn is_closed(state: &State) -> bool {
+    match state.status {
+        Status::Active => false,
+        Status::Closed => true,
     }
 }
@@ -1,4 +1,5 @@
 pub mod transfer;
 pub mod account;
+pub mod transfer_saga;
 
 pub type Money = i64;
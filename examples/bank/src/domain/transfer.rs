@@ -1,10 +1,10 @@
-use chronicle_domain::Aggregate;
 use uuid::Uuid;
 
 use super::Money;
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize, EventType, ApplyEvent)]
 #[serde(tag = "type", rename_all = "snake_case")]
+#[apply_event(state = "State")]
 pub enum Event {
     MoneyTransferRequested {
         debit_account: Uuid,
@@ -23,7 +23,9 @@ pub enum Command {
 }
 
 #[derive(Debug, Clone)]
-pub enum CommandError {}
+pub enum CommandError {
+    AlreadyRequested,
+}
 
 #[derive(Debug, Clone)]
 pub enum Status {
@@ -32,33 +34,54 @@ pub enum Status {
 
 #[derive(Debug, Clone)]
 pub struct State {
-    pub transfer_uuid: Uuid,
     pub debit_account: Uuid,
     pub credit_account: Uuid,
     pub amount: Money,
     pub state: Status,
 }
 
-pub struct Transfer;
+impl State {
+    fn apply_money_transfer_requested(&mut self,
+                                      _debit_account: Uuid,
+                                      _credit_account: Uuid,
+                                      _amount: Money) {
+    }
+}
 
-impl Aggregate for Transfer {
-    type State = Option<State>;
-    type Event = Event;
-    type Command = Command;
-    type CommandError = CommandError;
-    type EventsFuture = Result<Vec<Event>, CommandError>;
+#[derive(Aggregate)]
+#[aggregate(state = "State", event = "Event", command = "Command",
+            command_error = "CommandError")]
+pub struct Transfer;
 
-    fn initial_state() -> Option<State> {
-        None
+impl Transfer {
+    fn create(command: Command) -> Result<Vec<Event>, CommandError> {
+        match command {
+            Command::TransferMoney { debit_account, credit_account, amount } => {
+                Ok(vec![Event::MoneyTransferRequested {
+                            debit_account: debit_account,
+                            credit_account: credit_account,
+                            amount: amount,
+                        }])
+            },
+        }
     }
 
-    fn handle_command(_state: &Option<State>,
-                      _command: Command)
-                      -> Result<Vec<Event>, CommandError> {
-        unimplemented!()
+    fn handle(_state: &State, command: Command) -> Result<Vec<Event>, CommandError> {
+        match command {
+            Command::TransferMoney { .. } => Err(CommandError::AlreadyRequested),
+        }
     }
 
-    fn apply_event(_state: &mut Option<State>, _command: Event) {
-        unimplemented!()
+    fn created(event: Event) -> Option<State> {
+        match event {
+            Event::MoneyTransferRequested { debit_account, credit_account, amount } => {
+                Some(State {
+                    debit_account: debit_account,
+                    credit_account: credit_account,
+                    amount: amount,
+                    state: Status::Requested,
+                })
+            },
+        }
     }
 }
@@ -0,0 +1,374 @@
+//! Moves money between accounts once a transfer has been requested, by
+//! withdrawing it from the debit account and then depositing it into the
+//! credit account. If the deposit is rejected, or takes too long, the money
+//! is refunded to the debit account. The transfer is abandoned if the debit
+//! account doesn't accept the withdrawal in time, for example because it has
+//! been closed.
+
+use chronicle::{EventType, PersistedEvent};
+use chronicle_domain::command_bus::Addressed;
+use chronicle_domain::saga::{Outcome, Saga, Step};
+use std::time::Duration;
+use uuid::Uuid;
+
+use super::Money;
+use super::account::{self, Account};
+use super::transfer;
+
+/// How long to wait for the debit account to accept the withdrawal
+pub const WITHDRAW_TIMEOUT_SECS: u64 = 60;
+
+/// How long to wait for the credit account to accept the deposit
+pub const DEPOSIT_TIMEOUT_SECS: u64 = 60;
+
+/// The events from the accounts and transfers that the saga reacts to
+#[derive(Debug, Clone)]
+pub enum Input {
+    Account(account::Event),
+    Transfer(transfer::Event),
+}
+
+impl EventType for Input {
+    fn event_type(&self) -> &str {
+        match *self {
+            Input::Account(ref event) => event.event_type(),
+            Input::Transfer(ref event) => event.event_type(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Data {
+    pub debit_account: Uuid,
+    pub credit_account: Uuid,
+    pub amount: Money,
+}
+
+pub struct TransferSaga;
+
+impl Saga for TransferSaga {
+    type Input = Input;
+    type Data = Data;
+    type Command = Addressed<Account>;
+
+    fn saga_id<Offset>(event: &PersistedEvent<Offset, Input>) -> Option<Uuid> {
+        use self::account::Event::*;
+        use self::transfer::Event::*;
+
+        match event.payload {
+            Input::Transfer(MoneyTransferRequested { .. }) => Some(event.source_id),
+            Input::Account(MoneyWithdrawn { transfer_id, .. }) |
+            Input::Account(MoneyDeposited { transfer_id, .. }) |
+            Input::Account(MoneyDepositRejected { transfer_id, .. }) => Some(transfer_id),
+            Input::Account(_) => None,
+        }
+    }
+
+    fn start(event: &Input) -> Option<Data> {
+        match *event {
+            Input::Transfer(transfer::Event::MoneyTransferRequested { debit_account,
+                                                                      credit_account,
+                                                                      amount }) => {
+                Some(Data {
+                    debit_account: debit_account,
+                    credit_account: credit_account,
+                    amount: amount,
+                })
+            },
+            Input::Account(_) => None,
+        }
+    }
+
+    fn steps() -> Vec<Step<TransferSaga>> {
+        vec![Step::new(withdraw, withdrawn)
+                 .with_compensation(refund)
+                 .with_timeout(Duration::from_secs(WITHDRAW_TIMEOUT_SECS)),
+             Step::new(deposit, deposited).with_timeout(Duration::from_secs(DEPOSIT_TIMEOUT_SECS))]
+    }
+}
+
+fn withdraw(transfer_id: Uuid, data: &Data) -> Addressed<Account> {
+    let command = account::Command::WithdrawMoney {
+        transfer_id: transfer_id,
+        amount: data.amount,
+    };
+
+    Addressed::new(data.debit_account, command)
+}
+
+fn withdrawn(_: &Data, event: &Input) -> Option<Outcome> {
+    match *event {
+        Input::Account(account::Event::MoneyWithdrawn { .. }) => Some(Outcome::Completed),
+        _ => None,
+    }
+}
+
+fn refund(transfer_id: Uuid, data: &Data) -> Addressed<Account> {
+    let command = account::Command::RefundMoney {
+        transfer_id: transfer_id,
+        amount: data.amount,
+    };
+
+    Addressed::new(data.debit_account, command)
+}
+
+fn deposit(transfer_id: Uuid, data: &Data) -> Addressed<Account> {
+    let command = account::Command::DepositMoney {
+        transfer_id: transfer_id,
+        amount: data.amount,
+    };
+
+    Addressed::new(data.credit_account, command)
+}
+
+fn deposited(_: &Data, event: &Input) -> Option<Outcome> {
+    match *event {
+        Input::Account(account::Event::MoneyDeposited { .. }) => Some(Outcome::Completed),
+        Input::Account(account::Event::MoneyDepositRejected { .. }) => Some(Outcome::Failed),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chronicle::{EventStore, NewEvent};
+    use chronicle::ExpectedVersion::*;
+    use chronicle_domain::Repository;
+    use chronicle_domain::clock::ManualClock;
+    use chronicle_domain::process_manager::ProcessRunner;
+    use chronicle_domain::repository::RepositoryError;
+    use chronicle_domain::saga::{SagaEvent, SagaProcess, SagaState};
+    use chronicle_memory::{MemoryCheckpointStore, MemoryEventStore};
+    use futures::Future;
+    use std::cell::RefCell;
+    use std::time::UNIX_EPOCH;
+
+    use super::*;
+
+    type Runner = ProcessRunner<SagaProcess<TransferSaga>,
+                                MemoryEventStore<SagaEvent<Data>>,
+                                MemoryCheckpointStore<usize>,
+                                ManualClock>;
+
+    /// The accounts and the transfer saga, with the events of both
+    /// aggregates published to a single event stream
+    struct Bank {
+        events: MemoryEventStore<Input>,
+        accounts: Repository<Account, MemoryEventStore<account::Event>>,
+        clock: ManualClock,
+        runner: Runner,
+    }
+
+    impl Bank {
+        fn new() -> Bank {
+            let clock = ManualClock::new(UNIX_EPOCH);
+
+            Bank {
+                events: MemoryEventStore::new(),
+                accounts: Repository::new(MemoryEventStore::new()),
+                clock: clock.clone(),
+                runner: ProcessRunner::new("transfers",
+                                           MemoryEventStore::new(),
+                                           MemoryCheckpointStore::new())
+                    .with_clock(clock),
+            }
+        }
+
+        fn open_account(&self, initial_balance: Money) -> Uuid {
+            let account_id = Uuid::new_v4();
+            let command = account::Command::OpenAccount { initial_balance: initial_balance };
+
+            self.execute(Addressed::new(account_id, command));
+            account_id
+        }
+
+        fn close_account(&self, account_id: Uuid) {
+            self.execute(Addressed::new(account_id, account::Command::CloseAccount));
+        }
+
+        fn balance(&self, account_id: Uuid) -> Money {
+            let (state, _) = self.accounts.load(account_id).wait().unwrap();
+            state.unwrap().balance
+        }
+
+        /// Request a transfer, returning the id of the transfer
+        fn transfer(&self, debit_account: Uuid, credit_account: Uuid, amount: Money) -> Uuid {
+            let transfer_id = Uuid::new_v4();
+            let event = transfer::Event::MoneyTransferRequested {
+                debit_account: debit_account,
+                credit_account: credit_account,
+                amount: amount,
+            };
+
+            self.events
+                .append_events(transfer_id, NoStream, vec![NewEvent::new(Input::Transfer(event))])
+                .wait()
+                .unwrap();
+            transfer_id
+        }
+
+        /// Execute a command against an account, publishing the resulting
+        /// events. Rejected commands are dropped, and the saga finds out
+        /// about them by timing out.
+        fn execute(&self, command: Addressed<Account>) {
+            let account_id = command.aggregate_id;
+            let events = match self.accounts.execute(account_id, command.command).wait() {
+                Ok(events) => events,
+                Err(RepositoryError::Command(_)) => return,
+                Err(error) => panic!("failed to execute command: {:?}", error),
+            };
+
+            let new_events = events.into_iter()
+                .map(|event| NewEvent::new(Input::Account(event)))
+                .collect();
+
+            self.events.append_events(account_id, Any, new_events).wait().unwrap();
+        }
+
+        /// Run the saga, returning the commands that were dispatched
+        fn run(&self) -> Vec<Addressed<Account>> {
+            let commands = RefCell::new(Vec::new());
+            let dispatch = |command| {
+                commands.borrow_mut().push(command);
+                Ok::<_, ()>(())
+            };
+
+            self.runner.run(&self.events, dispatch).wait().unwrap();
+            commands.into_inner()
+        }
+
+        /// Time out the transfer, returning the commands that were
+        /// dispatched
+        fn expire(&self, transfer_id: Uuid) -> Vec<Addressed<Account>> {
+            let commands = RefCell::new(Vec::new());
+            let dispatch = |command| {
+                commands.borrow_mut().push(command);
+                Ok::<_, ()>(())
+            };
+
+            assert!(self.runner.expire(transfer_id, dispatch).wait().unwrap());
+            commands.into_inner()
+        }
+
+        /// Run the saga, executing the commands that it dispatches until
+        /// there are no more
+        fn settle(&self) {
+            loop {
+                let commands = self.run();
+                if commands.is_empty() {
+                    return;
+                }
+                for command in commands {
+                    self.execute(command);
+                }
+            }
+        }
+
+        fn state(&self, transfer_id: Uuid) -> SagaState<Data> {
+            self.runner.load(transfer_id).wait().unwrap().0
+        }
+    }
+
+    #[test]
+    fn transfer_money() {
+        let bank = Bank::new();
+        let debit_account = bank.open_account(100);
+        let credit_account = bank.open_account(0);
+
+        let transfer_id = bank.transfer(debit_account, credit_account, 30);
+        bank.settle();
+
+        assert_eq!(bank.balance(debit_account), 70);
+        assert_eq!(bank.balance(credit_account), 30);
+        assert_eq!(bank.state(transfer_id),
+                   SagaState::Completed {
+                       data: Data {
+                           debit_account: debit_account,
+                           credit_account: credit_account,
+                           amount: 30,
+                       },
+                   });
+    }
+
+    #[test]
+    fn transfer_money_to_the_same_account() {
+        let bank = Bank::new();
+        let account = bank.open_account(100);
+
+        let transfer_id = bank.transfer(account, account, 30);
+        bank.settle();
+
+        assert_eq!(bank.balance(account), 100);
+        match bank.state(transfer_id) {
+            SagaState::Completed { .. } => {},
+            state => panic!("unexpected state: {:?}", state),
+        }
+    }
+
+    #[test]
+    fn refund_rejected_deposit() {
+        let bank = Bank::new();
+        let debit_account = bank.open_account(100);
+        let credit_account = bank.open_account(0);
+        bank.close_account(credit_account);
+
+        let transfer_id = bank.transfer(debit_account, credit_account, 30);
+        bank.settle();
+
+        assert_eq!(bank.balance(debit_account), 100);
+        assert_eq!(bank.balance(credit_account), 0);
+        match bank.state(transfer_id) {
+            SagaState::Aborted { step: 1, .. } => {},
+            state => panic!("unexpected state: {:?}", state),
+        }
+    }
+
+    #[test]
+    fn refund_deposit_that_times_out() {
+        let bank = Bank::new();
+        let debit_account = bank.open_account(100);
+        let credit_account = bank.open_account(0);
+
+        let transfer_id = bank.transfer(debit_account, credit_account, 30);
+        for command in bank.run() {
+            bank.execute(command);
+        }
+
+        // The deposit is never delivered
+        assert_eq!(bank.run().len(), 1);
+        assert_eq!(bank.balance(debit_account), 70);
+
+        bank.clock.advance(Duration::from_secs(DEPOSIT_TIMEOUT_SECS));
+        for command in bank.expire(transfer_id) {
+            bank.execute(command);
+        }
+
+        assert_eq!(bank.balance(debit_account), 100);
+        assert_eq!(bank.balance(credit_account), 0);
+        match bank.state(transfer_id) {
+            SagaState::Aborted { step: 1, .. } => {},
+            state => panic!("unexpected state: {:?}", state),
+        }
+    }
+
+    #[test]
+    fn abandon_rejected_withdrawal() {
+        let bank = Bank::new();
+        let debit_account = bank.open_account(100);
+        let credit_account = bank.open_account(0);
+        bank.close_account(debit_account);
+
+        let transfer_id = bank.transfer(debit_account, credit_account, 30);
+        bank.settle();
+
+        bank.clock.advance(Duration::from_secs(WITHDRAW_TIMEOUT_SECS));
+        assert_eq!(bank.expire(transfer_id).len(), 0);
+
+        assert_eq!(bank.balance(debit_account), 100);
+        assert_eq!(bank.balance(credit_account), 0);
+        match bank.state(transfer_id) {
+            SagaState::Aborted { step: 0, .. } => {},
+            state => panic!("unexpected state: {:?}", state),
+        }
+    }
+}
@@ -1,6 +1,9 @@
 #![feature(plugin)]
 #![plugin(rocket_codegen)]
 
+extern crate chronicle;
+#[macro_use]
+extern crate chronicle_derive;
 extern crate chronicle_domain;
 extern crate chronicle_memory;
 extern crate futures;
@@ -1,4 +1,6 @@
 extern crate chronicle_domain;
+#[cfg(test)]
+extern crate quickcheck;
 
 use chronicle_domain::Aggregate;
 
@@ -6,16 +8,19 @@ pub struct State {
     pub count: i64,
 }
 
+#[derive(Debug, Clone, PartialEq)]
 pub enum Event {
     AmountAdded { amount: i64 },
 }
 
+#[derive(Debug, Clone, PartialEq)]
 pub enum Command {
     Increment,
     Decrement,
     Reset,
 }
 
+#[derive(Debug, Clone, PartialEq)]
 pub enum CommandError {
     OutOfBounds,
 }
@@ -35,10 +40,10 @@ impl Aggregate for Counter {
 
     fn handle_command(state: &i64, command: Command) -> Result<Vec<Event>, CommandError> {
         match command {
-            Command::Increment if *state + 1 <= 100 => Err(CommandError::OutOfBounds),
-            Command::Decrement if *state - 1 >= 0 => Err(CommandError::OutOfBounds),
-            Command::Increment => Ok(vec![Event::AmountAdded { amount: *state + 1 }]),
-            Command::Decrement => Ok(vec![Event::AmountAdded { amount: *state - 1 }]),
+            Command::Increment if *state + 1 > 100 => Err(CommandError::OutOfBounds),
+            Command::Decrement if *state - 1 < 0 => Err(CommandError::OutOfBounds),
+            Command::Increment => Ok(vec![Event::AmountAdded { amount: 1 }]),
+            Command::Decrement => Ok(vec![Event::AmountAdded { amount: -1 }]),
             Command::Reset => Ok(vec![Event::AmountAdded { amount: -*state }]),
         }
     }
@@ -49,3 +54,48 @@ impl Aggregate for Counter {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use chronicle_domain::laws;
+    use chronicle_domain::testing::given;
+    use quickcheck::{Arbitrary, Gen};
+
+    use super::*;
+
+    impl Arbitrary for Command {
+        fn arbitrary<G: Gen>(g: &mut G) -> Command {
+            match g.gen_range(0, 3) {
+                0 => Command::Increment,
+                1 => Command::Decrement,
+                _ => Command::Reset,
+            }
+        }
+    }
+
+    #[test]
+    fn obeys_laws() {
+        laws::check_laws_with::<Counter, _>(|count| 0 <= *count && *count <= 100);
+    }
+
+    #[test]
+    fn handles_increment() {
+        given::<Counter>(vec![])
+            .when(Command::Increment)
+            .then_expect(vec![Event::AmountAdded { amount: 1 }]);
+    }
+
+    #[test]
+    fn handles_increment_out_of_bounds() {
+        given::<Counter>(vec![Event::AmountAdded { amount: 100 }])
+            .when(Command::Increment)
+            .then_error(CommandError::OutOfBounds);
+    }
+
+    #[test]
+    fn handles_decrement_out_of_bounds() {
+        given::<Counter>(vec![])
+            .when(Command::Decrement)
+            .then_error(CommandError::OutOfBounds);
+    }
+}
@@ -0,0 +1,27 @@
+extern crate chronicle_domain;
+extern crate counter_example;
+
+use chronicle_domain::Aggregate;
+use counter_example::{Command, Counter};
+
+fn main() {
+    let commands = vec![Command::Decrement,
+                        Command::Increment,
+                        Command::Increment,
+                        Command::Decrement,
+                        Command::Reset];
+
+    let mut state = Counter::initial_state();
+    for command in commands {
+        print!("{:?}: ", command);
+        match Counter::handle_command(&state, command) {
+            Ok(events) => {
+                for event in events {
+                    Counter::apply_event(&mut state, event);
+                }
+                println!("{}", state);
+            },
+            Err(error) => println!("{:?}", error),
+        }
+    }
+}
@@ -1,11 +1,60 @@
-use chronicle_memory::MemoryEventStore;
+use chronicle_domain::{CommandBus, Repository, Scheduler};
+use chronicle_domain::command_bus::{Addressed, DispatchError};
+use chronicle_memory::{MemoryDeadlineStore, MemoryEventStore};
+use futures::Future;
 use rocket;
+use std::thread;
+use std::time::Duration;
 
-use domain::task::Event;
+use domain::task::{Event, Task};
 
 pub mod tasks;
 
-pub fn launch(event_store: MemoryEventStore<Event>) {
+/// A command addressed to a task
+pub type TaskCommand = Addressed<Task>;
+
+/// Schedules commands to be dispatched to tasks at a later time
+pub type TaskScheduler = Scheduler<MemoryDeadlineStore<TaskCommand>>;
+
+/// How often to check for commands that are due
+const POLL_INTERVAL_SECS: u64 = 1;
+
+fn command_bus(event_store: MemoryEventStore<Event>) -> CommandBus {
+    let mut command_bus = CommandBus::new();
+    command_bus.register::<TaskCommand, _>(Repository::new(event_store));
+    command_bus
+}
+
+/// Dispatch the scheduled commands as they become due. Commands that are
+/// rejected by the task, for example because it doesn't exist, no longer
+/// apply and are dropped. Commands that fail for any other reason are left
+/// pending, and are dispatched again on the next poll.
+fn spawn_scheduler(event_store: MemoryEventStore<Event>, scheduler: TaskScheduler) {
+    let command_bus = command_bus(event_store);
+
+    thread::spawn(move || loop {
+        let dispatched = scheduler.dispatch_due(|command| {
+            command_bus.dispatch(command).map(|_| ())
+        });
+        let dispatched = dispatched.wait().unwrap_or_else(|err| match err {});
+
+        for failure in dispatched.failed {
+            if let DispatchError::Command(_) = failure.error {
+                scheduler.acknowledge(failure.deadline_id, failure.due_at)
+                    .wait()
+                    .unwrap_or_else(|err| match err {});
+            }
+        }
+
+        thread::sleep(Duration::from_secs(POLL_INTERVAL_SECS));
+    });
+}
+
+pub fn launch(event_store: MemoryEventStore<Event>,
+              deadline_store: MemoryDeadlineStore<TaskCommand>) {
+    let scheduler = Scheduler::new(deadline_store);
+    spawn_scheduler(event_store.clone(), scheduler.clone());
+
     rocket::ignite()
         .mount("/api/",
                routes![
@@ -14,6 +63,7 @@ pub fn launch(event_store: MemoryEventStore<Event>) {
             tasks::complete,
             tasks::archive,
         ])
-        .manage(event_store)
+        .manage(command_bus(event_store))
+        .manage(scheduler)
         .launch();
 }
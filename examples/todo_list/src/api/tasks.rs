@@ -1,15 +1,16 @@
 #![allow(unused_variables)]
 
 
-use chronicle::EventStore;
-use chronicle_domain::Aggregate;
-use chronicle_memory::{MemoryEventStore, EventsStreamError};
-use futures::{Future, Stream};
+use chronicle_domain::CommandBus;
+use chronicle_domain::command_bus::DispatchError;
+use futures::Future;
 use rocket::State;
 use rocket_contrib::{JSON, UUID, Value};
+use std::time::Duration;
 use uuid::Uuid;
 
-use domain::task::{Command, Event, Task, State as TaskState};
+use api::{TaskCommand, TaskScheduler};
+use domain::task::{Command, CommandError};
 
 
 #[derive(Debug, Clone, Deserialize)]
@@ -24,79 +25,70 @@ pub struct ChangeDescriptionData {
 }
 
 
-fn initial_state<E>(id: Uuid,
-                    event_store: &MemoryEventStore<Event>)
-                    -> impl Future<Item = Option<TaskState>, Error = E> {
-    fn cast_error<T>(err: EventsStreamError) -> T {
-        match err {}
-    }
+/// How long a task may remain incomplete before it is archived
+const ARCHIVE_AFTER_SECS: u64 = 7 * 24 * 60 * 60;
 
-    event_store.events(id, 0)
-        .map_err(cast_error::<E>)
-        .fold(Task::initial_state(), |mut state, persisted_event| {
-            Task::apply_event(&mut state, persisted_event.payload);
-            Ok(state)
-        })
-}
+
+/// An error that may be returned from the task handlers
+pub type Error = DispatchError<CommandError>;
 
 
 #[post("/tasks", format = "application/json", data = "<data>")]
 pub fn create(data: JSON<CreateTaskData>,
-              event_store: State<MemoryEventStore<Event>>)
-              -> JSON<Value> {
+              command_bus: State<CommandBus>,
+              scheduler: State<TaskScheduler>)
+              -> Result<JSON<Value>, Error> {
     let id = Uuid::new_v4();
     let data = data.into_inner();
     let command = Command::Create(data.description);
 
-    initial_state(id, &event_store)
-        .and_then(|state| Task::handle_command(&state, command))
-        .and_then(|events| Ok(event_store.append_events(id, events)))
+    command_bus.dispatch(TaskCommand::new(id, command)).wait()?;
+
+    // Archive the task if it hasn't been completed in time. The deadline
+    // shares the id of the task, so that it can be cancelled later on.
+    let archive_after = Duration::from_secs(ARCHIVE_AFTER_SECS);
+    scheduler.schedule_in(id, archive_after, TaskCommand::new(id, Command::Archive))
         .wait()
-        .unwrap();
+        .unwrap_or_else(|err| match err {});
 
-    JSON(json!({
+    Ok(JSON(json!({
         "id": id,
-    }))
+    })))
 }
 
 
 #[post("/tasks/<id>/change_description", format = "application/json", data = "<data>")]
 pub fn change_description(id: UUID,
                           data: JSON<ChangeDescriptionData>,
-                          event_store: State<MemoryEventStore<Event>>) {
+                          command_bus: State<CommandBus>)
+                          -> Result<(), Error> {
     let id = id.into_inner();
     let data = data.into_inner();
     let command = Command::ChangeDescription(data.description);
 
-    initial_state(id, &event_store)
-        .and_then(|state| Task::handle_command(&state, command))
-        .and_then(|events| Ok(event_store.append_events(id, events)))
-        .wait()
-        .unwrap();
+    command_bus.dispatch(TaskCommand::new(id, command)).map(|_| ()).wait()
 }
 
 
 #[post("/tasks/<id>/complete", format = "application/json")]
-pub fn complete(id: UUID, event_store: State<MemoryEventStore<Event>>) {
+pub fn complete(id: UUID,
+                command_bus: State<CommandBus>,
+                scheduler: State<TaskScheduler>)
+                -> Result<(), Error> {
     let id = id.into_inner();
     let command = Command::Complete;
 
-    initial_state(id, &event_store)
-        .and_then(|state| Task::handle_command(&state, command))
-        .and_then(|events| Ok(event_store.append_events(id, events)))
-        .wait()
-        .unwrap();
+    command_bus.dispatch(TaskCommand::new(id, command)).wait()?;
+    scheduler.cancel(id).wait().unwrap_or_else(|err| match err {});
+
+    Ok(())
 }
 
 
 #[post("/tasks/<id>/archive", format = "application/json")]
-pub fn archive(id: UUID, event_store: State<MemoryEventStore<Event>>) {
+pub fn archive(id: UUID, command_bus: State<CommandBus>) -> Result<(), Error> {
     let id = id.into_inner();
     let command = Command::Archive;
 
-    initial_state(id, &event_store)
-        .and_then(|state| Task::handle_command(&state, command))
-        .and_then(|events| Ok(event_store.append_events(id, events)))
-        .wait()
-        .unwrap();
+    command_bus.dispatch(TaskCommand::new(id, command)).map(|_| ()).wait()
 }
@@ -1,7 +1,6 @@
-use chronicle_domain::Aggregate;
-
-#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, EventType, ApplyEvent)]
 #[serde(tag = "type", rename_all = "snake_case")]
+#[apply_event(state = "State")]
 pub enum Event {
     Created { description: String },
     DescriptionChanged { description: String },
@@ -43,58 +42,55 @@ impl State {
             status: status,
         }
     }
+
+    fn apply_created(&mut self, _description: String) {
+        // TODO: Log?
+    }
+
+    fn apply_description_changed(&mut self, description: String) {
+        self.description = description;
+    }
+
+    fn apply_completed(&mut self) {
+        self.status = Status::Completed;
+    }
+
+    fn apply_archived(&mut self) {
+        self.status = Status::Archived;
+    }
 }
 
+#[derive(Aggregate)]
+#[aggregate(state = "State", event = "Event", command = "Command", command_error = "CommandError")]
 pub struct Task;
 
-impl Aggregate for Task {
-    type State = Option<State>;
-    type Event = Event;
-    type Command = Command;
-    type CommandError = CommandError;
-    type EventsFuture = Result<Vec<Event>, CommandError>;
-
-    fn initial_state() -> Option<State> {
-        None
+impl Task {
+    fn create(command: Command) -> Result<Vec<Event>, CommandError> {
+        match command {
+            Command::Create(description) => Ok(vec![Event::Created { description: description }]),
+            _ => Err(CommandError::NotYetCreated),
+        }
     }
 
-    fn handle_command(state: &Option<State>, command: Command) -> Result<Vec<Event>, CommandError> {
+    fn handle(state: &State, command: Command) -> Result<Vec<Event>, CommandError> {
         use self::Command::*;
         use self::Event::*;
 
-        if let Some(ref state) = *state {
-            match command {
-                Create(_) => Err(CommandError::AlreadyCreated),
-                ChangeDescription(ref d) if *d == state.description => Ok(vec![]),
-                ChangeDescription(d) => Ok(vec![DescriptionChanged { description: d }]),
-                Complete if state.status == Status::Completed => Ok(vec![]),
-                Complete => Ok(vec![Event::Completed]),
-                Archive if state.status == Status::Archived => Ok(vec![]),
-                Archive => Ok(vec![Event::Archived]),
-            }
-        } else {
-            match command {
-                Create(description) => Ok(vec![Created { description: description }]),
-                _ => Err(CommandError::NotYetCreated),
-            }
+        match command {
+            Create(_) => Err(CommandError::AlreadyCreated),
+            ChangeDescription(ref d) if *d == state.description => Ok(vec![]),
+            ChangeDescription(d) => Ok(vec![DescriptionChanged { description: d }]),
+            Complete if state.status == Status::Completed => Ok(vec![]),
+            Complete => Ok(vec![Event::Completed]),
+            Archive if state.status == Status::Archived => Ok(vec![]),
+            Archive => Ok(vec![Event::Archived]),
         }
     }
 
-    fn apply_event(state: &mut Option<State>, event: Event) {
-        use self::Event::*;
-
-        if let Some(ref mut state) = *state {
-            match event {
-                Created { .. } => (), // TODO: Log?
-                DescriptionChanged { description } => state.description = description,
-                Completed => state.status = Status::Completed,
-                Archived => state.status = Status::Archived,
-            }
-        } else {
-            match event {
-                Created { description } => *state = Some(State::new(description, Status::Active)),
-                _ => (), // TODO: Log?
-            }
+    fn created(event: Event) -> Option<State> {
+        match event {
+            Event::Created { description } => Some(State::new(description, Status::Active)),
+            _ => None, // TODO: Log?
         }
     }
 }
@@ -102,112 +98,106 @@ impl Aggregate for Task {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use chronicle_domain::Aggregate;
+    use chronicle_domain::testing::given;
 
-    // TODO: These tests are very reptitive - would property based testing help?
+    fn created(description: &str) -> Event {
+        Event::Created { description: description.to_string() }
+    }
 
     #[test]
     fn handles_initial_create() {
-        let command = Command::Create("hi".to_string());
-
-        assert_eq!(Task::handle_command(&None, command),
-                   Ok(vec![Event::Created { description: "hi".to_string() }]));
+        given::<Task>(vec![])
+            .when(Command::Create("hi".to_string()))
+            .then_expect(vec![created("hi")]);
     }
 
     #[test]
     fn handles_initial_change_description() {
-        let command = Command::ChangeDescription("hi".to_string());
-
-        assert_eq!(Task::handle_command(&None, command),
-                   Err(CommandError::NotYetCreated));
+        given::<Task>(vec![])
+            .when(Command::ChangeDescription("hi".to_string()))
+            .then_error(CommandError::NotYetCreated);
     }
 
     #[test]
     fn handles_initial_completed() {
-        assert_eq!(Task::handle_command(&None, Command::Complete),
-                   Err(CommandError::NotYetCreated));
+        given::<Task>(vec![])
+            .when(Command::Complete)
+            .then_error(CommandError::NotYetCreated);
     }
 
     #[test]
     fn handles_initial_archive() {
-        assert_eq!(Task::handle_command(&None, Command::Archive),
-                   Err(CommandError::NotYetCreated));
+        given::<Task>(vec![])
+            .when(Command::Archive)
+            .then_error(CommandError::NotYetCreated);
     }
 
     #[test]
     fn handles_create() {
-        let state = State::new("hi".to_string(), Status::Active);
-        let command = Command::Create("yoho".to_string());
-
-        assert_eq!(Task::handle_command(&Some(state), command),
-                   Err(CommandError::AlreadyCreated));
+        given::<Task>(vec![created("hi")])
+            .when(Command::Create("yoho".to_string()))
+            .then_error(CommandError::AlreadyCreated);
     }
 
     #[test]
     fn handles_change_description_if_different() {
-        let state = State::new("hi".to_string(), Status::Active);
-        let command = Command::ChangeDescription("yoho".to_string());
-
-        assert_eq!(Task::handle_command(&Some(state), command),
-                   Ok(vec![Event::DescriptionChanged { description: "yoho".to_string() }]));
+        given::<Task>(vec![created("hi")])
+            .when(Command::ChangeDescription("yoho".to_string()))
+            .then_expect(vec![Event::DescriptionChanged { description: "yoho".to_string() }]);
     }
 
     #[test]
     fn ignores_change_description_on_no_change() {
-        let state = State::new("hi".to_string(), Status::Active);
-        let command = Command::ChangeDescription("hi".to_string());
-
-        assert_eq!(Task::handle_command(&Some(state), command), Ok(vec![]));
+        given::<Task>(vec![created("hi")])
+            .when(Command::ChangeDescription("hi".to_string()))
+            .then_expect(vec![]);
     }
 
     #[test]
     fn handles_complete_if_different() {
-        let state = State::new("hi".to_string(), Status::Active);
-
-        assert_eq!(Task::handle_command(&Some(state), Command::Complete),
-                   Ok(vec![Event::Completed]));
+        given::<Task>(vec![created("hi")])
+            .when(Command::Complete)
+            .then_expect(vec![Event::Completed]);
     }
 
     #[test]
     fn ignores_complete_on_no_change() {
-        let state = State::new("hi".to_string(), Status::Completed);
-
-        assert_eq!(Task::handle_command(&Some(state), Command::Complete),
-                   Ok(vec![]));
+        given::<Task>(vec![created("hi"), Event::Completed])
+            .when(Command::Complete)
+            .then_expect(vec![]);
     }
 
     #[test]
     fn handles_archive_if_different() {
-        let state = State::new("hi".to_string(), Status::Active);
-
-        assert_eq!(Task::handle_command(&Some(state), Command::Archive),
-                   Ok(vec![Event::Archived]));
+        given::<Task>(vec![created("hi")])
+            .when(Command::Archive)
+            .then_expect(vec![Event::Archived]);
     }
 
     #[test]
     fn ignores_archive_on_no_change() {
-        let state = State::new("hi".to_string(), Status::Archived);
-
-        assert_eq!(Task::handle_command(&Some(state), Command::Archive),
-                   Ok(vec![]));
+        given::<Task>(vec![created("hi"), Event::Archived])
+            .when(Command::Archive)
+            .then_expect(vec![]);
     }
 
     #[test]
     fn applies_initial_created() {
-        let mut state = None;
-        let event = Event::Created { description: "hi".to_string() };
-        Task::apply_event(&mut state, event);
-
-        assert_eq!(state, Some(State::new("hi".to_string(), Status::Active)));
+        given::<Task>(vec![created("hi")])
+            .then_state(Some(State::new("hi".to_string(), Status::Active)));
     }
 
     #[test]
     fn applies_created() {
-        let mut state = Some(State::new("hi".to_string(), Status::Active));
-        let event = Event::Created { description: "HELLO".to_string() };
-        Task::apply_event(&mut state, event);
+        given::<Task>(vec![created("hi"), created("HELLO")])
+            .then_state(Some(State::new("hi".to_string(), Status::Active)));
+    }
 
-        assert_eq!(state, Some(State::new("hi".to_string(), Status::Active)));
+    #[test]
+    fn applies_description_changed() {
+        given::<Task>(vec![created("hi"),
+                           Event::DescriptionChanged { description: "yoho".to_string() }])
+            .then_state(Some(State::new("yoho".to_string(), Status::Active)));
     }
 
     // TODO: more tests?
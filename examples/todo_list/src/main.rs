@@ -4,11 +4,12 @@ extern crate rocket;
 extern crate futures;
 
 
-use chronicle_memory::MemoryEventStore;
+use chronicle_memory::{MemoryDeadlineStore, MemoryEventStore};
 
 
 fn main() {
     let event_store = MemoryEventStore::new();
+    let deadline_store = MemoryDeadlineStore::new();
 
-    todo_list_example::api::launch(event_store);
+    todo_list_example::api::launch(event_store, deadline_store);
 }